@@ -64,6 +64,90 @@ fn default_cache_path() -> PathBuf {
     PathBuf::from("/tmp")
 }
 
+fn default_health_check_path() -> Cow<'static, str> {
+    Cow::Borrowed("/")
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    1
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    30
+}
+
+fn default_health_check_threshold() -> usize {
+    1
+}
+
+fn default_circuit_breaker_failures() -> usize {
+    5
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_static_index() -> Vec<Cow<'static, str>> {
+    vec![Cow::Borrowed("index.html")]
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+fn default_mirror_percent() -> u8 {
+    100
+}
+
+fn default_mirror_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_mirror_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_sticky_cookie_name() -> Cow<'static, str> {
+    Cow::Borrowed("proksi_sticky")
+}
+
+fn default_forwarded_headers() -> Vec<ForwardedHeader> {
+    vec![
+        ForwardedHeader::XForwardedFor,
+        ForwardedHeader::XForwardedProto,
+        ForwardedHeader::XForwardedHost,
+    ]
+}
+
+fn default_grpc_web_max_age_secs() -> u64 {
+    86400
+}
+
+fn default_listener_idle_timeout_secs() -> u64 {
+    60
+}
+
+fn default_listener_max_sessions() -> usize {
+    4096
+}
+
+fn default_proxy_protocol_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_http3_alt_svc_max_age_secs() -> u64 {
+    86400
+}
+
+fn default_retry_max_attempts() -> usize {
+    3
+}
+
+fn default_retry_on() -> Vec<RetryCondition> {
+    vec![RetryCondition::ConnectError]
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, ValueEnum)]
 pub(crate) enum DockerServiceMode {
     Swarm,
@@ -194,9 +278,11 @@ pub struct RouteHeader {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteUpstream {
     /// The TCP address of the upstream (ex. 10.0.0.1/24 etc)
+    /// or the path of its unix domain socket (ex: unix:///run/app.sock)
     pub ip: Cow<'static, str>,
 
-    /// The port of the upstream (ex: 3000, 5000, etc.)
+    /// The port of the upstream (ex: 3000, 5000, etc.), unset for unix domain sockets
+    #[serde(default)]
     pub port: u16,
 
     /// The network of the upstream (ex: 'public', 'shared') -- useful for docker discovery
@@ -204,11 +290,31 @@ pub struct RouteUpstream {
 
     /// Optional: The weight of the upstream (ex: 1, 2, 3, etc.) --
     /// used for weight-based load balancing.
+    /// An upstream with weight 3 receives three times the requests
+    /// of an upstream with weight 1 (default: 1).
     pub weight: Option<i8>,
 
     pub sni: Option<String>,
 
     pub headers: Option<RouteHeader>,
+
+    /// Optional: connection options for this upstream,
+    /// overriding the ones of the route
+    pub peer_options: Option<RoutePeerOptions>,
+
+    /// Optional: TLS settings used to connect to this upstream
+    pub tls: Option<RouteUpstreamTls>,
+
+    /// Optional: sends the address of the client to this upstream
+    /// with a PROXY protocol header, `v1` (text) or `v2` (binary)
+    #[serde(default, deserialize_with = "proxy_protocol_version_deser")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// Optional: the protocol spoken by this upstream,
+    /// one of: http1, h2 (over TLS), h2c (cleartext HTTP/2), grpc.
+    /// Overrides the `alpn` peer option (default: HTTP/2 or HTTP/1.1, see `alpn`)
+    #[serde(default, deserialize_with = "upstream_protocol_deser")]
+    pub protocol: Option<UpstreamProtocol>,
 }
 
 impl Default for RouteUpstream {
@@ -220,10 +326,118 @@ impl Default for RouteUpstream {
             weight: None,
             sni: None,
             headers: None,
+            peer_options: None,
+            tls: None,
+            proxy_protocol: None,
+            protocol: None,
+        }
+    }
+}
+
+/// The prefix of the upstreams listening on a unix domain socket
+pub const UNIX_SOCKET_PREFIX: &str = "unix://";
+
+impl RouteUpstream {
+    /// The path of the unix domain socket of the upstream, `None` for TCP upstreams
+    pub fn unix_socket(&self) -> Option<&std::path::Path> {
+        self.ip
+            .strip_prefix(UNIX_SOCKET_PREFIX)
+            .map(std::path::Path::new)
+    }
+
+    /// The address of the upstream (ex: `10.0.0.1:3000`, `unix:///run/app.sock`)
+    pub fn address(&self) -> String {
+        if self.unix_socket().is_some() {
+            return self.ip.to_string();
         }
+
+        format!("{}:{}", self.ip, self.port)
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct RouteUpstreamTls {
+    /// Whether to connect to the upstream over TLS
+    /// (default: only when the upstream port is 443)
+    pub enabled: Option<bool>,
+
+    /// Whether the upstream certificate is verified (default: true with a `ca_file`,
+    /// otherwise the `verify_cert` peer option, false if unset)
+    pub verify_cert: Option<bool>,
+
+    /// Path to a PEM bundle of the CAs trusted to sign the upstream certificate
+    /// (e.g. `/etc/proksi/certs/internal-ca.pem`). Defaults to the system CAs.
+    pub ca_file: Option<PathBuf>,
+
+    /// Path to the PEM client certificate (and chain) presented to the upstream (mTLS)
+    pub client_cert: Option<PathBuf>,
+
+    /// Path to the PEM private key of the client certificate
+    pub client_key: Option<PathBuf>,
+
+    /// The SNI sent to the upstream (default: the upstream `sni`, empty if unset)
+    pub sni: Option<String>,
+
+    /// Another name accepted in the upstream certificate,
+    /// in addition to the SNI (e.g. `internal.my-host.local`)
+    pub alternative_cn: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum UpstreamAlpn {
+    /// HTTP/1.1 only
+    H1,
+    /// HTTP/2 only
+    H2,
+    /// HTTP/2, falling back to HTTP/1.1
+    H2h1,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum UpstreamProtocol {
+    /// HTTP/1.1
+    Http1,
+    /// HTTP/2 over TLS
+    H2,
+    /// HTTP/2 without TLS (prior knowledge)
+    H2c,
+    /// gRPC, over HTTP/2 with or without TLS (see `tls.enabled`)
+    Grpc,
+}
+
+/// Connection options used when proxying requests to an upstream.
+/// Unset values fall back to the route options and then to the proxy defaults.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct RoutePeerOptions {
+    /// Timeout (in seconds) to establish the TCP connection (default: 10)
+    pub connection_timeout_secs: Option<u64>,
+
+    /// Timeout (in seconds) to establish the TCP and TLS connections (default: 20)
+    pub total_connection_timeout_secs: Option<u64>,
+
+    /// Timeout (in seconds) of each read from the upstream (default: 360)
+    pub read_timeout_secs: Option<u64>,
+
+    /// Timeout (in seconds) of each write to the upstream (default: 60)
+    pub write_timeout_secs: Option<u64>,
+
+    /// How long (in seconds) an idle connection is kept in the pool (default: 360)
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Maximum concurrent streams of a single HTTP/2 connection (default: 2)
+    pub max_h2_streams: Option<usize>,
+
+    /// Whether the upstream certificate is verified (default: false)
+    pub verify_cert: Option<bool>,
+
+    /// Whether the upstream certificate must match the SNI (default: true)
+    pub verify_hostname: Option<bool>,
+
+    /// HTTP versions offered to the upstream (one of: h1, h2, h2h1. default: h2h1)
+    #[serde(default, deserialize_with = "upstream_alpn_deser")]
+    pub alpn: Option<UpstreamAlpn>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RouteSslCertificate {
     /// Whether to use a self-signed certificate if the certificate can't be
@@ -232,16 +446,49 @@ pub struct RouteSslCertificate {
     pub self_signed_on_failure: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RoutePathMatcher {
     /// Optional: pattern to match the path
     /// (ex: /api/v1/*)
+    #[serde(default)]
     pub patterns: Vec<Cow<'static, str>>,
+
+    /// Optional: regular expression the path must match
+    /// (ex: ^/api/v[0-9]+/users$)
+    pub regex: Option<Cow<'static, str>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Matches a header or query parameter by name. Without `exact`, `prefix` or `regex`,
+/// the header or query parameter only has to be present.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteValueMatcher {
+    /// The name of the header or query parameter (ex: 'x-api-version')
+    pub name: Cow<'static, str>,
+
+    /// Optional: the value must be equal to this one
+    pub exact: Option<Cow<'static, str>>,
+
+    /// Optional: the value must start with this one
+    pub prefix: Option<Cow<'static, str>>,
+
+    /// Optional: the value must match this regular expression
+    pub regex: Option<Cow<'static, str>>,
+}
+
+/// Conditions a request must meet to be handled by the route.
+/// All the configured matchers must match (AND).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RouteMatcher {
     pub path: Option<RoutePathMatcher>,
+
+    /// Optional: the request method must be one of these (ex: `GET`, `HEAD`)
+    pub methods: Option<Vec<Cow<'static, str>>>,
+
+    /// Optional: headers the request must have
+    pub headers: Option<Vec<RouteValueMatcher>>,
+
+    /// Optional: query parameters the request must have
+    pub query: Option<Vec<RouteValueMatcher>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -256,894 +503,3202 @@ pub struct RoutePlugin {
     pub config: Option<HashMap<Cow<'static, str>, serde_json::Value>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RouteSslPath {
-    /// Path to the certificate .key file (e.g. `/etc/proksi/certs/my-host.key`)
-    pub key: PathBuf,
-
-    /// Path to the certificate .pem file (e.g. `/etc/proksi/certs/my-host.pem`)
-    pub pem: PathBuf,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum LoadBalancingAlgorithm {
+    /// Cycles through the upstreams, respecting their weights
+    #[default]
+    RoundRobin,
+    /// Picks the upstream with the fewest active connections (relative to its weight)
+    LeastConnections,
+    /// Picks a random upstream, respecting their weights
+    Random,
+    /// Picks two random upstreams and uses the one with the fewest active connections
+    PowerOfTwo,
+    /// Consistent hashing (Ketama) based on the configured `hash_key`
+    ConsistentHash,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ProtoVersion {
-    V1_1,
-    V1_2,
-    V1_3,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum HashKeySource {
+    #[default]
+    ClientIp,
+    Header,
+    Cookie,
+    Path,
 }
 
-/// Converts a `pingora::tls::ssl::SslVersion` to a `ProtoVersion`
-impl From<pingora::tls::ssl::SslVersion> for ProtoVersion {
-    fn from(v: pingora::tls::ssl::SslVersion) -> Self {
-        match v {
-            pingora::tls::ssl::SslVersion::TLS1_1 => ProtoVersion::V1_1,
-            pingora::tls::ssl::SslVersion::TLS1_2 => ProtoVersion::V1_2,
-            _ => ProtoVersion::V1_3,
-        }
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RouteHashKey {
+    /// Where the hash key is taken from: `client_ip`, `header`, `cookie` or `path`
+    /// (default: `client_ip`)
+    #[serde(default, deserialize_with = "hash_key_source_deser")]
+    pub source: HashKeySource,
+
+    /// The name of the header or cookie used as the hash key
+    /// (required when `source` is `header` or `cookie`)
+    pub name: Option<Cow<'static, str>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RouteSsl {
-    /// If provided, will be used instead of generating certificates from
-    /// Let's Encrypt or self-signed certificates.
-    pub path: Option<RouteSslPath>,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteLoadBalancing {
+    /// The algorithm used to pick an upstream for each request
+    /// (ex: `round_robin`, `least_connections`, `random`, `power_of_two`, `consistent_hash`)
+    /// (default: `round_robin`)
+    #[serde(default, deserialize_with = "load_balancing_algorithm_deser")]
+    pub algorithm: LoadBalancingAlgorithm,
+
+    /// The request property used to pick an upstream when using `consistent_hash`.
+    /// Requests with the same key are sent to the same upstream.
+    pub hash_key: Option<RouteHashKey>,
+}
 
-    /// The minimum and maximum protocol versions that the client can use.
-    #[serde(
-        default = "default_proto_version_min",
-        deserialize_with = "proto_version_deser"
-    )]
-    pub min_proto: ProtoVersion,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteUpstreamGroup {
+    /// The name of the group (ex: 'canary', 'v2')
+    pub name: Cow<'static, str>,
 
-    /// The maximum protocol version that the client can use.
-    #[serde(
-        default = "default_proto_version",
-        deserialize_with = "proto_version_deser"
-    )]
-    pub max_proto: ProtoVersion,
+    /// The percentage of the requests sent to the group (from 0 to 100)
+    pub percent: u8,
 
-    /// If the `self_signed_on_failure` is set to <true>,
-    /// the server will use a self-signed certificate if the Let's Encrypt certificate
-    /// issuance fails. This is useful for development and testing purposes.
-    ///
-    /// If the `self_signed_on_failure` is set to <false>
-    /// and let's encrypt fails to issue a certificate,
-    /// the server will respond with a SNI error that closes the connection.
-    ///
-    /// The default value is <true>.
-    #[serde(default = "bool_true")]
-    pub self_signed_fallback: bool,
+    /// The upstreams of the group, balanced with the load balancing,
+    /// health check and circuit breaker settings of the route
+    pub upstreams: Vec<RouteUpstream>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
-pub enum RouteCacheType {
-    Disk,
-    MemCache,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouteTrafficSplit {
+    /// The upstream groups receiving a percentage of the requests (ex: a canary release).
+    /// The remaining percentage is sent to the `upstreams` of the route.
+    pub groups: Vec<RouteUpstreamGroup>,
+
+    /// The request property (ex: a cookie or header) that keeps a client on the same group.
+    /// Requests without it are spread between the groups by their percentages.
+    pub sticky: Option<RouteHashKey>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct RouteCache {
-    pub enabled: Option<bool>,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum HealthCheckType {
+    /// Checks that a TCP connection can be established
+    #[default]
+    Tcp,
+    /// Sends a GET request and checks the response status
+    Http,
+    /// Same as `http`, but over TLS
+    Https,
+}
 
-    #[serde(
-        default = "default_cache_type",
-        deserialize_with = "deserialize_cache_type"
-    )]
-    pub cache_type: RouteCacheType,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct HealthCheckStatusRange {
+    /// The lowest status code considered healthy (inclusive)
+    pub min: u16,
+    /// The highest status code considered healthy (inclusive)
+    pub max: u16,
+}
 
-    #[serde(default = "default_cache_expire_secs")]
-    pub expires_in_secs: u64,
-    #[serde(default = "default_stale_secs")]
-    pub stale_if_error_secs: u32,
-    #[serde(default = "default_stale_secs")]
-    pub stale_while_revalidate_secs: u32,
+impl Default for HealthCheckStatusRange {
+    fn default() -> Self {
+        Self { min: 200, max: 299 }
+    }
+}
 
-    #[serde(default = "default_cache_path")]
-    pub path: PathBuf,
+impl HealthCheckStatusRange {
+    pub fn contains(self, status: u16) -> bool {
+        (self.min..=self.max).contains(&status)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Route {
-    /// The hostname that the proxy will accept
-    /// requests for the upstreams in the route.
-    /// (ex: 'example.com', 'www.example.com', etc.)
-    ///
-    /// This is the host header that the proxy will match and will
-    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
-    pub host: Cow<'static, str>,
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RouteHealthCheck {
+    /// The type of health check: `tcp`, `http` or `https` (default: `tcp`)
+    #[serde(rename = "type", default, deserialize_with = "health_check_type_deser")]
+    pub check_type: HealthCheckType,
 
-    pub cache: Option<RouteCache>,
+    /// The path requested by `http` and `https` checks (default: `/`)
+    #[serde(default = "default_health_check_path")]
+    pub path: Cow<'static, str>,
 
-    /// Plugins that will be applied to the route/host
-    /// (ex: rate limiting, oauth2, etc.)
-    pub plugins: Option<Vec<RoutePlugin>>,
+    /// The range of status codes considered healthy (default: 200-299)
+    #[serde(default)]
+    pub expected_status: HealthCheckStatusRange,
 
-    /// SSL certificate configurations for the given host
-    /// (ex: self-signed, path/object storage, etc.)
-    pub ssl_certificate: Option<RouteSslCertificate>,
+    /// The Host header (and SNI for `https`) sent by the check
+    /// (default: the route host)
+    pub host: Option<Cow<'static, str>>,
 
-    /// SSL configuration for the route
-    pub ssl: Option<RouteSsl>,
+    /// How long to wait for the connection and the response (default: 1 second)
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
 
-    /// Header modifications for the given route (remove, add, etc. )
-    pub headers: Option<RouteHeader>,
+    /// The interval (in seconds) between checks (default: 30 seconds)
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
 
-    /// The upstreams to which the request will be proxied,
-    pub upstreams: Vec<RouteUpstream>,
+    /// Consecutive successful checks to mark an upstream as healthy again (default: 1)
+    #[serde(default = "default_health_check_threshold")]
+    pub healthy_threshold: usize,
 
-    /// The matcher for the route
-    /// (ex: path, query, etc.)
-    pub match_with: Option<RouteMatcher>,
+    /// Consecutive failed checks to mark an upstream as unhealthy (default: 1)
+    #[serde(default = "default_health_check_threshold")]
+    pub unhealthy_threshold: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogLevel {
-    Debug,
-    Info,
-    Warn,
-    Error,
-    Trace,
+impl Default for RouteHealthCheck {
+    fn default() -> Self {
+        Self {
+            check_type: HealthCheckType::Tcp,
+            path: default_health_check_path(),
+            expected_status: HealthCheckStatusRange::default(),
+            host: None,
+            timeout_secs: default_health_check_timeout_secs(),
+            interval_secs: default_health_check_interval_secs(),
+            healthy_threshold: default_health_check_threshold(),
+            unhealthy_threshold: default_health_check_threshold(),
+        }
+    }
 }
 
-/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
-/// enum used by the `tracing` crate.
-impl From<&LogLevel> for tracing::level_filters::LevelFilter {
-    fn from(val: &LogLevel) -> Self {
-        match val {
-            LogLevel::Debug => LevelFilter::DEBUG,
-            LogLevel::Info => LevelFilter::INFO,
-            LogLevel::Warn => LevelFilter::WARN,
-            LogLevel::Error => LevelFilter::ERROR,
-            LogLevel::Trace => LevelFilter::TRACE,
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RouteCircuitBreaker {
+    /// Consecutive failures (connection errors, timeouts or 5xx responses)
+    /// before an upstream stops receiving requests (default: 5)
+    #[serde(default = "default_circuit_breaker_failures")]
+    pub consecutive_failures: usize,
+
+    /// How long (in seconds) an upstream is ejected before a single trial
+    /// request is let through to check if it recovered (default: 30 seconds)
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+impl Default for RouteCircuitBreaker {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: default_circuit_breaker_failures(),
+            cooldown_secs: default_circuit_breaker_cooldown_secs(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
-pub enum LogRotation {
-    #[default]
-    Never,
-    Daily,
-    Hourly,
-    Minutely,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum RetryCondition {
+    /// The connection to the upstream could not be established (or timed out)
+    ConnectError,
+    /// The upstream did not answer in time
+    Timeout,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
-pub enum LogFormat {
-    Json,
-    Pretty,
+impl RetryCondition {
+    /// The response status matching the condition, if any
+    pub fn status(self) -> Option<u16> {
+        match self {
+            RetryCondition::BadGateway => Some(502),
+            RetryCondition::ServiceUnavailable => Some(503),
+            RetryCondition::GatewayTimeout => Some(504),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
-#[group(id = "logging", requires = "level")]
-pub struct Logging {
-    /// If logging is enabled at all. Setting this to `false` will disable all logging output.
-    #[arg(
-        long = "log.enabled",
-        required = false,
-        value_parser,
-        default_value = "true",
-        id = "log.enabled"
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct RouteRetry {
+    /// Maximum number of attempts, including the first one (default: 3)
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: usize,
+
+    /// Which failures are retried on another upstream
+    /// (ex: connection errors, timeouts or 503 responses. default: connection errors)
+    #[serde(
+        default = "default_retry_on",
+        deserialize_with = "retry_conditions_deser"
     )]
-    pub enabled: bool,
+    pub retry_on: Vec<RetryCondition>,
 
-    /// The level of logging to be used.
-    #[serde(deserialize_with = "log_level_deser")]
-    #[arg(
-        long = "log.level",
-        required = false,
-        value_enum,
-        default_value = "info"
-    )]
-    pub level: LogLevel,
+    /// Whether non-idempotent requests (ex: POST, PATCH) can be retried.
+    /// Connection errors are always retried as the request never reached the upstream.
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
 
-    /// Whether to log access logs (request, duration, headers etc).
-    #[arg(
-        long = "log.access_logs_enabled",
-        required = false,
-        value_parser,
-        default_value = "true"
-    )]
-    pub access_logs_enabled: bool,
+    /// Delay (in milliseconds) before the first retry,
+    /// doubled on each following attempt (default: 0)
+    #[serde(default)]
+    pub backoff_ms: u64,
+}
 
-    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
-    #[arg(
-        long = "log.error_logs_enabled",
-        required = false,
-        value_parser,
-        default_value = "true"
-    )]
-    pub error_logs_enabled: bool,
+impl Default for RouteRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            retry_on: default_retry_on(),
+            retry_non_idempotent: false,
+            backoff_ms: 0,
+        }
+    }
+}
 
-    /// The format of the log output
-    #[serde(deserialize_with = "log_format_deser")]
-    #[arg(
-        long = "log.format",
-        required = false,
-        value_enum,
-        default_value = "json"
-    )]
-    pub format: LogFormat,
+/// Rewrites the request path before it is sent to the upstreams.
+/// The replacement is applied first, then the prefixes are stripped/added.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RouteRewrite {
+    /// Prefix removed from the request path (ex: `/grafana`)
+    pub strip_prefix: Option<Cow<'static, str>>,
 
-    /// If set, logs will be written to the specified file
-    #[arg(long = "log.path", required = false, value_parser)]
-    pub path: Option<PathBuf>,
+    /// Prefix added to the request path (ex: `/v1`)
+    pub add_prefix: Option<Cow<'static, str>>,
 
-    #[clap(skip)]
-    #[serde(deserialize_with = "log_rotation_deser", default)]
-    pub rotation: LogRotation,
+    /// Regular expression matched against the request path,
+    /// the matching part is replaced by `replacement`
+    pub regex: Option<Cow<'static, str>>,
+
+    /// The new request path. Can use the captures of `regex` (ex: `/users/$1`)
+    /// or, without `regex`, the named parameters of the path patterns (ex: `/users/{id}`)
+    pub replacement: Option<Cow<'static, str>>,
+
+    /// Whether `Location` headers of the upstream responses are rewritten back
+    /// when they point to a rewritten prefix (default: true)
+    pub location: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Args)]
-#[group(id = "auto_reload")]
-pub struct AutoReload {
-    /// Enables the auto-reload service
-    #[arg(long = "auto_reload.enabled", default_value = "false")]
-    pub enabled: Option<bool>,
+/// Redirects the requests matching `match_with` without sending them to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteRedirect {
+    /// The requests to redirect (default: every request of the route)
+    pub match_with: Option<RouteMatcher>,
 
-    /// The interval (in seconds) to check for changes in the configuration file
-    #[arg(
-        long = "auto_reload.interval_secs",
-        default_value = "30",
-        group = "auto_reload",
-        id = "auto_reload.interval_secs"
-    )]
-    pub interval_secs: Option<u64>,
+    /// The target of the redirect. Can use `{host}`, `{path}` and `{query}` (with its `?`),
+    /// the named parameters of the path patterns (ex: `{id}`)
+    /// and the captures of the path regex (ex: `$1`)
+    pub to: Cow<'static, str>,
 
-    #[clap(skip)]
-    pub paths: Vec<PathBuf>,
+    /// The status code of the redirect: 301, 302, 307 or 308 (default: 302)
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
 }
 
-impl Default for AutoReload {
+impl Default for RouteRedirect {
     fn default() -> Self {
         Self {
-            enabled: Some(false),
-            interval_secs: Some(30),
-            paths: vec![],
+            match_with: None,
+            to: Cow::Borrowed(""),
+            status: default_redirect_status(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Parser)]
-pub struct ServerCfg {
-    /// The address to bind the HTTPS server to.
-    #[arg(
-        long = "server.https_address",
-        required = false,
-        value_parser,
-        default_value = "0.0.0.0:443"
-    )]
-    pub https_address: Option<Cow<'static, str>>,
-
-    /// The address used to solve challenges (only HTTP)
-    #[arg(
-        long = "server.http_address",
-        required = false,
-        value_parser,
-        default_value = "0.0.0.0:80"
-    )]
-    pub http_address: Option<Cow<'static, str>>,
-}
-
-/// The main configuration struct.
-/// A configuration file (YAML, TOML or through ENV) will be parsed into this struct.
-/// Example:
-///
-/// ```yaml
-///
-/// # Example configuration file
-/// service_name: "proksi"
-/// logging:
-///   level: "INFO"
-///   access_logs_enabled: true
-///   error_logs_enabled: false
-/// letsencrypt:
-///   enabled: true
-///   email: "youremail@example.com"
-///   production: true
-/// paths:
-///   config_file: "/etc/proksi/config.toml"
-///   lets_encrypt: "/etc/proksi/letsencrypt"
-/// routes:
-///   - host: "example.com"
-///     match_with:
-///       path:
-///         patterns:
-///          - "/api/v1/*"
-///     headers:
-///       add:
-///         - name: "X-Forwarded-For"
-///           value: "<value>"
-///         - name: "X-Api-Version"
-///           value: "1.0"
-///       remove:
-///         - name: "Server"
-///     upstreams:
-///       - ip: "10.1.2.24/24"
-///         port: 3000
-///         network: "public"
-///       - ip: "10.1.2.23/24"
-///         port: 3000
-///         network: "shared"
-/// ```
-///
-#[derive(Debug, Serialize, Deserialize, Parser)]
-#[command(name = "Proksi")]
-#[command(version, about, long_about = None)]
-pub(crate) struct Config {
-    /// The name of the service (will appear as a log property)
-    #[serde(default)]
-    #[clap(short, long, default_value = "proksi")]
-    pub service_name: Cow<'static, str>,
+/// Sends a copy of the requests to a shadow upstream, whose responses are discarded
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteMirror {
+    /// The URL of the shadow upstream (ex: `http://10.0.0.5:3000`),
+    /// the path and query of the request are appended to it
+    pub target: Cow<'static, str>,
 
-    /// Store configuration for certificate storage
-    #[clap(skip)]
-    pub store: StoreConfig,
+    /// The percentage of the requests mirrored, from 0 to 100 (default: 100)
+    #[serde(default = "default_mirror_percent")]
+    pub percent: u8,
 
-    #[command(flatten)]
-    pub server: ServerCfg,
+    /// The requests to mirror (default: every request of the route)
+    pub match_with: Option<RouteMatcher>,
 
-    /// Runs the service in the background (daemon mode)
-    #[clap(short, long, default_value = "false")]
-    pub daemon: bool,
+    /// Requests with a larger body are not mirrored (default: 1MB)
+    #[serde(default = "default_mirror_max_body_bytes")]
+    pub max_body_bytes: usize,
 
-    /// Upgrades the service from an existing running instance
-    #[clap(short, long, default_value = "false")]
-    pub upgrade: bool,
+    /// How long the shadow upstream has to answer (default: 5000ms)
+    #[serde(default = "default_mirror_timeout_ms")]
+    pub timeout_ms: u64,
+}
 
-    /// The number of worker threads to be used by the HTTPS proxy service.
-    ///
-    /// For background services the default is always (1) and cannot be changed.
-    #[clap(short, long, required = false, default_value = "2")]
-    pub worker_threads: Option<usize>,
+impl Default for RouteMirror {
+    fn default() -> Self {
+        Self {
+            target: Cow::Borrowed(""),
+            percent: default_mirror_percent(),
+            match_with: None,
+            max_body_bytes: default_mirror_max_body_bytes(),
+            timeout_ms: default_mirror_timeout_ms(),
+        }
+    }
+}
 
-    /// The PATH to the configuration file to be used.
-    ///
-    /// The configuration file should be named either `proksi.hcl` or `proksi.yaml`
-    ///
-    /// and be present in that path. If no path is provided, a minimal default
-    /// configuration will be used.
-    #[clap(short, required = false, long)]
-    #[allow(clippy::struct_field_names)]
-    pub config_path: Option<Cow<'static, str>>,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum SameSitePolicy {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
 
-    /// General config
-    #[command(flatten)]
-    pub logging: Logging,
+/// Keeps a client on the same upstream with a cookie (sticky sessions)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteSticky {
+    /// Whether sticky sessions are enabled (default: true)
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
 
-    #[command(flatten)]
-    pub auto_reload: AutoReload,
+    /// The name of the cookie (default: `proksi_sticky`)
+    #[serde(default = "default_sticky_cookie_name")]
+    pub cookie_name: Cow<'static, str>,
 
-    #[command(flatten)]
-    pub docker: Docker,
+    /// How long the client stays on its upstream, in seconds
+    /// (default: until the browser is closed)
+    pub ttl_secs: Option<u64>,
 
-    #[clap(skip)]
-    pub lets_encrypt: LetsEncrypt,
+    /// The `SameSite` attribute of the cookie: `strict`, `lax` or `none` (default: `lax`)
+    #[serde(default, deserialize_with = "same_site_deser")]
+    pub same_site: SameSitePolicy,
 
-    /// Configuration for paths (TLS, config file, etc.)
-    #[clap(skip)]
-    pub paths: Path,
+    /// The `Secure` attribute of the cookie (default: true)
+    #[serde(default = "bool_true")]
+    pub secure: bool,
 
-    /// The routes to be proxied to.
-    #[clap(skip)]
-    pub routes: Vec<Route>,
-    // Listeners -- a list of specific listeners and upstrems
-    // that don't necessarily need to be HTTP/HTTPS related
-    // pub listeners: Vec<ConfigListener>,
+    /// The secret used to encrypt the cookie (at least 32 characters).
+    /// Instances sharing the secret accept each other's cookies, also across restarts
+    /// (default: a random secret per process)
+    pub secret: Option<Cow<'static, str>>,
 }
 
-impl Default for Config {
+impl Default for RouteSticky {
     fn default() -> Self {
-        Config {
-            config_path: Some(Cow::Borrowed("/etc/proksi/config")),
-            service_name: Cow::Borrowed("proksi"),
-            server: ServerCfg {
-                https_address: Some(Cow::Borrowed("0.0.0.0:443")),
-                http_address: Some(Cow::Borrowed("0.0.0.0:80")),
-            },
-            worker_threads: Some(2),
-            upgrade: false,
-            daemon: false,
-            docker: Docker::default(),
-            lets_encrypt: LetsEncrypt::default(),
-            routes: vec![],
-            auto_reload: AutoReload::default(),
-            store: StoreConfig::default(),
-            logging: Logging {
-                enabled: true,
-                level: LogLevel::Info,
-                access_logs_enabled: true,
-                error_logs_enabled: true,
-                format: LogFormat::Json,
-                path: None,
-                rotation: LogRotation::Never,
-            },
-            paths: Path::default(),
+        Self {
+            enabled: bool_true(),
+            cookie_name: default_sticky_cookie_name(),
+            ttl_secs: None,
+            same_site: SameSitePolicy::default(),
+            secure: bool_true(),
+            secret: None,
         }
     }
 }
 
-// impl Config {
-//     // Allow the configuration to be extracted from any `Provider`.
-//     fn from<T: figment::Provider>(provider: T) -> Result<Config, figment::Error> {
-//         Figment::from(provider).extract()
-//     }
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum ForwardedHeader {
+    XForwardedFor,
+    XForwardedProto,
+    XForwardedHost,
+    XRealIp,
+    /// The standard `Forwarded` header (RFC 7239)
+    Forwarded,
+}
 
-//     // Provide a default provider, a `Figment`.
-//     fn figment() -> Figment {
-//         use figment::providers::Env;
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ForwardedHeadersMode {
+    /// Keeps the values sent by trusted proxies and appends the client to them
+    #[default]
+    Append,
+    /// Always replaces the incoming values, for a proxy facing the clients directly
+    Overwrite,
+}
 
-//         // In reality, whatever the library desires.
-//         Figment::from(Config::default()).merge(Env::prefixed("APP_"))
-//     }
-// }
+/// Headers telling the upstreams about the client and the original request
+/// (ex: `X-Forwarded-For`, `X-Forwarded-Proto`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteForwardedHeaders {
+    /// The headers sent to the upstreams: `x_forwarded_for`, `x_forwarded_proto`,
+    /// `x_forwarded_host`, `x_real_ip` and/or `forwarded`
+    /// (default: the three `x_forwarded_*` headers)
+    #[serde(
+        default = "default_forwarded_headers",
+        deserialize_with = "forwarded_headers_deser"
+    )]
+    pub headers: Vec<ForwardedHeader>,
 
-/// Implement the `Provider` trait for the `Config` struct.
-/// This allows the `Config` struct to be used as a configuration provider with *defaults*.
-impl Provider for Config {
-    fn metadata(&self) -> figment::Metadata {
-        figment::Metadata::named("proksi")
-    }
+    /// Whether the incoming values are kept (`append`) or replaced (`overwrite`)
+    /// (default: `append`)
+    #[serde(default, deserialize_with = "forwarded_headers_mode_deser")]
+    pub mode: ForwardedHeadersMode,
 
-    fn data(
-        &self,
-    ) -> Result<figment::value::Map<figment::Profile, figment::value::Dict>, figment::Error> {
-        Serialized::defaults(Config::default()).data()
-    }
+    /// The proxies in front of Proksi, as IPs or CIDRs (ex: `10.0.0.0/8`).
+    /// Incoming values are only kept when the request comes from one of them,
+    /// otherwise they are replaced (default: none)
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cow<'static, str>>,
 }
 
-/// Load the configuration from the configuration file(s) as a `Config` struct.
-/// In theory one could create all 3 configurations formats and they will overlap each other
-///
-/// Nested keys can be separated by double underscores (__) in the environment variables.
-/// E.g. `PROKSI__LOGGING__LEVEL=DEBUG` will set the `level` key in the
-/// `logging` key in the `proksi` key.
-pub fn load(fallback: &str) -> Result<Config, figment::Error> {
-    let parsed_commands = Config::parse();
+impl Default for RouteForwardedHeaders {
+    fn default() -> Self {
+        Self {
+            headers: default_forwarded_headers(),
+            mode: ForwardedHeadersMode::default(),
+            trusted_proxies: vec![],
+        }
+    }
+}
 
-    let path_with_fallback = match &parsed_commands.config_path {
-        Some(path) => path.as_ref(),
-        None => fallback,
-    };
+/// gRPC-Web support of a route: `application/grpc-web(+proto)` and
+/// `application/grpc-web-text(+proto)` requests are sent as gRPC to the upstreams
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteGrpcWeb {
+    /// The origins allowed to call the route from a browser (ex: `https://app.example.com`),
+    /// `*` for any origin (default: none, only same-origin calls)
+    #[serde(default)]
+    pub allowed_origins: Vec<Cow<'static, str>>,
 
-    // Check if no explicit config path was provided by the user
-    let use_minimal_default = parsed_commands.config_path.is_none();
+    /// How long (in seconds) browsers cache the answer to CORS preflight requests
+    /// (default: 86400)
+    #[serde(default = "default_grpc_web_max_age_secs")]
+    pub max_age_secs: u64,
+}
 
-    load_from_path(path_with_fallback, &parsed_commands, use_minimal_default)
+impl Default for RouteGrpcWeb {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            max_age_secs: default_grpc_web_max_age_secs(),
+        }
+    }
 }
 
-/// Test-friendly version of load that doesn't parse command line arguments
-#[cfg(test)]
-pub(crate) fn load_for_test(fallback: &str) -> Result<Config, figment::Error> {
-    let default_config = Config::default();
+/// Serves the files of a directory instead of proxying requests to upstreams
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteStatic {
+    /// The directory the files are served from (ex: `/var/www/app/dist`)
+    pub root: PathBuf,
 
-    // For tests, always use the regular behavior (no minimal default)
-    // since tests should be explicit about what they're testing
-    load_from_path(fallback, &default_config, false)
-}
+    /// Files served for requests to a directory (default: `index.html`)
+    #[serde(default = "default_static_index")]
+    pub index: Vec<Cow<'static, str>>,
 
-/// Creates a minimal default configuration with Let's Encrypt disabled.
-/// This is used when no configuration file is found.
-fn create_minimal_default_config() -> Config {
-    let mut config = Config::default();
-    // Disable Let's Encrypt by default in minimal config
-    config.lets_encrypt.enabled = Some(false);
-    config
+    /// Serves the index file of the root directory for paths without a file,
+    /// for single page applications (default: false)
+    #[serde(default)]
+    pub spa: bool,
+
+    /// Serves the `.br`/`.gz` variant of a file, if it exists,
+    /// to clients accepting them (default: true)
+    #[serde(default = "bool_true")]
+    pub precompressed: bool,
 }
 
-/// Load configuration from a specific path, used for testing and internal logic
-pub(crate) fn load_from_path(
-    config_path: &str,
-    parsed_commands: &Config,
-    use_minimal_default: bool,
-) -> Result<Config, figment::Error> {
-    let mut figment = Figment::new()
-        .merge(Config::default())
-        .merge(Serialized::defaults(parsed_commands));
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteSslPath {
+    /// Path to the certificate .key file (e.g. `/etc/proksi/certs/my-host.key`)
+    pub key: PathBuf,
 
-    // Check if the path is a file or directory
-    if std::path::Path::new(config_path).is_file() {
-        // If it's a file, load it directly based on its extension
-        let path_buf = std::path::PathBuf::from(config_path);
-        if let Some(extension) = path_buf.extension() {
-            match extension.to_str() {
-                Some("yml" | "yaml") => {
-                    figment = figment.merge(Yaml::file(config_path));
-                }
-                Some("hcl") => {
-                    figment = figment.merge(Hcl::file(config_path));
-                }
-                _ => {
-                    // Try to load as both formats for compatibility
-                    figment = figment
-                        .merge(Yaml::file(config_path))
-                        .merge(Hcl::file(config_path));
-                }
-            }
-        } else {
-            // No extension, try both formats
-            figment = figment
-                .merge(Yaml::file(config_path))
-                .merge(Hcl::file(config_path));
-        }
-    } else {
-        // If it's a directory, check if any config files exist
-        let config_files = [
-            format!("{config_path}/proksi.yml"),
-            format!("{config_path}/proksi.yaml"),
-            format!("{config_path}/proksi.hcl"),
-        ];
+    /// Path to the certificate .pem file (e.g. `/etc/proksi/certs/my-host.pem`)
+    pub pem: PathBuf,
+}
 
-        let config_files_found = config_files
-            .iter()
-            .any(|config_file| std::path::Path::new(config_file).exists());
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ProtoVersion {
+    V1_1,
+    V1_2,
+    V1_3,
+}
 
-        if config_files_found {
-            // If at least one config file exists, use the original behavior
-            figment = figment
-                .merge(Yaml::file(format!("{config_path}/proksi.yml")))
-                .merge(Yaml::file(format!("{config_path}/proksi.yaml")))
-                .merge(Hcl::file(format!("{config_path}/proksi.hcl")));
-        } else if use_minimal_default {
-            // No config files found and user didn't explicitly provide a path
-            eprintln!("Warning: No configuration files found. Using minimal default configuration with Let's Encrypt disabled.");
-            figment = figment.merge(Serialized::defaults(create_minimal_default_config()));
-        } else {
-            // Use original behavior - try to load the files anyway (figment will handle missing files)
-            figment = figment
-                .merge(Yaml::file(format!("{config_path}/proksi.yml")))
-                .merge(Yaml::file(format!("{config_path}/proksi.yaml")))
-                .merge(Hcl::file(format!("{config_path}/proksi.hcl")));
+/// Converts a `pingora::tls::ssl::SslVersion` to a `ProtoVersion`
+impl From<pingora::tls::ssl::SslVersion> for ProtoVersion {
+    fn from(v: pingora::tls::ssl::SslVersion) -> Self {
+        match v {
+            pingora::tls::ssl::SslVersion::TLS1_1 => ProtoVersion::V1_1,
+            pingora::tls::ssl::SslVersion::TLS1_2 => ProtoVersion::V1_2,
+            _ => ProtoVersion::V1_3,
         }
     }
+}
 
-    let config: Config = figment
-        .merge(Env::prefixed("PROKSI_").split("__"))
-        .extract()?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteSsl {
+    /// If provided, will be used instead of generating certificates from
+    /// Let's Encrypt or self-signed certificates.
+    pub path: Option<RouteSslPath>,
 
-    // validate configuration and throw error upwards
-    validate::check_config(&config).map_err(|err| figment::Error::from(err.to_string()))?;
+    /// The minimum and maximum protocol versions that the client can use.
+    #[serde(
+        default = "default_proto_version_min",
+        deserialize_with = "proto_version_deser"
+    )]
+    pub min_proto: ProtoVersion,
 
-    Ok(config)
-}
+    /// The maximum protocol version that the client can use.
+    #[serde(
+        default = "default_proto_version",
+        deserialize_with = "proto_version_deser"
+    )]
+    pub max_proto: ProtoVersion,
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn log_level_deser<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "debug" => Ok(LogLevel::Debug),
-        "info" => Ok(LogLevel::Info),
-        "warn" => Ok(LogLevel::Warn),
-        "error" => Ok(LogLevel::Error),
-        "trace" => Ok(LogLevel::Trace),
-        _ => Err(serde::de::Error::custom(
-            "expected one of DEBUG, INFO, WARN, ERROR, TRACE",
-        )),
-    }
+    /// If the `self_signed_on_failure` is set to <true>,
+    /// the server will use a self-signed certificate if the Let's Encrypt certificate
+    /// issuance fails. This is useful for development and testing purposes.
+    ///
+    /// If the `self_signed_on_failure` is set to <false>
+    /// and let's encrypt fails to issue a certificate,
+    /// the server will respond with a SNI error that closes the connection.
+    ///
+    /// The default value is <true>.
+    #[serde(default = "bool_true")]
+    pub self_signed_fallback: bool,
 }
 
-/// Deserialize function to convert a string to a `DockerServiceMode` Enum
-fn docker_mode_deser<'de, D>(deserializer: D) -> Result<DockerServiceMode, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "swarm" => Ok(DockerServiceMode::Swarm),
-        "container" => Ok(DockerServiceMode::Container),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: Swarm, Container",
-        )),
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum RouteCacheType {
+    Disk,
+    MemCache,
 }
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn log_format_deser<'de, D>(deserializer: D) -> Result<LogFormat, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "json" => Ok(LogFormat::Json),
-        "pretty" => Ok(LogFormat::Pretty),
-        _ => Err(serde::de::Error::custom("expected one of: json, pretty")),
-    }
-}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteCache {
+    pub enabled: Option<bool>,
 
-fn log_rotation_deser<'de, D>(deserializer: D) -> Result<LogRotation, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "daily" => Ok(LogRotation::Daily),
-        "hourly" => Ok(LogRotation::Hourly),
-        "minutely" => Ok(LogRotation::Minutely),
-        "never" => Ok(LogRotation::Never),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: daily, hourly, minutely, never",
-        )),
-    }
-}
+    #[serde(
+        default = "default_cache_type",
+        deserialize_with = "deserialize_cache_type"
+    )]
+    pub cache_type: RouteCacheType,
 
-/// Deserialize function to convert a string to a `LogLevel` Enum
-fn proto_version_deser<'de, D>(deserializer: D) -> Result<ProtoVersion, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "v1.1" => Ok(ProtoVersion::V1_1),
-        "v1.2" => Ok(ProtoVersion::V1_2),
-        "v1.3" => Ok(ProtoVersion::V1_3),
-        _ => Err(serde::de::Error::custom(
-            "expected one of: v1.1, v1.2, v1.3",
-        )),
-    }
+    #[serde(default = "default_cache_expire_secs")]
+    pub expires_in_secs: u64,
+    #[serde(default = "default_stale_secs")]
+    pub stale_if_error_secs: u32,
+    #[serde(default = "default_stale_secs")]
+    pub stale_while_revalidate_secs: u32,
+
+    #[serde(default = "default_cache_path")]
+    pub path: PathBuf,
 }
 
-fn deserialize_cache_type<'de, D>(deserializer: D) -> Result<RouteCacheType, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "disk" => Ok(RouteCacheType::Disk),
-        "memcache" => Ok(RouteCacheType::MemCache),
-        _ => Err(serde::de::Error::custom("expected one of: disk, memcache")),
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Route {
+    /// The hostname that the proxy will accept
+    /// requests for the upstreams in the route.
+    /// (ex: 'example.com', 'www.example.com', etc.)
+    ///
+    /// This is the host header that the proxy will match and will
+    /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
+    ///
+    /// A wildcard host (ex: '*.preview.example.com') matches any subdomain
+    /// without a route of its own. Let's Encrypt does not issue certificates for them.
+    pub host: Cow<'static, str>,
+
+    /// Whether this route serves requests that match no other route (default: false).
+    /// Only one route can be the default.
+    pub default: Option<bool>,
+
+    /// Forwards the TLS connections of the host to the upstreams without decrypting them,
+    /// picked by the SNI of the client (default: false). The upstreams terminate TLS
+    /// themselves, so the HTTP options of the route don't apply.
+    #[serde(default)]
+    pub tls_passthrough: bool,
+
+    pub cache: Option<RouteCache>,
+
+    /// Plugins that will be applied to the route/host
+    /// (ex: rate limiting, oauth2, etc.)
+    pub plugins: Option<Vec<RoutePlugin>>,
+
+    /// SSL certificate configurations for the given host
+    /// (ex: self-signed, path/object storage, etc.)
+    pub ssl_certificate: Option<RouteSslCertificate>,
+
+    /// SSL configuration for the route
+    pub ssl: Option<RouteSsl>,
+
+    /// Header modifications for the given route (remove, add, etc. )
+    pub headers: Option<RouteHeader>,
+
+    /// The upstreams to which the request will be proxied,
+    #[serde(default)]
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// Serves files from a directory instead of proxying to upstreams
+    #[serde(rename = "static")]
+    pub static_files: Option<RouteStatic>,
+
+    /// How requests are distributed between the upstreams of the route
+    /// (default: round robin)
+    pub load_balancing: Option<RouteLoadBalancing>,
+
+    /// Sends a percentage of the requests to other upstream groups
+    /// (ex: 5% of the requests to a canary release)
+    pub traffic_split: Option<RouteTrafficSplit>,
+
+    /// Sends a copy of the requests to a shadow upstream (ex: to test a rewrite of a service)
+    pub mirror: Option<RouteMirror>,
+
+    /// Keeps a client on the upstream that served its first request, with a cookie
+    /// (default: disabled)
+    pub sticky: Option<RouteSticky>,
+
+    /// Headers telling the upstreams about the client (ex: `X-Forwarded-For`)
+    /// (default: none are added)
+    pub forwarded_headers: Option<RouteForwardedHeaders>,
+
+    /// Translates the gRPC-Web requests of browsers to gRPC for the upstreams
+    /// (default: disabled)
+    pub grpc_web: Option<RouteGrpcWeb>,
+
+    /// Active health checks for the upstreams of the route
+    /// (default: a TCP check every 30 seconds)
+    pub health_check: Option<RouteHealthCheck>,
+
+    /// Passive health checks: ejects upstreams that keep failing requests
+    /// (default: disabled)
+    pub circuit_breaker: Option<RouteCircuitBreaker>,
+
+    /// Retries failed requests on another upstream
+    /// (default: disabled)
+    pub retry: Option<RouteRetry>,
+
+    /// Connection options (timeouts, TLS verification, ALPN)
+    /// of the upstreams of the route
+    pub peer_options: Option<RoutePeerOptions>,
+
+    /// The matcher for the route
+    /// (ex: path, query, etc.)
+    pub match_with: Option<RouteMatcher>,
+
+    /// Path rewrites applied before the request is sent to the upstreams
+    /// (ex: strip the `/grafana` prefix)
+    pub rewrite: Option<RouteRewrite>,
+
+    /// Redirects served without reaching the upstreams, the first matching one is used
+    pub redirects: Option<Vec<RouteRedirect>>,
+
+    /// Permanently redirects every request to the same path on another host
+    /// (ex: 'www.example.com' to 'example.com')
+    pub redirect_to_host: Option<Cow<'static, str>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ListenerProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// A raw TCP or UDP proxy (ex: a database or a game server),
+/// for traffic that is not HTTP/HTTPS
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigListener {
+    /// The name of the listener, used in the logs (ex: 'postgres')
+    pub name: Cow<'static, str>,
+
+    /// The protocol proxied by the listener: `tcp` or `udp` (default: `tcp`)
+    #[serde(default, deserialize_with = "listener_protocol_deser")]
+    pub protocol: ListenerProtocol,
+
+    /// The address to bind the listener to (ex: 0.0.0.0:5432)
+    pub address: Cow<'static, str>,
+
+    /// The upstreams the connections are proxied to.
+    /// Only their `ip`, `port` and `weight` are used.
+    pub upstreams: Vec<RouteUpstream>,
+
+    /// How connections are distributed between the upstreams (default: round robin).
+    /// Consistent hashing can only use the IP of the client as its key.
+    pub load_balancing: Option<RouteLoadBalancing>,
+
+    /// Active health checks for the upstreams of the listener
+    /// (default: a TCP check every 30 seconds for `tcp` listeners, none for `udp` ones)
+    pub health_check: Option<RouteHealthCheck>,
+
+    /// How long (in seconds) a UDP client is kept on its upstream without traffic
+    /// (default: 60 seconds)
+    #[serde(default = "default_listener_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+
+    /// How many UDP clients can be proxied at the same time, each one uses a socket.
+    /// The datagrams of new clients are dropped past it (default: 4096)
+    #[serde(default = "default_listener_max_sessions")]
+    pub max_sessions: usize,
+}
+
+impl Default for ConfigListener {
+    fn default() -> Self {
+        Self {
+            name: Cow::Borrowed(""),
+            protocol: ListenerProtocol::default(),
+            address: Cow::Borrowed(""),
+            upstreams: vec![],
+            load_balancing: None,
+            health_check: None,
+            idle_timeout_secs: default_listener_idle_timeout_secs(),
+            max_sessions: default_listener_max_sessions(),
+        }
     }
 }
 
-fn store_type_deser<'de, D>(deserializer: D) -> Result<StoreType, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.to_lowercase().as_str() {
-        "memory" => Ok(StoreType::Memory),
-        "redis" => Ok(StoreType::Redis),
-        _ => Err(serde::de::Error::custom("expected one of: memory, redis")),
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Trace,
+}
+
+/// Transforms our custom `LogLevel` enum into a `tracing::level_filters::LevelFilter`
+/// enum used by the `tracing` crate.
+impl From<&LogLevel> for tracing::level_filters::LevelFilter {
+    fn from(val: &LogLevel) -> Self {
+        match val {
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
+pub enum LogFormat {
+    Json,
+    Pretty,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+#[group(id = "logging", requires = "level")]
+pub struct Logging {
+    /// If logging is enabled at all. Setting this to `false` will disable all logging output.
+    #[arg(
+        long = "log.enabled",
+        required = false,
+        value_parser,
+        default_value = "true",
+        id = "log.enabled"
+    )]
+    pub enabled: bool,
+
+    /// The level of logging to be used.
+    #[serde(deserialize_with = "log_level_deser")]
+    #[arg(
+        long = "log.level",
+        required = false,
+        value_enum,
+        default_value = "info"
+    )]
+    pub level: LogLevel,
+
+    /// Whether to log access logs (request, duration, headers etc).
+    #[arg(
+        long = "log.access_logs_enabled",
+        required = false,
+        value_parser,
+        default_value = "true"
+    )]
+    pub access_logs_enabled: bool,
+
+    /// Whether to log error logs (errors, panics, etc) from the Rust runtime.
+    #[arg(
+        long = "log.error_logs_enabled",
+        required = false,
+        value_parser,
+        default_value = "true"
+    )]
+    pub error_logs_enabled: bool,
+
+    /// The format of the log output
+    #[serde(deserialize_with = "log_format_deser")]
+    #[arg(
+        long = "log.format",
+        required = false,
+        value_enum,
+        default_value = "json"
+    )]
+    pub format: LogFormat,
+
+    /// If set, logs will be written to the specified file
+    #[arg(long = "log.path", required = false, value_parser)]
+    pub path: Option<PathBuf>,
+
+    #[clap(skip)]
+    #[serde(deserialize_with = "log_rotation_deser", default)]
+    pub rotation: LogRotation,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Args)]
+#[group(id = "auto_reload")]
+pub struct AutoReload {
+    /// Enables the auto-reload service
+    #[arg(long = "auto_reload.enabled", default_value = "false")]
+    pub enabled: Option<bool>,
+
+    /// The interval (in seconds) to check for changes in the configuration file
+    #[arg(
+        long = "auto_reload.interval_secs",
+        default_value = "30",
+        group = "auto_reload",
+        id = "auto_reload.interval_secs"
+    )]
+    pub interval_secs: Option<u64>,
+
+    #[clap(skip)]
+    pub paths: Vec<PathBuf>,
+}
+
+impl Default for AutoReload {
+    fn default() -> Self {
+        Self {
+            enabled: Some(false),
+            interval_secs: Some(30),
+            paths: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Parser)]
+#[allow(clippy::struct_field_names)]
+pub struct ServerCfg {
+    /// The address to bind the HTTPS server to.
+    #[arg(
+        long = "server.https_address",
+        required = false,
+        value_parser,
+        default_value = "0.0.0.0:443"
+    )]
+    pub https_address: Option<Cow<'static, str>>,
+
+    /// The address used to solve challenges (only HTTP)
+    #[arg(
+        long = "server.http_address",
+        required = false,
+        value_parser,
+        default_value = "0.0.0.0:80"
+    )]
+    pub http_address: Option<Cow<'static, str>>,
+
+    /// The address to expose the Prometheus metrics on (ex: 0.0.0.0:9090).
+    /// Metrics are disabled if not set.
+    #[arg(long = "server.metrics_address", required = false, value_parser)]
+    pub metrics_address: Option<Cow<'static, str>>,
+
+    /// Reads the PROXY protocol header sent by the load balancers
+    /// in front of the HTTP and HTTPS addresses (default: disabled)
+    #[clap(skip)]
+    #[serde(default)]
+    pub proxy_protocol: ServerProxyProtocol,
+
+    /// How the IP of the client is resolved behind other proxies
+    /// (default: the address of the connection)
+    #[clap(skip)]
+    #[serde(default)]
+    pub client_ip: ServerClientIp,
+
+    /// Serves the HTTPS routes over HTTP/3 (QUIC) too (default: disabled)
+    #[clap(skip)]
+    #[serde(default)]
+    pub http3: ServerHttp3,
+}
+
+impl ServerCfg {
+    /// The UDP address of the HTTP/3 listener, the HTTPS address unless configured
+    pub fn http3_address(&self) -> &str {
+        self.http3
+            .address
+            .as_deref()
+            .or(self.https_address.as_deref())
+            .unwrap_or_default()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// PROXY protocol (v1 and v2) on the HTTP and HTTPS listeners, so that the address
+/// of the clients is known behind a TCP load balancer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerProxyProtocol {
+    /// Whether the listeners read the PROXY protocol header (default: false)
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The load balancers sending the header, as IPs or CIDRs (ex: `10.0.0.0/8`).
+    /// Connections from other addresses are served without reading it.
+    #[serde(default)]
+    pub trusted_sources: Vec<Cow<'static, str>>,
+
+    /// How long a load balancer has to send the header (default: 5000ms)
+    #[serde(default = "default_proxy_protocol_timeout_ms")]
+    pub header_timeout_ms: u64,
+}
+
+impl Default for ServerProxyProtocol {
+    fn default() -> Self {
+        ServerProxyProtocol {
+            enabled: false,
+            trusted_sources: vec![],
+            header_timeout_ms: default_proxy_protocol_timeout_ms(),
+        }
+    }
+}
+
+/// An HTTP/3 (QUIC) listener next to the HTTPS one, with the same routes and certificates.
+/// The clients learn about it from the `Alt-Svc` header of the HTTPS responses.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerHttp3 {
+    /// Whether the HTTP/3 listener is enabled (default: false)
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The UDP address of the HTTP/3 listener (default: the HTTPS address)
+    pub address: Option<Cow<'static, str>>,
+
+    /// How long the clients remember that HTTP/3 is available (default: 86400s)
+    #[serde(default = "default_http3_alt_svc_max_age_secs")]
+    pub alt_svc_max_age_secs: u64,
+}
+
+impl Default for ServerHttp3 {
+    fn default() -> Self {
+        ServerHttp3 {
+            enabled: false,
+            address: None,
+            alt_svc_max_age_secs: default_http3_alt_svc_max_age_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ClientIpSource {
+    /// The right-most address of `X-Forwarded-For` that is not a trusted proxy
+    #[default]
+    XForwardedFor,
+    /// The `CF-Connecting-IP` header set by Cloudflare
+    CfConnectingIp,
+    /// The `X-Real-IP` header
+    XRealIp,
+    /// The address of the connection, ignoring the headers
+    Peer,
+}
+
+/// The IP of the client used by the load balancers, the plugins and the access log
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ServerClientIp {
+    /// The proxies in front of Proksi, as IPs or CIDRs (ex: `10.0.0.0/8`).
+    /// The headers are only read on requests coming from them.
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cow<'static, str>>,
+
+    /// Where the IP of the client comes from (default: `x_forwarded_for`)
+    #[serde(default, deserialize_with = "client_ip_source_deser")]
+    pub source: ClientIpSource,
+}
+
+/// The main configuration struct.
+/// A configuration file (YAML, TOML or through ENV) will be parsed into this struct.
+/// Example:
+///
+/// ```yaml
+///
+/// # Example configuration file
+/// service_name: "proksi"
+/// logging:
+///   level: "INFO"
+///   access_logs_enabled: true
+///   error_logs_enabled: false
+/// letsencrypt:
+///   enabled: true
+///   email: "youremail@example.com"
+///   production: true
+/// paths:
+///   config_file: "/etc/proksi/config.toml"
+///   lets_encrypt: "/etc/proksi/letsencrypt"
+/// routes:
+///   - host: "example.com"
+///     match_with:
+///       path:
+///         patterns:
+///          - "/api/v1/*"
+///     headers:
+///       add:
+///         - name: "X-Forwarded-For"
+///           value: "<value>"
+///         - name: "X-Api-Version"
+///           value: "1.0"
+///       remove:
+///         - name: "Server"
+///     upstreams:
+///       - ip: "10.1.2.24/24"
+///         port: 3000
+///         network: "public"
+///       - ip: "10.1.2.23/24"
+///         port: 3000
+///         network: "shared"
+/// ```
+///
+#[derive(Debug, Serialize, Deserialize, Parser)]
+#[command(name = "Proksi")]
+#[command(version, about, long_about = None)]
+pub(crate) struct Config {
+    /// The name of the service (will appear as a log property)
+    #[serde(default)]
+    #[clap(short, long, default_value = "proksi")]
+    pub service_name: Cow<'static, str>,
+
+    /// Store configuration for certificate storage
+    #[clap(skip)]
+    pub store: StoreConfig,
+
+    #[command(flatten)]
+    pub server: ServerCfg,
+
+    /// Runs the service in the background (daemon mode)
+    #[clap(short, long, default_value = "false")]
+    pub daemon: bool,
+
+    /// Upgrades the service from an existing running instance
+    #[clap(short, long, default_value = "false")]
+    pub upgrade: bool,
+
+    /// The number of worker threads to be used by the HTTPS proxy service.
+    ///
+    /// For background services the default is always (1) and cannot be changed.
+    #[clap(short, long, required = false, default_value = "2")]
+    pub worker_threads: Option<usize>,
+
+    /// The PATH to the configuration file to be used.
+    ///
+    /// The configuration file should be named either `proksi.hcl` or `proksi.yaml`
+    ///
+    /// and be present in that path. If no path is provided, a minimal default
+    /// configuration will be used.
+    #[clap(short, required = false, long)]
+    #[allow(clippy::struct_field_names)]
+    pub config_path: Option<Cow<'static, str>>,
+
+    /// General config
+    #[command(flatten)]
+    pub logging: Logging,
+
+    #[command(flatten)]
+    pub auto_reload: AutoReload,
+
+    #[command(flatten)]
+    pub docker: Docker,
+
+    #[clap(skip)]
+    pub lets_encrypt: LetsEncrypt,
+
+    /// Configuration for paths (TLS, config file, etc.)
+    #[clap(skip)]
+    pub paths: Path,
+
+    /// The routes to be proxied to.
+    #[clap(skip)]
+    pub routes: Vec<Route>,
+
+    /// Listeners and upstreams that don't necessarily need
+    /// to be HTTP/HTTPS related (ex: TCP and UDP proxies)
+    #[clap(skip)]
+    #[serde(default)]
+    pub listeners: Vec<ConfigListener>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_path: Some(Cow::Borrowed("/etc/proksi/config")),
+            service_name: Cow::Borrowed("proksi"),
+            server: ServerCfg {
+                https_address: Some(Cow::Borrowed("0.0.0.0:443")),
+                http_address: Some(Cow::Borrowed("0.0.0.0:80")),
+                metrics_address: None,
+                proxy_protocol: ServerProxyProtocol::default(),
+                client_ip: ServerClientIp::default(),
+                http3: ServerHttp3::default(),
+            },
+            worker_threads: Some(2),
+            upgrade: false,
+            daemon: false,
+            docker: Docker::default(),
+            lets_encrypt: LetsEncrypt::default(),
+            routes: vec![],
+            listeners: vec![],
+            auto_reload: AutoReload::default(),
+            store: StoreConfig::default(),
+            logging: Logging {
+                enabled: true,
+                level: LogLevel::Info,
+                access_logs_enabled: true,
+                error_logs_enabled: true,
+                format: LogFormat::Json,
+                path: None,
+                rotation: LogRotation::Never,
+            },
+            paths: Path::default(),
+        }
+    }
+}
+
+// impl Config {
+//     // Allow the configuration to be extracted from any `Provider`.
+//     fn from<T: figment::Provider>(provider: T) -> Result<Config, figment::Error> {
+//         Figment::from(provider).extract()
+//     }
+
+//     // Provide a default provider, a `Figment`.
+//     fn figment() -> Figment {
+//         use figment::providers::Env;
+
+//         // In reality, whatever the library desires.
+//         Figment::from(Config::default()).merge(Env::prefixed("APP_"))
+//     }
+// }
+
+/// Implement the `Provider` trait for the `Config` struct.
+/// This allows the `Config` struct to be used as a configuration provider with *defaults*.
+impl Provider for Config {
+    fn metadata(&self) -> figment::Metadata {
+        figment::Metadata::named("proksi")
+    }
+
+    fn data(
+        &self,
+    ) -> Result<figment::value::Map<figment::Profile, figment::value::Dict>, figment::Error> {
+        Serialized::defaults(Config::default()).data()
+    }
+}
+
+/// Load the configuration from the configuration file(s) as a `Config` struct.
+/// In theory one could create all 3 configurations formats and they will overlap each other
+///
+/// Nested keys can be separated by double underscores (__) in the environment variables.
+/// E.g. `PROKSI__LOGGING__LEVEL=DEBUG` will set the `level` key in the
+/// `logging` key in the `proksi` key.
+pub fn load(fallback: &str) -> Result<Config, figment::Error> {
+    let parsed_commands = Config::parse();
+
+    let path_with_fallback = match &parsed_commands.config_path {
+        Some(path) => path.as_ref(),
+        None => fallback,
+    };
+
+    // Check if no explicit config path was provided by the user
+    let use_minimal_default = parsed_commands.config_path.is_none();
+
+    load_from_path(path_with_fallback, &parsed_commands, use_minimal_default)
+}
+
+/// Test-friendly version of load that doesn't parse command line arguments
+#[cfg(test)]
+pub(crate) fn load_for_test(fallback: &str) -> Result<Config, figment::Error> {
+    let default_config = Config::default();
+
+    // For tests, always use the regular behavior (no minimal default)
+    // since tests should be explicit about what they're testing
+    load_from_path(fallback, &default_config, false)
+}
+
+/// Creates a minimal default configuration with Let's Encrypt disabled.
+/// This is used when no configuration file is found.
+fn create_minimal_default_config() -> Config {
+    let mut config = Config::default();
+    // Disable Let's Encrypt by default in minimal config
+    config.lets_encrypt.enabled = Some(false);
+    config
+}
+
+/// Load configuration from a specific path, used for testing and internal logic
+pub(crate) fn load_from_path(
+    config_path: &str,
+    parsed_commands: &Config,
+    use_minimal_default: bool,
+) -> Result<Config, figment::Error> {
+    let mut figment = Figment::new()
+        .merge(Config::default())
+        .merge(Serialized::defaults(parsed_commands));
+
+    // Check if the path is a file or directory
+    if std::path::Path::new(config_path).is_file() {
+        // If it's a file, load it directly based on its extension
+        let path_buf = std::path::PathBuf::from(config_path);
+        if let Some(extension) = path_buf.extension() {
+            match extension.to_str() {
+                Some("yml" | "yaml") => {
+                    figment = figment.merge(Yaml::file(config_path));
+                }
+                Some("hcl") => {
+                    figment = figment.merge(Hcl::file(config_path));
+                }
+                _ => {
+                    // Try to load as both formats for compatibility
+                    figment = figment
+                        .merge(Yaml::file(config_path))
+                        .merge(Hcl::file(config_path));
+                }
+            }
+        } else {
+            // No extension, try both formats
+            figment = figment
+                .merge(Yaml::file(config_path))
+                .merge(Hcl::file(config_path));
+        }
+    } else {
+        // If it's a directory, check if any config files exist
+        let config_files = [
+            format!("{config_path}/proksi.yml"),
+            format!("{config_path}/proksi.yaml"),
+            format!("{config_path}/proksi.hcl"),
+        ];
+
+        let config_files_found = config_files
+            .iter()
+            .any(|config_file| std::path::Path::new(config_file).exists());
+
+        if config_files_found {
+            // If at least one config file exists, use the original behavior
+            figment = figment
+                .merge(Yaml::file(format!("{config_path}/proksi.yml")))
+                .merge(Yaml::file(format!("{config_path}/proksi.yaml")))
+                .merge(Hcl::file(format!("{config_path}/proksi.hcl")));
+        } else if use_minimal_default {
+            // No config files found and user didn't explicitly provide a path
+            eprintln!("Warning: No configuration files found. Using minimal default configuration with Let's Encrypt disabled.");
+            figment = figment.merge(Serialized::defaults(create_minimal_default_config()));
+        } else {
+            // Use original behavior - try to load the files anyway (figment will handle missing files)
+            figment = figment
+                .merge(Yaml::file(format!("{config_path}/proksi.yml")))
+                .merge(Yaml::file(format!("{config_path}/proksi.yaml")))
+                .merge(Hcl::file(format!("{config_path}/proksi.hcl")));
+        }
+    }
+
+    let config: Config = figment
+        .merge(Env::prefixed("PROKSI_").split("__"))
+        .extract()?;
+
+    // validate configuration and throw error upwards
+    validate::check_config(&config).map_err(|err| figment::Error::from(err.to_string()))?;
+
+    Ok(config)
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn log_level_deser<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "debug" => Ok(LogLevel::Debug),
+        "info" => Ok(LogLevel::Info),
+        "warn" => Ok(LogLevel::Warn),
+        "error" => Ok(LogLevel::Error),
+        "trace" => Ok(LogLevel::Trace),
+        _ => Err(serde::de::Error::custom(
+            "expected one of DEBUG, INFO, WARN, ERROR, TRACE",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `DockerServiceMode` Enum
+fn docker_mode_deser<'de, D>(deserializer: D) -> Result<DockerServiceMode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "swarm" => Ok(DockerServiceMode::Swarm),
+        "container" => Ok(DockerServiceMode::Container),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: Swarm, Container",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn log_format_deser<'de, D>(deserializer: D) -> Result<LogFormat, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "json" => Ok(LogFormat::Json),
+        "pretty" => Ok(LogFormat::Pretty),
+        _ => Err(serde::de::Error::custom("expected one of: json, pretty")),
+    }
+}
+
+fn log_rotation_deser<'de, D>(deserializer: D) -> Result<LogRotation, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "daily" => Ok(LogRotation::Daily),
+        "hourly" => Ok(LogRotation::Hourly),
+        "minutely" => Ok(LogRotation::Minutely),
+        "never" => Ok(LogRotation::Never),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: daily, hourly, minutely, never",
+        )),
+    }
+}
+
+/// Deserialize function to convert a string to a `LogLevel` Enum
+fn proto_version_deser<'de, D>(deserializer: D) -> Result<ProtoVersion, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "v1.1" => Ok(ProtoVersion::V1_1),
+        "v1.2" => Ok(ProtoVersion::V1_2),
+        "v1.3" => Ok(ProtoVersion::V1_3),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: v1.1, v1.2, v1.3",
+        )),
+    }
+}
+
+fn deserialize_cache_type<'de, D>(deserializer: D) -> Result<RouteCacheType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "disk" => Ok(RouteCacheType::Disk),
+        "memcache" => Ok(RouteCacheType::MemCache),
+        _ => Err(serde::de::Error::custom("expected one of: disk, memcache")),
+    }
+}
+
+fn load_balancing_algorithm_deser<'de, D>(
+    deserializer: D,
+) -> Result<LoadBalancingAlgorithm, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "round_robin" => Ok(LoadBalancingAlgorithm::RoundRobin),
+        "least_connections" => Ok(LoadBalancingAlgorithm::LeastConnections),
+        "random" => Ok(LoadBalancingAlgorithm::Random),
+        "power_of_two" => Ok(LoadBalancingAlgorithm::PowerOfTwo),
+        "consistent_hash" => Ok(LoadBalancingAlgorithm::ConsistentHash),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: round_robin, least_connections, random, power_of_two, consistent_hash",
+        )),
+    }
+}
+
+fn hash_key_source_deser<'de, D>(deserializer: D) -> Result<HashKeySource, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "client_ip" => Ok(HashKeySource::ClientIp),
+        "header" => Ok(HashKeySource::Header),
+        "cookie" => Ok(HashKeySource::Cookie),
+        "path" => Ok(HashKeySource::Path),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: client_ip, header, cookie, path",
+        )),
+    }
+}
+
+fn health_check_type_deser<'de, D>(deserializer: D) -> Result<HealthCheckType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "tcp" => Ok(HealthCheckType::Tcp),
+        "http" => Ok(HealthCheckType::Http),
+        "https" => Ok(HealthCheckType::Https),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: tcp, http, https",
+        )),
+    }
+}
+
+fn same_site_deser<'de, D>(deserializer: D) -> Result<SameSitePolicy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "strict" => Ok(SameSitePolicy::Strict),
+        "lax" => Ok(SameSitePolicy::Lax),
+        "none" => Ok(SameSitePolicy::None),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: strict, lax, none",
+        )),
+    }
+}
+
+fn proxy_protocol_version_deser<'de, D>(
+    deserializer: D,
+) -> Result<Option<ProxyProtocolVersion>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+    match s.map(|s| s.to_lowercase()).as_deref() {
+        None => Ok(None),
+        Some("v1") => Ok(Some(ProxyProtocolVersion::V1)),
+        Some("v2") => Ok(Some(ProxyProtocolVersion::V2)),
+        _ => Err(serde::de::Error::custom("expected one of: v1, v2")),
+    }
+}
+
+fn forwarded_headers_deser<'de, D>(deserializer: D) -> Result<Vec<ForwardedHeader>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|s| match s.to_lowercase().replace('-', "_").as_str() {
+            "x_forwarded_for" => Ok(ForwardedHeader::XForwardedFor),
+            "x_forwarded_proto" => Ok(ForwardedHeader::XForwardedProto),
+            "x_forwarded_host" => Ok(ForwardedHeader::XForwardedHost),
+            "x_real_ip" => Ok(ForwardedHeader::XRealIp),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            _ => Err(serde::de::Error::custom(
+                "expected one of: x_forwarded_for, x_forwarded_proto, x_forwarded_host, x_real_ip, forwarded",
+            )),
+        })
+        .collect()
+}
+
+fn forwarded_headers_mode_deser<'de, D>(deserializer: D) -> Result<ForwardedHeadersMode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "append" => Ok(ForwardedHeadersMode::Append),
+        "overwrite" => Ok(ForwardedHeadersMode::Overwrite),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: append, overwrite",
+        )),
+    }
+}
+
+fn upstream_protocol_deser<'de, D>(deserializer: D) -> Result<Option<UpstreamProtocol>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+    match s.map(|s| s.to_lowercase()).as_deref() {
+        None => Ok(None),
+        Some("http1") => Ok(Some(UpstreamProtocol::Http1)),
+        Some("h2") => Ok(Some(UpstreamProtocol::H2)),
+        Some("h2c") => Ok(Some(UpstreamProtocol::H2c)),
+        Some("grpc") => Ok(Some(UpstreamProtocol::Grpc)),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: http1, h2, h2c, grpc",
+        )),
+    }
+}
+
+fn client_ip_source_deser<'de, D>(deserializer: D) -> Result<ClientIpSource, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    // the defaults are serialized with the variant names (ex: `XForwardedFor`)
+    match s.to_lowercase().replace(['-', '_'], "").as_str() {
+        "xforwardedfor" => Ok(ClientIpSource::XForwardedFor),
+        "cfconnectingip" => Ok(ClientIpSource::CfConnectingIp),
+        "xrealip" => Ok(ClientIpSource::XRealIp),
+        "peer" => Ok(ClientIpSource::Peer),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: x_forwarded_for, cf_connecting_ip, x_real_ip, peer",
+        )),
+    }
+}
+
+fn retry_conditions_deser<'de, D>(deserializer: D) -> Result<Vec<RetryCondition>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|s| match s.to_lowercase().as_str() {
+            "connect_error" => Ok(RetryCondition::ConnectError),
+            "timeout" => Ok(RetryCondition::Timeout),
+            "502" => Ok(RetryCondition::BadGateway),
+            "503" => Ok(RetryCondition::ServiceUnavailable),
+            "504" => Ok(RetryCondition::GatewayTimeout),
+            _ => Err(serde::de::Error::custom(
+                "expected one of: connect_error, timeout, 502, 503, 504",
+            )),
+        })
+        .collect()
+}
+
+fn upstream_alpn_deser<'de, D>(deserializer: D) -> Result<Option<UpstreamAlpn>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "h1" => Ok(Some(UpstreamAlpn::H1)),
+        "h2" => Ok(Some(UpstreamAlpn::H2)),
+        "h2h1" => Ok(Some(UpstreamAlpn::H2h1)),
+        _ => Err(serde::de::Error::custom("expected one of: h1, h2, h2h1")),
+    }
+}
+
+fn listener_protocol_deser<'de, D>(deserializer: D) -> Result<ListenerProtocol, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "tcp" => Ok(ListenerProtocol::Tcp),
+        "udp" => Ok(ListenerProtocol::Udp),
+        _ => Err(serde::de::Error::custom("expected one of: tcp, udp")),
+    }
+}
+
+fn store_type_deser<'de, D>(deserializer: D) -> Result<StoreType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "memory" => Ok(StoreType::Memory),
+        "redis" => Ok(StoreType::Redis),
+        _ => Err(serde::de::Error::custom("expected one of: memory, redis")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_store_config() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "user@domain.net"
+                store:
+                  store_type: "redis"
+                  redis_url: "redis://localhost:6379"
+                "#,
+            )?;
+
+            let config = load_for_test(&tmp_dir);
+            let proxy_config = config.unwrap();
+
+            assert!(matches!(proxy_config.store.store_type, StoreType::Redis));
+            assert_eq!(
+                proxy_config.store.redis_url,
+                Some("redis://localhost:6379".to_string())
+            );
+
+            Ok(())
+        });
+
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  email: "user@domain.net"
+                store:
+                  store_type: "memory"
+                "#,
+            )?;
+
+            let config = load_for_test(&tmp_dir);
+            let proxy_config = config.unwrap();
+
+            assert!(matches!(proxy_config.store.store_type, StoreType::Memory));
+            assert_eq!(proxy_config.store.redis_url, None);
+
+            Ok(())
+        });
+    }
+
+    fn helper_config_file() -> &'static str {
+        r#"
+        service_name: "proksi"
+        lets_encrypt:
+          email: "user@domain.net"
+        logging:
+          level: "INFO"
+          access_logs_enabled: true
+          error_logs_enabled: false
+        paths:
+          lets_encrypt: "/test/letsencrypt"
+        routes:
+          - host: "example.com"
+            plugins:
+              - name: "cors"
+                config:
+                  allowed_origins: ["*"]
+            headers:
+              add:
+                - name: "X-Forwarded-For"
+                  value: "<value>"
+                - name: "X-Api-Version"
+                  value: "1.0"
+              remove:
+                - name: "Server"
+            upstreams:
+              - ip: "10.0.1.3/25"
+                port: 3000
+                network: "public"
+      "#
+    }
+
+    #[test]
+    fn test_load_config_from_yaml() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(format!("{}/proksi.yaml", tmp_dir), helper_config_file())?;
+
+            let config = load_for_test(&tmp_dir);
+            let proxy_config = config.unwrap();
+            assert_eq!(proxy_config.service_name, "proksi");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_from_direct_file_path_hcl() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_file_path = format!("{}/custom_config.hcl", tmp_dir);
+
+            jail.create_file(
+                &config_file_path,
+                r#"
+                lets_encrypt {
+                  enabled = false
+                  email = "test@domain.com"
+                }
+
+                routes = [
+                  {
+                    host = "example.localhost",
+                    upstreams = [
+                      {
+                        ip = "localhost"
+                        port = 3000
+                      }
+                    ]
+                  }
+                ]
+                "#,
+            )?;
+
+            // Create a default config to use as parsed_commands
+            let default_config = Config::default();
+            let config = load_from_path(&config_file_path, &default_config, false);
+            let proxy_config = config.unwrap();
+
+            assert_eq!(proxy_config.routes.len(), 1);
+            assert_eq!(proxy_config.routes[0].host, "example.localhost");
+            assert_eq!(proxy_config.routes[0].upstreams[0].port, 3000);
+            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_from_direct_file_path_yaml() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_file_path = format!("{}/custom_config.yml", tmp_dir);
+
+            jail.create_file(
+                &config_file_path,
+                r#"
+                lets_encrypt:
+                  enabled: false
+                  email: "test@domain.com"
+
+                routes:
+                  - host: "yaml.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3001
+                "#,
+            )?;
+
+            // Create a default config to use as parsed_commands
+            let default_config = Config::default();
+            let config = load_from_path(&config_file_path, &default_config, false);
+            let proxy_config = config.unwrap();
+
+            assert_eq!(proxy_config.routes.len(), 1);
+            assert_eq!(proxy_config.routes[0].host, "yaml.localhost");
+            assert_eq!(proxy_config.routes[0].upstreams[0].port, 3001);
+            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_lets_encrypt_validation_when_disabled() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  enabled: false
+                  email: "contact@example.com"  # This should not cause validation error when disabled
+
+                routes:
+                  - host: "test.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#,
+            )?;
+
+            let config = load_for_test(&tmp_dir);
+            // This should not panic or return error due to @example.com email when Let's Encrypt is disabled
+            assert!(config.is_ok());
+            let proxy_config = config.unwrap();
+            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_lets_encrypt_validation_when_enabled() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                lets_encrypt:
+                  enabled: true
+                  email: "contact@example.com"  # This should cause validation error when enabled
+
+                routes:
+                  - host: "test.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#,
+            )?;
+
+            let config = load_for_test(&tmp_dir);
+            // This should return an error due to @example.com email when Let's Encrypt is enabled
+            assert!(config.is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_upstream_weight_validation() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                routes:
+                  - host: "weighted.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                        weight: 3
+                      - ip: "localhost"
+                        port: 3001
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert_eq!(proxy_config.routes[0].upstreams[0].weight, Some(3));
+            assert_eq!(proxy_config.routes[0].upstreams[1].weight, None);
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                routes:
+                  - host: "weighted.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                        weight: 0
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_load_balancing() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                routes:
+                  - host: "hashed.localhost"
+                    load_balancing:
+                      algorithm: "consistent_hash"
+                      hash_key:
+                        source: "cookie"
+                        name: "session"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                  - host: "default.localhost"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let load_balancing = proxy_config.routes[0].load_balancing.as_ref().unwrap();
+            let hash_key = load_balancing.hash_key.as_ref().unwrap();
+
+            assert_eq!(
+                load_balancing.algorithm,
+                LoadBalancingAlgorithm::ConsistentHash
+            );
+            assert_eq!(hash_key.source, HashKeySource::Cookie);
+            assert_eq!(hash_key.name, Some(Cow::Borrowed("session")));
+            assert!(proxy_config.routes[1].load_balancing.is_none());
+
+            // cookie keys require a name
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                routes:
+                  - host: "hashed.localhost"
+                    load_balancing:
+                      algorithm: "consistent_hash"
+                      hash_key:
+                        source: "cookie"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            // hash keys are only used by consistent hashing
+            jail.create_file(
+                format!("{}/proksi.yaml", tmp_dir),
+                r#"
+                routes:
+                  - host: "hashed.localhost"
+                    load_balancing:
+                      algorithm: "least_connections"
+                      hash_key:
+                        source: "path"
+                    upstreams:
+                      - ip: "localhost"
+                        port: 3000
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_health_check() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "checked.localhost"
+                    health_check = {
+                      type = "http"
+                      path = "/health"
+                      expected_status = { min = 200, max = 399 }
+                      interval_secs = 5
+                      unhealthy_threshold = 3
+                    }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  },
+                  {
+                    host = "default.localhost"
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let health_check = proxy_config.routes[0].health_check.as_ref().unwrap();
+
+            assert_eq!(health_check.check_type, HealthCheckType::Http);
+            assert_eq!(health_check.path, "/health");
+            assert!(health_check.expected_status.contains(302));
+            assert!(!health_check.expected_status.contains(500));
+            assert_eq!(health_check.interval_secs, 5);
+            assert_eq!(health_check.timeout_secs, 1);
+            assert_eq!(health_check.healthy_threshold, 1);
+            assert_eq!(health_check.unhealthy_threshold, 3);
+            assert!(proxy_config.routes[1].health_check.is_none());
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "checked.localhost"
+                    health_check = {
+                      type = "http"
+                      expected_status = { min = 400, max = 200 }
+                    }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_circuit_breaker() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                server = {
+                  metrics_address = "127.0.0.1:9090"
+                }
+                routes = [
+                  {
+                    host = "breaker.localhost"
+                    circuit_breaker = {
+                      consecutive_failures = 3
+                    }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let circuit_breaker = proxy_config.routes[0].circuit_breaker.as_ref().unwrap();
+
+            assert_eq!(circuit_breaker.consecutive_failures, 3);
+            assert_eq!(circuit_breaker.cooldown_secs, 30);
+            assert_eq!(
+                proxy_config.server.metrics_address.as_deref(),
+                Some("127.0.0.1:9090")
+            );
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "breaker.localhost"
+                    circuit_breaker = { cooldown_secs = 0 }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_retry() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "retry.localhost"
+                    retry = {
+                      max_attempts = 2
+                      retry_on = ["connect_error", "timeout", "503"]
+                      backoff_ms = 50
+                    }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  },
+                  {
+                    host = "default.localhost"
+                    retry = {}
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let retry = proxy_config.routes[0].retry.as_ref().unwrap();
+
+            assert_eq!(retry.max_attempts, 2);
+            assert_eq!(
+                retry.retry_on,
+                vec![
+                    RetryCondition::ConnectError,
+                    RetryCondition::Timeout,
+                    RetryCondition::ServiceUnavailable
+                ]
+            );
+            assert!(!retry.retry_non_idempotent);
+            assert_eq!(retry.backoff_ms, 50);
+            assert_eq!(proxy_config.routes[1].retry, Some(RouteRetry::default()));
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "retry.localhost"
+                    retry = { retry_on = ["500"] }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_peer_options() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "streaming.localhost"
+                    peer_options = {
+                      read_timeout_secs = 1800
+                      alpn = "h1"
+                    }
+                    upstreams = [
+                      {
+                        ip = "localhost"
+                        port = 3000
+                        peer_options = { connection_timeout_secs = 2, verify_cert = true }
+                      }
+                    ]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let route = &proxy_config.routes[0];
+            let peer_options = route.peer_options.as_ref().unwrap();
+            assert_eq!(peer_options.read_timeout_secs, Some(1800));
+            assert_eq!(peer_options.alpn, Some(UpstreamAlpn::H1));
+            assert_eq!(peer_options.connection_timeout_secs, None);
+
+            let upstream_options = route.upstreams[0].peer_options.as_ref().unwrap();
+            assert_eq!(upstream_options.connection_timeout_secs, Some(2));
+            assert_eq!(upstream_options.verify_cert, Some(true));
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "streaming.localhost"
+                    peer_options = { read_timeout_secs = 0 }
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_upstream_tls() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            jail.create_file("ca.pem", "")?;
+            jail.create_file("client.pem", "")?;
+            jail.create_file("client.key", "")?;
+
+            let config_with_tls = |tls: &str| {
+                format!(
+                    r#"
+                    routes = [
+                      {{
+                        host = "internal.localhost"
+                        upstreams = [{{ ip = "localhost", port = 8443, tls = {tls} }}]
+                      }}
+                    ]
+                    "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_tls(&format!(
+                    r#"{{
+                      enabled = true
+                      verify_cert = true
+                      ca_file = "{tmp_dir}/ca.pem"
+                      client_cert = "{tmp_dir}/client.pem"
+                      client_key = "{tmp_dir}/client.key"
+                      sni = "internal.local"
+                    }}"#
+                )),
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let tls = proxy_config.routes[0].upstreams[0].tls.as_ref().unwrap();
+            assert_eq!(tls.enabled, Some(true));
+            assert_eq!(tls.verify_cert, Some(true));
+            assert_eq!(tls.sni.as_deref(), Some("internal.local"));
+            assert!(tls.alternative_cn.is_none());
+
+            let invalid = [
+                // client certificate without a key
+                format!(r#"{{ client_cert = "{tmp_dir}/client.pem" }}"#),
+                // missing CA file
+                format!(r#"{{ ca_file = "{tmp_dir}/missing.pem" }}"#),
+                // settings with TLS disabled
+                r#"{ enabled = false, verify_cert = true }"#.to_string(),
+            ];
+
+            for tls in invalid {
+                jail.create_file(format!("{}/proksi.hcl", tmp_dir), &config_with_tls(&tls))?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_wildcard_and_default_hosts() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "*.preview.example.com"
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  },
+                  {
+                    host = "example.com"
+                    default = true
+                    upstreams = [{ ip = "localhost", port = 3001 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert_eq!(proxy_config.routes[0].host, "*.preview.example.com");
+            assert!(proxy_config.routes[0].default.is_none());
+            assert_eq!(proxy_config.routes[1].default, Some(true));
+
+            let invalid = [
+                // wildcard outside of the first label
+                r#"[{ host = "preview.*.example.com", upstreams = [] }]"#,
+                // more than one default route
+                r#"[
+                  { host = "a.example.com", default = true, upstreams = [] },
+                  { host = "b.example.com", default = true, upstreams = [] }
+                ]"#,
+            ];
+
+            for routes in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!("routes = {routes}"),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_matchers() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            let config_with_matcher = |match_with: &str| {
+                format!(
+                    r#"
+                    routes = [
+                      {{
+                        host = "api.localhost"
+                        match_with = {match_with}
+                        upstreams = [{{ ip = "localhost", port = 3000 }}]
+                      }}
+                    ]
+                    "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_matcher(
+                    r#"{
+                      path = { regex = "^/api/v[0-9]+/" }
+                      methods = ["GET", "HEAD"]
+                      headers = [{ name = "x-api-version", exact = "2" }]
+                      query = [{ name = "debug" }]
+                    }"#,
+                ),
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let matcher = proxy_config.routes[0].match_with.as_ref().unwrap();
+            let path = matcher.path.as_ref().unwrap();
+            assert!(path.patterns.is_empty());
+            assert_eq!(path.regex.as_deref(), Some("^/api/v[0-9]+/"));
+            assert_eq!(matcher.methods.as_ref().unwrap().len(), 2);
+            assert_eq!(matcher.headers.as_ref().unwrap()[0].name, "x-api-version");
+            assert!(matcher.query.as_ref().unwrap()[0].exact.is_none());
+
+            let invalid = [
+                // invalid regex
+                r#"{ path = { regex = "^/api/(" } }"#,
+                // invalid method
+                r#"{ methods = ["GE T"] }"#,
+                // more than one value match
+                r#"{ headers = [{ name = "accept", exact = "a", prefix = "b" }] }"#,
+                // empty query parameter name
+                r#"{ query = [{ name = "" }] }"#,
+            ];
+
+            for match_with in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &config_with_matcher(match_with),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_rewrite() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            let config_with_rewrite = |rewrite: &str| {
+                format!(
+                    r#"
+                    routes = [
+                      {{
+                        host = "apps.localhost"
+                        match_with = {{ path = {{ patterns = ["/grafana/*"] }} }}
+                        rewrite = {rewrite}
+                        upstreams = [{{ ip = "localhost", port = 3000 }}]
+                      }}
+                    ]
+                    "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_rewrite(r#"{ strip_prefix = "/grafana", location = false }"#),
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let rewrite = proxy_config.routes[0].rewrite.as_ref().unwrap();
+            assert_eq!(rewrite.strip_prefix.as_deref(), Some("/grafana"));
+            assert_eq!(rewrite.location, Some(false));
+            assert!(rewrite.add_prefix.is_none());
+
+            let invalid = [
+                // prefix without a leading slash
+                r#"{ strip_prefix = "grafana" }"#,
+                // regex without a replacement
+                r#"{ regex = "^/grafana/(.*)$" }"#,
+                // invalid regex
+                r#"{ regex = "^/grafana/(", replacement = "/$1" }"#,
+            ];
+
+            for rewrite in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &config_with_rewrite(rewrite),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_redirects() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "www.example.com"
+                    redirect_to_host = "example.com"
+                  },
+                  {
+                    host = "example.com"
+                    redirects = [
+                      {
+                        match_with = { path = { patterns = ["/blog/:slug"] } }
+                        to = "https://blog.example.com/{slug}"
+                        status = 301
+                      },
+                      { match_with = { path = { patterns = ["/old"] } }, to = "/new" }
+                    ]
+                    upstreams = [{ ip = "localhost", port = 3000 }]
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert!(proxy_config.routes[0].upstreams.is_empty());
+            assert_eq!(
+                proxy_config.routes[0].redirect_to_host.as_deref(),
+                Some("example.com")
+            );
+
+            let redirects = proxy_config.routes[1].redirects.as_ref().unwrap();
+            assert_eq!(redirects[0].status, 301);
+            assert_eq!(redirects[1].status, 302);
+            assert_eq!(redirects[1].to, "/new");
+
+            let invalid = [
+                // unsupported status code
+                r#"redirects = [{ to = "/new", status = 200 }]"#,
+                // empty target
+                r#"redirects = [{ to = "" }]"#,
+                // not a hostname
+                r#"redirect_to_host = "https://example.com/""#,
+                // redirects to itself
+                r#"redirect_to_host = "Example.com""#,
+            ];
+
+            for redirect in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"routes = [{{ host = "example.com", {redirect} }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_static_files() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            std::fs::create_dir(jail.directory().join("dist")).unwrap();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &format!(
+                    r#"
+                    routes = [
+                      {{
+                        host = "app.localhost"
+                        static = {{ root = "{tmp_dir}/dist", spa = true }}
+                      }}
+                    ]
+                    "#
+                ),
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let static_files = proxy_config.routes[0].static_files.as_ref().unwrap();
+            assert!(static_files.spa);
+            assert!(static_files.precompressed);
+            assert_eq!(static_files.index, vec!["index.html"]);
+
+            let invalid = [
+                // missing root directory
+                format!(r#"static = {{ root = "{tmp_dir}/missing" }}"#),
+                // static files and upstreams
+                format!(
+                    r#"static = {{ root = "{tmp_dir}/dist" }}, upstreams = [{{ ip = "localhost", port = 3000 }}]"#
+                ),
+                // index outside of the directory
+                format!(r#"static = {{ root = "{tmp_dir}/dist", index = ["../index.html"] }}"#),
+            ];
+
+            for route in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"routes = [{{ host = "app.localhost", {route} }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_traffic_split() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    traffic_split = {
+                      groups = [
+                        { name = "canary", percent = 5, upstreams = [{ ip = "10.0.0.2", port = 3000 }] }
+                      ]
+                      sticky = { source = "cookie", name = "session_id" }
+                    }
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let traffic_split = proxy_config.routes[0].traffic_split.as_ref().unwrap();
+            assert_eq!(traffic_split.groups[0].name, "canary");
+            assert_eq!(traffic_split.groups[0].percent, 5);
+            assert_eq!(traffic_split.groups[0].upstreams[0].ip, "10.0.0.2");
+
+            let sticky = traffic_split.sticky.as_ref().unwrap();
+            assert_eq!(sticky.source, HashKeySource::Cookie);
+            assert_eq!(sticky.name.as_deref(), Some("session_id"));
+
+            let group = |name: &str, percent: u8| {
+                format!(
+                    r#"{{ name = "{name}", percent = {percent}, upstreams = [{{ ip = "10.0.0.2", port = 3000 }}] }}"#
+                )
+            };
+            let invalid = [
+                // more than 100 percent
+                format!("groups = [{}, {}]", group("canary", 60), group("beta", 50)),
+                // duplicated group name
+                format!("groups = [{}, {}]", group("canary", 5), group("canary", 5)),
+                // group without upstreams
+                r#"groups = [{ name = "canary", percent = 5, upstreams = [] }]"#.to_string(),
+                // cookie without a name
+                format!(
+                    r#"groups = [{}], sticky = {{ source = "cookie" }}"#,
+                    group("canary", 5)
+                ),
+            ];
+
+            for traffic_split in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(
+                        r#"routes = [{{ host = "example.com", upstreams = [{{ ip = "10.0.0.1", port = 3000 }}], traffic_split = {{ {traffic_split} }} }}]"#
+                    ),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            // the remaining percentage needs the upstreams of the route
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &format!(
+                    r#"routes = [{{ host = "example.com", traffic_split = {{ groups = [{}] }} }}]"#,
+                    group("canary", 5)
+                ),
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_mirror() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    mirror = {
+                      target = "http://10.0.0.5:3000"
+                      percent = 10
+                      match_with = { path = { patterns = ["/api/*"] } }
+                    }
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let mirror = proxy_config.routes[0].mirror.as_ref().unwrap();
+            assert_eq!(mirror.target, "http://10.0.0.5:3000");
+            assert_eq!(mirror.percent, 10);
+            assert_eq!(mirror.max_body_bytes, 1024 * 1024);
+            assert_eq!(mirror.timeout_ms, 5000);
+
+            let invalid = [
+                // not an http(s) URL
+                r#"target = "10.0.0.5:3000""#,
+                // more than 100 percent
+                r#"target = "http://10.0.0.5:3000", percent = 150"#,
+                // no timeout
+                r#"target = "http://10.0.0.5:3000", timeout_ms = 0"#,
+            ];
+
+            for mirror in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"routes = [{{ host = "example.com", mirror = {{ {mirror} }} }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_sticky() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    sticky = { ttl_secs = 3600, same_site = "none" }
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let sticky = proxy_config.routes[0].sticky.as_ref().unwrap();
+            assert!(sticky.enabled);
+            assert!(sticky.secure);
+            assert_eq!(sticky.cookie_name, "proksi_sticky");
+            assert_eq!(sticky.ttl_secs, Some(3600));
+            assert_eq!(sticky.same_site, SameSitePolicy::None);
+
+            let invalid = [
+                // invalid cookie name
+                r#"cookie_name = "my session""#,
+                // browsers reject insecure SameSite=None cookies
+                r#"same_site = "none", secure = false"#,
+                // unknown SameSite policy
+                r#"same_site = "always""#,
+                // short secret
+                r#"secret = "secret""#,
+            ];
+
+            for sticky in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"routes = [{{ host = "example.com", sticky = {{ {sticky} }} }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_route_forwarded_headers() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    forwarded_headers = {
+                      headers = ["X-Forwarded-For", "x_real_ip", "forwarded"]
+                      trusted_proxies = ["10.0.0.0/8", "192.168.1.10"]
+                    }
+                  },
+                  {
+                    host = "api.example.com"
+                    upstreams = [{ ip = "10.0.0.2", port = 3000 }]
+                    forwarded_headers = { mode = "overwrite" }
+                  }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let forwarded = proxy_config.routes[0].forwarded_headers.as_ref().unwrap();
+            assert_eq!(
+                forwarded.headers,
+                vec![
+                    ForwardedHeader::XForwardedFor,
+                    ForwardedHeader::XRealIp,
+                    ForwardedHeader::Forwarded
+                ]
+            );
+            assert_eq!(forwarded.mode, ForwardedHeadersMode::Append);
+            assert_eq!(
+                forwarded.trusted_proxies,
+                vec!["10.0.0.0/8", "192.168.1.10"]
+            );
 
-    use super::*;
+            let forwarded = proxy_config.routes[1].forwarded_headers.as_ref().unwrap();
+            assert_eq!(forwarded.headers.len(), 3);
+            assert_eq!(forwarded.mode, ForwardedHeadersMode::Overwrite);
+
+            let invalid = [
+                // unknown header
+                r#"headers = ["x_forwarded_port"]"#,
+                // no headers
+                r#"headers = []"#,
+                // invalid CIDR
+                r#"trusted_proxies = ["10.0.0.0/33"]"#,
+                // trusted proxies are ignored when overwriting
+                r#"mode = "overwrite", trusted_proxies = ["10.0.0.0/8"]"#,
+            ];
+
+            for forwarded in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(
+                        r#"routes = [{{ host = "example.com", forwarded_headers = {{ {forwarded} }} }}]"#
+                    ),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            Ok(())
+        });
+    }
 
     #[test]
-    fn test_store_config() {
+    fn test_proxy_protocol() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
 
             jail.create_file(
-                format!("{}/proksi.yaml", tmp_dir),
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt:
-                  email: "user@domain.net"
-                store:
-                  store_type: "redis"
-                  redis_url: "redis://localhost:6379"
+                server {
+                  proxy_protocol = {
+                    enabled = true
+                    trusted_sources = ["10.0.0.0/8", "192.168.1.10"]
+                  }
+                }
+
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [
+                      { ip = "10.0.0.1", port = 3000, proxy_protocol = "v2" },
+                      { ip = "10.0.0.2", port = 3000 }
+                    ]
+                  }
+                ]
                 "#,
             )?;
 
-            let config = load_for_test(&tmp_dir);
-            let proxy_config = config.unwrap();
-
-            assert!(matches!(proxy_config.store.store_type, StoreType::Redis));
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let proxy_protocol = &proxy_config.server.proxy_protocol;
+            assert!(proxy_protocol.enabled);
             assert_eq!(
-                proxy_config.store.redis_url,
-                Some("redis://localhost:6379".to_string())
+                proxy_protocol.trusted_sources,
+                vec!["10.0.0.0/8", "192.168.1.10"]
             );
+            assert_eq!(proxy_protocol.header_timeout_ms, 5000);
+
+            let upstreams = &proxy_config.routes[0].upstreams;
+            assert_eq!(upstreams[0].proxy_protocol, Some(ProxyProtocolVersion::V2));
+            assert_eq!(upstreams[1].proxy_protocol, None);
+
+            let invalid = [
+                // any client could send the header
+                r#"server { proxy_protocol = { enabled = true } }"#,
+                // invalid CIDR
+                r#"server { proxy_protocol = { enabled = true, trusted_sources = ["10.0.0.0/33"] } }"#,
+                // unknown version
+                r#"routes = [{ host = "example.com", upstreams = [{ ip = "10.0.0.1", port = 3000, proxy_protocol = "v3" }] }]"#,
+            ];
+
+            for config in invalid {
+                jail.create_file(format!("{}/proksi.hcl", tmp_dir), config)?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
 
             Ok(())
         });
+    }
 
+    #[test]
+    fn test_http3() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
 
             jail.create_file(
-                format!("{}/proksi.yaml", tmp_dir),
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt:
-                  email: "user@domain.net"
-                store:
-                  store_type: "memory"
+                server {
+                  https_address = "0.0.0.0:8443"
+                  http3 = { enabled = true }
+                }
                 "#,
             )?;
 
-            let config = load_for_test(&tmp_dir);
-            let proxy_config = config.unwrap();
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let http3 = &proxy_config.server.http3;
+            assert!(http3.enabled);
+            assert_eq!(http3.alt_svc_max_age_secs, 86400);
+            // the HTTPS port is used over UDP
+            assert_eq!(proxy_config.server.http3_address(), "0.0.0.0:8443");
 
-            assert!(matches!(proxy_config.store.store_type, StoreType::Memory));
-            assert_eq!(proxy_config.store.redis_url, None);
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"server { http3 = { enabled = true, address = "[::]:4433", alt_svc_max_age_secs = 60 } }"#,
+            )?;
+
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert_eq!(proxy_config.server.http3_address(), "[::]:4433");
+            assert_eq!(proxy_config.server.http3.alt_svc_max_age_secs, 60);
+
+            // not an IP and a port
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"server { http3 = { enabled = true, address = "localhost" } }"#,
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
 
             Ok(())
         });
     }
 
-    fn helper_config_file() -> &'static str {
-        r#"
-        service_name: "proksi"
-        lets_encrypt:
-          email: "user@domain.net"
-        logging:
-          level: "INFO"
-          access_logs_enabled: true
-          error_logs_enabled: false
-        paths:
-          lets_encrypt: "/test/letsencrypt"
-        routes:
-          - host: "example.com"
-            plugins:
-              - name: "cors"
-                config:
-                  allowed_origins: ["*"]
-            headers:
-              add:
-                - name: "X-Forwarded-For"
-                  value: "<value>"
-                - name: "X-Api-Version"
-                  value: "1.0"
-              remove:
-                - name: "Server"
-            upstreams:
-              - ip: "10.0.1.3/25"
-                port: 3000
-                network: "public"
-      "#
-    }
-
     #[test]
-    fn test_load_config_from_yaml() {
+    fn test_upstream_protocol() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
 
-            jail.create_file(format!("{}/proksi.yaml", tmp_dir), helper_config_file())?;
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                routes = [
+                  {
+                    host = "grpc.example.com"
+                    upstreams = [
+                      { ip = "10.0.0.1", port = 50051, protocol = "grpc" },
+                      { ip = "10.0.0.2", port = 8080, protocol = "H2C" },
+                      { ip = "10.0.0.3", port = 3000 }
+                    ]
+                  }
+                ]
+                "#,
+            )?;
 
-            let config = load_for_test(&tmp_dir);
-            let proxy_config = config.unwrap();
-            assert_eq!(proxy_config.service_name, "proksi");
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let upstreams = &proxy_config.routes[0].upstreams;
+            assert_eq!(upstreams[0].protocol, Some(UpstreamProtocol::Grpc));
+            assert_eq!(upstreams[1].protocol, Some(UpstreamProtocol::H2c));
+            assert_eq!(upstreams[2].protocol, None);
+
+            let invalid = [
+                // unknown protocol
+                r#"protocol = "h3""#,
+                // h2 is always over TLS
+                r#"protocol = "h2", tls = { enabled = false }"#,
+                // h2c never is
+                r#"protocol = "h2c", tls = { enabled = true }"#,
+                // the protocol decides the ALPN
+                r#"protocol = "grpc", peer_options = { alpn = "h2h1" }"#,
+            ];
+
+            for upstream in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(
+                        r#"routes = [{{ host = "example.com", upstreams = [{{ ip = "10.0.0.1", port = 3000, {upstream} }}] }}]"#
+                    ),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
 
             Ok(())
         });
     }
 
     #[test]
-    fn test_load_config_from_direct_file_path_hcl() {
+    fn test_route_grpc_web() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
-            let config_file_path = format!("{}/custom_config.hcl", tmp_dir);
 
             jail.create_file(
-                &config_file_path,
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt {
-                  enabled = false
-                  email = "test@domain.com"
-                }
-
                 routes = [
                   {
-                    host = "example.localhost",
-                    upstreams = [
-                      {
-                        ip = "localhost"
-                        port = 3000
-                      }
-                    ]
+                    host = "api.example.com"
+                    upstreams = [{ ip = "10.0.0.1", port = 50051, protocol = "h2c" }]
+                    grpc_web = {
+                      allowed_origins = ["https://app.example.com", "http://localhost:8080"]
+                      max_age_secs = 600
+                    }
+                  },
+                  {
+                    host = "web.example.com"
+                    upstreams = [{ ip = "10.0.0.2", port = 50051 }]
+                    grpc_web = {}
                   }
                 ]
                 "#,
             )?;
 
-            // Create a default config to use as parsed_commands
-            let default_config = Config::default();
-            let config = load_from_path(&config_file_path, &default_config, false);
-            let proxy_config = config.unwrap();
-
-            assert_eq!(proxy_config.routes.len(), 1);
-            assert_eq!(proxy_config.routes[0].host, "example.localhost");
-            assert_eq!(proxy_config.routes[0].upstreams[0].port, 3000);
-            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let grpc_web = proxy_config.routes[0].grpc_web.as_ref().unwrap();
+            assert_eq!(
+                grpc_web.allowed_origins,
+                vec!["https://app.example.com", "http://localhost:8080"]
+            );
+            assert_eq!(grpc_web.max_age_secs, 600);
+
+            let grpc_web = proxy_config.routes[1].grpc_web.as_ref().unwrap();
+            assert!(grpc_web.allowed_origins.is_empty());
+            assert_eq!(grpc_web.max_age_secs, 86400);
+
+            let invalid = [
+                // origins have a scheme
+                (r#"allowed_origins = ["app.example.com"]"#, ""),
+                // and nothing after the host
+                (r#"allowed_origins = ["https://app.example.com/"]"#, ""),
+                // gRPC needs HTTP/2
+                ("", r#", protocol = "http1""#),
+            ];
+
+            for (grpc_web, upstream) in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(
+                        r#"routes = [{{ host = "example.com", grpc_web = {{ {grpc_web} }}, upstreams = [{{ ip = "10.0.0.1", port = 8080{upstream} }}] }}]"#
+                    ),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
 
             Ok(())
         });
     }
 
     #[test]
-    fn test_load_config_from_direct_file_path_yaml() {
+    fn test_listeners() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
-            let config_file_path = format!("{}/custom_config.yml", tmp_dir);
 
             jail.create_file(
-                &config_file_path,
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt:
-                  enabled: false
-                  email: "test@domain.com"
-
-                routes:
-                  - host: "yaml.localhost"
-                    upstreams:
-                      - ip: "localhost"
-                        port: 3001
+                listeners = [
+                  {
+                    name = "postgres"
+                    address = "0.0.0.0:5432"
+                    upstreams = [{ ip = "10.0.0.1", port = 5432 }, { ip = "10.0.0.2", port = 5432 }]
+                    load_balancing = { algorithm = "least_connections" }
+                  },
+                  {
+                    name = "game"
+                    protocol = "UDP"
+                    address = "[::]:27015"
+                    upstreams = [{ ip = "10.0.0.3", port = 27015 }]
+                    idle_timeout_secs = 120
+                    max_sessions = 100
+                  }
+                ]
                 "#,
             )?;
 
-            // Create a default config to use as parsed_commands
-            let default_config = Config::default();
-            let config = load_from_path(&config_file_path, &default_config, false);
-            let proxy_config = config.unwrap();
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let postgres = &proxy_config.listeners[0];
+            assert_eq!(postgres.protocol, ListenerProtocol::Tcp);
+            assert_eq!(postgres.upstreams.len(), 2);
+            assert_eq!(postgres.idle_timeout_secs, 60);
+            assert_eq!(postgres.max_sessions, 4096);
+            assert_eq!(
+                postgres.load_balancing.as_ref().unwrap().algorithm,
+                LoadBalancingAlgorithm::LeastConnections
+            );
 
-            assert_eq!(proxy_config.routes.len(), 1);
-            assert_eq!(proxy_config.routes[0].host, "yaml.localhost");
-            assert_eq!(proxy_config.routes[0].upstreams[0].port, 3001);
-            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+            let game = &proxy_config.listeners[1];
+            assert_eq!(game.protocol, ListenerProtocol::Udp);
+            assert_eq!(game.idle_timeout_secs, 120);
+            assert_eq!(game.max_sessions, 100);
+
+            let upstreams = r#"upstreams = [{ ip = "10.0.0.1", port = 5432 }]"#;
+            let invalid = [
+                // unknown protocol
+                format!(r#"address = "0.0.0.0:5432", protocol = "sctp", {upstreams}"#),
+                // not an address to bind to
+                format!(r#"address = "db.example.com:5432", {upstreams}"#),
+                // no upstreams
+                r#"address = "0.0.0.0:5432", upstreams = []"#.to_string(),
+                // the upstreams are not HTTP
+                r#"address = "0.0.0.0:5432", upstreams = [{ ip = "10.0.0.1", port = 5432, protocol = "h2c" }]"#.to_string(),
+                // connections carry no headers or cookies
+                format!(
+                    r#"address = "0.0.0.0:5432", {upstreams}, load_balancing = {{ algorithm = "consistent_hash", hash_key = {{ source = "header", name = "x-user" }} }}"#
+                ),
+                format!(r#"address = "0.0.0.0:5432", {upstreams}, idle_timeout_secs = 0"#),
+                format!(r#"address = "0.0.0.0:5432", {upstreams}, max_sessions = 0"#),
+            ];
+
+            for listener in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"listeners = [{{ name = "db", {listener} }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            // names are unique
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                listeners = [
+                  { name = "db", address = "0.0.0.0:5432", upstreams = [{ ip = "10.0.0.1", port = 5432 }] },
+                  { name = "db", address = "0.0.0.0:5433", upstreams = [{ ip = "10.0.0.2", port = 5432 }] }
+                ]
+                "#,
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
 
             Ok(())
         });
     }
 
     #[test]
-    fn test_lets_encrypt_validation_when_disabled() {
+    fn test_route_tls_passthrough() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
 
             jail.create_file(
-                format!("{}/proksi.yaml", tmp_dir),
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt:
-                  enabled: false
-                  email: "contact@example.com"  # This should not cause validation error when disabled
-
-                routes:
-                  - host: "test.localhost"
-                    upstreams:
-                      - ip: "localhost"
-                        port: 3000
+                routes = [
+                  {
+                    host = "db.example.com"
+                    tls_passthrough = true
+                    upstreams = [{ ip = "10.0.0.1", port = 5432 }, { ip = "10.0.0.2", port = 5432 }]
+                    load_balancing = { algorithm = "consistent_hash" }
+                  },
+                  {
+                    host = "example.com"
+                    upstreams = [{ ip = "10.0.0.3", port = 8080 }]
+                  }
+                ]
                 "#,
             )?;
 
-            let config = load_for_test(&tmp_dir);
-            // This should not panic or return error due to @example.com email when Let's Encrypt is disabled
-            assert!(config.is_ok());
-            let proxy_config = config.unwrap();
-            assert!(!proxy_config.lets_encrypt.enabled.unwrap_or(true));
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert!(proxy_config.routes[0].tls_passthrough);
+            assert!(!proxy_config.routes[1].tls_passthrough);
+
+            let invalid = [
+                // the upstreams terminate TLS
+                r#"{ host = "example.com", tls_passthrough = true, upstreams = [] }"#,
+                r#"{ host = "example.com", tls_passthrough = true, upstreams = [{ ip = "10.0.0.1", port = 443, tls = {} }] }"#,
+                // requests are never decrypted
+                r#"{ host = "example.com", tls_passthrough = true, headers = { add = [] }, upstreams = [{ ip = "10.0.0.1", port = 443 }] }"#,
+                r#"{ host = "example.com", tls_passthrough = true, upstreams = [{ ip = "10.0.0.1", port = 443 }], load_balancing = { algorithm = "consistent_hash", hash_key = { source = "path" } } }"#,
+                // every route of the host is passed through
+                r#"{ host = "example.com", tls_passthrough = true, upstreams = [{ ip = "10.0.0.1", port = 443 }] }, { host = "example.com", upstreams = [{ ip = "10.0.0.2", port = 80 }] }"#,
+            ];
+
+            for routes in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!("routes = [{routes}]"),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
 
             Ok(())
         });
     }
 
     #[test]
-    fn test_lets_encrypt_validation_when_enabled() {
+    fn test_route_unix_socket_upstream() {
         figment::Jail::expect_with(|jail| {
             let tmp_dir = jail.directory().to_string_lossy();
 
             jail.create_file(
-                format!("{}/proksi.yaml", tmp_dir),
+                format!("{}/proksi.hcl", tmp_dir),
                 r#"
-                lets_encrypt:
-                  enabled: true
-                  email: "contact@example.com"  # This should cause validation error when enabled
+                routes = [
+                  {
+                    host = "example.com"
+                    upstreams = [
+                      { ip = "unix:///run/app.sock", weight = 2 },
+                      { ip = "10.0.0.1", port = 3000 }
+                    ]
+                    health_check = { type = "http", path = "/health" }
+                  }
+                ]
+                "#,
+            )?;
 
-                routes:
-                  - host: "test.localhost"
-                    upstreams:
-                      - ip: "localhost"
-                        port: 3000
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let upstreams = &proxy_config.routes[0].upstreams;
+            assert_eq!(
+                upstreams[0].unix_socket(),
+                Some(std::path::Path::new("/run/app.sock"))
+            );
+            assert_eq!(upstreams[0].address(), "unix:///run/app.sock");
+            assert_eq!(upstreams[1].unix_socket(), None);
+            assert_eq!(upstreams[1].address(), "10.0.0.1:3000");
+
+            let invalid = [
+                // sockets are absolute paths
+                r#"{ ip = "unix://run/app.sock" }"#,
+                // without a port
+                r#"{ ip = "unix:///run/app.sock", port = 3000 }"#,
+                r#"{ ip = "unix:///run/app.sock", proxy_protocol = "v2" }"#,
+                // TCP upstreams still need one
+                r#"{ ip = "10.0.0.1" }"#,
+            ];
+
+            for upstream in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"routes = [{{ host = "example.com", upstreams = [{upstream}] }}]"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
+
+            // TCP and UDP listeners only proxy to IP addresses
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"listeners = [{ name = "app", address = "0.0.0.0:5432", upstreams = [{ ip = "unix:///run/app.sock" }] }]"#,
+            )?;
+            assert!(load_for_test(&tmp_dir).is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_client_ip() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                server {
+                  client_ip = {
+                    trusted_proxies = ["10.0.0.0/8", "2001:db8::1"]
+                    source = "cf-connecting-ip"
+                  }
+                }
                 "#,
             )?;
 
-            let config = load_for_test(&tmp_dir);
-            // This should return an error due to @example.com email when Let's Encrypt is enabled
-            assert!(config.is_err());
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            let client_ip = &proxy_config.server.client_ip;
+            assert_eq!(client_ip.trusted_proxies, vec!["10.0.0.0/8", "2001:db8::1"]);
+            assert_eq!(client_ip.source, ClientIpSource::CfConnectingIp);
+
+            jail.create_file(format!("{}/proksi.hcl", tmp_dir), "")?;
+            let proxy_config = load_for_test(&tmp_dir).unwrap();
+            assert!(proxy_config.server.client_ip.trusted_proxies.is_empty());
+            assert_eq!(
+                proxy_config.server.client_ip.source,
+                ClientIpSource::XForwardedFor
+            );
+
+            let invalid = [
+                // unknown source
+                r#"source = "forwarded""#,
+                // invalid CIDR
+                r#"trusted_proxies = ["10.0.0.0/33"]"#,
+            ];
+
+            for client_ip in invalid {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &format!(r#"server {{ client_ip = {{ {client_ip} }} }}"#),
+                )?;
+                assert!(load_for_test(&tmp_dir).is_err());
+            }
 
             Ok(())
         });
@@ -1225,6 +3780,10 @@ mod tests {
             assert!(logging.error_logs_enabled);
 
             assert_eq!(proxy_config.routes.len(), 0);
+            assert_eq!(
+                proxy_config.server.client_ip.source,
+                ClientIpSource::XForwardedFor
+            );
 
             Ok(())
         })
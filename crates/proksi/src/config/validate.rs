@@ -1,6 +1,15 @@
+use std::net::{IpAddr, SocketAddr};
+
 use anyhow::anyhow;
+use ipnet::IpNet;
 
-use super::Config;
+use super::{
+    Config, ConfigListener, ForwardedHeadersMode, HashKeySource, LoadBalancingAlgorithm, Route,
+    RouteForwardedHeaders, RouteGrpcWeb, RouteHashKey, RouteHealthCheck, RouteLoadBalancing,
+    RouteMatcher, RouteMirror, RoutePeerOptions, RouteRedirect, RouteRewrite, RouteStatic,
+    RouteSticky, RouteTrafficSplit, RouteUpstream, RouteUpstreamTls, RouteValueMatcher,
+    SameSitePolicy, ServerCfg, ServerProxyProtocol, UpstreamProtocol,
+};
 
 /// given a Config struct, validate the values to ensure
 /// That we program won't panic when we try to use them
@@ -27,28 +36,985 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         return Err(anyhow!("paths.lets_encrypt cannot be empty"));
     }
 
+    // Validate the PROXY protocol of the listeners
+    if config.server.proxy_protocol.enabled {
+        check_proxy_protocol(&config.server.proxy_protocol)?;
+    }
+
+    // Validate the HTTP/3 listener
+    if config.server.http3.enabled {
+        check_http3(&config.server)?;
+    }
+
+    // Validate the proxies allowed to tell the IP of the client
+    for proxy in &config.server.client_ip.trusted_proxies {
+        if !is_ip_or_cidr(proxy) {
+            return Err(anyhow!(
+                "server.client_ip.trusted_proxies must be IPs or CIDRs (ex: 10.0.0.0/8), got {}",
+                proxy
+            ));
+        }
+    }
+
+    // Validate that there is at most one default route
+    if config
+        .routes
+        .iter()
+        .filter(|route| route.default.unwrap_or(false))
+        .count()
+        > 1
+    {
+        return Err(anyhow!("only one route can be the default route"));
+    }
+
     // Validate the routes
     for (route_index, route) in config.routes.iter().enumerate() {
+        // Validate the route's host, wildcards are only allowed as the first label
+        let host = route.host.strip_prefix("*.").unwrap_or(&route.host);
+        if host.is_empty() || host.contains('*') {
+            return Err(anyhow!(
+                "routes{}.host must be a hostname or a wildcard hostname (ex: *.example.com)",
+                route_index
+            ));
+        }
+
+        // Validate the route's TLS passthrough
+        if route.tls_passthrough {
+            check_tls_passthrough(route_index, route, &config.routes)?;
+        }
+
         // Validate the route's upstreams
         for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
-            // Validate the upstream's address
-            if upstream.ip.is_empty() {
+            let prefix = format!("routes{route_index}.upstreams{upstream_index}");
+            check_upstream(&prefix, upstream)?;
+        }
+
+        // Validate the route's static files
+        if let Some(static_files) = route.static_files.as_ref() {
+            if !route.upstreams.is_empty() {
+                return Err(anyhow!(
+                    "routes{}.static cannot be used together with upstreams",
+                    route_index
+                ));
+            }
+
+            check_static(route_index, static_files)?;
+        }
+
+        // Validate the route's traffic split
+        if let Some(traffic_split) = route.traffic_split.as_ref() {
+            if route.static_files.is_some() {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.id cannot be empty",
-                    route_index,
-                    upstream_index
+                    "routes{}.traffic_split cannot be used together with static",
+                    route_index
                 ));
             }
 
-            if upstream.port == 0 {
+            check_traffic_split(route_index, traffic_split, route.upstreams.is_empty())?;
+        }
+
+        // Validate the route's connection options
+        if let Some(peer_options) = route.peer_options.as_ref() {
+            check_peer_options(&format!("routes{route_index}"), peer_options)?;
+        }
+
+        // Validate the route's matchers
+        if let Some(match_with) = route.match_with.as_ref() {
+            check_matcher(&format!("routes{route_index}.match_with"), match_with)?;
+        }
+
+        // Validate the route's redirects
+        for (redirect_index, redirect) in route.redirects.iter().flatten().enumerate() {
+            let prefix = format!("routes{route_index}.redirects{redirect_index}");
+            check_redirect(&prefix, redirect)?;
+        }
+
+        if route
+            .redirect_to_host
+            .as_ref()
+            .is_some_and(|host| host.is_empty() || host.contains('/'))
+        {
+            return Err(anyhow!(
+                "routes{}.redirect_to_host must be a hostname (ex: example.com)",
+                route_index
+            ));
+        }
+
+        // the requests would be redirected to the same route forever
+        if route
+            .redirect_to_host
+            .as_ref()
+            .is_some_and(|host| host.eq_ignore_ascii_case(&route.host))
+        {
+            return Err(anyhow!(
+                "routes{}.redirect_to_host must differ from the host of the route",
+                route_index
+            ));
+        }
+
+        // Validate the route's traffic mirroring
+        if let Some(mirror) = route.mirror.as_ref() {
+            check_mirror(route_index, mirror)?;
+        }
+
+        // Validate the route's sticky sessions
+        if let Some(sticky) = route.sticky.as_ref() {
+            check_sticky(route_index, sticky)?;
+        }
+
+        // Validate the route's forwarding headers
+        if let Some(forwarded_headers) = route.forwarded_headers.as_ref() {
+            check_forwarded_headers(route_index, forwarded_headers)?;
+        }
+
+        // Validate the route's gRPC-Web support
+        if let Some(grpc_web) = route.grpc_web.as_ref() {
+            check_grpc_web(route_index, route, grpc_web)?;
+        }
+
+        // Validate the route's path rewrites
+        if let Some(rewrite) = route.rewrite.as_ref() {
+            check_rewrite(route_index, rewrite)?;
+        }
+
+        // Validate the route's load balancing
+        if let Some(load_balancing) = route.load_balancing.as_ref() {
+            check_load_balancing(&format!("routes{route_index}"), load_balancing)?;
+        }
+
+        // Validate the route's health check
+        if let Some(health_check) = route.health_check.as_ref() {
+            check_health_check(&format!("routes{route_index}"), health_check)?;
+        }
+
+        // Validate the route's circuit breaker
+        if let Some(circuit_breaker) = route.circuit_breaker.as_ref() {
+            if circuit_breaker.consecutive_failures == 0 {
                 return Err(anyhow!(
-                    "routes{}.upstreams{}.port must be greater than 0",
-                    route_index,
-                    upstream_index
+                    "routes{}.circuit_breaker.consecutive_failures must be greater than 0",
+                    route_index
                 ));
             }
+
+            if circuit_breaker.cooldown_secs == 0 {
+                return Err(anyhow!(
+                    "routes{}.circuit_breaker.cooldown_secs must be greater than 0",
+                    route_index
+                ));
+            }
+        }
+
+        // Validate the route's retry policy
+        if route
+            .retry
+            .as_ref()
+            .is_some_and(|retry| retry.max_attempts == 0)
+        {
+            return Err(anyhow!(
+                "routes{}.retry.max_attempts must be greater than 0",
+                route_index
+            ));
+        }
+    }
+
+    // Validate the TCP and UDP listeners
+    for (listener_index, listener) in config.listeners.iter().enumerate() {
+        let prefix = format!("listeners{listener_index}");
+        if config.listeners[..listener_index]
+            .iter()
+            .any(|other| other.name == listener.name)
+        {
+            return Err(anyhow!("{prefix}.name {:?} is already used", listener.name));
+        }
+
+        check_listener(&prefix, listener)?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the hash key is only used with consistent hashing
+/// and that header/cookie based keys have a name to look for.
+fn check_load_balancing(
+    prefix: &str,
+    load_balancing: &RouteLoadBalancing,
+) -> Result<(), anyhow::Error> {
+    let Some(hash_key) = load_balancing.hash_key.as_ref() else {
+        return Ok(());
+    };
+
+    if load_balancing.algorithm != LoadBalancingAlgorithm::ConsistentHash {
+        return Err(anyhow!(
+            "{prefix}.load_balancing.hash_key can only be used with the consistent_hash algorithm"
+        ));
+    }
+
+    check_hash_key(&format!("{prefix}.load_balancing.hash_key"), hash_key)
+}
+
+/// Validates that header/cookie based keys have a name to look for
+fn check_hash_key(prefix: &str, hash_key: &RouteHashKey) -> Result<(), anyhow::Error> {
+    let requires_name = matches!(
+        hash_key.source,
+        HashKeySource::Header | HashKeySource::Cookie
+    );
+    if requires_name && hash_key.name.as_ref().is_none_or(|name| name.is_empty()) {
+        return Err(anyhow!(
+            "{prefix}.name cannot be empty for header or cookie keys"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the address, weight and connection options of an upstream
+fn check_upstream(prefix: &str, upstream: &RouteUpstream) -> Result<(), anyhow::Error> {
+    if upstream.ip.is_empty() {
+        return Err(anyhow!("{prefix}.id cannot be empty"));
+    }
+
+    if let Some(path) = upstream.unix_socket() {
+        if !path.is_absolute() {
+            return Err(anyhow!(
+                "{prefix}.ip must be the absolute path of a unix socket (ex: unix:///run/app.sock)"
+            ));
+        }
+
+        if upstream.port != 0 {
+            return Err(anyhow!("{prefix}.port cannot be used with a unix socket"));
+        }
+
+        if upstream.proxy_protocol.is_some() {
+            return Err(anyhow!(
+                "{prefix}.proxy_protocol cannot be used with a unix socket"
+            ));
+        }
+    } else if upstream.port == 0 {
+        return Err(anyhow!("{prefix}.port must be greater than 0"));
+    }
+
+    if upstream.weight.is_some_and(|w| w <= 0) {
+        return Err(anyhow!("{prefix}.weight must be greater than 0"));
+    }
+
+    if let Some(peer_options) = upstream.peer_options.as_ref() {
+        check_peer_options(prefix, peer_options)?;
+    }
+
+    if let Some(tls) = upstream.tls.as_ref() {
+        check_upstream_tls(prefix, tls)?;
+    }
+
+    if let Some(protocol) = upstream.protocol {
+        check_upstream_protocol(prefix, upstream, protocol)?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the protocol of an upstream agrees with its TLS and ALPN settings
+fn check_upstream_protocol(
+    prefix: &str,
+    upstream: &RouteUpstream,
+    protocol: UpstreamProtocol,
+) -> Result<(), anyhow::Error> {
+    let tls_enabled = upstream.tls.as_ref().and_then(|tls| tls.enabled);
+    match (protocol, tls_enabled) {
+        (UpstreamProtocol::H2, Some(false)) => {
+            return Err(anyhow!(
+                "{prefix}.protocol h2 requires TLS, use h2c for cleartext HTTP/2"
+            ));
+        }
+        (UpstreamProtocol::H2c, Some(true)) => {
+            return Err(anyhow!(
+                "{prefix}.protocol h2c is cleartext HTTP/2, use h2 with TLS"
+            ));
+        }
+        _ => {}
+    }
+
+    if upstream
+        .peer_options
+        .as_ref()
+        .is_some_and(|options| options.alpn.is_some())
+    {
+        return Err(anyhow!(
+            "{prefix}.peer_options.alpn cannot be used with {prefix}.protocol"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates that the upstream groups have unique names and upstreams,
+/// that their percentages add up to at most 100 and that the remaining
+/// percentage has upstreams to go to.
+fn check_traffic_split(
+    route_index: usize,
+    traffic_split: &RouteTrafficSplit,
+    without_upstreams: bool,
+) -> Result<(), anyhow::Error> {
+    let mut total_percent = 0u16;
+
+    for (group_index, group) in traffic_split.groups.iter().enumerate() {
+        let prefix = format!("routes{route_index}.traffic_split.groups{group_index}");
+
+        if group.name.is_empty() {
+            return Err(anyhow!("{prefix}.name cannot be empty"));
+        }
+
+        if traffic_split.groups[..group_index]
+            .iter()
+            .any(|other| other.name == group.name)
+        {
+            return Err(anyhow!("{prefix}.name {:?} is already used", group.name));
+        }
+
+        if group.upstreams.is_empty() {
+            return Err(anyhow!("{prefix}.upstreams cannot be empty"));
+        }
+
+        for (upstream_index, upstream) in group.upstreams.iter().enumerate() {
+            check_upstream(&format!("{prefix}.upstreams{upstream_index}"), upstream)?;
+        }
+
+        total_percent += u16::from(group.percent);
+    }
+
+    if total_percent > 100 {
+        return Err(anyhow!(
+            "routes{}.traffic_split.groups percentages cannot add up to more than 100",
+            route_index
+        ));
+    }
+
+    if total_percent < 100 && without_upstreams {
+        return Err(anyhow!(
+            "routes{}.upstreams cannot be empty when the traffic split groups receive less than 100 percent",
+            route_index
+        ));
+    }
+
+    if let Some(sticky) = traffic_split.sticky.as_ref() {
+        check_hash_key(&format!("routes{route_index}.traffic_split.sticky"), sticky)?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the health check intervals/thresholds are usable
+/// and that the expected status range is a valid HTTP status range.
+fn check_health_check(prefix: &str, health_check: &RouteHealthCheck) -> Result<(), anyhow::Error> {
+    if health_check.interval_secs == 0 {
+        return Err(anyhow!(
+            "{prefix}.health_check.interval_secs must be greater than 0"
+        ));
+    }
+
+    if health_check.timeout_secs == 0 {
+        return Err(anyhow!(
+            "{prefix}.health_check.timeout_secs must be greater than 0"
+        ));
+    }
+
+    if health_check.healthy_threshold == 0 || health_check.unhealthy_threshold == 0 {
+        return Err(anyhow!(
+            "{prefix}.health_check thresholds must be greater than 0"
+        ));
+    }
+
+    let status = health_check.expected_status;
+    if status.min > status.max || status.min < 100 || status.max > 599 {
+        return Err(anyhow!(
+            "{prefix}.health_check.expected_status must be a range between 100 and 599"
+        ));
+    }
+
+    if !health_check.path.starts_with('/') {
+        return Err(anyhow!("{prefix}.health_check.path must start with a `/`"));
+    }
+
+    Ok(())
+}
+
+/// Validates that a TLS passthrough route only relies on the SNI of the connections:
+/// its upstreams can't be told apart by anything else than the host,
+/// and the requests are never decrypted by Proksi
+fn check_tls_passthrough(
+    route_index: usize,
+    route: &Route,
+    routes: &[Route],
+) -> Result<(), anyhow::Error> {
+    let prefix = format!("routes{route_index}");
+    if route.upstreams.is_empty() {
+        return Err(anyhow!(
+            "{prefix}.upstreams cannot be empty with tls_passthrough"
+        ));
+    }
+
+    if routes
+        .iter()
+        .any(|other| other.host == route.host && !other.tls_passthrough)
+    {
+        return Err(anyhow!(
+            "{prefix}.host {:?} has other routes without tls_passthrough",
+            route.host
+        ));
+    }
+
+    let http_only = [
+        ("default", route.default.unwrap_or(false)),
+        ("cache", route.cache.is_some()),
+        ("plugins", route.plugins.is_some()),
+        ("ssl_certificate", route.ssl_certificate.is_some()),
+        ("ssl", route.ssl.is_some()),
+        ("headers", route.headers.is_some()),
+        ("static", route.static_files.is_some()),
+        ("traffic_split", route.traffic_split.is_some()),
+        ("mirror", route.mirror.is_some()),
+        ("sticky", route.sticky.is_some()),
+        ("forwarded_headers", route.forwarded_headers.is_some()),
+        ("grpc_web", route.grpc_web.is_some()),
+        ("retry", route.retry.is_some()),
+        ("match_with", route.match_with.is_some()),
+        ("rewrite", route.rewrite.is_some()),
+        ("redirects", route.redirects.is_some()),
+        ("redirect_to_host", route.redirect_to_host.is_some()),
+    ];
+    if let Some((field, _)) = http_only.iter().find(|(_, is_set)| *is_set) {
+        return Err(anyhow!(
+            "{prefix}.{field} cannot be used together with tls_passthrough"
+        ));
+    }
+
+    for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
+        if upstream.tls.is_some()
+            || upstream.protocol.is_some()
+            || upstream.proxy_protocol.is_some()
+        {
+            return Err(anyhow!(
+                "{prefix}.upstreams{upstream_index} only supports ip, port and weight with tls_passthrough"
+            ));
+        }
+
+        if upstream.unix_socket().is_some() {
+            return Err(anyhow!(
+                "{prefix}.upstreams{upstream_index}.ip must be an IP address with tls_passthrough"
+            ));
+        }
+    }
+
+    if route
+        .load_balancing
+        .as_ref()
+        .and_then(|load_balancing| load_balancing.hash_key.as_ref())
+        .is_some_and(|hash_key| hash_key.source != HashKeySource::ClientIp)
+    {
+        return Err(anyhow!(
+            "{prefix}.load_balancing.hash_key.source must be client_ip with tls_passthrough"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the address and upstreams of a listener, and that its load balancing
+/// and health check only rely on what a TCP or UDP proxy knows about the clients
+fn check_listener(prefix: &str, listener: &ConfigListener) -> Result<(), anyhow::Error> {
+    if listener.name.is_empty() {
+        return Err(anyhow!("{prefix}.name cannot be empty"));
+    }
+
+    if listener.address.parse::<SocketAddr>().is_err() {
+        return Err(anyhow!(
+            "{prefix}.address must be an IP and a port (ex: 0.0.0.0:5432)"
+        ));
+    }
+
+    if listener.upstreams.is_empty() {
+        return Err(anyhow!("{prefix}.upstreams cannot be empty"));
+    }
+
+    for (upstream_index, upstream) in listener.upstreams.iter().enumerate() {
+        let prefix = format!("{prefix}.upstreams{upstream_index}");
+        check_upstream(&prefix, upstream)?;
+
+        let http_only = [
+            ("tls", upstream.tls.is_some()),
+            ("protocol", upstream.protocol.is_some()),
+            ("proxy_protocol", upstream.proxy_protocol.is_some()),
+        ];
+        if let Some((field, _)) = http_only.iter().find(|(_, is_set)| *is_set) {
+            return Err(anyhow!("{prefix}.{field} is only supported by routes"));
+        }
+
+        if upstream.unix_socket().is_some() {
+            return Err(anyhow!("{prefix}.ip must be an IP address for listeners"));
+        }
+    }
+
+    if let Some(load_balancing) = listener.load_balancing.as_ref() {
+        check_load_balancing(prefix, load_balancing)?;
+
+        if load_balancing
+            .hash_key
+            .as_ref()
+            .is_some_and(|hash_key| hash_key.source != HashKeySource::ClientIp)
+        {
+            return Err(anyhow!(
+                "{prefix}.load_balancing.hash_key.source must be client_ip for listeners"
+            ));
+        }
+    }
+
+    if let Some(health_check) = listener.health_check.as_ref() {
+        check_health_check(prefix, health_check)?;
+    }
+
+    if listener.idle_timeout_secs == 0 {
+        return Err(anyhow!("{prefix}.idle_timeout_secs must be greater than 0"));
+    }
+
+    if listener.max_sessions == 0 {
+        return Err(anyhow!("{prefix}.max_sessions must be greater than 0"));
+    }
+
+    Ok(())
+}
+
+/// Validates that the configured timeouts and HTTP/2 streams are usable
+/// (a zero timeout would fail every request).
+fn check_peer_options(prefix: &str, peer_options: &RoutePeerOptions) -> Result<(), anyhow::Error> {
+    let timeouts = [
+        (
+            "connection_timeout_secs",
+            peer_options.connection_timeout_secs,
+        ),
+        (
+            "total_connection_timeout_secs",
+            peer_options.total_connection_timeout_secs,
+        ),
+        ("read_timeout_secs", peer_options.read_timeout_secs),
+        ("write_timeout_secs", peer_options.write_timeout_secs),
+        ("idle_timeout_secs", peer_options.idle_timeout_secs),
+    ];
+
+    for (name, value) in timeouts {
+        if value == Some(0) {
+            return Err(anyhow!(
+                "{}.peer_options.{} must be greater than 0",
+                prefix,
+                name
+            ));
+        }
+    }
+
+    if peer_options.max_h2_streams == Some(0) {
+        return Err(anyhow!(
+            "{}.peer_options.max_h2_streams must be greater than 0",
+            prefix
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates that the upstream TLS settings are consistent
+/// and that the certificate files exist.
+fn check_upstream_tls(prefix: &str, tls: &RouteUpstreamTls) -> Result<(), anyhow::Error> {
+    let has_settings = tls.verify_cert.is_some()
+        || tls.ca_file.is_some()
+        || tls.client_cert.is_some()
+        || tls.client_key.is_some()
+        || tls.sni.is_some()
+        || tls.alternative_cn.is_some();
+
+    if tls.enabled == Some(false) && has_settings {
+        return Err(anyhow!(
+            "{}.tls settings cannot be used when tls.enabled is false",
+            prefix
+        ));
+    }
+
+    if tls.client_cert.is_some() != tls.client_key.is_some() {
+        return Err(anyhow!(
+            "{}.tls.client_cert and tls.client_key must be set together",
+            prefix
+        ));
+    }
+
+    if tls.ca_file.is_some() && tls.verify_cert == Some(false) {
+        return Err(anyhow!(
+            "{}.tls.ca_file cannot be used when tls.verify_cert is false",
+            prefix
+        ));
+    }
+
+    let files = [
+        ("ca_file", tls.ca_file.as_ref()),
+        ("client_cert", tls.client_cert.as_ref()),
+        ("client_key", tls.client_key.as_ref()),
+    ];
+
+    for (name, path) in files {
+        if path.is_some_and(|path| !path.is_file()) {
+            return Err(anyhow!(
+                "{}.tls.{} file {:?} does not exist",
+                prefix,
+                name,
+                path.unwrap()
+            ));
         }
     }
 
     Ok(())
 }
+
+/// Validates that the methods and regular expressions of the matchers are valid
+/// and that each header/query matcher uses a single kind of value match.
+fn check_matcher(prefix: &str, matcher: &RouteMatcher) -> Result<(), anyhow::Error> {
+    if let Some(regex) = matcher.path.as_ref().and_then(|path| path.regex.as_ref()) {
+        regex::Regex::new(regex)
+            .map_err(|err| anyhow!("{}.path.regex is not a valid regex: {}", prefix, err))?;
+    }
+
+    for method in matcher.methods.iter().flatten() {
+        if http::Method::from_bytes(method.to_uppercase().as_bytes()).is_err() {
+            return Err(anyhow!(
+                "{}.methods contains an invalid method: {}",
+                prefix,
+                method
+            ));
+        }
+    }
+
+    let value_matchers = [
+        ("headers", matcher.headers.as_ref()),
+        ("query", matcher.query.as_ref()),
+    ];
+
+    for (kind, value_matchers) in value_matchers {
+        for (index, value_matcher) in value_matchers.into_iter().flatten().enumerate() {
+            check_value_matcher(&format!("{prefix}.{kind}{index}"), value_matcher)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_value_matcher(prefix: &str, matcher: &RouteValueMatcher) -> Result<(), anyhow::Error> {
+    if matcher.name.is_empty() {
+        return Err(anyhow!("{}.name cannot be empty", prefix));
+    }
+
+    let value_matches = [
+        matcher.exact.is_some(),
+        matcher.prefix.is_some(),
+        matcher.regex.is_some(),
+    ];
+
+    if value_matches.into_iter().filter(|is_set| *is_set).count() > 1 {
+        return Err(anyhow!(
+            "{}: only one of exact, prefix or regex can be set",
+            prefix
+        ));
+    }
+
+    if let Some(regex) = matcher.regex.as_ref() {
+        regex::Regex::new(regex)
+            .map_err(|err| anyhow!("{}.regex is not a valid regex: {}", prefix, err))?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the rewritten paths are absolute paths
+/// and that the regex replacement is complete and valid.
+fn check_rewrite(route_index: usize, rewrite: &RouteRewrite) -> Result<(), anyhow::Error> {
+    let prefixes = [
+        ("strip_prefix", rewrite.strip_prefix.as_ref()),
+        ("add_prefix", rewrite.add_prefix.as_ref()),
+    ];
+
+    for (name, prefix) in prefixes {
+        if prefix.is_some_and(|prefix| !prefix.starts_with('/')) {
+            return Err(anyhow!(
+                "routes{}.rewrite.{} must start with a `/`",
+                route_index,
+                name
+            ));
+        }
+    }
+
+    let Some(regex) = rewrite.regex.as_ref() else {
+        if rewrite
+            .replacement
+            .as_ref()
+            .is_some_and(|replacement| !replacement.starts_with('/'))
+        {
+            return Err(anyhow!(
+                "routes{}.rewrite.replacement must start with a `/`",
+                route_index
+            ));
+        }
+
+        return Ok(());
+    };
+
+    if rewrite.replacement.is_none() {
+        return Err(anyhow!(
+            "routes{}.rewrite.replacement is required when rewrite.regex is set",
+            route_index
+        ));
+    }
+
+    regex::Regex::new(regex).map_err(|err| {
+        anyhow!(
+            "routes{}.rewrite.regex is not a valid regex: {}",
+            route_index,
+            err
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Validates that the redirect has a target, a redirect status code and valid matchers
+fn check_redirect(prefix: &str, redirect: &RouteRedirect) -> Result<(), anyhow::Error> {
+    if redirect.to.is_empty() {
+        return Err(anyhow!("{}.to cannot be empty", prefix));
+    }
+
+    if ![301, 302, 307, 308].contains(&redirect.status) {
+        return Err(anyhow!(
+            "{}.status must be one of 301, 302, 307 or 308",
+            prefix
+        ));
+    }
+
+    if let Some(match_with) = redirect.match_with.as_ref() {
+        check_matcher(&format!("{prefix}.match_with"), match_with)?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the mirror target is an HTTP(S) URL and that its settings are usable
+fn check_mirror(route_index: usize, mirror: &RouteMirror) -> Result<(), anyhow::Error> {
+    let target = mirror.target.parse::<http::Uri>().ok();
+    let is_http_url = target.is_some_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some()
+    });
+    if !is_http_url {
+        return Err(anyhow!(
+            "routes{}.mirror.target must be an http(s) URL (ex: http://10.0.0.5:3000)",
+            route_index
+        ));
+    }
+
+    if mirror.percent > 100 {
+        return Err(anyhow!(
+            "routes{}.mirror.percent must be between 0 and 100",
+            route_index
+        ));
+    }
+
+    if mirror.timeout_ms == 0 {
+        return Err(anyhow!(
+            "routes{}.mirror.timeout_ms must be greater than 0",
+            route_index
+        ));
+    }
+
+    if let Some(match_with) = mirror.match_with.as_ref() {
+        let prefix = format!("routes{route_index}.mirror.match_with");
+        check_matcher(&prefix, match_with)?;
+    }
+
+    Ok(())
+}
+
+/// Validates that the sticky cookie can be set by browsers
+/// and that its secret is long enough
+fn check_sticky(route_index: usize, sticky: &RouteSticky) -> Result<(), anyhow::Error> {
+    let is_token = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+    if sticky.cookie_name.is_empty() || !sticky.cookie_name.chars().all(is_token) {
+        return Err(anyhow!(
+            "routes{}.sticky.cookie_name must be a valid cookie name (ex: proksi_sticky)",
+            route_index
+        ));
+    }
+
+    if sticky.ttl_secs == Some(0) {
+        return Err(anyhow!(
+            "routes{}.sticky.ttl_secs must be greater than 0",
+            route_index
+        ));
+    }
+
+    // browsers reject `SameSite=None` cookies without the `Secure` attribute
+    if sticky.same_site == SameSitePolicy::None && !sticky.secure {
+        return Err(anyhow!(
+            "routes{}.sticky.secure must be true when same_site is none",
+            route_index
+        ));
+    }
+
+    if sticky
+        .secret
+        .as_ref()
+        .is_some_and(|secret| secret.len() < 32)
+    {
+        return Err(anyhow!(
+            "routes{}.sticky.secret must be at least 32 characters long",
+            route_index
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the headers and trusted proxies of the forwarding headers
+fn check_forwarded_headers(
+    route_index: usize,
+    forwarded_headers: &RouteForwardedHeaders,
+) -> Result<(), anyhow::Error> {
+    if forwarded_headers.headers.is_empty() {
+        return Err(anyhow!(
+            "routes{}.forwarded_headers.headers must not be empty",
+            route_index
+        ));
+    }
+
+    for proxy in &forwarded_headers.trusted_proxies {
+        if !is_ip_or_cidr(proxy) {
+            return Err(anyhow!(
+                "routes{}.forwarded_headers.trusted_proxies must be IPs or CIDRs (ex: 10.0.0.0/8), got {}",
+                route_index,
+                proxy
+            ));
+        }
+    }
+
+    // incoming values are never kept when overwriting them
+    if forwarded_headers.mode == ForwardedHeadersMode::Overwrite
+        && !forwarded_headers.trusted_proxies.is_empty()
+    {
+        return Err(anyhow!(
+            "routes{}.forwarded_headers.trusted_proxies has no effect with the overwrite mode",
+            route_index
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the CORS origins of gRPC-Web and that the upstreams can speak gRPC
+fn check_grpc_web(
+    route_index: usize,
+    route: &Route,
+    grpc_web: &RouteGrpcWeb,
+) -> Result<(), anyhow::Error> {
+    for origin in &grpc_web.allowed_origins {
+        if origin != "*" && !is_origin(origin) {
+            return Err(anyhow!(
+                "routes{}.grpc_web.allowed_origins must be origins (ex: https://app.example.com) or *, got {}",
+                route_index,
+                origin
+            ));
+        }
+    }
+
+    // gRPC needs the trailers of HTTP/2
+    if route
+        .upstreams
+        .iter()
+        .any(|upstream| upstream.protocol == Some(UpstreamProtocol::Http1))
+    {
+        return Err(anyhow!(
+            "routes{}.grpc_web requires HTTP/2 upstreams, their protocol cannot be http1",
+            route_index
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the value is a browser origin: a scheme and a host, with an optional port
+fn is_origin(value: &str) -> bool {
+    let Some((scheme, authority)) = value.split_once("://") else {
+        return false;
+    };
+
+    matches!(scheme, "http" | "https")
+        && !authority.is_empty()
+        && !authority.contains(['/', '@'])
+        && authority.parse::<http::uri::Authority>().is_ok()
+}
+
+fn check_proxy_protocol(proxy_protocol: &ServerProxyProtocol) -> Result<(), anyhow::Error> {
+    // any client could pretend to be someone else without an allowlist
+    if proxy_protocol.trusted_sources.is_empty() {
+        return Err(anyhow!(
+            "server.proxy_protocol.trusted_sources cannot be empty (ex: the IPs of the load balancers)"
+        ));
+    }
+
+    for source in &proxy_protocol.trusted_sources {
+        if !is_ip_or_cidr(source) {
+            return Err(anyhow!(
+                "server.proxy_protocol.trusted_sources must be IPs or CIDRs (ex: 10.0.0.0/8), got {}",
+                source
+            ));
+        }
+    }
+
+    if proxy_protocol.header_timeout_ms == 0 {
+        return Err(anyhow!(
+            "server.proxy_protocol.header_timeout_ms must be greater than 0"
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_http3(server: &ServerCfg) -> Result<(), anyhow::Error> {
+    let address = server.http3_address();
+    if address.parse::<SocketAddr>().is_err() {
+        return Err(anyhow!(
+            "server.http3.address must be an IP and a port (ex: 0.0.0.0:443), got {}",
+            address
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the value is an IP or a CIDR (ex: `10.0.0.1` or `10.0.0.0/8`)
+fn is_ip_or_cidr(value: &str) -> bool {
+    value.parse::<IpNet>().is_ok() || value.parse::<IpAddr>().is_ok()
+}
+
+/// Validates that the static root is a directory and that the index files are file names
+fn check_static(route_index: usize, static_files: &RouteStatic) -> Result<(), anyhow::Error> {
+    if !static_files.root.is_dir() {
+        return Err(anyhow!(
+            "routes{}.static.root {:?} is not a directory",
+            route_index,
+            static_files.root
+        ));
+    }
+
+    if static_files
+        .index
+        .iter()
+        .any(|index| index.is_empty() || index.contains('/') || index == "..")
+    {
+        return Err(anyhow!(
+            "routes{}.static.index must only contain file names (ex: index.html)",
+            route_index
+        ));
+    }
+
+    Ok(())
+}
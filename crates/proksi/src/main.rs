@@ -2,15 +2,20 @@ use ::pingora::server::Server;
 
 use bytes::Bytes;
 use clap::crate_version;
-use config::{load, LogFormat, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
+use config::{
+    load, LogFormat, Route, RouteHeaderAdd, RouteHeaderRemove, RouteHealthCheck, RoutePlugin,
+};
 use stores::{MemoryStore, global::init_store};
 use tracing_subscriber::EnvFilter;
 
 use std::{borrow::Cow, sync::Arc};
 
-use pingora::{listeners::tls::TlsSettings, proxy::http_proxy_service, server::configuration::Opt};
+use pingora::{
+    apps::HttpServerOptions, listeners::tls::TlsSettings, proxy::http_proxy_service,
+    server::configuration::Opt,
+};
 
-use proxy_server::cert_store::CertStore;
+use proxy_server::{cert_store::CertStore, client_ip::ClientIpResolver};
 use services::{logger::ProxyLoggerReceiver, BackgroundFunctionService};
 
 mod cache;
@@ -24,14 +29,23 @@ mod stores;
 mod tools;
 mod wasm;
 
+#[derive(Clone, Debug, Default)]
+pub struct MsgUpstream {
+    addr: String,
+    weight: Option<i8>,
+    /// The traffic split group of the upstream and its percentage (ex: a canary release)
+    group: Option<(String, u8)>,
+}
+
 #[derive(Clone, Default)]
 pub struct MsgRoute {
     host: Cow<'static, str>,
-    upstreams: Vec<String>,
+    upstreams: Vec<MsgUpstream>,
     path_matchers: Vec<String>,
     host_headers_add: Vec<RouteHeaderAdd>,
     host_headers_remove: Vec<RouteHeaderRemove>,
     plugins: Vec<RoutePlugin>,
+    health_check: Option<RouteHealthCheck>,
 
     self_signed_certs: bool,
 }
@@ -44,9 +58,10 @@ pub struct MsgCert {
 
 #[derive(Clone)]
 pub enum MsgProxy {
-    NewRoute(MsgRoute),
+    NewRoute(Box<MsgRoute>),
     NewCertificate(MsgCert),
-    ConfigUpdate(()),
+    /// The routes of the configuration file, when they can be applied without a restart
+    ConfigUpdate(Arc<Vec<Route>>),
 }
 
 #[deny(
@@ -58,6 +73,7 @@ pub enum MsgProxy {
     clippy::suspicious,
     clippy::complexity
 )]
+#[allow(clippy::too_many_lines)]
 fn main() -> Result<(), anyhow::Error> {
     // Configuration can be refreshed on file change
     // Loads configuration from command-line, YAML or TOML sources
@@ -139,9 +155,34 @@ fn main() -> Result<(), anyhow::Error> {
 
     // Service: HTTPS Load Balancer (main service)
     // The router will also handle health checks and failover in case of upstream failure
-    let router = proxy_server::https_proxy::Router {};
+    // HTTP/3 requests are relayed to the HTTPS service on a unix socket
+    let http3_relay = services::http3::add_service(&mut pingora_server, &proxy_config);
+    let router = proxy_server::https_proxy::Router {
+        client_ip: ClientIpResolver::from_config(&proxy_config.server.client_ip),
+        alt_svc: services::http3::alt_svc(&proxy_config.server),
+        http3_relay: http3_relay.clone(),
+    };
     let mut https_secure_service = http_proxy_service(&pingora_server.configuration, router);
-    http_public_service.add_tcp(&le_address);
+
+    // Behind a PROXY protocol load balancer, the listeners read the header of the connections
+    // and relay them to the services listening on unix sockets
+    let (https_service_address, le_service_address) = services::proxy_protocol::add_services(
+        &mut pingora_server,
+        &proxy_config,
+        &https_address,
+        &le_address,
+    );
+    http_public_service.add_address(le_service_address);
+
+    // Passthrough routes forward the TLS connections to their upstreams by SNI
+    let https_service_address = services::tls_passthrough::add_service(
+        &mut pingora_server,
+        &proxy_config,
+        https_service_address,
+    );
+
+    // Raw TCP and UDP proxies (ex: databases), next to the HTTP and HTTPS services
+    services::listeners::add_services(&mut pingora_server, &proxy_config)?;
 
     // Worker threads per configuration
     https_secure_service.threads = proxy_config.worker_threads;
@@ -161,12 +202,23 @@ fn main() -> Result<(), anyhow::Error> {
     tls_settings.set_max_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_3))?;
 
     // Add TLS settings to the HTTPS service
-    https_secure_service.add_tls_with_settings(&https_address, None, tls_settings);
+    https_secure_service
+        .endpoints()
+        .add_endpoint(https_service_address, Some(tls_settings));
+    if let Some(http3_relay) = http3_relay {
+        // The relay speaks HTTP/2 without TLS, the TLS addresses are not affected
+        let mut server_options = HttpServerOptions::default();
+        server_options.h2c = true;
+        if let Some(router) = https_secure_service.app_logic_mut() {
+            router.server_options = Some(server_options);
+        }
+        https_secure_service.add_address(services::relay::service_address(&http3_relay));
+    }
 
-    // Add Prometheus service
-    // let mut prometheus_service_http = Service::prometheus_http_service();
-    // prometheus_service_http.add_tcp("0.0.0.0:9090");
-    // pingora_server.add_service(prometheus_service_http);
+    // Prometheus metrics (disabled unless an address is configured)
+    if let Some(metrics_address) = proxy_config.server.metrics_address.as_deref() {
+        pingora_server.add_service(services::metrics::metrics_service(metrics_address));
+    }
 
     // Non-dedicated background services
     pingora_server.add_service(BackgroundFunctionService::new(proxy_config.clone(), sender));
@@ -0,0 +1,91 @@
+use std::any::Any;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::HeaderValue;
+use pingora::{
+    http::ResponseHeader,
+    modules::http::{HttpModule, HttpModuleBuilder, Module},
+    protocols::http::ServerSession,
+    proxy::Session,
+};
+
+/// Adds the `Alt-Svc` header advertising the HTTP/3 listener to the responses.
+///
+/// As a downstream module it also sees the responses written by Proksi itself
+/// (redirects, static files, errors and plugins), not only the proxied ones.
+pub struct AltSvcBuilder {
+    value: HeaderValue,
+}
+
+impl AltSvcBuilder {
+    pub fn new(value: HeaderValue) -> Self {
+        AltSvcBuilder { value }
+    }
+}
+
+impl HttpModuleBuilder for AltSvcBuilder {
+    fn init(&self) -> Module {
+        Box::new(AltSvc {
+            value: self.value.clone(),
+        })
+    }
+}
+
+struct AltSvc {
+    value: HeaderValue,
+}
+
+#[async_trait]
+impl HttpModule for AltSvc {
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        resp.insert_header(http::header::ALT_SVC, &self.value)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Like [`Session::respond_error`], but the response goes through the downstream modules
+pub async fn respond_error(session: &mut Session, code: u16) -> pingora::Result<()> {
+    let mut resp = ServerSession::generate_error(code);
+    session
+        .downstream_modules_ctx
+        .response_header_filter(&mut resp, true)
+        .await?;
+
+    session
+        .as_downstream_mut()
+        .write_error_response(resp, Bytes::new())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_alt_svc() {
+        let builder = AltSvcBuilder::new(HeaderValue::from_static("h3=\":443\"; ma=86400"));
+        let mut module = builder.init();
+
+        let mut resp = ResponseHeader::build(301, None).unwrap();
+        module
+            .response_header_filter(&mut resp, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.headers.get(http::header::ALT_SVC).unwrap(),
+            "h3=\":443\"; ma=86400"
+        );
+    }
+}
@@ -4,7 +4,7 @@ use pingora::listeners::TlsAccept;
 use pingora::tls::ext;
 use pingora::tls::ssl::NameType;
 
-use crate::stores::{self};
+use crate::stores::{self, certificates::Certificate};
 
 /// Provides the correct certificates when performing SSL handshakes
 #[derive(Debug, Clone)]
@@ -33,6 +33,24 @@ impl CertStore {
     }
 }
 
+/// Finds the certificate of a host, falling back to the certificate of the
+/// wildcard route and then of the default route matching the host.
+async fn find_certificate(host_name: &str) -> Option<Certificate> {
+    let store = stores::global::get_store();
+    if let Some(cert) = store.get_certificate(host_name).await {
+        return Some(cert);
+    }
+
+    for wildcard in stores::wildcard_hosts(host_name) {
+        if let Some(cert) = store.get_certificate(&wildcard).await {
+            return Some(cert);
+        }
+    }
+
+    let default_host = stores::get_default_route_host()?;
+    store.get_certificate(&default_host).await
+}
+
 #[async_trait]
 impl TlsAccept for CertStore {
     /// This function is called when the SSL handshake is performed
@@ -42,7 +60,7 @@ impl TlsAccept for CertStore {
         // Due to the sni_callback function, we can safely unwrap here
         let host_name = ssl.servername(NameType::HOST_NAME).unwrap_or_default();
 
-        let Some(cert) = stores::global::get_store().get_certificate(host_name).await else {
+        let Some(cert) = find_certificate(host_name).await else {
             tracing::info!("No certificate found for host: {:?}", host_name);
             return;
         };
@@ -0,0 +1,209 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use pingora::{lb::Backend, protocols::l4::socket::SocketAddr};
+
+use crate::config::RouteCircuitBreaker;
+use crate::services::metrics::{UPSTREAM_CIRCUIT_STATE, UPSTREAM_FAILURES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// The upstream receives requests normally
+    Closed,
+    /// The upstream is ejected until the cooldown elapses
+    Open,
+    /// A single trial request is checking whether the upstream recovered
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_metric(self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: usize,
+    /// When the circuit was opened (or when the half-open trial started)
+    since: Instant,
+}
+
+/// Passive health checking for the upstreams of a route.
+///
+/// Upstreams are ejected after `consecutive_failures` failed requests and
+/// get a single trial request (half-open) once the cooldown elapses.
+/// A successful trial closes the circuit, a failed one opens it again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    host: String,
+    consecutive_failures: usize,
+    cooldown: Duration,
+    circuits: HashMap<SocketAddr, Mutex<Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(host: &str, config: &RouteCircuitBreaker, backends: &BTreeSet<Backend>) -> Self {
+        let circuits = backends
+            .iter()
+            .map(|b| {
+                let circuit = Circuit {
+                    state: CircuitState::Closed,
+                    consecutive_failures: 0,
+                    since: Instant::now(),
+                };
+                UPSTREAM_CIRCUIT_STATE
+                    .with_label_values(&[host, &b.addr.to_string()])
+                    .set(CircuitState::Closed.as_metric());
+
+                (b.addr.clone(), Mutex::new(circuit))
+            })
+            .collect();
+
+        Self {
+            host: host.to_string(),
+            consecutive_failures: config.consecutive_failures,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            circuits,
+        }
+    }
+
+    /// Whether the upstream can receive requests: the circuit is closed
+    /// or it has been open (or half-open) for longer than the cooldown.
+    pub fn is_available(&self, addr: &SocketAddr) -> bool {
+        let Some(circuit) = self.circuits.get(addr) else {
+            return true;
+        };
+
+        let circuit = circuit.lock().unwrap();
+        circuit.state == CircuitState::Closed || circuit.since.elapsed() >= self.cooldown
+    }
+
+    /// Called once the upstream is picked for a request.
+    /// An open circuit moves to half-open, letting this request through as the trial.
+    pub fn on_selected(&self, addr: &SocketAddr) {
+        let Some(circuit) = self.circuits.get(addr) else {
+            return;
+        };
+
+        let mut circuit = circuit.lock().unwrap();
+        if circuit.state != CircuitState::Closed {
+            circuit.since = Instant::now();
+            self.transition(&mut circuit, addr, CircuitState::HalfOpen);
+        }
+    }
+
+    /// Records the outcome of a request sent to the upstream
+    pub fn report(&self, addr: &SocketAddr, failed: bool) {
+        let Some(circuit) = self.circuits.get(addr) else {
+            return;
+        };
+
+        let mut circuit = circuit.lock().unwrap();
+        if !failed {
+            circuit.consecutive_failures = 0;
+            self.transition(&mut circuit, addr, CircuitState::Closed);
+            return;
+        }
+
+        UPSTREAM_FAILURES
+            .with_label_values(&[&self.host, &addr.to_string()])
+            .inc();
+        circuit.consecutive_failures += 1;
+
+        if circuit.state == CircuitState::HalfOpen
+            || circuit.consecutive_failures >= self.consecutive_failures
+        {
+            circuit.since = Instant::now();
+            self.transition(&mut circuit, addr, CircuitState::Open);
+        }
+    }
+
+    fn transition(&self, circuit: &mut Circuit, addr: &SocketAddr, state: CircuitState) {
+        if circuit.state == state {
+            return;
+        }
+
+        tracing::warn!(
+            host = self.host,
+            upstream = addr.to_string(),
+            consecutive_failures = circuit.consecutive_failures,
+            "upstream circuit changed from {:?} to {:?}",
+            circuit.state,
+            state
+        );
+
+        circuit.state = state;
+        UPSTREAM_CIRCUIT_STATE
+            .with_label_values(&[&self.host, &addr.to_string()])
+            .set(state.as_metric());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuit_breaker(cooldown_secs: u64) -> (CircuitBreaker, SocketAddr) {
+        let backend = Backend::new("127.0.0.1:3000").unwrap();
+        let addr = backend.addr.clone();
+        let config = RouteCircuitBreaker {
+            consecutive_failures: 2,
+            cooldown_secs,
+        };
+
+        let breaker = CircuitBreaker::new("example.com", &config, &BTreeSet::from([backend]));
+        (breaker, addr)
+    }
+
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        let (breaker, addr) = circuit_breaker(30);
+
+        breaker.report(&addr, true);
+        breaker.report(&addr, false);
+        breaker.report(&addr, true);
+        assert!(breaker.is_available(&addr));
+
+        breaker.report(&addr, true);
+        assert!(!breaker.is_available(&addr));
+    }
+
+    #[test]
+    fn test_circuit_half_open_after_cooldown() {
+        let (breaker, addr) = circuit_breaker(0);
+
+        breaker.report(&addr, true);
+        breaker.report(&addr, true);
+
+        // the cooldown elapsed, a trial request is let through
+        assert!(breaker.is_available(&addr));
+        breaker.on_selected(&addr);
+        assert_eq!(
+            breaker.circuits[&addr].lock().unwrap().state,
+            CircuitState::HalfOpen
+        );
+
+        // a failed trial opens the circuit again, a successful one closes it
+        breaker.report(&addr, true);
+        assert_eq!(
+            breaker.circuits[&addr].lock().unwrap().state,
+            CircuitState::Open
+        );
+
+        breaker.on_selected(&addr);
+        breaker.report(&addr, false);
+        assert_eq!(
+            breaker.circuits[&addr].lock().unwrap().state,
+            CircuitState::Closed
+        );
+    }
+}
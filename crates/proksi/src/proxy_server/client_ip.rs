@@ -0,0 +1,182 @@
+use std::net::{IpAddr, SocketAddr};
+
+use pingora::http::RequestHeader;
+
+use crate::config::{ClientIpSource, ServerClientIp};
+
+use super::forwarded::{header_values, TrustedProxies};
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const CF_CONNECTING_IP: &str = "CF-Connecting-IP";
+const X_REAL_IP: &str = "X-Real-IP";
+
+/// Resolves the IP of the client of a request, trusting the headers
+/// only when the request comes from one of the trusted proxies
+#[derive(Debug, Default)]
+pub struct ClientIpResolver {
+    source: ClientIpSource,
+    trusted_proxies: TrustedProxies,
+}
+
+impl ClientIpResolver {
+    pub fn from_config(config: &ServerClientIp) -> Self {
+        ClientIpResolver {
+            source: config.source,
+            trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+        }
+    }
+
+    /// The IP of the client, `peer` being the IP of the connection
+    /// (the one of the original client behind a PROXY protocol load balancer)
+    pub fn resolve(&self, req: &RequestHeader, peer: IpAddr) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.trusted_proxies.contains(peer) {
+            return peer;
+        }
+
+        let resolved = match self.source {
+            ClientIpSource::XForwardedFor => Some(self.forwarded_for_ip(req, peer)),
+            ClientIpSource::CfConnectingIp => header_ip(req, CF_CONNECTING_IP),
+            ClientIpSource::XRealIp => header_ip(req, X_REAL_IP),
+            ClientIpSource::Peer => None,
+        };
+
+        resolved.unwrap_or(peer)
+    }
+
+    /// The right-most address of `X-Forwarded-For` that is not a trusted proxy.
+    /// The addresses on its left were added by the client itself and can't be trusted.
+    fn forwarded_for_ip(&self, req: &RequestHeader, peer: IpAddr) -> IpAddr {
+        let hops = header_values(req, X_FORWARDED_FOR)
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            // the last trusted proxy is the client if the chain is invalid
+            let Some(ip) = parse_ip(hop) else {
+                break;
+            };
+
+            client = ip;
+            if !self.trusted_proxies.contains(ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// The IP of a header holding a single address
+fn header_ip(req: &RequestHeader, name: &str) -> Option<IpAddr> {
+    let value = req.headers.get(name)?.to_str().ok()?;
+    parse_ip(value)
+}
+
+/// Parses an IP, with or without a port (ex: `203.0.113.7:56324` or `[2001:db8::1]:443`)
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn resolver(trusted_proxies: &[&'static str], source: ClientIpSource) -> ClientIpResolver {
+        ClientIpResolver::from_config(&ServerClientIp {
+            trusted_proxies: trusted_proxies.iter().map(|p| Cow::Borrowed(*p)).collect(),
+            source,
+        })
+    }
+
+    fn request(headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip_from_forwarded_for() {
+        let resolver = resolver(
+            &["10.0.0.0/8", "2001:db8::1"],
+            ClientIpSource::XForwardedFor,
+        );
+
+        // the right-most address that is not a trusted proxy
+        let req = request(&[
+            ("x-forwarded-for", "198.51.100.1, 203.0.113.7"),
+            ("x-forwarded-for", "10.0.0.3"),
+        ]);
+        assert_eq!(resolver.resolve(&req, ip("10.0.0.2")), ip("203.0.113.7"));
+        assert_eq!(
+            resolver.resolve(&req, ip("::ffff:10.0.0.2")),
+            ip("203.0.113.7")
+        );
+
+        // untrusted peers can't tell who the client is
+        assert_eq!(resolver.resolve(&req, ip("192.0.2.1")), ip("192.0.2.1"));
+
+        // addresses with a port
+        let req = request(&[("x-forwarded-for", "[2001:db8::7]:443, 10.0.0.3:8080")]);
+        assert_eq!(resolver.resolve(&req, ip("2001:db8::1")), ip("2001:db8::7"));
+
+        // only trusted proxies, the left-most one is the client
+        let req = request(&[("x-forwarded-for", "10.0.0.4, 10.0.0.3")]);
+        assert_eq!(resolver.resolve(&req, ip("10.0.0.2")), ip("10.0.0.4"));
+
+        // an invalid address stops the chain
+        let req = request(&[("x-forwarded-for", "203.0.113.7, unknown, 10.0.0.3")]);
+        assert_eq!(resolver.resolve(&req, ip("10.0.0.2")), ip("10.0.0.3"));
+
+        // no header
+        assert_eq!(
+            resolver.resolve(&request(&[]), ip("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_client_ip_from_single_headers() {
+        let req = request(&[
+            ("cf-connecting-ip", "203.0.113.7"),
+            ("x-real-ip", "198.51.100.1"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+
+        let cloudflare = resolver(&["173.245.48.0/20"], ClientIpSource::CfConnectingIp);
+        assert_eq!(
+            cloudflare.resolve(&req, ip("173.245.48.1")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(cloudflare.resolve(&req, ip("192.0.2.2")), ip("192.0.2.2"));
+
+        let real_ip = resolver(&["10.0.0.0/8"], ClientIpSource::XRealIp);
+        assert_eq!(real_ip.resolve(&req, ip("10.0.0.2")), ip("198.51.100.1"));
+
+        // the peer is kept without a valid header
+        let req = request(&[("x-real-ip", "unknown")]);
+        assert_eq!(real_ip.resolve(&req, ip("10.0.0.2")), ip("10.0.0.2"));
+
+        let peer = resolver(&["10.0.0.0/8"], ClientIpSource::Peer);
+        let req = request(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(peer.resolve(&req, ip("10.0.0.2")), ip("10.0.0.2"));
+
+        // without trusted proxies, the headers are ignored
+        let default = ClientIpResolver::default();
+        assert_eq!(default.resolve(&req, ip("10.0.0.2")), ip("10.0.0.2"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{borrow::Cow, net::IpAddr};
+
+use ipnet::IpNet;
+use itertools::Itertools;
+use pingora::http::RequestHeader;
+
+use crate::config::{ForwardedHeader, ForwardedHeadersMode, RouteForwardedHeaders};
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+const X_REAL_IP: &str = "X-Real-IP";
+const FORWARDED: &str = "Forwarded";
+
+/// Parses a trusted proxy, either a CIDR (ex: `10.0.0.0/8`) or a single IP
+fn parse_trusted_proxy(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// The proxies allowed to tell who the client is, as IPs or CIDRs
+#[derive(Debug, Default, Clone)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Invalid entries are ignored, the configuration validation reports them
+    pub fn new(proxies: &[Cow<'static, str>]) -> Self {
+        TrustedProxies(
+            proxies
+                .iter()
+                .filter_map(|proxy| parse_trusted_proxy(proxy))
+                .collect(),
+        )
+    }
+
+    /// Whether the IP is one of the proxies, IPv4-mapped IPv6 addresses match as IPv4 ones
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// The values of a header, a header can be sent multiple times
+/// and its values form a single list
+pub fn header_values<'a>(req: &'a RequestHeader, name: &str) -> impl Iterator<Item = &'a str> {
+    req.headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+}
+
+/// Tells the upstreams about the client and the original request
+/// with the `X-Forwarded-*`, `X-Real-IP` and `Forwarded` headers
+#[derive(Debug)]
+pub struct ForwardedHeaders {
+    headers: Vec<ForwardedHeader>,
+    mode: ForwardedHeadersMode,
+    trusted_proxies: TrustedProxies,
+}
+
+/// What happens to the value of a header already present in the request
+#[derive(Clone, Copy)]
+enum Incoming {
+    Replace,
+    Keep,
+    Append,
+}
+
+impl ForwardedHeaders {
+    pub fn from_config(config: &RouteForwardedHeaders) -> Self {
+        ForwardedHeaders {
+            headers: config.headers.clone(),
+            mode: config.mode,
+            trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+        }
+    }
+
+    /// Sets the headers of the request sent to the upstream.
+    /// `client_ip` is the peer of the connection, `proto` the scheme of the request
+    /// and `host` its original host.
+    pub fn apply(
+        &self,
+        req: &mut RequestHeader,
+        client_ip: Option<IpAddr>,
+        proto: &str,
+        host: &str,
+    ) {
+        let client_ip = client_ip.map(|ip| ip.to_canonical());
+
+        // values sent by the client itself can't be trusted
+        let trusted = self.mode == ForwardedHeadersMode::Append
+            && client_ip.is_some_and(|ip| self.trusted_proxies.contains(ip));
+        let (chain, single) = if trusted {
+            (Incoming::Append, Incoming::Keep)
+        } else {
+            (Incoming::Replace, Incoming::Replace)
+        };
+
+        let client = client_ip.map(|ip| ip.to_string());
+        for header in &self.headers {
+            match header {
+                ForwardedHeader::XForwardedFor => {
+                    set_header(req, X_FORWARDED_FOR, client.clone(), chain);
+                }
+                ForwardedHeader::XForwardedProto => {
+                    set_header(req, X_FORWARDED_PROTO, Some(proto.to_string()), single);
+                }
+                ForwardedHeader::XForwardedHost => {
+                    set_header(req, X_FORWARDED_HOST, Some(host.to_string()), single);
+                }
+                ForwardedHeader::XRealIp => {
+                    set_header(req, X_REAL_IP, client.clone(), single);
+                }
+                ForwardedHeader::Forwarded => {
+                    let element = forwarded_element(client_ip, proto, host);
+                    set_header(req, FORWARDED, Some(element), chain);
+                }
+            }
+        }
+    }
+}
+
+/// Sets the header to `value`, or removes it if the value is unknown
+/// and the incoming one is not kept
+fn set_header(
+    req: &mut RequestHeader,
+    name: &'static str,
+    value: Option<String>,
+    incoming: Incoming,
+) {
+    let current = header_values(req, name).join(", ");
+
+    let value = match (incoming, value) {
+        (Incoming::Keep, _) | (Incoming::Append, None) if !current.is_empty() => return,
+        (Incoming::Append, Some(value)) if !current.is_empty() => format!("{current}, {value}"),
+        (_, Some(value)) => value,
+        (_, None) => {
+            req.remove_header(name);
+            return;
+        }
+    };
+
+    req.insert_header(name, value).ok();
+}
+
+/// The element of the `Forwarded` header describing this hop (RFC 7239)
+fn forwarded_element(client_ip: Option<IpAddr>, proto: &str, host: &str) -> String {
+    let node = match client_ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+        None => "unknown".to_string(),
+    };
+
+    format!("for={node};proto={proto};host={}", quoted(host))
+}
+
+/// Quotes the value if it is not a token (ex: a host with a port)
+fn quoted(value: &str) -> String {
+    let is_token = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+    if !value.is_empty() && value.chars().all(is_token) {
+        return value.to_string();
+    }
+
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded_headers(config: RouteForwardedHeaders) -> ForwardedHeaders {
+        ForwardedHeaders::from_config(&config)
+    }
+
+    fn request(headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    fn header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+        req.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    const ALL_HEADERS: [ForwardedHeader; 5] = [
+        ForwardedHeader::XForwardedFor,
+        ForwardedHeader::XForwardedProto,
+        ForwardedHeader::XForwardedHost,
+        ForwardedHeader::XRealIp,
+        ForwardedHeader::Forwarded,
+    ];
+
+    #[test]
+    fn test_forwarded_headers_replace_values_of_untrusted_clients() {
+        let forwarded = forwarded_headers(RouteForwardedHeaders {
+            headers: ALL_HEADERS.to_vec(),
+            trusted_proxies: vec![Cow::Borrowed("10.0.0.0/8")],
+            ..Default::default()
+        });
+
+        let mut req = request(&[
+            ("x-forwarded-for", "1.1.1.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-real-ip", "1.1.1.1"),
+            ("forwarded", "for=1.1.1.1"),
+        ]);
+        let client_ip = Some("203.0.113.7".parse().unwrap());
+        forwarded.apply(&mut req, client_ip, "http", "example.com:8080");
+
+        assert_eq!(header(&req, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(header(&req, "x-forwarded-proto"), Some("http"));
+        assert_eq!(header(&req, "x-forwarded-host"), Some("example.com:8080"));
+        assert_eq!(header(&req, "x-real-ip"), Some("203.0.113.7"));
+        assert_eq!(
+            header(&req, "forwarded"),
+            Some("for=203.0.113.7;proto=http;host=\"example.com:8080\"")
+        );
+        assert_eq!(req.headers.get_all("x-forwarded-for").iter().count(), 1);
+    }
+
+    #[test]
+    fn test_forwarded_headers_extend_values_of_trusted_proxies() {
+        let forwarded = forwarded_headers(RouteForwardedHeaders {
+            headers: ALL_HEADERS.to_vec(),
+            trusted_proxies: vec![Cow::Borrowed("10.0.0.0/8"), Cow::Borrowed("2001:db8::1")],
+            ..Default::default()
+        });
+
+        let mut req = request(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-real-ip", "203.0.113.7"),
+            ("forwarded", "for=203.0.113.7"),
+        ]);
+        let client_ip = Some("2001:db8::1".parse().unwrap());
+        forwarded.apply(&mut req, client_ip, "http", "example.com");
+
+        assert_eq!(
+            header(&req, "x-forwarded-for"),
+            Some("203.0.113.7, 192.0.2.1, 2001:db8::1")
+        );
+        assert_eq!(header(&req, "x-forwarded-proto"), Some("https"));
+        assert_eq!(header(&req, "x-forwarded-host"), Some("example.com"));
+        assert_eq!(header(&req, "x-real-ip"), Some("203.0.113.7"));
+        assert_eq!(
+            header(&req, "forwarded"),
+            Some("for=203.0.113.7, for=\"[2001:db8::1]\";proto=http;host=example.com")
+        );
+
+        // IPv4-mapped addresses are matched as IPv4 ones
+        let mut req = request(&[("x-forwarded-for", "203.0.113.7")]);
+        let client_ip = Some("::ffff:10.0.0.2".parse().unwrap());
+        forwarded.apply(&mut req, client_ip, "https", "example.com");
+        assert_eq!(
+            header(&req, "x-forwarded-for"),
+            Some("203.0.113.7, 10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers_overwrite_mode() {
+        let forwarded = forwarded_headers(RouteForwardedHeaders {
+            mode: ForwardedHeadersMode::Overwrite,
+            ..Default::default()
+        });
+
+        let mut req = request(&[("x-forwarded-for", "1.1.1.1"), ("x-real-ip", "1.1.1.1")]);
+        let client_ip = Some("10.0.0.2".parse().unwrap());
+        forwarded.apply(&mut req, client_ip, "https", "example.com");
+
+        assert_eq!(header(&req, "x-forwarded-for"), Some("10.0.0.2"));
+        assert_eq!(header(&req, "x-forwarded-proto"), Some("https"));
+        // only the configured headers are changed
+        assert_eq!(header(&req, "x-real-ip"), Some("1.1.1.1"));
+        assert_eq!(header(&req, "forwarded"), None);
+    }
+}
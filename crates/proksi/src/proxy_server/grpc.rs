@@ -0,0 +1,281 @@
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, HeaderValue};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::HttpTask,
+    proxy::Session,
+    upstreams::peer::HttpPeer,
+    ErrorSource, ErrorType,
+};
+
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+/// The error of gRPC requests whose deadline passed before reaching an upstream
+pub const DEADLINE_EXCEEDED: ErrorType = ErrorType::Custom("GrpcDeadlineExceeded");
+
+/// The gRPC status codes sent by Proksi itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcStatus {
+    Unknown = 2,
+    DeadlineExceeded = 4,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+impl GrpcStatus {
+    /// The status of a request that failed with an HTTP status
+    /// (<https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md>)
+    pub fn from_http(status: u16) -> Self {
+        match status {
+            400 => GrpcStatus::Internal,
+            401 => GrpcStatus::Unauthenticated,
+            403 => GrpcStatus::PermissionDenied,
+            404 => GrpcStatus::Unimplemented,
+            429 => GrpcStatus::ResourceExhausted,
+            502..=504 => GrpcStatus::Unavailable,
+            _ => GrpcStatus::Unknown,
+        }
+    }
+
+    /// The status of a request that could not be proxied
+    pub fn from_error(e: &pingora::Error) -> Self {
+        match e.etype() {
+            ErrorType::HTTPStatus(status) => GrpcStatus::from_http(*status),
+            ErrorType::ReadTimedout | ErrorType::WriteTimedout => GrpcStatus::DeadlineExceeded,
+            etype if *etype == DEADLINE_EXCEEDED => GrpcStatus::DeadlineExceeded,
+            _ if e.esource() == &ErrorSource::Upstream => GrpcStatus::Unavailable,
+            _ => GrpcStatus::Internal,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            GrpcStatus::Unknown => "unknown error",
+            GrpcStatus::DeadlineExceeded => "deadline exceeded",
+            GrpcStatus::PermissionDenied => "permission denied",
+            GrpcStatus::ResourceExhausted => "too many requests",
+            GrpcStatus::Unimplemented => "no route matches the request",
+            GrpcStatus::Internal => "internal error",
+            GrpcStatus::Unavailable => "upstream unavailable",
+            GrpcStatus::Unauthenticated => "unauthenticated",
+        }
+    }
+
+    /// The `grpc-status` and `grpc-message` of the call
+    pub fn trailers(self) -> HeaderMap {
+        let mut trailers = HeaderMap::with_capacity(2);
+        trailers.insert(GRPC_STATUS, HeaderValue::from(self as u16));
+        trailers.insert(GRPC_MESSAGE, HeaderValue::from_static(self.message()));
+        trailers
+    }
+}
+
+/// A gRPC request and its deadline
+#[derive(Debug, Clone, Copy)]
+pub struct GrpcCall {
+    pub deadline: Option<Instant>,
+}
+
+impl GrpcCall {
+    /// The gRPC call of the request, `None` for other requests (including gRPC-Web ones)
+    pub fn from_request(req: &RequestHeader) -> Option<Self> {
+        if !is_grpc(req) {
+            return None;
+        }
+
+        Some(GrpcCall::with_deadline(req))
+    }
+
+    /// A gRPC call whose deadline is set by the `grpc-timeout` header of the request
+    pub fn with_deadline(req: &RequestHeader) -> Self {
+        let deadline = req
+            .headers
+            .get(GRPC_TIMEOUT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_timeout)
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+
+        GrpcCall { deadline }
+    }
+
+    /// The time left before the deadline, `None` if the client didn't set one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Caps the timeouts of the upstream to the time left before the deadline,
+    /// fails if it already passed
+    pub fn apply_deadline(&self, peer: &mut HttpPeer) -> pingora::Result<()> {
+        let Some(remaining) = self.remaining() else {
+            return Ok(());
+        };
+
+        if remaining.is_zero() {
+            return Err(pingora::Error::explain(
+                DEADLINE_EXCEEDED,
+                "the deadline of the gRPC call passed",
+            ));
+        }
+
+        let options = &mut peer.options;
+        for timeout in [
+            &mut options.total_connection_timeout,
+            &mut options.read_timeout,
+            &mut options.write_timeout,
+        ] {
+            *timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the request is a gRPC one (`application/grpc`, `application/grpc+proto`, etc.)
+fn is_grpc(req: &RequestHeader) -> bool {
+    req.headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(GRPC_CONTENT_TYPE))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+}
+
+/// Parses a `grpc-timeout` value: up to 8 digits followed by a unit (ex: `100m`, `5S`)
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    if !value.is_ascii() || !(2..=9).contains(&value.len()) {
+        return None;
+    }
+
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let amount = amount.parse::<u64>().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Encodes a `grpc-timeout` value with the most precise unit fitting in 8 digits
+pub fn encode_timeout(timeout: Duration) -> String {
+    let units = [
+        (timeout.as_nanos(), 'n'),
+        (timeout.as_micros(), 'u'),
+        (timeout.as_millis(), 'm'),
+        (u128::from(timeout.as_secs()), 'S'),
+        (u128::from(timeout.as_secs() / 60), 'M'),
+        (u128::from(timeout.as_secs() / 3600), 'H'),
+    ];
+
+    units
+        .iter()
+        .find(|(amount, _)| *amount < 100_000_000)
+        .map_or_else(
+            || "99999999H".to_string(),
+            |(amount, unit)| format!("{amount}{unit}"),
+        )
+}
+
+/// Sends a gRPC error to the client.
+/// Before the response started, it is a "Trailers-Only" response carrying the status
+/// in its headers, otherwise the status is sent in the trailers of the response.
+pub async fn respond_error(session: &mut Session, status: GrpcStatus) -> pingora::Result<()> {
+    if session.response_written().is_some() {
+        let trailers = Box::new(status.trailers());
+        session
+            .write_response_tasks(vec![HttpTask::Trailer(Some(trailers))])
+            .await?;
+        return Ok(());
+    }
+
+    let mut res = ResponseHeader::build(200, Some(3))?;
+    res.insert_header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)?;
+    for (name, value) in status.trailers() {
+        if let Some(name) = name {
+            res.insert_header(name, value)?;
+        }
+    }
+
+    session.write_response_header(Box::new(res), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_call_from_request() {
+        let request = |content_type: &str| {
+            let mut req =
+                RequestHeader::build("POST", b"/helloworld.Greeter/SayHello", None).unwrap();
+            req.insert_header("content-type", content_type).unwrap();
+            req.insert_header(GRPC_TIMEOUT, "2S").unwrap();
+            req
+        };
+
+        let call = GrpcCall::from_request(&request("application/grpc+proto")).unwrap();
+        let remaining = call.remaining().unwrap();
+        assert!(remaining > Duration::from_secs(1) && remaining <= Duration::from_secs(2));
+
+        assert!(GrpcCall::from_request(&request("application/grpc")).is_some());
+        assert!(GrpcCall::from_request(&request("application/grpc-web")).is_none());
+        assert!(GrpcCall::from_request(&request("application/json")).is_none());
+    }
+
+    #[test]
+    fn test_grpc_timeout() {
+        assert_eq!(parse_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(
+            parse_timeout("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+        assert_eq!(parse_timeout("100000000S"), None);
+        assert_eq!(parse_timeout("+1S"), None);
+        assert_eq!(parse_timeout("1s"), None);
+        assert_eq!(parse_timeout("S"), None);
+
+        assert_eq!(encode_timeout(Duration::from_millis(15)), "15000000n");
+        assert_eq!(encode_timeout(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(encode_timeout(Duration::from_secs(3600)), "3600000m");
+        assert_eq!(encode_timeout(Duration::from_secs(30 * 86400)), "2592000S");
+        assert_eq!(encode_timeout(Duration::ZERO), "0n");
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        assert_eq!(GrpcStatus::from_http(404), GrpcStatus::Unimplemented);
+        assert_eq!(GrpcStatus::from_http(503), GrpcStatus::Unavailable);
+        assert_eq!(GrpcStatus::from_http(418), GrpcStatus::Unknown);
+
+        let e = pingora::Error::new(ErrorType::HTTPStatus(429));
+        assert_eq!(GrpcStatus::from_error(&e), GrpcStatus::ResourceExhausted);
+
+        let e = pingora::Error::new_up(ErrorType::ConnectRefused);
+        assert_eq!(GrpcStatus::from_error(&e), GrpcStatus::Unavailable);
+
+        let e = pingora::Error::new(ErrorType::ReadTimedout);
+        assert_eq!(GrpcStatus::from_error(&e), GrpcStatus::DeadlineExceeded);
+
+        let e = pingora::Error::new(DEADLINE_EXCEEDED);
+        assert_eq!(GrpcStatus::from_error(&e), GrpcStatus::DeadlineExceeded);
+
+        let e = pingora::Error::new(ErrorType::InternalError);
+        assert_eq!(GrpcStatus::from_error(&e), GrpcStatus::Internal);
+    }
+}
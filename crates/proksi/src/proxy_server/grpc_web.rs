@@ -0,0 +1,503 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue, Method};
+use openssl::base64;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+    ErrorType::HTTPStatus,
+};
+
+use crate::config::RouteGrpcWeb;
+
+use super::grpc::{GrpcStatus, GRPC_CONTENT_TYPE};
+
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+/// The flag of the frame carrying the trailers at the end of a gRPC-Web response body
+const TRAILERS_FRAME: u8 = 0x80;
+
+/// The headers browsers can read to get the status of a call
+const EXPOSED_HEADERS: &str = "grpc-status, grpc-message, grpc-status-details-bin";
+
+/// Translates the gRPC-Web requests of browsers to gRPC and answers their CORS preflight requests
+/// (<https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md>)
+#[derive(Debug)]
+pub struct GrpcWeb {
+    allowed_origins: Vec<String>,
+    max_age_secs: u64,
+}
+
+impl GrpcWeb {
+    pub fn from_config(config: &RouteGrpcWeb) -> Self {
+        GrpcWeb {
+            allowed_origins: config
+                .allowed_origins
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            max_age_secs: config.max_age_secs,
+        }
+    }
+
+    /// The `Access-Control-Allow-Origin` of the request, `None` if its origin is not allowed
+    fn allowed_origin(&self, req: &RequestHeader) -> Option<HeaderValue> {
+        let origin = req.headers.get(header::ORIGIN)?;
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let value = origin.to_str().ok()?;
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(value))
+            .then(|| origin.clone())
+    }
+
+    /// Answers the CORS preflight requests of browsers, returns whether the request was one
+    pub async fn preflight(&self, session: &mut Session) -> pingora::Result<bool> {
+        let req = session.req_header();
+        if req.method != Method::OPTIONS
+            || !req
+                .headers
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return Ok(false);
+        }
+
+        let mut preflight = ResponseHeader::build(204, Some(7))?;
+        if let Some(origin) = self.allowed_origin(req) {
+            preflight.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
+            preflight.insert_header(header::ACCESS_CONTROL_ALLOW_METHODS, "POST, OPTIONS")?;
+            if let Some(headers) = req.headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                preflight.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, headers.clone())?;
+            }
+            preflight.insert_header(header::ACCESS_CONTROL_MAX_AGE, self.max_age_secs)?;
+        }
+        preflight.insert_header(header::VARY, "Origin")?;
+        preflight.insert_header(header::CONTENT_LENGTH, 0)?;
+
+        session
+            .write_response_header(Box::new(preflight), true)
+            .await?;
+        Ok(true)
+    }
+
+    /// The gRPC-Web call of the request, `None` for other requests
+    pub fn start(&self, req: &RequestHeader) -> Option<GrpcWebCall> {
+        let content_type = req
+            .headers
+            .get(header::CONTENT_TYPE)?
+            .to_str()
+            .ok()?
+            .to_ascii_lowercase();
+
+        let (text, suffix) = match content_type.strip_prefix(GRPC_WEB_TEXT) {
+            Some(suffix) => (true, suffix),
+            None => (false, content_type.strip_prefix(GRPC_WEB)?),
+        };
+
+        // ex: `+proto`, `+json`
+        if !suffix.is_empty() && !suffix.starts_with(['+', ';']) {
+            return None;
+        }
+
+        Some(GrpcWebCall {
+            text,
+            suffix: suffix.to_string(),
+            origin: self.allowed_origin(req),
+            request_pending: Vec::new(),
+            response_pending: Vec::new(),
+            grpc_response: false,
+        })
+    }
+}
+
+/// A gRPC-Web call being translated to gRPC
+#[derive(Debug)]
+pub struct GrpcWebCall {
+    /// `application/grpc-web-text` calls have base64 bodies
+    text: bool,
+    /// The rest of the content type (ex: `+proto`)
+    suffix: String,
+    /// The `Access-Control-Allow-Origin` of the responses
+    origin: Option<HeaderValue>,
+    /// Base64 characters of the request body not decoded yet
+    request_pending: Vec<u8>,
+    /// Bytes of the response body not encoded yet (base64 encodes groups of 3 bytes)
+    response_pending: Vec<u8>,
+    /// Whether the upstream answered with gRPC, other responses are sent as is
+    grpc_response: bool,
+}
+
+impl GrpcWebCall {
+    /// Turns the request into a gRPC one
+    pub fn upstream_request(&self, req: &mut RequestHeader) -> pingora::Result<()> {
+        req.insert_header(
+            header::CONTENT_TYPE,
+            format!("{GRPC_CONTENT_TYPE}{}", self.suffix),
+        )?;
+        // gRPC servers use it to detect proxies dropping the trailers
+        req.insert_header(header::TE, "trailers")?;
+
+        // the decoded body is shorter
+        if self.text {
+            req.remove_header(&header::CONTENT_LENGTH);
+        }
+
+        // gRPC requests end with an empty DATA frame
+        req.set_send_end_stream(false);
+        Ok(())
+    }
+
+    /// Decodes the base64 body of `grpc-web-text` requests
+    pub fn request_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if !self.text {
+            return Ok(());
+        }
+
+        if let Some(data) = body.as_ref() {
+            let chars = data.iter().filter(|c| !c.is_ascii_whitespace());
+            self.request_pending.extend(chars);
+        }
+
+        let len = if end_of_stream {
+            self.request_pending.len()
+        } else {
+            self.request_pending.len() / 4 * 4
+        };
+        let chars = self.request_pending.drain(..len).collect::<Vec<_>>();
+
+        let decoded = decode_text(&chars).ok_or_else(|| {
+            pingora::Error::explain(HTTPStatus(400), "invalid base64 gRPC-Web request body")
+        })?;
+        if body.is_some() || !decoded.is_empty() {
+            *body = Some(Bytes::from(decoded));
+        }
+
+        Ok(())
+    }
+
+    /// Drops the base64 characters left by the previous attempt,
+    /// retries replay the request body from the start
+    pub fn restart_request_body(&mut self) {
+        self.request_pending.clear();
+    }
+
+    /// Adds the CORS headers allowing the browser to read the response
+    fn add_cors_headers(&self, res: &mut ResponseHeader) -> pingora::Result<()> {
+        if let Some(origin) = self.origin.as_ref() {
+            res.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone())?;
+            res.insert_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, EXPOSED_HEADERS)?;
+            res.append_header(header::VARY, "Origin")?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns the gRPC response into a gRPC-Web one
+    pub fn response(&mut self, res: &mut ResponseHeader) -> pingora::Result<()> {
+        if res.status.is_informational() {
+            return Ok(());
+        }
+        self.add_cors_headers(res)?;
+
+        let content_type = res
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(GRPC_CONTENT_TYPE))
+            .filter(|suffix| suffix.is_empty() || suffix.starts_with(['+', ';']))
+            .map(|suffix| format!("{}{suffix}", self.content_type()));
+        let Some(content_type) = content_type else {
+            return Ok(());
+        };
+
+        self.grpc_response = true;
+        res.insert_header(header::CONTENT_TYPE, content_type)?;
+        // the trailers are added to the body
+        res.remove_header(&header::CONTENT_LENGTH);
+        Ok(())
+    }
+
+    fn content_type(&self) -> &'static str {
+        if self.text {
+            GRPC_WEB_TEXT
+        } else {
+            GRPC_WEB
+        }
+    }
+
+    /// Encodes the response body of `grpc-web-text` calls in base64
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if !self.grpc_response || !self.text {
+            return;
+        }
+
+        if let Some(data) = body.as_ref() {
+            self.response_pending.extend_from_slice(data);
+        }
+
+        let encoded = self.encode_pending(end_of_stream);
+        *body = (!encoded.is_empty()).then(|| Bytes::from(encoded));
+    }
+
+    /// Encodes the pending bytes of the response, keeping the ones
+    /// that don't fill a group of 3 unless it is the end of the response
+    fn encode_pending(&mut self, end_of_stream: bool) -> String {
+        let len = if end_of_stream {
+            self.response_pending.len()
+        } else {
+            self.response_pending.len() / 3 * 3
+        };
+
+        let bytes = self.response_pending.drain(..len).collect::<Vec<_>>();
+        base64::encode_block(&bytes)
+    }
+
+    /// The trailers of the gRPC response, sent at the end of the gRPC-Web response body
+    pub fn response_trailers(&mut self, trailers: &HeaderMap) -> Option<Bytes> {
+        if !self.grpc_response {
+            return None;
+        }
+
+        let frame = trailers_frame(trailers);
+        if !self.text {
+            return Some(frame);
+        }
+
+        self.response_pending.extend_from_slice(&frame);
+        Some(Bytes::from(self.encode_pending(true)))
+    }
+
+    /// Sends a gRPC error to the browser, in the headers of the response
+    /// or in its trailers frame if the response already started
+    pub async fn respond_error(
+        &mut self,
+        session: &mut Session,
+        status: GrpcStatus,
+    ) -> pingora::Result<()> {
+        if session.response_written().is_some() {
+            let trailers = self.response_trailers(&status.trailers());
+            if trailers.is_some() {
+                session.write_response_body(trailers, true).await?;
+            }
+            return Ok(());
+        }
+
+        let mut res = ResponseHeader::build(200, Some(7))?;
+        res.insert_header(header::CONTENT_TYPE, self.content_type())?;
+        res.insert_header(header::CONTENT_LENGTH, 0)?;
+        for (name, value) in status.trailers() {
+            if let Some(name) = name {
+                res.insert_header(name, value)?;
+            }
+        }
+        self.add_cors_headers(&mut res)?;
+
+        session.write_response_header(Box::new(res), true).await
+    }
+}
+
+/// Decodes base64 text made of one or more padded chunks (ex: `AAAAAA==AAAAAA==`)
+fn decode_text(chars: &[u8]) -> Option<Vec<u8>> {
+    if chars.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(chars.len() / 4 * 3);
+    let mut start = 0;
+    for (index, group) in chars.chunks(4).enumerate() {
+        let end = (index + 1) * 4;
+        if group.contains(&b'=') || end == chars.len() {
+            let chunk = std::str::from_utf8(&chars[start..end]).ok()?;
+            decoded.extend(base64::decode_block(chunk).ok()?);
+            start = end;
+        }
+    }
+
+    Some(decoded)
+}
+
+/// The frame carrying the trailers in a gRPC-Web response body:
+/// its flag, its length and the trailers as HTTP/1 headers
+fn trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut headers = BytesMut::new();
+    for (name, value) in trailers {
+        headers.put_slice(name.as_str().as_bytes());
+        headers.put_slice(b":");
+        headers.put_slice(value.as_bytes());
+        headers.put_slice(b"\r\n");
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + headers.len());
+    frame.put_u8(TRAILERS_FRAME);
+    frame.put_u32(u32::try_from(headers.len()).unwrap_or(u32::MAX));
+    frame.put_slice(&headers);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn grpc_web(allowed_origins: &[&'static str]) -> GrpcWeb {
+        GrpcWeb::from_config(&RouteGrpcWeb {
+            allowed_origins: allowed_origins.iter().map(|o| Cow::Borrowed(*o)).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn request(content_type: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/helloworld.Greeter/SayHello", None).unwrap();
+        req.insert_header("content-type", content_type).unwrap();
+        req.insert_header("content-length", "12").unwrap();
+        req.insert_header("origin", "https://app.example.com")
+            .unwrap();
+        req
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn test_grpc_web_binary_call() {
+        let grpc_web = grpc_web(&["https://app.example.com"]);
+        assert!(grpc_web.start(&request("application/grpc")).is_none());
+        assert!(grpc_web.start(&request("application/grpc-webby")).is_none());
+
+        let mut req = request("application/grpc-web+proto");
+        let mut call = grpc_web.start(&req).unwrap();
+        call.upstream_request(&mut req).unwrap();
+        assert_eq!(
+            header(&req.headers, "content-type"),
+            Some("application/grpc+proto")
+        );
+        assert_eq!(header(&req.headers, "te"), Some("trailers"));
+        assert_eq!(header(&req.headers, "content-length"), Some("12"));
+
+        let mut body = Some(Bytes::from_static(b"\0\0\0\0\x02ok"));
+        call.request_body(&mut body, true).unwrap();
+        assert_eq!(body, Some(Bytes::from_static(b"\0\0\0\0\x02ok")));
+
+        let mut res = ResponseHeader::build(200, None).unwrap();
+        res.insert_header("content-type", "application/grpc+proto")
+            .unwrap();
+        res.insert_header("content-length", "7").unwrap();
+        call.response(&mut res).unwrap();
+        assert_eq!(
+            header(&res.headers, "content-type"),
+            Some("application/grpc-web+proto")
+        );
+        assert_eq!(header(&res.headers, "content-length"), None);
+        assert_eq!(
+            header(&res.headers, "access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+
+        let trailers = call.response_trailers(&GrpcStatus::Unavailable.trailers());
+        assert_eq!(
+            trailers,
+            Some(Bytes::from_static(
+                b"\x80\0\0\0\x33grpc-status:14\r\ngrpc-message:upstream unavailable\r\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_grpc_web_text_call() {
+        let grpc_web = grpc_web(&[]);
+
+        let mut req = request("application/grpc-web-text");
+        let mut call = grpc_web.start(&req).unwrap();
+        call.upstream_request(&mut req).unwrap();
+        assert_eq!(
+            header(&req.headers, "content-type"),
+            Some("application/grpc")
+        );
+        assert_eq!(header(&req.headers, "content-length"), None);
+
+        // the base64 text can be split anywhere, and made of padded chunks
+        let mut body = Some(Bytes::from_static(b"AAAAAA"));
+        call.request_body(&mut body, false).unwrap();
+        assert_eq!(body, Some(Bytes::from_static(b"\0\0\0")));
+        let mut body = Some(Bytes::from_static(b"J"));
+        call.request_body(&mut body, false).unwrap();
+        assert_eq!(body, Some(Bytes::new()));
+        let mut body = Some(Bytes::from_static(b"v\r\naw==AAAAAAA="));
+        call.request_body(&mut body, true).unwrap();
+        assert_eq!(body, Some(Bytes::from_static(b"\0\x02ok\0\0\0\0\0")));
+
+        let mut body = Some(Bytes::from_static(b"!!!!"));
+        assert!(call.request_body(&mut body, true).is_err());
+
+        let mut res = ResponseHeader::build(200, None).unwrap();
+        res.insert_header("content-type", "application/grpc")
+            .unwrap();
+        call.response(&mut res).unwrap();
+        assert_eq!(
+            header(&res.headers, "content-type"),
+            Some("application/grpc-web-text")
+        );
+        // no allowed origins
+        assert_eq!(header(&res.headers, "access-control-allow-origin"), None);
+
+        // groups of 3 bytes are encoded as they come, the rest with the trailers
+        let mut body = Some(Bytes::from_static(b"\0\0\0\0\x02ok"));
+        call.response_body(&mut body, false);
+        assert_eq!(body, Some(Bytes::from_static(b"AAAAAAJv")));
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let encoded = call.response_trailers(&trailers).unwrap();
+        let decoded = base64::decode_block(std::str::from_utf8(&encoded).unwrap()).unwrap();
+        assert_eq!(decoded, b"k\x80\0\0\0\x0fgrpc-status:0\r\n");
+    }
+
+    #[test]
+    fn test_grpc_web_text_retry_replays_the_body() {
+        let grpc_web = grpc_web(&[]);
+        let mut call = grpc_web
+            .start(&request("application/grpc-web-text"))
+            .unwrap();
+
+        // the first attempt fails with characters left to decode
+        let mut body = Some(Bytes::from_static(b"AAAAAAJ"));
+        call.request_body(&mut body, false).unwrap();
+        assert_eq!(body, Some(Bytes::from_static(b"\0\0\0")));
+
+        call.restart_request_body();
+        let mut body = Some(Bytes::from_static(b"AAAAAAJvaw=="));
+        call.request_body(&mut body, true).unwrap();
+        assert_eq!(body, Some(Bytes::from_static(b"\0\0\0\0\x02ok")));
+    }
+
+    #[test]
+    fn test_grpc_web_other_responses_are_untouched() {
+        let grpc_web = grpc_web(&["*"]);
+        let mut call = grpc_web
+            .start(&request("application/grpc-web-text"))
+            .unwrap();
+
+        let mut res = ResponseHeader::build(502, None).unwrap();
+        res.insert_header("content-type", "text/html").unwrap();
+        call.response(&mut res).unwrap();
+        assert_eq!(header(&res.headers, "content-type"), Some("text/html"));
+        assert_eq!(
+            header(&res.headers, "access-control-allow-origin"),
+            Some("*")
+        );
+
+        let mut body = Some(Bytes::from_static(b"<html>"));
+        call.response_body(&mut body, true);
+        assert_eq!(body, Some(Bytes::from_static(b"<html>")));
+        assert_eq!(call.response_trailers(&HeaderMap::new()), None);
+    }
+}
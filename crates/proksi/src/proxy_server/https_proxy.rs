@@ -1,4 +1,4 @@
-use std::net::ToSocketAddrs;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use std::{borrow::Cow, collections::HashMap};
@@ -6,29 +6,44 @@ use std::{borrow::Cow, collections::HashMap};
 use async_trait::async_trait;
 
 use http::uri::PathAndQuery;
-use http::{HeaderName, HeaderValue, Uri};
+use http::{HeaderName, HeaderValue};
 use once_cell::sync::Lazy;
 
 use openssl::base64;
 use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::modules::http::{compression::ResponseCompressionBuilder, HttpModules};
+use pingora::protocols::l4::socket::SocketAddr;
 use pingora::protocols::Digest;
-use pingora::proxy::{ProxyHttp, Session};
+use pingora::proxy::{FailToProxy, ProxyHttp, Session};
 use pingora::upstreams::peer::Peer;
-use pingora::{upstreams::peer::HttpPeer, ErrorType::HTTPStatus};
+use pingora::{
+    upstreams::peer::HttpPeer,
+    ErrorSource,
+    ErrorType::{self, HTTPStatus},
+};
 
 use pingora_cache::lock::CacheLock;
 
 use pingora_cache::{CacheKey, CacheMeta, ForcedInvalidationKind, NoCacheReason, RespCacheable};
 
 use crate::cache::disk::storage::DiskCache;
-use crate::config::{RouteCacheType, RouteUpstream};
+use crate::config::{RetryCondition, RouteCacheType, RouteUpstream, UpstreamProtocol};
 use crate::stores::{self, routes::RouteStoreContainer};
 
-use super::default_peer_opts;
+use super::alt_svc::{self, AltSvcBuilder};
+use super::client_ip::ClientIpResolver;
+use super::grpc::{self, GrpcCall, GrpcStatus, GRPC_TIMEOUT};
+use super::grpc_web::GrpcWebCall;
+use super::load_balancer::ConnectionGuard;
 use super::middleware::{
     execute_request_plugins, execute_response_plugins, execute_upstream_request_plugins,
     execute_upstream_response_plugins,
 };
+use super::mirror::MirrorRequest;
+use super::proxy_protocol::{connection_addrs, upstream_proxy, ConnectionAddrs};
+use super::{
+    default_upstream_tls, format_upstream_addr, is_upstream_addr, peer_opts, protocol_alpn,
+};
 
 static STORAGE_MEM_CACHE: Lazy<pingora_cache::MemCache> = Lazy::new(pingora_cache::MemCache::new);
 static STORAGE_CACHE: Lazy<DiskCache> = Lazy::new(DiskCache::new);
@@ -37,7 +52,31 @@ static CACHEABLE_METHODS: Lazy<Vec<http::Method>> =
 static CACHE_LOCK: Lazy<CacheLock> = Lazy::new(|| CacheLock::new(Duration::from_secs(1)));
 
 /// Load balancer proxy struct
-pub struct Router {}
+pub struct Router {
+    pub client_ip: ClientIpResolver,
+    /// Advertises the HTTP/3 listener to the clients, `None` if it is disabled
+    pub alt_svc: Option<HeaderValue>,
+    /// The unix socket receiving the requests of the HTTP/3 listener
+    pub http3_relay: Option<std::path::PathBuf>,
+}
+
+impl Router {
+    /// Whether the client connected over TLS, directly or through the HTTP/3 listener
+    fn is_tls(&self, session: &Session) -> bool {
+        let is_http3 = self.http3_relay.as_deref().is_some_and(|relay| {
+            session
+                .server_addr()
+                .and_then(SocketAddr::as_unix)
+                .and_then(|addr| addr.as_pathname())
+                .is_some_and(|addr| addr == relay)
+        });
+
+        is_http3
+            || session
+                .digest()
+                .is_some_and(|digest| digest.ssl_digest.is_some())
+    }
+}
 
 // type Container = mapref::one::Ref<'static, String, RouteStoreContainer>;
 
@@ -52,12 +91,59 @@ fn get_cache_storage(cache_type: &RouteCacheType) -> &'static (dyn pingora_cache
     }
 }
 
+/// Whether the request can be sent again to another upstream after failing
+/// with the given condition, according to the retry policy of the route.
+fn should_retry(session: &Session, ctx: &RouterContext, condition: RetryCondition) -> bool {
+    let Some(retry) = ctx.route_container.retry.as_ref() else {
+        return false;
+    };
+
+    // Connection errors never reached the upstream, any method can be sent again
+    let is_idempotent = condition == RetryCondition::ConnectError
+        || retry.retry_non_idempotent
+        || session.req_header().method.is_idempotent();
+
+    ctx.attempts < retry.max_attempts
+        && retry.retry_on.contains(&condition)
+        && is_idempotent
+        // the request body must still be available to send it again
+        && !session.as_ref().retry_buffer_truncated()
+        && session.response_written().is_none()
+}
+
 pub struct RouterContext {
     pub host: String,
+    /// The addresses of the client connection, the ones of the original client
+    /// for connections behind a PROXY protocol load balancer
+    pub connection: Option<ConnectionAddrs>,
+    /// The IP of the client, resolved once from the trusted proxies
+    /// and used by every phase of the request and the access log
+    pub client_ip: Option<IpAddr>,
+    /// The gRPC call of the request and its deadline, `None` for other requests
+    pub grpc: Option<GrpcCall>,
+    /// The gRPC-Web call translated to gRPC, `None` for other requests
+    pub grpc_web: Option<GrpcWebCall>,
     pub route_container: RouteStoreContainer,
     pub upstream: RouteUpstream,
     pub extensions: HashMap<Cow<'static, str>, String>,
 
+    /// Counts this request as an active connection of the selected upstream
+    /// (used by the least connections and power of two algorithms)
+    pub upstream_connection: Option<ConnectionGuard>,
+    /// The selected upstream and its response status,
+    /// reported to the circuit breaker once the request is done
+    pub upstream_addr: Option<SocketAddr>,
+    pub upstream_status: Option<u16>,
+    /// The traffic split group of the request, `None` for the upstreams of the route
+    pub upstream_group: Option<usize>,
+    /// The sticky session cookie sent with the response, if the client has a new upstream
+    pub sticky_cookie: Option<String>,
+    /// Copy of the request sent to the shadow upstream of the route once the request is done
+    pub mirror: Option<MirrorRequest>,
+    /// Upstreams already tried by this request, skipped when retrying
+    pub attempts: usize,
+    pub tried_upstreams: Vec<SocketAddr>,
+
     pub timings: RouterTimings,
 }
 
@@ -74,9 +160,21 @@ impl ProxyHttp for Router {
     fn new_ctx(&self) -> Self::CTX {
         RouterContext {
             host: String::new(),
+            connection: None,
+            client_ip: None,
+            grpc: None,
+            grpc_web: None,
             route_container: RouteStoreContainer::default(),
             upstream: RouteUpstream::default(),
             extensions: HashMap::with_capacity(2),
+            upstream_connection: None,
+            upstream_addr: None,
+            upstream_status: None,
+            upstream_group: None,
+            mirror: None,
+            sticky_cookie: None,
+            attempts: 0,
+            tried_upstreams: Vec::new(),
 
             timings: RouterTimings {
                 request_filter_start: std::time::Instant::now(),
@@ -84,6 +182,16 @@ impl ProxyHttp for Router {
         }
     }
 
+    /// Adds the modules filtering every response to the downstream
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        // the default module of pingora (compression, disabled unless a request enables it)
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+
+        if let Some(alt_svc) = self.alt_svc.as_ref() {
+            modules.add_module(Box::new(AltSvcBuilder::new(alt_svc.clone())));
+        }
+    }
+
     // Define the filter that will be executed before the request is sent to the upstream.
     // If the filter returns `true`, the request has already been handled.
     // If the filter returns `false`, the request will be sent to the upstream.
@@ -92,27 +200,60 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
+        ctx.connection = connection_addrs(session);
+        ctx.client_ip = ctx.connection.map(|connection| {
+            self.client_ip
+                .resolve(session.req_header(), connection.client.ip())
+        });
+        ctx.grpc = GrpcCall::from_request(session.req_header());
+
         let req_host = get_host(session);
         let host_without_port = req_host.split(':').collect::<Vec<_>>()[0];
         host_without_port.clone_into(&mut ctx.host);
 
         ctx.host = host_without_port.to_string();
 
-        // If there's no host matching, returns a 404
-        let Some(route_container) = stores::get_route_by_key(host_without_port) else {
-            session.respond_error(404).await?;
+        // If there's no host matching (exact, wildcard or default) or none of its routes
+        // match the request (path, methods, headers and query), returns a 404
+        let Some(route_container) = stores::match_route(&ctx.host, session.req_header()) else {
+            if ctx.grpc.is_some() {
+                grpc::respond_error(session, GrpcStatus::from_http(404)).await?;
+            } else {
+                alt_svc::respond_error(session, 404).await?;
+            }
             return Ok(true);
         };
 
-        // Match request pattern based on the URI
-        let uri = get_uri(session);
+        // Redirects are served directly, without reaching the upstreams
+        let redirect = route_container.redirects.iter().find_map(|redirect| {
+            let location = redirect.location(session.req_header(), &ctx.host)?;
+            Some((redirect.status(), location))
+        });
+
+        if let Some((status, location)) = redirect {
+            let mut res_headers = ResponseHeader::build_no_case(status, Some(3))?;
+            res_headers.append_header(http::header::LOCATION, location)?;
+            res_headers.append_header(http::header::CONTENT_LENGTH, 0)?;
+
+            session
+                .write_response_header(Box::new(res_headers), false)
+                .await?;
+            session
+                .write_response_body(Some(bytes::Bytes::from_static(b"")), true)
+                .await?;
+            return Ok(true);
+        }
 
-        match &route_container.path_matcher.pattern {
-            Some(pattern) if pattern.find(uri.path()).is_none() => {
-                session.respond_error(404).await?;
+        // gRPC-Web calls are sent as gRPC to the upstreams, with their own CORS preflight requests
+        if let Some(grpc_web) = route_container.grpc_web.as_ref() {
+            if grpc_web.preflight(session).await? {
                 return Ok(true);
             }
-            _ => {}
+
+            ctx.grpc_web = grpc_web.start(session.req_header());
+            if ctx.grpc_web.is_some() {
+                ctx.grpc = Some(GrpcCall::with_deadline(session.req_header()));
+            }
         }
 
         // Middleware phase: request_filterx
@@ -122,6 +263,13 @@ impl ProxyHttp for Router {
             return Ok(true);
         }
 
+        // Static routes serve files from a directory instead of the upstreams
+        if let Some(static_files) = route_container.static_files.as_ref() {
+            static_files.serve(session, &route_container).await?;
+            ctx.route_container = route_container;
+            return Ok(true);
+        }
+
         if route_container.cache.is_some() {
             let cache = route_container.cache.as_ref().unwrap();
             if cache.enabled.unwrap_or(false) {
@@ -138,11 +286,37 @@ impl ProxyHttp for Router {
             }
         }
 
+        // Sampled requests are copied to the shadow upstream of the route
+        ctx.mirror = route_container
+            .mirror
+            .as_ref()
+            .and_then(|mirror| mirror.start(session.req_header()));
+
         ctx.route_container = route_container.clone();
 
         Ok(false)
     }
 
+    /// Decodes the body of gRPC-Web calls and collects the request body
+    /// for the copy sent to the shadow upstream (if any)
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        if let Some(grpc_web) = ctx.grpc_web.as_mut() {
+            grpc_web.request_body(body, end_of_stream)?;
+        }
+
+        if let (Some(mirror), Some(body)) = (ctx.mirror.as_mut(), body.as_ref()) {
+            mirror.push_body(body);
+        }
+
+        Ok(())
+    }
+
     /// Define where the proxy should send the request to.
     ///
     /// The returned [HttpPeer] contains the information regarding
@@ -159,34 +333,109 @@ impl ProxyHttp for Router {
             session.cache.set_max_file_size_bytes(100 * 1024 * 1024);
         }
 
-        let Some(healthy_upstream) = route_container.load_balancer.select(b"", 32) else {
-            return Err(pingora::Error::new(HTTPStatus(503)));
-        };
+        let client_ip = ctx.client_ip;
+        // Retries wait for the configured backoff, doubled on each attempt
+        if let Some(retry) = route_container.retry.as_ref() {
+            if ctx.attempts > 0 && retry.backoff_ms > 0 {
+                let exponent = u32::try_from(ctx.attempts - 1).unwrap_or(u32::MAX);
+                let backoff = retry
+                    .backoff_ms
+                    .saturating_mul(2u64.saturating_pow(exponent));
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+            }
+        }
 
-        let (healthy_ip, healthy_port) = if let Some(scr) = healthy_upstream.addr.as_inet() {
-            (scr.ip().to_string(), scr.port())
-        } else {
+        // Retries replay the request body through `request_body_filter`
+        if ctx.attempts > 0 {
+            if let Some(mirror) = ctx.mirror.as_mut() {
+                mirror.restart_body();
+            }
+            if let Some(grpc_web) = ctx.grpc_web.as_mut() {
+                grpc_web.restart_request_body();
+            }
+        }
+
+        // Retries stay in the traffic split group picked by the first attempt
+        if ctx.attempts == 0 {
+            ctx.upstream_group = route_container
+                .traffic_split
+                .as_ref()
+                .and_then(|split| split.select(session.req_header(), client_ip));
+        }
+
+        let load_balancer = route_container.group_load_balancer(ctx.upstream_group);
+        let key = load_balancer.request_key(session.req_header(), client_ip);
+
+        // Sticky sessions go back to the upstream of their cookie while it is available
+        let sticky = route_container.sticky.as_ref();
+        let sticky_upstream = sticky.and_then(|sticky| sticky.upstream(session.req_header()));
+        let selected = sticky_upstream
+            .as_ref()
+            .and_then(|addr| load_balancer.select_backend(addr, &ctx.tried_upstreams))
+            .or_else(|| load_balancer.select(&key, &ctx.tried_upstreams));
+
+        let Some(healthy_upstream) = selected else {
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
-        let Some(upstream) = route_container.upstreams.iter().find(|u| {
-            format!("{}:{}", u.ip, u.port)
-                .to_socket_addrs()
-                .unwrap()
-                .any(|s| s.ip().to_string() == healthy_ip && s.port() == healthy_port)
-        }) else {
+        // Clients without a cookie (or whose upstream is unavailable) stick to the new one
+        ctx.sticky_cookie = sticky
+            .filter(|_| sticky_upstream.as_ref() != Some(&healthy_upstream.addr))
+            .and_then(|sticky| sticky.set_cookie(&healthy_upstream.addr));
+        ctx.upstream_connection = load_balancer.track(&healthy_upstream);
+        ctx.upstream_addr = Some(healthy_upstream.addr.clone());
+        ctx.upstream_status = None;
+        ctx.attempts += 1;
+        ctx.tried_upstreams.push(healthy_upstream.addr.clone());
+
+        let upstreams = route_container.group_upstreams(ctx.upstream_group);
+        let Some(upstream) = upstreams
+            .iter()
+            .find(|u| is_upstream_addr(u, &healthy_upstream.addr))
+        else {
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
         ctx.upstream = upstream.clone();
 
+        let upstream_tls = route_container.upstream_tls.get(&upstream.address());
+        let tls = upstream_tls
+            .and_then(|tls| tls.enabled())
+            .unwrap_or_else(|| default_upstream_tls(upstream.protocol, upstream.port));
+        let sni = upstream_tls
+            .and_then(|tls| tls.sni())
+            .or(upstream.sni.as_deref())
+            .unwrap_or_default();
+
         // https://github.com/cloudflare/pingora/blob/main/docs/user_guide/peer.md?plain=1#L17
-        let mut peer = HttpPeer::new(
-            healthy_upstream,
-            healthy_port == 443,
-            upstream.sni.clone().unwrap_or(String::new()),
+        let mut peer = match upstream.unix_socket() {
+            Some(path) => HttpPeer::new_uds(&path.to_string_lossy(), tls, sni.to_string())?,
+            None => HttpPeer::new(healthy_upstream, tls, sni.to_string()),
+        };
+        peer.options = peer_opts(
+            route_container.peer_options.as_ref(),
+            upstream.peer_options.as_ref(),
         );
-        peer.options = default_peer_opts();
+        if let Some(upstream_tls) = upstream_tls {
+            upstream_tls.apply(&mut peer);
+        }
+        // gRPC-Web calls are translated to gRPC, which needs HTTP/2
+        let protocol = upstream
+            .protocol
+            .or(ctx.grpc_web.as_ref().map(|_| UpstreamProtocol::Grpc));
+        if let Some(protocol) = protocol {
+            peer.options.alpn = protocol_alpn(protocol);
+        }
+        if let Some(grpc) = ctx.grpc.as_ref() {
+            grpc.apply_deadline(&mut peer)?;
+        }
+
+        // The upstream gets the address of the client in a PROXY protocol header
+        if let (Some(version), Some(connection)) = (upstream.proxy_protocol, ctx.connection) {
+            if let Some(addr) = peer._address.as_inet() {
+                peer.proxy = Some(upstream_proxy(version, &connection, addr));
+            }
+        }
         Ok(Box::new(peer))
     }
 
@@ -212,6 +461,27 @@ impl ProxyHttp for Router {
             upstream_response.remove_header(name);
         }
 
+        // Rewrite redirects to the upstream paths back to the paths of the route
+        if let Some(rewrite) = route_container.rewrite.as_ref() {
+            let location = upstream_response
+                .headers
+                .get(http::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| rewrite.rewrite_location(location, &ctx.host));
+
+            if let Some(location) = location {
+                upstream_response.insert_header(http::header::LOCATION, location)?;
+            }
+        }
+
+        if let Some(cookie) = ctx.sticky_cookie.take() {
+            upstream_response.append_header(http::header::SET_COOKIE, cookie)?;
+        }
+
+        if let Some(grpc_web) = ctx.grpc_web.as_mut() {
+            grpc_web.response(upstream_response)?;
+        }
+
         let cache_state = ctx.extensions.get("cache_state").cloned();
         if session.cache.enabled() && cache_state.is_some() {
             let cache_state = cache_state.unwrap();
@@ -234,6 +504,34 @@ impl ProxyHttp for Router {
         Ok(())
     }
 
+    /// Encodes the body of the gRPC responses to gRPC-Web calls
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<Duration>> {
+        if let Some(grpc_web) = ctx.grpc_web.as_mut() {
+            grpc_web.response_body(body, end_of_stream);
+        }
+
+        Ok(None)
+    }
+
+    /// gRPC-Web calls get the trailers of the upstream at the end of the response body
+    async fn response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<bytes::Bytes>> {
+        Ok(ctx
+            .grpc_web
+            .as_mut()
+            .and_then(|grpc_web| grpc_web.response_trailers(upstream_trailers)))
+    }
+
     /// Modify the request before it is sent to the upstream
     ///
     /// Unlike [Self::request_filter()], this filter allows to change the request headers to send
@@ -249,6 +547,44 @@ impl ProxyHttp for Router {
 
         let upstream = &ctx.upstream;
 
+        if let Some(grpc_web) = ctx.grpc_web.as_ref() {
+            grpc_web.upstream_request(upstream_request)?;
+        }
+
+        // The upstream gets the time left before the deadline of the client
+        if let Some(remaining) = ctx.grpc.and_then(|grpc| grpc.remaining()) {
+            upstream_request.insert_header(GRPC_TIMEOUT, grpc::encode_timeout(remaining))?;
+        }
+
+        // Rewrite the request path (the query string is kept as is)
+        if let Some(rewrite) = ctx.route_container.rewrite.as_ref() {
+            let pattern = ctx.route_container.path_matcher.pattern.as_ref();
+            if let Some(uri) = rewrite.rewrite_uri(&upstream_request.uri, pattern) {
+                upstream_request.set_uri(uri);
+            }
+        }
+
+        // Tell the upstream about the client and the original request
+        if let Some(forwarded_headers) = ctx.route_container.forwarded_headers.as_ref() {
+            let client_ip = ctx.connection.map(|connection| connection.client.ip());
+            let proto = if self.is_tls(session) {
+                "https"
+            } else {
+                "http"
+            };
+
+            // HTTP/2 requests carry the host in the URI only
+            let req = session.req_header();
+            let host = req
+                .headers
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| req.uri.authority().map(http::uri::Authority::as_str))
+                .unwrap_or(&ctx.host);
+
+            forwarded_headers.apply(upstream_request, client_ip, proto, host);
+        }
+
         // TODO: refactor
         if let Some(headers) = upstream.headers.as_ref() {
             if let Some(add) = headers.add.as_ref() {
@@ -282,12 +618,136 @@ impl ProxyHttp for Router {
     ) -> Result<(), Box<pingora::Error>> {
         // If there's no host matching, returns a 404
         // let route_container = process_route(ctx);
+        let status = upstream_response.status.as_u16();
+        ctx.upstream_status = Some(status);
+
+        // Fail the attempt so that the request is retried on another upstream
+        let is_retryable_status = ctx.route_container.retry.as_ref().and_then(|retry| {
+            retry
+                .retry_on
+                .iter()
+                .find(|condition| condition.status() == Some(status))
+                .copied()
+        });
+        if let Some(condition) = is_retryable_status {
+            if should_retry(session, ctx, condition) {
+                let mut e = pingora::Error::new_up(HTTPStatus(status));
+                e.set_retry(true);
+                return Err(e);
+            }
+        }
 
         execute_upstream_response_plugins(session, upstream_response, ctx);
 
         Ok(())
     }
 
+    /// This filter is called when there is an error while connecting to the upstream.
+    /// The failure is reported to the circuit breaker of the route and the
+    /// request is retried on another upstream if the retry policy allows it.
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        ctx.route_container
+            .group_load_balancer(ctx.upstream_group)
+            .report(peer.address(), true);
+
+        // Already reported, the logging phase should not report it again
+        ctx.upstream_addr = None;
+
+        // the request never reached the upstream, connection timeouts included
+        if should_retry(session, ctx, RetryCondition::ConnectError) {
+            e.set_retry(true);
+        }
+
+        e
+    }
+
+    /// This filter is called when there is an error after a connection is established
+    /// (or reused) to the upstream. Timeouts and retryable responses are retried on
+    /// another upstream if the retry policy allows it.
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+
+        if e.esource() == &ErrorSource::Upstream {
+            if let Some(upstream_addr) = ctx.upstream_addr.take() {
+                ctx.route_container
+                    .group_load_balancer(ctx.upstream_group)
+                    .report(&upstream_addr, true);
+            }
+        }
+
+        let condition = match e.etype() {
+            ErrorType::ReadTimedout | ErrorType::WriteTimedout => Some(RetryCondition::Timeout),
+            HTTPStatus(_) => return e,
+            _ => None,
+        };
+
+        if condition.is_some_and(|condition| should_retry(session, ctx, condition)) {
+            e.set_retry(true);
+        } else {
+            // only reused client connections where retry buffer is not truncated
+            e.retry
+                .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        }
+
+        e
+    }
+
+    /// This filter is called when the request fails before (or while) proxying it.
+    /// gRPC clients get a `grpc-status` instead of an HTML error page.
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // the connection is already dead
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        if code > 0 {
+            let responded = if let Some(grpc_web) = ctx.grpc_web.as_mut() {
+                grpc_web
+                    .respond_error(session, GrpcStatus::from_error(e))
+                    .await
+            } else if ctx.grpc.is_some() {
+                grpc::respond_error(session, GrpcStatus::from_error(e)).await
+            } else {
+                alt_svc::respond_error(session, code).await
+            };
+
+            if let Err(e) = responded {
+                tracing::error!("failed to send error response to downstream: {e}");
+            }
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     /// This filter is called when the entire response is sent to the downstream successfully or
     /// there is a fatal error that terminate the request.
     ///
@@ -296,9 +756,25 @@ impl ProxyHttp for Router {
     async fn logging(
         &self,
         session: &mut Session,
-        _: Option<&pingora::Error>,
+        e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        // Upstream errors (ex: timeouts) and 5xx responses count as failures
+        if let Some(upstream_addr) = ctx.upstream_addr.take() {
+            let failed = e.is_some_and(|e| e.esource() == &ErrorSource::Upstream)
+                || ctx.upstream_status.is_some_and(|status| status >= 500);
+
+            ctx.route_container
+                .group_load_balancer(ctx.upstream_group)
+                .report(&upstream_addr, failed);
+        }
+
+        // The copy is sent once the client got its response, so it never delays it
+        let mirrored = ctx
+            .mirror
+            .take()
+            .is_some_and(|mirror| mirror.send(&ctx.host));
+
         let duration_ms = ctx.timings.request_filter_start.elapsed().as_millis();
 
         let http_version = if session.is_http2() {
@@ -324,9 +800,10 @@ impl ProxyHttp for Router {
             .get("user-agent")
             .unwrap_or(&empty_header);
 
-        let client_ip = session
-            .client_addr()
-            .map(ToString::to_string)
+        let client_ip = ctx
+            .client_ip
+            .or_else(|| connection_addrs(session).map(|connection| connection.client.ip()))
+            .map(|ip| ip.to_string())
             .unwrap_or_default();
 
         let status_code = session
@@ -334,6 +811,13 @@ impl ProxyHttp for Router {
             .map(|v| v.status.as_u16())
             .unwrap_or_default();
 
+        let upstream_group = ctx
+            .route_container
+            .traffic_split
+            .as_ref()
+            .and_then(|split| split.groups.get(ctx.upstream_group?))
+            .map(|group| group.name.as_str());
+
         tracing::info!(
             method,
             path,
@@ -347,6 +831,8 @@ impl ProxyHttp for Router {
             http_version,
             reused_connection = ctx.extensions.get("reused").unwrap_or(&String::new()),
             peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
+            upstream_group,
+            mirrored,
             request_id = ctx.extensions.get("request_id_header"),
             access_log = true
         );
@@ -455,15 +941,11 @@ impl ProxyHttp for Router {
         ctx.extensions
             .insert(Cow::Borrowed("reused"), reused.to_string());
         ctx.extensions
-            .insert(Cow::Borrowed("peer"), peer.address().to_string());
+            .insert(Cow::Borrowed("peer"), format_upstream_addr(peer.address()));
         Ok(())
     }
 }
 
-fn get_uri(session: &mut Session) -> Uri {
-    session.req_header().uri.clone()
-}
-
 /// Retrieves the host from the request headers based on
 /// whether the request is HTTP/1.1 or HTTP/2
 fn get_host(session: &mut Session) -> &str {
@@ -0,0 +1,471 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use cookie::Cookie;
+use pingora::{
+    http::RequestHeader,
+    lb::{
+        discovery,
+        health_check::HealthCheck,
+        selection::{Consistent, Random, RoundRobin},
+        Backend, Backends, LoadBalancer,
+    },
+    protocols::l4::socket::SocketAddr,
+};
+
+use crate::config::{HashKeySource, LoadBalancingAlgorithm, RouteHashKey, RouteLoadBalancing};
+
+use super::circuit_breaker::CircuitBreaker;
+
+/// Bounds the search for a healthy backend
+const MAX_ITERATIONS: usize = 32;
+
+/// The pingora selection algorithm backing the route load balancer
+enum Selector {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    Consistent(LoadBalancer<Consistent>),
+}
+
+/// Load balancer of a route, picks an upstream with the configured algorithm.
+///
+/// Connection-aware algorithms (least connections and power of two choices)
+/// keep a counter of the active requests of each backend, see [`ConnectionGuard`].
+/// Backends ejected by the circuit breaker are skipped while any other is available.
+pub struct RouteLoadBalancer {
+    algorithm: LoadBalancingAlgorithm,
+    hash_key: RouteHashKey,
+    selector: Selector,
+    connections: HashMap<SocketAddr, Arc<AtomicUsize>>,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+/// Keeps a backend's active connection count incremented while alive
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RouteLoadBalancer {
+    pub fn from_backends(config: &RouteLoadBalancing, backends: BTreeSet<Backend>) -> Self {
+        let connections = match config.algorithm {
+            LoadBalancingAlgorithm::LeastConnections | LoadBalancingAlgorithm::PowerOfTwo => {
+                backends
+                    .iter()
+                    .map(|b| (b.addr.clone(), Arc::new(AtomicUsize::new(0))))
+                    .collect()
+            }
+            _ => HashMap::new(),
+        };
+
+        let backends = Backends::new(discovery::Static::new(backends));
+        let selector = match config.algorithm {
+            LoadBalancingAlgorithm::RoundRobin | LoadBalancingAlgorithm::LeastConnections => {
+                Selector::RoundRobin(LoadBalancer::from_backends(backends))
+            }
+            LoadBalancingAlgorithm::Random | LoadBalancingAlgorithm::PowerOfTwo => {
+                Selector::Random(LoadBalancer::from_backends(backends))
+            }
+            LoadBalancingAlgorithm::ConsistentHash => {
+                Selector::Consistent(LoadBalancer::from_backends(backends))
+            }
+        };
+
+        RouteLoadBalancer {
+            algorithm: config.algorithm,
+            hash_key: config.hash_key.clone().unwrap_or_default(),
+            selector,
+            connections,
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Runs the service discovery and rebuilds the selection algorithm
+    pub async fn update(&self) -> pingora::Result<()> {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.update().await,
+            Selector::Random(lb) => lb.update().await,
+            Selector::Consistent(lb) => lb.update().await,
+        }
+    }
+
+    pub fn backends(&self) -> &Backends {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.backends(),
+            Selector::Random(lb) => lb.backends(),
+            Selector::Consistent(lb) => lb.backends(),
+        }
+    }
+
+    pub fn set_health_check(&mut self, hc: Box<dyn HealthCheck + Send + Sync + 'static>) {
+        match &mut self.selector {
+            Selector::RoundRobin(lb) => lb.set_health_check(hc),
+            Selector::Random(lb) => lb.set_health_check(hc),
+            Selector::Consistent(lb) => lb.set_health_check(hc),
+        }
+    }
+
+    /// Builds the key used by consistent hashing from the incoming request.
+    /// Other algorithms ignore the key, so an empty one is returned.
+    pub fn request_key(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Vec<u8> {
+        if self.algorithm != LoadBalancingAlgorithm::ConsistentHash {
+            return Vec::new();
+        }
+
+        hash_key_value(&self.hash_key, req, client_ip).unwrap_or_default()
+    }
+
+    /// Builds the key used by consistent hashing from the IP of the client
+    /// of a TCP or UDP connection, which carries no request to read it from
+    pub fn connection_key(&self, client_ip: IpAddr) -> Vec<u8> {
+        if self.algorithm != LoadBalancingAlgorithm::ConsistentHash {
+            return Vec::new();
+        }
+
+        client_ip.to_string().into_bytes()
+    }
+
+    /// Selects a healthy backend using the configured algorithm, skipping the
+    /// `excluded` ones (ex: upstreams that already failed the request).
+    /// If no other backend is available, the circuit breaker and then the
+    /// exclusions are ignored so that requests are still proxied somewhere.
+    pub fn select(&self, key: &[u8], excluded: &[SocketAddr]) -> Option<Backend> {
+        let selected = self
+            .select_available(key, excluded, true)
+            .or_else(|| self.select_available(key, excluded, false))
+            .or_else(|| self.select_available(key, &[], false))?;
+
+        if let Some(circuit_breaker) = self.circuit_breaker.as_ref() {
+            circuit_breaker.on_selected(&selected.addr);
+        }
+
+        Some(selected)
+    }
+
+    /// Selects the backend with the given address if it is healthy and available
+    /// (not `excluded` nor ejected by the circuit breaker), ex: the upstream of a sticky session
+    pub fn select_backend(&self, addr: &SocketAddr, excluded: &[SocketAddr]) -> Option<Backend> {
+        let backends = self.backends();
+        let backend = backends
+            .get_backend()
+            .iter()
+            .find(|backend| &backend.addr == addr)?
+            .clone();
+
+        let is_available = backends.ready(&backend)
+            && !excluded.contains(addr)
+            && self
+                .circuit_breaker
+                .as_ref()
+                .is_none_or(|cb| cb.is_available(addr));
+        if !is_available {
+            return None;
+        }
+
+        if let Some(circuit_breaker) = self.circuit_breaker.as_ref() {
+            circuit_breaker.on_selected(addr);
+        }
+
+        Some(backend)
+    }
+
+    /// Records the outcome of a request sent to the given upstream,
+    /// used by the circuit breaker to eject failing upstreams.
+    pub fn report(&self, addr: &SocketAddr, failed: bool) {
+        if let Some(circuit_breaker) = self.circuit_breaker.as_ref() {
+            circuit_breaker.report(addr, failed);
+        }
+    }
+
+    /// Increments the active connections of the given backend until the
+    /// returned guard is dropped. Returns `None` if the algorithm does not
+    /// take connections into account.
+    pub fn track(&self, backend: &Backend) -> Option<ConnectionGuard> {
+        let counter = self.connections.get(&backend.addr)?;
+        counter.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard(counter.clone()))
+    }
+
+    fn select_available(
+        &self,
+        key: &[u8],
+        excluded: &[SocketAddr],
+        check_circuit: bool,
+    ) -> Option<Backend> {
+        let is_available = |backend: &Backend, healthy: bool| {
+            healthy
+                && !excluded.contains(&backend.addr)
+                && (!check_circuit
+                    || self
+                        .circuit_breaker
+                        .as_ref()
+                        .is_none_or(|cb| cb.is_available(&backend.addr)))
+        };
+
+        match self.algorithm {
+            LoadBalancingAlgorithm::LeastConnections => self.select_least_connections(is_available),
+            LoadBalancingAlgorithm::PowerOfTwo => self.select_power_of_two(is_available),
+            _ => self.select_with(key, is_available),
+        }
+    }
+
+    fn select_with<F>(&self, key: &[u8], accept: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.select_with(key, MAX_ITERATIONS, accept),
+            Selector::Random(lb) => lb.select_with(key, MAX_ITERATIONS, accept),
+            Selector::Consistent(lb) => lb.select_with(key, MAX_ITERATIONS, accept),
+        }
+    }
+
+    fn active_connections(&self, backend: &Backend) -> usize {
+        self.connections
+            .get(&backend.addr)
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// Whether `a` has less load than `b`, relative to their weights
+    fn is_less_loaded(&self, a: &Backend, b: &Backend) -> bool {
+        self.active_connections(a) * b.weight < self.active_connections(b) * a.weight
+    }
+
+    /// Starts from the round robin pick (so idle backends take turns)
+    /// and moves to any healthy backend with less load.
+    fn select_least_connections<F>(&self, accept: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        let mut selected = self.select_with(b"", &accept)?;
+
+        let backends = self.backends();
+        for backend in backends.get_backend().iter() {
+            if accept(backend, backends.ready(backend)) && self.is_less_loaded(backend, &selected) {
+                selected = backend.clone();
+            }
+        }
+
+        Some(selected)
+    }
+
+    /// Picks two random healthy backends and keeps the least loaded one
+    fn select_power_of_two<F>(&self, accept: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        let first = self.select_with(b"", &accept)?;
+        let Some(second) = self.select_with(b"", |b, healthy| accept(b, healthy) && b != &first)
+        else {
+            return Some(first);
+        };
+
+        if self.is_less_loaded(&second, &first) {
+            return Some(second);
+        }
+
+        Some(first)
+    }
+}
+
+/// Reads the value of the hash key from the request,
+/// `None` if the request does not have it (ex: a missing cookie)
+pub fn hash_key_value(
+    hash_key: &RouteHashKey,
+    req: &RequestHeader,
+    client_ip: Option<IpAddr>,
+) -> Option<Vec<u8>> {
+    let name = hash_key.name.as_deref().unwrap_or_default();
+    match hash_key.source {
+        HashKeySource::ClientIp => client_ip.map(|ip| ip.to_string().into_bytes()),
+        HashKeySource::Path => Some(req.uri.path().as_bytes().to_vec()),
+        HashKeySource::Header => req.headers.get(name).map(|v| v.as_bytes().to_vec()),
+        HashKeySource::Cookie => req
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(Cookie::split_parse)
+            .flatten()
+            .find(|c| c.name() == name)
+            .map(|c| c.value().as_bytes().to_vec()),
+    }
+}
+
+impl From<LoadBalancer<RoundRobin>> for RouteLoadBalancer {
+    fn from(load_balancer: LoadBalancer<RoundRobin>) -> Self {
+        RouteLoadBalancer {
+            algorithm: LoadBalancingAlgorithm::RoundRobin,
+            hash_key: RouteHashKey::default(),
+            selector: Selector::RoundRobin(load_balancer),
+            connections: HashMap::new(),
+            circuit_breaker: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::config::RouteCircuitBreaker;
+
+    fn backends(addrs: &[&str]) -> BTreeSet<Backend> {
+        addrs.iter().map(|a| Backend::new(a).unwrap()).collect()
+    }
+
+    async fn load_balancer(config: RouteLoadBalancing, addrs: &[&str]) -> RouteLoadBalancer {
+        let lb = RouteLoadBalancer::from_backends(&config, backends(addrs));
+        lb.update().await.unwrap();
+        lb
+    }
+
+    #[tokio::test]
+    async fn test_least_connections_picks_least_loaded_backend() {
+        let config = RouteLoadBalancing {
+            algorithm: LoadBalancingAlgorithm::LeastConnections,
+            hash_key: None,
+        };
+        let lb = load_balancer(config, &["127.0.0.1:80", "127.0.0.2:80"]).await;
+
+        let first = lb.select(b"", &[]).unwrap();
+        let guard = lb.track(&first);
+        assert!(guard.is_some());
+
+        // The busy backend is skipped until its connection is released
+        for _ in 0..4 {
+            assert_ne!(lb.select(b"", &[]).unwrap(), first);
+        }
+
+        drop(guard);
+        assert_eq!(lb.active_connections(&first), 0);
+    }
+
+    #[tokio::test]
+    async fn test_power_of_two_picks_least_loaded_backend() {
+        let config = RouteLoadBalancing {
+            algorithm: LoadBalancingAlgorithm::PowerOfTwo,
+            hash_key: None,
+        };
+        let lb = load_balancer(config, &["127.0.0.1:80", "127.0.0.2:80"]).await;
+
+        let busy = Backend::new("127.0.0.1:80").unwrap();
+        let _guard = lb.track(&busy);
+
+        for _ in 0..10 {
+            assert_ne!(lb.select(b"", &[]).unwrap(), busy);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consistent_hash_is_sticky() {
+        let config = RouteLoadBalancing {
+            algorithm: LoadBalancingAlgorithm::ConsistentHash,
+            hash_key: None,
+        };
+        let lb = load_balancer(config, &["127.0.0.1:80", "127.0.0.2:80", "127.0.0.3:80"]).await;
+
+        let selected = lb.select(b"10.0.0.1", &[]).unwrap();
+        for _ in 0..10 {
+            assert_eq!(lb.select(b"10.0.0.1", &[]).unwrap(), selected);
+        }
+
+        // Non connection-aware algorithms do not track connections
+        assert!(lb.track(&selected).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_is_skipped() {
+        let config = RouteLoadBalancing::default();
+        let addrs = ["127.0.0.1:80", "127.0.0.2:80"];
+        let circuit_breaker = CircuitBreaker::new(
+            "example.com",
+            &RouteCircuitBreaker {
+                consecutive_failures: 1,
+                cooldown_secs: 30,
+            },
+            &backends(&addrs),
+        );
+        let lb = RouteLoadBalancer::from_backends(&config, backends(&addrs))
+            .with_circuit_breaker(circuit_breaker);
+        lb.update().await.unwrap();
+
+        let failing = Backend::new("127.0.0.1:80").unwrap();
+        lb.report(&failing.addr, true);
+        for _ in 0..4 {
+            assert_ne!(lb.select(b"", &[]).unwrap(), failing);
+        }
+
+        // With every circuit open, requests still go through
+        let other = Backend::new("127.0.0.2:80").unwrap();
+        lb.report(&other.addr, true);
+        assert!(lb.select(b"", &[]).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_excluded_backends_are_skipped() {
+        let config = RouteLoadBalancing {
+            algorithm: LoadBalancingAlgorithm::ConsistentHash,
+            hash_key: None,
+        };
+        let lb = load_balancer(config, &["127.0.0.1:80", "127.0.0.2:80"]).await;
+
+        let first = lb.select(b"10.0.0.1", &[]).unwrap();
+        let excluded = [first.addr.clone()];
+        for _ in 0..4 {
+            assert_ne!(lb.select(b"10.0.0.1", &excluded).unwrap(), first);
+        }
+
+        // Every backend was tried, the exclusions are ignored
+        let all = [
+            excluded[0].clone(),
+            Backend::new("127.0.0.2:80").unwrap().addr,
+        ];
+        assert!(lb.select(b"10.0.0.1", &all).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_request_key_sources() {
+        let mut req = RequestHeader::build("GET", b"/api/users?id=1", None).unwrap();
+        req.insert_header("x-user-id", "42").unwrap();
+        req.insert_header("cookie", "theme=dark; session=abc")
+            .unwrap();
+        let client_ip = Some("10.0.0.1".parse().unwrap());
+
+        let cases = [
+            (HashKeySource::ClientIp, None, "10.0.0.1"),
+            (HashKeySource::Path, None, "/api/users"),
+            (HashKeySource::Header, Some("x-user-id"), "42"),
+            (HashKeySource::Cookie, Some("session"), "abc"),
+            (HashKeySource::Cookie, Some("missing"), ""),
+        ];
+
+        for (source, name, expected) in cases {
+            let config = RouteLoadBalancing {
+                algorithm: LoadBalancingAlgorithm::ConsistentHash,
+                hash_key: Some(RouteHashKey {
+                    source,
+                    name: name.map(Cow::Borrowed),
+                }),
+            };
+            let lb = load_balancer(config, &["127.0.0.1:80"]).await;
+
+            assert_eq!(lb.request_key(&req, client_ip), expected.as_bytes());
+        }
+    }
+}
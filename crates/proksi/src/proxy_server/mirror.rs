@@ -0,0 +1,241 @@
+use std::{
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use http::{header, uri::PathAndQuery, HeaderName, HeaderValue};
+use once_cell::sync::Lazy;
+use pingora::http::RequestHeader;
+use tokio::sync::Semaphore;
+
+use crate::config::RouteMirror;
+use crate::services::metrics::MIRROR_REQUESTS;
+use crate::stores::routes::RouteStorePathMatcher;
+
+use super::traffic_split::next_bucket;
+
+/// Header added to the mirrored requests so that the shadow upstream can tell them apart
+pub const MIRROR_HEADER: &str = "x-proksi-mirror";
+
+/// Mirrored requests in flight at once, further copies are dropped
+/// so that a slow shadow upstream never piles up work on the proxy
+const MAX_IN_FLIGHT: usize = 512;
+
+/// Headers that only apply to the connection of the client
+const HOP_BY_HOP_HEADERS: [HeaderName; 5] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+static IN_FLIGHT: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(MAX_IN_FLIGHT)));
+
+/// Copies a sample of the requests of a route to a shadow upstream
+#[derive(Debug)]
+pub struct Mirror {
+    target: String,
+    percent: u8,
+    matcher: RouteStorePathMatcher,
+    max_body_bytes: usize,
+    timeout: Duration,
+    requests: AtomicUsize,
+}
+
+impl Mirror {
+    pub fn from_config(config: &RouteMirror) -> Result<Self, anyhow::Error> {
+        let mut matcher = RouteStorePathMatcher::new();
+        if let Some(match_with) = config.match_with.as_ref() {
+            matcher.with_matcher(match_with)?;
+        }
+
+        Ok(Mirror {
+            target: config.target.trim_end_matches('/').to_string(),
+            percent: config.percent,
+            matcher,
+            max_body_bytes: config.max_body_bytes,
+            timeout: Duration::from_millis(config.timeout_ms),
+            requests: AtomicUsize::new(0),
+        })
+    }
+
+    /// Starts the copy of the request if it matches the mirror and is part of the sample,
+    /// its body is added with [`MirrorRequest::push_body`]
+    pub fn start(self: &Arc<Self>, req: &RequestHeader) -> Option<MirrorRequest> {
+        if self.percent == 0 || !self.matcher.matches(req) {
+            return None;
+        }
+
+        if next_bucket(&self.requests) >= usize::from(self.percent) {
+            return None;
+        }
+
+        Some(MirrorRequest {
+            mirror: self.clone(),
+            header: req.clone(),
+            body: Some(BytesMut::new()),
+        })
+    }
+
+    fn url(&self, req: &RequestHeader) -> String {
+        let path = req.uri.path_and_query().map_or("/", PathAndQuery::as_str);
+        format!("{}{path}", self.target)
+    }
+}
+
+/// A copy of a request, sent to the shadow upstream once the request is done
+pub struct MirrorRequest {
+    mirror: Arc<Mirror>,
+    header: RequestHeader,
+    /// `None` once the body is larger than the mirror accepts
+    body: Option<BytesMut>,
+}
+
+impl MirrorRequest {
+    pub fn push_body(&mut self, chunk: &[u8]) {
+        let Some(body) = self.body.as_mut() else {
+            return;
+        };
+
+        if body.len() + chunk.len() > self.mirror.max_body_bytes {
+            self.body = None;
+            return;
+        }
+
+        body.extend_from_slice(chunk);
+    }
+
+    /// Drops the body collected so far, retries replay it from the start
+    pub fn restart_body(&mut self) {
+        self.body = Some(BytesMut::new());
+    }
+
+    /// Sends the copy in the background, its response is discarded.
+    /// Returns `false` if the copy was dropped (body too large or too many copies in flight).
+    pub fn send(self, host: &str) -> bool {
+        let Some(body) = self.body else {
+            tracing::debug!("request body too large to be mirrored for host {host}");
+            MIRROR_REQUESTS.with_label_values(&[host, "dropped"]).inc();
+            return false;
+        };
+
+        let Ok(permit) = IN_FLIGHT.clone().try_acquire_owned() else {
+            tracing::debug!("too many mirrored requests in flight, dropping one for host {host}");
+            MIRROR_REQUESTS.with_label_values(&[host, "dropped"]).inc();
+            return false;
+        };
+
+        let mut headers = self.header.headers.clone();
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+
+        // HTTP/2 requests carry the host in the URI only
+        if !headers.contains_key(header::HOST) {
+            if let Ok(host) = HeaderValue::from_str(host) {
+                headers.insert(header::HOST, host);
+            }
+        }
+        headers.insert(MIRROR_HEADER, HeaderValue::from_static("true"));
+
+        let request = HTTP_CLIENT
+            .request(self.header.method.clone(), self.mirror.url(&self.header))
+            .headers(headers)
+            .body(body.freeze())
+            .timeout(self.mirror.timeout);
+
+        let host = host.to_string();
+        tokio::spawn(async move {
+            let result = match request.send().await {
+                Ok(_) => "sent",
+                Err(err) => {
+                    tracing::debug!("mirrored request for host {host} failed: {err}");
+                    "failed"
+                }
+            };
+
+            MIRROR_REQUESTS
+                .with_label_values(&[host.as_str(), result])
+                .inc();
+            drop(permit);
+        });
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::config::RouteMatcher;
+
+    fn mirror(config: RouteMirror) -> Arc<Mirror> {
+        Arc::new(Mirror::from_config(&config).unwrap())
+    }
+
+    fn request(method: &str, path: &str) -> RequestHeader {
+        RequestHeader::build(method, path.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_mirror_samples_matching_requests() {
+        let mirror = mirror(RouteMirror {
+            target: Cow::Borrowed("http://10.0.0.5:3000/"),
+            percent: 25,
+            match_with: Some(RouteMatcher {
+                methods: Some(vec![Cow::Borrowed("POST")]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let mirrored = (0..100)
+            .filter(|_| mirror.start(&request("POST", "/orders")).is_some())
+            .count();
+        assert_eq!(mirrored, 25);
+
+        assert!((0..100).all(|_| mirror.start(&request("GET", "/orders")).is_none()));
+        assert_eq!(
+            mirror.url(&request("POST", "/orders?id=1")),
+            "http://10.0.0.5:3000/orders?id=1"
+        );
+    }
+
+    #[test]
+    fn test_mirror_drops_large_bodies() {
+        let mirror = mirror(RouteMirror {
+            target: Cow::Borrowed("http://10.0.0.5:3000"),
+            max_body_bytes: 8,
+            ..Default::default()
+        });
+
+        let mut copy = mirror.start(&request("POST", "/upload")).unwrap();
+        copy.push_body(b"12345");
+        assert_eq!(copy.body.as_deref(), Some(&b"12345"[..]));
+
+        copy.push_body(b"6789");
+        assert!(copy.body.is_none());
+        assert!(!copy.send("example.com"));
+    }
+
+    #[test]
+    fn test_mirror_keeps_a_single_body_on_retries() {
+        let mirror = mirror(RouteMirror {
+            target: Cow::Borrowed("http://10.0.0.5:3000"),
+            ..Default::default()
+        });
+
+        let mut copy = mirror.start(&request("POST", "/orders")).unwrap();
+        copy.push_body(b"{\"id\":1}");
+
+        // the retry replays the body sent to the failed upstream
+        copy.restart_body();
+        copy.push_body(b"{\"id\":1}");
+        assert_eq!(copy.body.as_deref(), Some(&b"{\"id\":1}"[..]));
+    }
+}
@@ -1,14 +1,32 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{collections::BTreeMap, net::ToSocketAddrs, time::Duration};
 
 use pingora::{
-    protocols::{TcpKeepalive, ALPN},
+    protocols::{l4::socket::SocketAddr, TcpKeepalive, ALPN},
     upstreams::peer::PeerOptions,
 };
 
+use crate::config::{RoutePeerOptions, RouteUpstream, UpstreamAlpn, UpstreamProtocol};
+
+pub mod alt_svc;
 pub mod cert_store;
+pub mod circuit_breaker;
+pub mod client_ip;
+pub mod forwarded;
+pub mod grpc;
+pub mod grpc_web;
 pub mod http_proxy;
 pub mod https_proxy;
+pub mod load_balancer;
 pub mod middleware;
+pub mod mirror;
+pub mod proxy_protocol;
+pub mod redirect;
+pub mod rewrite;
+pub mod static_files;
+pub mod sticky;
+pub mod tls_passthrough;
+pub mod traffic_split;
+pub mod upstream_tls;
 
 /// Default peer options to be used on every upstream connection
 pub fn default_peer_opts() -> PeerOptions {
@@ -42,3 +60,157 @@ pub fn default_peer_opts() -> PeerOptions {
     po.custom_l4 = None;
     po
 }
+
+/// Peer options of an upstream: the defaults, overridden by the options of the
+/// route and then by the options of the upstream itself
+pub fn peer_opts(
+    route: Option<&RoutePeerOptions>,
+    upstream: Option<&RoutePeerOptions>,
+) -> PeerOptions {
+    let mut po = default_peer_opts();
+
+    for options in [route, upstream].into_iter().flatten() {
+        apply_peer_options(&mut po, options);
+    }
+
+    po
+}
+
+/// Whether an upstream is reached over TLS when its `tls.enabled` is unset:
+/// always for h2, never for h2c and only on port 443 for the other upstreams
+pub fn default_upstream_tls(protocol: Option<UpstreamProtocol>, port: u16) -> bool {
+    match protocol {
+        Some(UpstreamProtocol::H2) => true,
+        Some(UpstreamProtocol::H2c) => false,
+        _ => port == 443,
+    }
+}
+
+/// The HTTP versions offered to an upstream speaking `protocol`
+/// (gRPC needs HTTP/2 for its trailers)
+pub fn protocol_alpn(protocol: UpstreamProtocol) -> ALPN {
+    match protocol {
+        UpstreamProtocol::Http1 => ALPN::H1,
+        UpstreamProtocol::H2 | UpstreamProtocol::H2c | UpstreamProtocol::Grpc => ALPN::H2,
+    }
+}
+
+/// Whether the address selected by a load balancer is the one of the upstream
+pub fn is_upstream_addr(upstream: &RouteUpstream, addr: &SocketAddr) -> bool {
+    match (upstream.unix_socket(), addr) {
+        (Some(path), SocketAddr::Unix(addr)) => addr.as_pathname() == Some(path),
+        (None, SocketAddr::Inet(addr)) => upstream
+            .address()
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.any(|upstream_addr| upstream_addr == *addr)),
+        _ => false,
+    }
+}
+
+/// The address of an upstream in the access logs and sticky cookies
+/// (ex: `10.0.0.1:3000`, `unix:/run/app.sock`), which parse back into a [`SocketAddr`]
+pub fn format_upstream_addr(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::Inet(addr) => addr.to_string(),
+        SocketAddr::Unix(_) => format!("unix:{addr}"),
+    }
+}
+
+fn apply_peer_options(po: &mut PeerOptions, options: &RoutePeerOptions) {
+    if let Some(secs) = options.connection_timeout_secs {
+        po.connection_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = options.total_connection_timeout_secs {
+        po.total_connection_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = options.read_timeout_secs {
+        po.read_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = options.write_timeout_secs {
+        po.write_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = options.idle_timeout_secs {
+        po.idle_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(max_h2_streams) = options.max_h2_streams {
+        po.max_h2_streams = max_h2_streams;
+    }
+    if let Some(verify_cert) = options.verify_cert {
+        po.verify_cert = verify_cert;
+    }
+    if let Some(verify_hostname) = options.verify_hostname {
+        po.verify_hostname = verify_hostname;
+    }
+    if let Some(alpn) = options.alpn {
+        po.alpn = match alpn {
+            UpstreamAlpn::H1 => ALPN::H1,
+            UpstreamAlpn::H2 => ALPN::H2,
+            UpstreamAlpn::H2h1 => ALPN::H2H1,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_opts_upstream_overrides_route() {
+        let route = RoutePeerOptions {
+            read_timeout_secs: Some(1800),
+            connection_timeout_secs: Some(5),
+            alpn: Some(UpstreamAlpn::H1),
+            ..Default::default()
+        };
+        let upstream = RoutePeerOptions {
+            connection_timeout_secs: Some(2),
+            ..Default::default()
+        };
+
+        let po = peer_opts(Some(&route), Some(&upstream));
+        assert_eq!(po.read_timeout, Some(Duration::from_secs(1800)));
+        assert_eq!(po.connection_timeout, Some(Duration::from_secs(2)));
+        assert!(matches!(po.alpn, ALPN::H1));
+        // unset values keep the defaults
+        assert_eq!(po.max_h2_streams, 2);
+        assert_eq!(po.write_timeout, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_upstream_protocol() {
+        assert!(default_upstream_tls(Some(UpstreamProtocol::H2), 8443));
+        assert!(!default_upstream_tls(Some(UpstreamProtocol::H2c), 443));
+        assert!(default_upstream_tls(Some(UpstreamProtocol::Grpc), 443));
+        assert!(!default_upstream_tls(Some(UpstreamProtocol::Grpc), 50051));
+        assert!(!default_upstream_tls(None, 8080));
+
+        assert!(matches!(protocol_alpn(UpstreamProtocol::Http1), ALPN::H1));
+        assert!(matches!(protocol_alpn(UpstreamProtocol::H2c), ALPN::H2));
+        assert!(matches!(protocol_alpn(UpstreamProtocol::Grpc), ALPN::H2));
+    }
+
+    #[test]
+    fn test_upstream_addr() {
+        let tcp = RouteUpstream {
+            ip: "127.0.0.1".into(),
+            port: 3000,
+            ..Default::default()
+        };
+        let unix = RouteUpstream {
+            ip: "unix:///run/app.sock".into(),
+            port: 0,
+            ..Default::default()
+        };
+
+        let tcp_addr = "127.0.0.1:3000".parse::<SocketAddr>().unwrap();
+        let unix_addr = "unix:/run/app.sock".parse::<SocketAddr>().unwrap();
+
+        assert!(is_upstream_addr(&tcp, &tcp_addr));
+        assert!(!is_upstream_addr(&tcp, &unix_addr));
+        assert!(is_upstream_addr(&unix, &unix_addr));
+        assert!(!is_upstream_addr(&unix, &tcp_addr));
+
+        assert_eq!(format_upstream_addr(&tcp_addr), "127.0.0.1:3000");
+        assert_eq!(format_upstream_addr(&unix_addr), "unix:/run/app.sock");
+    }
+}
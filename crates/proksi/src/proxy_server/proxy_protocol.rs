@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use pingora::{
+    protocols::l4::socket::SocketAddr as StreamAddr, proxy::Session, upstreams::peer::Proxy,
+};
+
+use crate::config::ProxyProtocolVersion;
+
+/// The signature starting every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header, `\r\n` included
+const V1_MAX_LEN: usize = 107;
+
+/// Headers of the CONNECT requests sent to the PROXY protocol emitter
+pub const CLIENT_HEADER: &str = "x-proksi-client";
+pub const SERVER_HEADER: &str = "x-proksi-server";
+pub const VERSION_HEADER: &str = "x-proksi-proxy-protocol";
+
+/// The socket of the service sending the PROXY protocol headers to the upstreams
+pub static EMITTER_PATH: Lazy<PathBuf> = Lazy::new(|| socket_path("proxy-protocol"));
+
+/// Connections relayed by a listener to the proxy service behind it,
+/// by the path of the unix socket the listener connected from
+static RELAYED: Lazy<DashMap<PathBuf, ConnectionAddrs>> = Lazy::new(DashMap::new);
+
+/// The path of a unix socket of this process (ex: `/tmp/proksi-42-proxy-protocol.sock`)
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("proksi-{}-{name}.sock", std::process::id()))
+}
+
+/// The addresses of a connection, as seen by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionAddrs {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// More bytes are needed to read the header
+    Incomplete,
+    /// A header of `len` bytes. `addrs` is `None` for the connections of the load balancer
+    /// itself (ex: health checks) or of unknown protocols
+    Complete {
+        len: usize,
+        addrs: Option<ConnectionAddrs>,
+    },
+}
+
+/// Parses the PROXY protocol header (v1 or v2) at the start of the connection
+pub fn parse_header(buf: &[u8]) -> Result<ProxyHeader, anyhow::Error> {
+    if buf.starts_with(&V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+
+    if buf.starts_with(V1_PREFIX) {
+        return parse_v1(buf);
+    }
+
+    if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        return Ok(ProxyHeader::Incomplete);
+    }
+
+    bail!("the connection does not start with a PROXY protocol header")
+}
+
+fn parse_v1(buf: &[u8]) -> Result<ProxyHeader, anyhow::Error> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            bail!("PROXY protocol v1 header too long");
+        }
+        return Ok(ProxyHeader::Incomplete);
+    };
+
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        bail!("PROXY protocol v1 header too long");
+    }
+
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    let addrs = match parts.as_slice() {
+        ["UNKNOWN", ..] => None,
+        [protocol @ ("TCP4" | "TCP6"), client_ip, server_ip, client_port, server_port] => {
+            let client_ip = client_ip.parse::<IpAddr>()?;
+            let server_ip = server_ip.parse::<IpAddr>()?;
+            if client_ip.is_ipv4() != (*protocol == "TCP4")
+                || server_ip.is_ipv4() != client_ip.is_ipv4()
+            {
+                bail!("PROXY protocol v1 addresses do not match {protocol}");
+            }
+
+            Some(ConnectionAddrs {
+                client: SocketAddr::new(client_ip, client_port.parse()?),
+                server: SocketAddr::new(server_ip, server_port.parse()?),
+            })
+        }
+        _ => bail!("invalid PROXY protocol v1 header: {line}"),
+    };
+
+    Ok(ProxyHeader::Complete { len, addrs })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<ProxyHeader, anyhow::Error> {
+    if buf.len() < 16 {
+        return Ok(ProxyHeader::Incomplete);
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    if version != 2 {
+        bail!("unsupported PROXY protocol version {version}");
+    }
+
+    let len = 16 + usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    if buf.len() < len {
+        return Ok(ProxyHeader::Incomplete);
+    }
+
+    let payload = &buf[16..len];
+    let addrs = match (command, buf[13]) {
+        // LOCAL: a connection of the load balancer itself
+        (0x0, _) => None,
+        // PROXY over TCP/IPv4
+        (0x1, 0x11) if payload.len() >= 12 => {
+            let ip = |offset: usize| {
+                let octets: [u8; 4] = payload[offset..offset + 4].try_into().unwrap_or_default();
+                IpAddr::from(octets)
+            };
+            let port = |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+
+            Some(ConnectionAddrs {
+                client: SocketAddr::new(ip(0), port(8)),
+                server: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        // PROXY over TCP/IPv6
+        (0x1, 0x21) if payload.len() >= 36 => {
+            let ip = |offset: usize| {
+                let octets: [u8; 16] = payload[offset..offset + 16].try_into().unwrap_or_default();
+                IpAddr::from(octets)
+            };
+            let port = |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+
+            Some(ConnectionAddrs {
+                client: SocketAddr::new(ip(0), port(32)),
+                server: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        // other protocols (UDP, unix sockets) are accepted without their addresses
+        (0x1, _) => None,
+        (command, _) => return Err(anyhow!("unsupported PROXY protocol command {command}")),
+    };
+
+    Ok(ProxyHeader::Complete { len, addrs })
+}
+
+/// Writes the PROXY protocol header of a connection, `addrs` is `None` if they are unknown
+pub fn encode_header(version: ProxyProtocolVersion, addrs: Option<&ConnectionAddrs>) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => encode_v1(addrs),
+        ProxyProtocolVersion::V2 => encode_v2(addrs),
+    }
+}
+
+fn encode_v1(addrs: Option<&ConnectionAddrs>) -> Vec<u8> {
+    // both addresses must be of the same family
+    let Some(addrs) = addrs.filter(|addrs| addrs.client.is_ipv4() == addrs.server.is_ipv4()) else {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    };
+
+    let protocol = if addrs.client.is_ipv4() {
+        "TCP4"
+    } else {
+        "TCP6"
+    };
+    format!(
+        "PROXY {protocol} {} {} {} {}\r\n",
+        addrs.client.ip(),
+        addrs.server.ip(),
+        addrs.client.port(),
+        addrs.server.port()
+    )
+    .into_bytes()
+}
+
+fn encode_v2(addrs: Option<&ConnectionAddrs>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let Some(addrs) = addrs else {
+        // LOCAL command, no addresses
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        return header;
+    };
+
+    let mut payload = Vec::with_capacity(36);
+    let family = match (addrs.client.ip(), addrs.server.ip()) {
+        (IpAddr::V4(client), IpAddr::V4(server)) => {
+            payload.extend_from_slice(&client.octets());
+            payload.extend_from_slice(&server.octets());
+            0x11
+        }
+        // mixed families are sent as IPv6 (with IPv4-mapped addresses)
+        (client, server) => {
+            payload.extend_from_slice(&to_ipv6(client).octets());
+            payload.extend_from_slice(&to_ipv6(server).octets());
+            0x21
+        }
+    };
+    payload.extend_from_slice(&addrs.client.port().to_be_bytes());
+    payload.extend_from_slice(&addrs.server.port().to_be_bytes());
+
+    let len = u16::try_from(payload.len()).unwrap_or_default();
+    header.extend_from_slice(&[0x21, family]);
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&payload);
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Keeps the addresses of a relayed connection known while it is open
+pub struct RelayedConnection {
+    path: PathBuf,
+}
+
+impl RelayedConnection {
+    /// Records the addresses of the connection relayed from the unix socket at `path`
+    pub fn new(path: PathBuf, addrs: ConnectionAddrs) -> Self {
+        RELAYED.insert(path.clone(), addrs);
+        RelayedConnection { path }
+    }
+}
+
+impl Drop for RelayedConnection {
+    fn drop(&mut self) {
+        RELAYED.remove(&self.path);
+    }
+}
+
+/// The addresses of the connection of the session. For connections relayed
+/// by another listener (ex: PROXY protocol), these are the ones of the original client.
+pub fn connection_addrs(session: &Session) -> Option<ConnectionAddrs> {
+    relayed_addrs(session.client_addr()?, session.server_addr()?)
+}
+
+/// The addresses of a connection accepted from `client` on `server`,
+/// the ones of the original client if it was relayed by another listener
+pub fn relayed_addrs(client: &StreamAddr, server: &StreamAddr) -> Option<ConnectionAddrs> {
+    if let Some(path) = client.as_unix().and_then(|addr| addr.as_pathname()) {
+        return RELAYED.get(path).map(|addrs| *addrs);
+    }
+
+    // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+    let canonical = |addr: &SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    Some(ConnectionAddrs {
+        client: canonical(client.as_inet()?),
+        server: canonical(server.as_inet()?),
+    })
+}
+
+/// Connects to the upstream through the PROXY protocol emitter,
+/// which sends the addresses of the connection before the request
+pub fn upstream_proxy(
+    version: ProxyProtocolVersion,
+    addrs: &ConnectionAddrs,
+    upstream: &SocketAddr,
+) -> Proxy {
+    let version = match version {
+        ProxyProtocolVersion::V1 => "v1",
+        ProxyProtocolVersion::V2 => "v2",
+    };
+
+    let headers = BTreeMap::from([
+        (
+            CLIENT_HEADER.to_string(),
+            addrs.client.to_string().into_bytes(),
+        ),
+        (
+            SERVER_HEADER.to_string(),
+            addrs.server.to_string().into_bytes(),
+        ),
+        (VERSION_HEADER.to_string(), version.as_bytes().to_vec()),
+    ]);
+
+    Proxy {
+        next_hop: EMITTER_PATH.clone().into_boxed_path(),
+        host: upstream.ip().to_string(),
+        port: upstream.port(),
+        headers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(client: &str, server: &str) -> ConnectionAddrs {
+        ConnectionAddrs {
+            client: client.parse().unwrap(),
+            server: server.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_v1_header() {
+        let header = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            parse_header(header).unwrap(),
+            ProxyHeader::Complete {
+                len: 43,
+                addrs: Some(addrs("203.0.113.7:56324", "10.0.0.1:443")),
+            }
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parse_header(header).unwrap(),
+            ProxyHeader::Complete {
+                len: header.len(),
+                addrs: Some(addrs("[2001:db8::1]:56324", "[2001:db8::2]:443")),
+            }
+        );
+
+        let header = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(
+            parse_header(header).unwrap(),
+            ProxyHeader::Complete {
+                len: header.len(),
+                addrs: None,
+            }
+        );
+
+        for incomplete in [&b"PRO"[..], b"PROXY TCP4 203.0.113.7"] {
+            assert_eq!(parse_header(incomplete).unwrap(), ProxyHeader::Incomplete);
+        }
+
+        for invalid in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 10.0.0.1 56324 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n",
+            &[b'1'; 120],
+        ] {
+            assert!(parse_header(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_v2_header_roundtrip() {
+        for connection in [
+            addrs("203.0.113.7:56324", "10.0.0.1:443"),
+            addrs("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+        ] {
+            let mut header = encode_header(ProxyProtocolVersion::V2, Some(&connection));
+            let len = header.len();
+            header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+            assert_eq!(
+                parse_header(&header[..len - 1]).unwrap(),
+                ProxyHeader::Incomplete
+            );
+            assert_eq!(
+                parse_header(&header).unwrap(),
+                ProxyHeader::Complete {
+                    len,
+                    addrs: Some(connection),
+                }
+            );
+        }
+
+        // a LOCAL header, sent by the health checks of the load balancer
+        let header = encode_header(ProxyProtocolVersion::V2, None);
+        assert_eq!(
+            parse_header(&header).unwrap(),
+            ProxyHeader::Complete {
+                len: 16,
+                addrs: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_encode_v1_header() {
+        let connection = addrs("203.0.113.7:56324", "10.0.0.1:443");
+        assert_eq!(
+            encode_header(ProxyProtocolVersion::V1, Some(&connection)),
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n"
+        );
+
+        // mixed families can't be described
+        let mixed = addrs("203.0.113.7:56324", "[2001:db8::2]:443");
+        assert_eq!(
+            encode_header(ProxyProtocolVersion::V1, Some(&mixed)),
+            b"PROXY UNKNOWN\r\n"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use http::StatusCode;
+use pingora::http::RequestHeader;
+
+use crate::config::{Route, RouteRedirect};
+use crate::stores::routes::RouteStorePathMatcher;
+
+/// A redirect served without reaching the upstreams
+#[derive(Debug)]
+pub struct Redirect {
+    matcher: RouteStorePathMatcher,
+    to: String,
+    status: StatusCode,
+}
+
+impl Redirect {
+    pub fn from_config(config: &RouteRedirect) -> Result<Self, anyhow::Error> {
+        let mut matcher = RouteStorePathMatcher::new();
+        if let Some(match_with) = config.match_with.as_ref() {
+            matcher.with_matcher(match_with)?;
+        }
+
+        Ok(Redirect {
+            matcher,
+            to: config.to.to_string(),
+            status: StatusCode::from_u16(config.status)?,
+        })
+    }
+
+    /// Permanently redirects every request to the same path and query on `host`
+    pub fn to_host(host: &str) -> Self {
+        Redirect {
+            matcher: RouteStorePathMatcher::new(),
+            to: format!("https://{host}{{path}}{{query}}"),
+            status: StatusCode::MOVED_PERMANENTLY,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The target of the redirect for the given request (on `host`),
+    /// or `None` if the request does not match the redirect
+    pub fn location(&self, req: &RequestHeader, host: &str) -> Option<String> {
+        if !self.matcher.matches(req) {
+            return None;
+        }
+
+        let path = req.uri.path();
+        let mut location = String::with_capacity(self.to.len());
+
+        // Captures of the path regex (ex: `$1`, `${name}`)
+        match self.matcher.regex.as_ref().and_then(|r| r.captures(path)) {
+            Some(captures) => captures.expand(&self.to, &mut location),
+            None => location.push_str(&self.to),
+        }
+
+        // Named parameters of the path patterns (ex: `{id}`)
+        if let Some((_, matched)) = self.matcher.pattern.as_ref().and_then(|p| p.find(path)) {
+            for (name, value) in matched.params_iter() {
+                location = location.replace(&format!("{{{name}}}"), value);
+            }
+        }
+
+        let query = req.uri.query().map(|q| format!("?{q}")).unwrap_or_default();
+        Some(
+            location
+                .replace("{host}", host)
+                .replace("{path}", path)
+                .replace("{query}", &query),
+        )
+    }
+}
+
+/// Creates the redirects of a route: its redirect rules in order,
+/// then the redirect to the canonical host (if any)
+pub fn route_redirects(route: &Route) -> Result<Vec<Arc<Redirect>>, anyhow::Error> {
+    let mut redirects = route
+        .redirects
+        .iter()
+        .flatten()
+        .map(|redirect| Redirect::from_config(redirect).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(host) = route.redirect_to_host.as_ref() {
+        redirects.push(Arc::new(Redirect::to_host(host)));
+    }
+
+    Ok(redirects)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::config::{RouteMatcher, RoutePathMatcher};
+
+    fn path_matcher(path: RoutePathMatcher) -> Option<RouteMatcher> {
+        Some(RouteMatcher {
+            path: Some(path),
+            ..Default::default()
+        })
+    }
+
+    fn request(path: &str) -> RequestHeader {
+        RequestHeader::build("GET", path.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let redirect = Redirect::from_config(&RouteRedirect {
+            match_with: path_matcher(RoutePathMatcher {
+                patterns: vec![Cow::Borrowed("/blog/:slug")],
+                ..Default::default()
+            }),
+            to: Cow::Borrowed("https://blog.{host}/posts/{slug}{query}"),
+            status: 308,
+        })
+        .unwrap();
+
+        assert_eq!(redirect.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            redirect.location(&request("/blog/hello?ref=home"), "example.com"),
+            Some("https://blog.example.com/posts/hello?ref=home".to_string())
+        );
+        assert_eq!(redirect.location(&request("/about"), "example.com"), None);
+    }
+
+    #[test]
+    fn test_redirect_location_with_regex_captures() {
+        let redirect = Redirect::from_config(&RouteRedirect {
+            match_with: path_matcher(RoutePathMatcher {
+                regex: Some(Cow::Borrowed("^/docs/v(?<version>[0-9]+)/(.*)$")),
+                ..Default::default()
+            }),
+            to: Cow::Borrowed("/documentation/$2?version=${version}"),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(redirect.status(), StatusCode::FOUND);
+        assert_eq!(
+            redirect.location(&request("/docs/v2/install"), "example.com"),
+            Some("/documentation/install?version=2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_redirect_to_host() {
+        let redirect = Redirect::to_host("example.com");
+
+        assert_eq!(redirect.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            redirect.location(&request("/pricing?plan=pro"), "www.example.com"),
+            Some("https://example.com/pricing?plan=pro".to_string())
+        );
+    }
+}
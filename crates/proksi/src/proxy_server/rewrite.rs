@@ -0,0 +1,229 @@
+use http::{uri::PathAndQuery, Uri};
+use path_tree::PathTree;
+use regex::Regex;
+
+use crate::config::RouteRewrite;
+
+/// Path rewrites of a route, with the regular expression compiled once.
+#[derive(Debug)]
+pub struct PathRewrite {
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    regex: Option<Regex>,
+    replacement: Option<String>,
+    location: bool,
+}
+
+impl PathRewrite {
+    pub fn from_config(config: &RouteRewrite) -> Result<Self, regex::Error> {
+        let trim_prefix = |prefix: &str| prefix.trim_end_matches('/').to_string();
+
+        Ok(PathRewrite {
+            strip_prefix: config.strip_prefix.as_deref().map(trim_prefix),
+            add_prefix: config.add_prefix.as_deref().map(trim_prefix),
+            regex: config.regex.as_deref().map(Regex::new).transpose()?,
+            replacement: config.replacement.as_deref().map(ToString::to_string),
+            location: config.location.unwrap_or(true),
+        })
+    }
+
+    /// Rewrites the path of the request URI, keeping its query string.
+    /// `pattern` holds the route path patterns, whose named parameters
+    /// can be used by the replacement.
+    pub fn rewrite_uri(&self, uri: &Uri, pattern: Option<&PathTree<usize>>) -> Option<Uri> {
+        let path = self.rewrite_path(uri.path(), pattern);
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+        Uri::from_parts(parts).ok()
+    }
+
+    fn rewrite_path(&self, path: &str, pattern: Option<&PathTree<usize>>) -> String {
+        let mut path = match (self.regex.as_ref(), self.replacement.as_ref()) {
+            (Some(regex), Some(replacement)) => regex.replace(path, replacement).into_owned(),
+            (None, Some(replacement)) => {
+                let mut rewritten = replacement.clone();
+                if let Some((_, matched)) = pattern.and_then(|p| p.find(path)) {
+                    for (name, value) in matched.params_iter() {
+                        rewritten = rewritten.replace(&format!("{{{name}}}"), value);
+                    }
+                }
+                rewritten
+            }
+            _ => path.to_string(),
+        };
+
+        if let Some(prefix) = self.strip_prefix.as_deref() {
+            if let Some(stripped) = strip_path_prefix(&path, prefix) {
+                path = stripped;
+            }
+        }
+
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+
+        match self.add_prefix.as_deref() {
+            Some(prefix) => format!("{prefix}{path}"),
+            None => path,
+        }
+    }
+
+    /// Rewrites a `Location` header pointing to the upstream paths back to the paths
+    /// of the route (ex: `/login` to `/grafana/login` when stripping `/grafana`).
+    /// Only relative locations and absolute ones pointing to `host` are rewritten.
+    pub fn rewrite_location(&self, location: &str, host: &str) -> Option<String> {
+        if !self.location || (self.strip_prefix.is_none() && self.add_prefix.is_none()) {
+            return None;
+        }
+
+        if location.starts_with('/') && !location.starts_with("//") {
+            return self.restore_path(location);
+        }
+
+        let uri = location.parse::<Uri>().ok()?;
+        if uri.host() != Some(host) {
+            return None;
+        }
+
+        let path = self.restore_path(uri.path_and_query()?.as_str())?;
+        Some(format!(
+            "{}://{}{path}",
+            uri.scheme_str()?,
+            uri.authority()?
+        ))
+    }
+
+    fn restore_path(&self, path: &str) -> Option<String> {
+        let path = match self.add_prefix.as_deref() {
+            Some(prefix) => strip_path_prefix(path, prefix)?,
+            None => path.to_string(),
+        };
+
+        let Some(prefix) = self.strip_prefix.as_deref() else {
+            return Some(path);
+        };
+
+        // the upstream already redirects under the prefix (ex: a configured root url)
+        if self.add_prefix.is_none() && strip_path_prefix(&path, prefix).is_some() {
+            return None;
+        }
+
+        Some(format!("{prefix}{path}"))
+    }
+}
+
+/// Removes the prefix from the path if the path is under it
+/// (ex: `/grafana/login` is under `/grafana`, `/grafana-admin` is not)
+fn strip_path_prefix(path: &str, prefix: &str) -> Option<String> {
+    let rest = path.strip_prefix(prefix)?;
+
+    match rest.chars().next() {
+        None => Some("/".to_string()),
+        Some('/') => Some(rest.to_string()),
+        Some('?') => Some(format!("/{rest}")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn rewrite(config: RouteRewrite) -> PathRewrite {
+        PathRewrite::from_config(&config).unwrap()
+    }
+
+    fn rewrite_uri(rewrite: &PathRewrite, uri: &str, pattern: Option<&PathTree<usize>>) -> String {
+        let uri = uri.parse::<Uri>().unwrap();
+        rewrite.rewrite_uri(&uri, pattern).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_rewrite_prefixes() {
+        let strip = rewrite(RouteRewrite {
+            strip_prefix: Some(Cow::Borrowed("/grafana/")),
+            ..Default::default()
+        });
+        assert_eq!(
+            rewrite_uri(&strip, "/grafana/d/1?orgId=1", None),
+            "/d/1?orgId=1"
+        );
+        assert_eq!(rewrite_uri(&strip, "/grafana", None), "/");
+        assert_eq!(
+            rewrite_uri(&strip, "/grafana-admin", None),
+            "/grafana-admin"
+        );
+
+        let replace = rewrite(RouteRewrite {
+            strip_prefix: Some(Cow::Borrowed("/admin")),
+            add_prefix: Some(Cow::Borrowed("/internal")),
+            ..Default::default()
+        });
+        assert_eq!(
+            rewrite_uri(&replace, "https://example.com/admin/users", None),
+            "https://example.com/internal/users"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_replacement() {
+        let regex = rewrite(RouteRewrite {
+            regex: Some(Cow::Borrowed("^/api/(?<version>v[0-9]+)/(.*)$")),
+            replacement: Some(Cow::Borrowed("/${version}-api/$2")),
+            ..Default::default()
+        });
+        assert_eq!(rewrite_uri(&regex, "/api/v2/users", None), "/v2-api/users");
+
+        let mut pattern = PathTree::new();
+        let _ = pattern.insert("/users/:id/*", 0);
+        let params = rewrite(RouteRewrite {
+            replacement: Some(Cow::Borrowed("/profiles/{id}/{*1}")),
+            ..Default::default()
+        });
+        assert_eq!(
+            rewrite_uri(&params, "/users/42/avatar.png?size=2", Some(&pattern)),
+            "/profiles/42/avatar.png?size=2"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_location() {
+        let strip = rewrite(RouteRewrite {
+            strip_prefix: Some(Cow::Borrowed("/grafana")),
+            ..Default::default()
+        });
+        assert_eq!(
+            strip.rewrite_location("/login?next=%2F", "example.com"),
+            Some("/grafana/login?next=%2F".to_string())
+        );
+        assert_eq!(
+            strip.rewrite_location("https://example.com/login", "example.com"),
+            Some("https://example.com/grafana/login".to_string())
+        );
+        assert_eq!(
+            strip.rewrite_location("/grafana/login", "example.com"),
+            None
+        );
+        assert_eq!(
+            strip.rewrite_location("https://other.com/", "example.com"),
+            None
+        );
+
+        let add = rewrite(RouteRewrite {
+            add_prefix: Some(Cow::Borrowed("/v1")),
+            ..Default::default()
+        });
+        assert_eq!(
+            add.rewrite_location("/v1/users", "example.com"),
+            Some("/users".to_string())
+        );
+        assert_eq!(add.rewrite_location("/users", "example.com"), None);
+    }
+}
@@ -0,0 +1,593 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Bytes, BytesMut};
+use http::{header, Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::{Error, ErrorType};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::RouteStatic;
+use crate::stores::routes::RouteStoreContainer;
+
+/// Maximum size of the chunks a file is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves the files of a directory: index files, single page application fallback,
+/// precompressed variants, conditional and range requests.
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Vec<String>,
+    spa: bool,
+    precompressed: bool,
+}
+
+/// Where a request path leads to
+#[derive(Debug, PartialEq)]
+enum Lookup {
+    File(PathBuf),
+    /// A directory requested without its trailing slash
+    Redirect(String),
+    NotFound,
+}
+
+/// The requested bytes of a file
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    /// Start and end (inclusive) of the range
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// The part of a file sent as the response body
+#[derive(Debug, PartialEq)]
+struct FileBody {
+    path: PathBuf,
+    start: u64,
+    len: u64,
+}
+
+impl StaticFiles {
+    pub fn from_config(config: &RouteStatic) -> Result<Self, anyhow::Error> {
+        let root = config.root.canonicalize().map_err(|err| {
+            anyhow::anyhow!("Failed to open static root {:?}: {err}", config.root)
+        })?;
+
+        Ok(StaticFiles {
+            root,
+            index: config.index.iter().map(ToString::to_string).collect(),
+            spa: config.spa,
+            precompressed: config.precompressed,
+        })
+    }
+
+    /// Responds to the request with the requested file, applying the
+    /// header and cache settings of the route to the response.
+    pub async fn serve(
+        &self,
+        session: &mut Session,
+        route_container: &RouteStoreContainer,
+    ) -> pingora::Result<()> {
+        let (mut res, body) = self.respond(session.req_header()).await?;
+
+        let cache = route_container.cache.as_ref();
+        if let Some(cache) = cache.filter(|cache| cache.enabled.unwrap_or(false)) {
+            let cache_control = format!("public, max-age={}", cache.expires_in_secs);
+            res.insert_header(header::CACHE_CONTROL, cache_control)?;
+        }
+
+        for (name, value) in &route_container.host_header_add {
+            res.insert_header(name, value)?;
+        }
+
+        for name in &route_container.host_header_remove {
+            res.remove_header(name);
+        }
+
+        session.write_response_header(Box::new(res), false).await?;
+
+        if let Some(body) = body {
+            let read_error = |err| Error::because(ErrorType::FileReadError, "static file", err);
+
+            let mut file = tokio::fs::File::open(&body.path)
+                .await
+                .map_err(|err| Error::because(ErrorType::FileOpenError, "static file", err))?;
+            file.seek(SeekFrom::Start(body.start))
+                .await
+                .map_err(read_error)?;
+
+            let mut file = file.take(body.len);
+            loop {
+                let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+                if file.read_buf(&mut chunk).await.map_err(read_error)? == 0 {
+                    break;
+                }
+
+                session
+                    .write_response_body(Some(chunk.freeze()), false)
+                    .await?;
+            }
+        }
+
+        session.write_response_body(Some(Bytes::new()), true).await
+    }
+
+    /// Builds the response header of the request and the part of the file to send
+    async fn respond(
+        &self,
+        req: &RequestHeader,
+    ) -> pingora::Result<(ResponseHeader, Option<FileBody>)> {
+        if req.method != Method::GET && req.method != Method::HEAD {
+            let mut res = ResponseHeader::build(StatusCode::METHOD_NOT_ALLOWED, Some(2))?;
+            res.insert_header(header::ALLOW, "GET, HEAD")?;
+            res.insert_header(header::CONTENT_LENGTH, 0)?;
+            return Ok((res, None));
+        }
+
+        let path = match self.lookup(req.uri.path()).await {
+            Lookup::File(path) => path,
+            Lookup::Redirect(location) => {
+                let location = match req.uri.query() {
+                    Some(query) => format!("{location}?{query}"),
+                    None => location,
+                };
+
+                let mut res = ResponseHeader::build(StatusCode::MOVED_PERMANENTLY, Some(2))?;
+                res.insert_header(header::LOCATION, location)?;
+                res.insert_header(header::CONTENT_LENGTH, 0)?;
+                return Ok((res, None));
+            }
+            Lookup::NotFound => {
+                let mut res = ResponseHeader::build(StatusCode::NOT_FOUND, Some(1))?;
+                res.insert_header(header::CONTENT_LENGTH, 0)?;
+                return Ok((res, None));
+            }
+        };
+
+        let content_type = content_type(&path);
+        let accept_encoding = header_str(req, &header::ACCEPT_ENCODING).unwrap_or_default();
+        let (path, encoding) = self.variant(&path, accept_encoding).await;
+
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|err| Error::because(ErrorType::FileOpenError, "static file", err))?;
+        let len = metadata.len();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let etag = format!("\"{:x}-{len:x}\"", unix_secs(modified));
+
+        if is_not_modified(req, &etag, modified) {
+            let mut res = ResponseHeader::build(StatusCode::NOT_MODIFIED, Some(3))?;
+            self.insert_validators(&mut res, &etag, modified)?;
+            return Ok((res, None));
+        }
+
+        let (mut res, start, body_len) = match byte_range(req, &etag, modified, len) {
+            ByteRange::Full => (ResponseHeader::build(StatusCode::OK, Some(8))?, 0, len),
+            ByteRange::Partial(start, end) => {
+                let mut res = ResponseHeader::build(StatusCode::PARTIAL_CONTENT, Some(9))?;
+                res.insert_header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))?;
+                (res, start, end - start + 1)
+            }
+            ByteRange::Unsatisfiable => {
+                let mut res = ResponseHeader::build(StatusCode::RANGE_NOT_SATISFIABLE, Some(2))?;
+                res.insert_header(header::CONTENT_RANGE, format!("bytes */{len}"))?;
+                res.insert_header(header::CONTENT_LENGTH, 0)?;
+                return Ok((res, None));
+            }
+        };
+
+        self.insert_validators(&mut res, &etag, modified)?;
+        res.insert_header(header::CONTENT_TYPE, content_type)?;
+        res.insert_header(header::CONTENT_LENGTH, body_len)?;
+        res.insert_header(header::ACCEPT_RANGES, "bytes")?;
+
+        if let Some(encoding) = encoding {
+            res.insert_header(header::CONTENT_ENCODING, encoding)?;
+        }
+
+        // HEAD requests only get the headers
+        let body = (req.method == Method::GET).then_some(FileBody {
+            path,
+            start,
+            len: body_len,
+        });
+
+        Ok((res, body))
+    }
+
+    fn insert_validators(
+        &self,
+        res: &mut ResponseHeader,
+        etag: &str,
+        modified: SystemTime,
+    ) -> pingora::Result<()> {
+        res.insert_header(header::ETAG, etag)?;
+        res.insert_header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified))?;
+
+        // the served file depends on the encodings accepted by the client
+        if self.precompressed {
+            res.insert_header(header::VARY, "Accept-Encoding")?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the file of a request path: the file itself, the index file of a directory
+    /// or, for single page applications, the index file of the root directory
+    async fn lookup(&self, uri_path: &str) -> Lookup {
+        let Some(relative_path) = relative_path(uri_path) else {
+            return Lookup::NotFound;
+        };
+
+        let path = self.root.join(relative_path);
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => {
+                if let Some(path) = self.contained(&path).await {
+                    return Lookup::File(path);
+                }
+            }
+            Ok(metadata) if metadata.is_dir() => {
+                if !uri_path.ends_with('/') {
+                    return Lookup::Redirect(format!("{uri_path}/"));
+                }
+
+                if let Some(path) = self.index_file(&path).await {
+                    return Lookup::File(path);
+                }
+            }
+            _ => {}
+        }
+
+        if self.spa {
+            if let Some(path) = self.index_file(&self.root).await {
+                return Lookup::File(path);
+            }
+        }
+
+        Lookup::NotFound
+    }
+
+    /// The first index file that exists in the directory
+    async fn index_file(&self, dir: &Path) -> Option<PathBuf> {
+        for index in &self.index {
+            let path = dir.join(index);
+            if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+                return self.contained(&path).await;
+            }
+        }
+
+        None
+    }
+
+    /// The canonical path of the file if it is inside the root directory
+    /// (symbolic links cannot be used to serve files outside of it)
+    async fn contained(&self, path: &Path) -> Option<PathBuf> {
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+        path.starts_with(&self.root).then_some(path)
+    }
+
+    /// The precompressed variant of the file accepted by the client (brotli first),
+    /// or the file itself
+    async fn variant(&self, path: &Path, accept_encoding: &str) -> (PathBuf, Option<&'static str>) {
+        if self.precompressed {
+            for (encoding, extension) in [("br", "br"), ("gzip", "gz")] {
+                if !accepts_encoding(accept_encoding, encoding) {
+                    continue;
+                }
+
+                let mut variant = path.as_os_str().to_owned();
+                variant.push(".");
+                variant.push(extension);
+
+                let variant = PathBuf::from(variant);
+                if tokio::fs::metadata(&variant)
+                    .await
+                    .is_ok_and(|m| m.is_file())
+                {
+                    if let Some(variant) = self.contained(&variant).await {
+                        return (variant, Some(encoding));
+                    }
+                }
+            }
+        }
+
+        (path.to_path_buf(), None)
+    }
+}
+
+/// Converts the request path into a path relative to the root directory.
+/// Paths trying to leave the root directory (ex: `/../etc/passwd`) are rejected.
+fn relative_path(uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(uri_path).decode_utf8().ok()?;
+    let mut relative_path = PathBuf::new();
+
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains(['\\', '\0']) => return None,
+            segment => relative_path.push(segment),
+        }
+    }
+
+    Some(relative_path)
+}
+
+fn header_str<'a>(req: &'a RequestHeader, name: &header::HeaderName) -> Option<&'a str> {
+    req.headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether the encoding is listed in the `Accept-Encoding` header (and not with `q=0`)
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|value| {
+        let mut params = value.split(';');
+        let name = params.next().unwrap_or_default().trim();
+
+        name.eq_ignore_ascii_case(encoding)
+            && !params.any(|param| {
+                let quality = param.trim().strip_prefix("q=");
+                quality.and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            })
+    })
+}
+
+/// Whether the client already has the current version of the file
+/// (`If-None-Match` takes precedence over `If-Modified-Since`)
+fn is_not_modified(req: &RequestHeader, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = header_str(req, &header::IF_NONE_MATCH) {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    header_str(req, &header::IF_MODIFIED_SINCE)
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .is_some_and(|since| unix_secs(modified) <= unix_secs(since))
+}
+
+/// The range of the file requested by the client. The range is ignored
+/// when `If-Range` does not match the current version of the file.
+fn byte_range(req: &RequestHeader, etag: &str, modified: SystemTime, len: u64) -> ByteRange {
+    let Some(range) = header_str(req, &header::RANGE) else {
+        return ByteRange::Full;
+    };
+
+    if let Some(if_range) = header_str(req, &header::IF_RANGE) {
+        let is_current = if_range == etag
+            || httpdate::parse_http_date(if_range)
+                .is_ok_and(|date| unix_secs(date) == unix_secs(modified));
+
+        if !is_current {
+            return ByteRange::Full;
+        }
+    }
+
+    parse_range(range, len)
+}
+
+/// Parses a single range of a `Range` header (ex: `bytes=0-499`, `bytes=500-`, `bytes=-500`).
+/// Invalid and multiple ranges are ignored, the whole file is sent instead.
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let Some((start, end)) = range
+        .strip_prefix("bytes=")
+        .filter(|range| !range.contains(','))
+        .and_then(|range| range.trim().split_once('-'))
+    else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return ByteRange::Full,
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial(start, end)
+}
+
+/// The content type of a file, from its extension
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    /// Creates a static root with an index, an asset (and its brotli variant)
+    /// and a file outside of the root
+    fn static_files(name: &str) -> (StaticFiles, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("proksi-static-{}-{name}", std::process::id()));
+        let root = dir.join("dist");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+        std::fs::write(root.join("app.js"), "console.log('app');").unwrap();
+        std::fs::write(root.join("app.js.br"), "brotli").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        let config = RouteStatic {
+            root,
+            index: vec![Cow::Borrowed("index.html")],
+            spa: true,
+            precompressed: true,
+        };
+
+        (StaticFiles::from_config(&config).unwrap(), dir)
+    }
+
+    fn request(method: &str, path: &str, headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    fn header<'a>(res: &'a ResponseHeader, name: &str) -> Option<&'a str> {
+        res.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn test_relative_path_rejects_traversal() {
+        assert_eq!(
+            relative_path("/assets/./app%20v2.js"),
+            Some(PathBuf::from("assets/app v2.js"))
+        );
+        assert_eq!(relative_path("/../secret.txt"), None);
+        assert_eq!(relative_path("/assets/%2e%2e/%2e%2e/secret.txt"), None);
+        assert_eq!(relative_path("/assets/..%5c..%5csecret.txt"), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=50-500", 100), ByteRange::Partial(50, 99));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 100), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn test_static_files_lookup() {
+        let (static_files, dir) = static_files("lookup");
+        let root = &static_files.root;
+
+        assert_eq!(
+            static_files.lookup("/app.js").await,
+            Lookup::File(root.join("app.js"))
+        );
+        assert_eq!(
+            static_files.lookup("/assets").await,
+            Lookup::Redirect("/assets/".to_string())
+        );
+        // single page application fallback
+        assert_eq!(
+            static_files.lookup("/users/42").await,
+            Lookup::File(root.join("index.html"))
+        );
+        assert_eq!(
+            static_files.lookup("/assets/").await,
+            Lookup::File(root.join("index.html"))
+        );
+        // paths leaving the root are never served, not even the fallback
+        assert_eq!(
+            static_files.lookup("/../secret.txt").await,
+            Lookup::NotFound
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_static_files_respond() {
+        let (static_files, dir) = static_files("respond");
+
+        let (res, body) = static_files
+            .respond(&request(
+                "GET",
+                "/app.js",
+                &[("accept-encoding", "gzip, br")],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(header(&res, "content-encoding"), Some("br"));
+        assert_eq!(
+            header(&res, "content-type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(body.unwrap().path, static_files.root.join("app.js.br"));
+
+        let (res, body) = static_files
+            .respond(&request("GET", "/app.js", &[("range", "bytes=0-6")]))
+            .await
+            .unwrap();
+        assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&res, "content-range"), Some("bytes 0-6/19"));
+        assert_eq!(body.unwrap().len, 7);
+
+        let etag = header(&res, "etag").unwrap().to_string();
+        let (res, body) = static_files
+            .respond(&request("GET", "/app.js", &[("if-none-match", &etag)]))
+            .await
+            .unwrap();
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_none());
+
+        let (res, body) = static_files
+            .respond(&request("HEAD", "/app.js", &[]))
+            .await
+            .unwrap();
+        assert_eq!(header(&res, "content-length"), Some("19"));
+        assert!(body.is_none());
+
+        let (res, _) = static_files
+            .respond(&request("POST", "/app.js", &[]))
+            .await
+            .unwrap();
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
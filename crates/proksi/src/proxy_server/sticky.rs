@@ -0,0 +1,156 @@
+use cookie::{time::Duration, Cookie, CookieJar, Key, SameSite};
+use once_cell::sync::Lazy;
+use pingora::{http::RequestHeader, protocols::l4::socket::SocketAddr};
+
+use crate::config::{RouteSticky, SameSitePolicy};
+
+use super::format_upstream_addr;
+
+/// Key of the routes without a secret, random for each process
+static DEFAULT_KEY: Lazy<Key> = Lazy::new(Key::generate);
+
+/// Sticky sessions of a route: the upstream serving a client is kept in an
+/// encrypted cookie, so the client can't read nor pick its upstream.
+#[derive(Clone)]
+pub struct StickySessions {
+    cookie_name: String,
+    ttl: Option<Duration>,
+    same_site: SameSite,
+    secure: bool,
+    key: Key,
+}
+
+impl StickySessions {
+    pub fn from_config(config: &RouteSticky) -> Self {
+        let key = match config.secret.as_deref() {
+            // the 64 bytes key of the cookies is derived from the secret
+            Some(secret) => Key::from(&openssl::sha::sha512(secret.as_bytes())),
+            None => DEFAULT_KEY.clone(),
+        };
+
+        let same_site = match config.same_site {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        };
+
+        StickySessions {
+            cookie_name: config.cookie_name.to_string(),
+            ttl: config
+                .ttl_secs
+                .map(|ttl| Duration::seconds(i64::try_from(ttl).unwrap_or(i64::MAX))),
+            same_site,
+            secure: config.secure,
+            key,
+        }
+    }
+
+    /// The upstream the client is stuck to,
+    /// `None` if the request has no cookie or it was not issued by the route
+    pub fn upstream(&self, req: &RequestHeader) -> Option<SocketAddr> {
+        let cookie = req
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(Cookie::split_parse)
+            .flatten()
+            .find(|c| c.name() == self.cookie_name)?;
+
+        let cookie = CookieJar::new()
+            .private(&self.key)
+            .decrypt(cookie.into_owned())?;
+        cookie.value().parse::<SocketAddr>().ok()
+    }
+
+    /// The `Set-Cookie` header sticking the client to the given upstream
+    pub fn set_cookie(&self, upstream: &SocketAddr) -> Option<String> {
+        let mut cookie = Cookie::build((self.cookie_name.clone(), format_upstream_addr(upstream)))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        if let Some(ttl) = self.ttl {
+            cookie = cookie.max_age(ttl);
+        }
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key).add(cookie);
+        jar.get(&self.cookie_name).map(ToString::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn sticky_config(secret: &'static str) -> RouteSticky {
+        RouteSticky {
+            secret: Some(Cow::Borrowed(secret)),
+            ..Default::default()
+        }
+    }
+
+    fn request(cookie: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("cookie", cookie).unwrap();
+        req
+    }
+
+    /// The `name=value` part of a `Set-Cookie` header
+    fn cookie_pair(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    #[test]
+    fn test_sticky_cookie_roundtrip() {
+        let sticky = StickySessions::from_config(&RouteSticky {
+            cookie_name: Cow::Borrowed("backend"),
+            ttl_secs: Some(3600),
+            same_site: SameSitePolicy::Strict,
+            ..sticky_config("a-secret-of-at-least-32-characters")
+        });
+        let upstream = SocketAddr::Inet("10.0.0.2:3000".parse().unwrap());
+
+        let set_cookie = sticky.set_cookie(&upstream).unwrap();
+        assert!(set_cookie.starts_with("backend="));
+        // the upstream address is not readable by the client
+        assert!(!set_cookie.contains("10.0.0.2"));
+        for attribute in [
+            "HttpOnly",
+            "SameSite=Strict",
+            "Secure",
+            "Path=/",
+            "Max-Age=3600",
+        ] {
+            assert!(set_cookie.contains(attribute), "missing {attribute}");
+        }
+
+        let req = request(&format!("theme=dark; {}", cookie_pair(&set_cookie)));
+        assert_eq!(sticky.upstream(&req), Some(upstream));
+
+        // upstreams listening on a unix socket
+        let upstream = "unix:/run/app.sock".parse::<SocketAddr>().unwrap();
+        let set_cookie = sticky.set_cookie(&upstream).unwrap();
+        let req = request(cookie_pair(&set_cookie));
+        assert_eq!(sticky.upstream(&req), Some(upstream));
+    }
+
+    #[test]
+    fn test_sticky_cookie_rejects_foreign_cookies() {
+        let sticky =
+            StickySessions::from_config(&sticky_config("a-secret-of-at-least-32-characters"));
+        let other =
+            StickySessions::from_config(&sticky_config("another-secret-of-at-least-32-characters"));
+        let upstream = SocketAddr::Inet("10.0.0.2:3000".parse().unwrap());
+
+        // a cookie written by the client or by another secret is ignored
+        let forged = request("proksi_sticky=10.0.0.2:3000");
+        assert_eq!(sticky.upstream(&forged), None);
+
+        let set_cookie = other.set_cookie(&upstream).unwrap();
+        assert_eq!(sticky.upstream(&request(cookie_pair(&set_cookie))), None);
+    }
+}
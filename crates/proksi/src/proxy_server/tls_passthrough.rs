@@ -0,0 +1,215 @@
+use anyhow::{anyhow, bail};
+
+/// The content type of the TLS records carrying handshake messages
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+
+/// The largest `ClientHello` read before giving up on finding its SNI
+pub const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More bytes are needed to read the `ClientHello`
+    Incomplete,
+    /// The `ClientHello` was read, `server_name` is `None` for clients that don't send the SNI
+    Complete { server_name: Option<String> },
+}
+
+/// Parses the `ClientHello` starting a TLS connection, which can span multiple records
+pub fn parse_client_hello(buf: &[u8]) -> Result<ClientHello, anyhow::Error> {
+    let mut handshake = Vec::new();
+    let mut records = buf;
+
+    loop {
+        let Some(header) = records.get(..5) else {
+            return Ok(ClientHello::Incomplete);
+        };
+
+        if header[0] != HANDSHAKE_RECORD {
+            bail!("the connection does not start with a TLS handshake");
+        }
+
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(fragment) = records.get(5..5 + len) else {
+            return Ok(ClientHello::Incomplete);
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + len..];
+
+        let Some(message) = handshake.get(..4) else {
+            continue;
+        };
+
+        if message[0] != CLIENT_HELLO {
+            bail!("the TLS handshake does not start with a ClientHello");
+        }
+
+        let len = (usize::from(message[1]) << 16)
+            | (usize::from(message[2]) << 8)
+            | usize::from(message[3]);
+        if let Some(body) = handshake.get(4..4 + len) {
+            return Ok(ClientHello::Complete {
+                server_name: parse_server_name(body)?,
+            });
+        }
+    }
+}
+
+/// Reads the `server_name` extension of the body of a `ClientHello`
+fn parse_server_name(body: &[u8]) -> Result<Option<String>, anyhow::Error> {
+    let mut reader = Reader(body);
+
+    // legacy_version and random
+    reader.take(2 + 32)?;
+    // session_id, cipher_suites and compression_methods
+    reader.take_u8_prefixed()?;
+    reader.take_u16_prefixed()?;
+    reader.take_u8_prefixed()?;
+
+    // clients without extensions don't send the SNI
+    if reader.0.is_empty() {
+        return Ok(None);
+    }
+
+    let mut extensions = Reader(reader.take_u16_prefixed()?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.take_u16_prefixed()?;
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let mut names = Reader(Reader(data).take_u16_prefixed()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.take_u16_prefixed()?;
+            if name_type != HOST_NAME {
+                continue;
+            }
+
+            let name = std::str::from_utf8(name)
+                .map_err(|_| anyhow!("the SNI of the ClientHello is not a hostname"))?;
+            let name = name.strip_suffix('.').unwrap_or(name);
+            return Ok(Some(name.to_ascii_lowercase()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the big-endian fields of a TLS message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.0.len() < len {
+            bail!("malformed TLS ClientHello");
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn take_u8_prefixed(&mut self) -> Result<&'a [u8], anyhow::Error> {
+        let len = self.u8()?;
+        self.take(usize::from(len))
+    }
+
+    fn take_u16_prefixed(&mut self) -> Result<&'a [u8], anyhow::Error> {
+        let len = self.u16()?;
+        self.take(usize::from(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `ClientHello` with the given extensions, in records of at most `record_len` bytes
+    fn client_hello(extensions: &[u8], record_len: usize) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        // session id, one cipher suite and the null compression
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&u16::try_from(extensions.len()).unwrap().to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut handshake = vec![CLIENT_HELLO, 0x00];
+        handshake.extend_from_slice(&u16::try_from(body.len()).unwrap().to_be_bytes());
+        handshake.extend_from_slice(&body);
+
+        handshake
+            .chunks(record_len)
+            .flat_map(|fragment| {
+                let mut record = vec![HANDSHAKE_RECORD, 0x03, 0x01];
+                record.extend_from_slice(&u16::try_from(fragment.len()).unwrap().to_be_bytes());
+                record.extend_from_slice(fragment);
+                record
+            })
+            .collect()
+    }
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let len = u16::try_from(name.len()).unwrap();
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&(len + 5).to_be_bytes());
+        extension.extend_from_slice(&(len + 3).to_be_bytes());
+        extension.push(HOST_NAME);
+        extension.extend_from_slice(&len.to_be_bytes());
+        extension.extend_from_slice(name.as_bytes());
+        extension
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        // an extension before the SNI (supported versions)
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend(server_name_extension("DB.Example.com."));
+
+        let hello = client_hello(&extensions, 16 * 1024);
+        assert_eq!(
+            parse_client_hello(&hello).unwrap(),
+            ClientHello::Complete {
+                server_name: Some("db.example.com".to_string())
+            }
+        );
+
+        // partial reads
+        for len in [0, 3, 5, hello.len() - 1] {
+            assert_eq!(
+                parse_client_hello(&hello[..len]).unwrap(),
+                ClientHello::Incomplete
+            );
+        }
+
+        // a ClientHello split in multiple records
+        let hello = client_hello(&extensions, 20);
+        assert_eq!(
+            parse_client_hello(&hello).unwrap(),
+            ClientHello::Complete {
+                server_name: Some("db.example.com".to_string())
+            }
+        );
+
+        // no SNI
+        let hello = client_hello(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04], 16 * 1024);
+        assert_eq!(
+            parse_client_hello(&hello).unwrap(),
+            ClientHello::Complete { server_name: None }
+        );
+
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use pingora::http::RequestHeader;
+
+use crate::config::{RouteHashKey, RouteUpstream};
+
+use super::load_balancer::{hash_key_value, RouteLoadBalancer};
+
+/// Spreads consecutive requests over the 100 buckets (37 and 100 are coprime,
+/// so every bucket is used once every 100 requests)
+const BUCKET_STRIDE: usize = 37;
+
+/// An upstream group receiving a percentage of the requests of a route
+pub struct UpstreamGroup {
+    pub name: String,
+    /// Changed in place by configuration reloads (see [`UpstreamGroup::set_percent`])
+    percent: AtomicU8,
+    pub load_balancer: Arc<RouteLoadBalancer>,
+    pub upstreams: Vec<RouteUpstream>,
+}
+
+impl UpstreamGroup {
+    pub fn new(
+        name: String,
+        percent: u8,
+        load_balancer: Arc<RouteLoadBalancer>,
+        upstreams: Vec<RouteUpstream>,
+    ) -> Self {
+        UpstreamGroup {
+            name,
+            percent: AtomicU8::new(percent),
+            load_balancer,
+            upstreams,
+        }
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent.load(Ordering::Relaxed)
+    }
+
+    /// Applies a new percentage, keeping the load balancer of the group
+    /// (health checks, circuit breaker) and the upstreams of the requests in flight
+    pub fn set_percent(&self, percent: u8) {
+        self.percent.store(percent, Ordering::Relaxed);
+    }
+}
+
+/// Splits the requests of a route between its upstream groups.
+///
+/// Each request falls in one of 100 buckets, the groups own consecutive ranges of buckets
+/// sized by their percentages and the buckets left belong to the upstreams of the route.
+/// Sticky requests always fall in the same bucket, so raising the percentage of a group
+/// only moves clients to it (ex: a canary release going from 5% to 10%).
+pub struct TrafficSplit {
+    pub groups: Vec<UpstreamGroup>,
+    pub sticky: Option<RouteHashKey>,
+    requests: AtomicUsize,
+}
+
+impl TrafficSplit {
+    pub fn new(groups: Vec<UpstreamGroup>, sticky: Option<RouteHashKey>) -> Self {
+        TrafficSplit {
+            groups,
+            sticky,
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks the group of the request,
+    /// `None` if the request goes to the upstreams of the route
+    pub fn select(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> Option<usize> {
+        let sticky_key = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| hash_key_value(sticky, req, client_ip));
+
+        let bucket = match sticky_key {
+            Some(key) => hash_bucket(&key),
+            None => next_bucket(&self.requests),
+        };
+
+        self.group_of_bucket(bucket)
+    }
+
+    fn group_of_bucket(&self, bucket: usize) -> Option<usize> {
+        let mut upper_bound = 0;
+
+        self.groups.iter().position(|group| {
+            upper_bound += usize::from(group.percent());
+            bucket < upper_bound
+        })
+    }
+}
+
+/// The bucket of the next request counted by `requests`, the buckets
+/// of any 100 consecutive requests are all different
+pub fn next_bucket(requests: &AtomicUsize) -> usize {
+    requests.fetch_add(1, Ordering::Relaxed) % 100 * BUCKET_STRIDE % 100
+}
+
+/// Hashes the key into one of the 100 buckets (FNV-1a, stable across restarts)
+fn hash_bucket(key: &[u8]) -> usize {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    usize::try_from(hash % 100).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use pingora::lb::{selection::RoundRobin, LoadBalancer};
+
+    use super::*;
+    use crate::config::HashKeySource;
+
+    fn group(name: &str, percent: u8) -> UpstreamGroup {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]);
+
+        UpstreamGroup::new(
+            name.to_string(),
+            percent,
+            Arc::new(load_balancer.unwrap().into()),
+            vec![],
+        )
+    }
+
+    fn request(cookie: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(cookie) = cookie {
+            req.insert_header("cookie", cookie).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_traffic_split_follows_percentages() {
+        let split = TrafficSplit::new(vec![group("canary", 5), group("beta", 20)], None);
+
+        let mut counts = [0; 3];
+        for _ in 0..200 {
+            match split.select(&request(None), None) {
+                Some(index) => counts[index] += 1,
+                None => counts[2] += 1,
+            }
+        }
+
+        assert_eq!(counts, [10, 40, 150]);
+    }
+
+    #[test]
+    fn test_traffic_split_sticky_requests_keep_their_group() {
+        let sticky = RouteHashKey {
+            source: HashKeySource::Cookie,
+            name: Some(Cow::Borrowed("user_id")),
+        };
+        let split = TrafficSplit::new(vec![group("canary", 50)], Some(sticky));
+
+        let users = (0..20)
+            .map(|id| request(Some(&format!("user_id={id}"))))
+            .collect::<Vec<_>>();
+        let selected = users
+            .iter()
+            .map(|req| split.select(req, None))
+            .collect::<Vec<_>>();
+
+        // the same client always gets the same group
+        for (req, group) in users.iter().zip(&selected) {
+            assert_eq!(split.select(req, None), *group);
+        }
+
+        // raising the percentage keeps the clients already in the canary group
+        split.groups[0].set_percent(80);
+        for (req, group) in users.iter().zip(&selected) {
+            if group.is_some() {
+                assert_eq!(split.select(req, None), Some(0));
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use openssl::{pkey::PKey, x509::X509};
+use pingora::{upstreams::peer::HttpPeer, utils::tls::CertKey};
+
+use crate::config::RouteUpstreamTls;
+
+/// TLS settings of an upstream, with the CA bundle and client
+/// certificate loaded once from their files.
+pub struct UpstreamTls {
+    config: RouteUpstreamTls,
+    ca: Option<Arc<Box<[X509]>>>,
+    client_cert_key: Option<Arc<CertKey>>,
+}
+
+impl UpstreamTls {
+    pub fn load(config: &RouteUpstreamTls) -> Result<Self, anyhow::Error> {
+        let ca = match config.ca_file.as_ref() {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|err| {
+                    anyhow::anyhow!("Failed to load CA bundle from file {path:?}: {err}")
+                })?;
+                let certs = X509::stack_from_pem(&pem)
+                    .ok()
+                    .filter(|certs| !certs.is_empty())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Failed to parse CA bundle from file {path:?}")
+                    })?;
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+
+        let client_cert_key = match (config.client_cert.as_ref(), config.client_key.as_ref()) {
+            (Some(cert_path), Some(key_path)) => {
+                let pem = std::fs::read(cert_path).map_err(|err| {
+                    anyhow::anyhow!(
+                        "Failed to load client certificate from file {cert_path:?}: {err}"
+                    )
+                })?;
+                let certs = X509::stack_from_pem(&pem)
+                    .ok()
+                    .filter(|certs| !certs.is_empty())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Failed to parse client certificate from file {cert_path:?}"
+                        )
+                    })?;
+                let key = std::fs::read(key_path).map_err(|err| {
+                    anyhow::anyhow!("Failed to load client key from file {key_path:?}: {err}")
+                })?;
+                let key = PKey::private_key_from_pem(&key).map_err(|err| {
+                    anyhow::anyhow!("Failed to parse client key from file {key_path:?}: {err}")
+                })?;
+                Some(Arc::new(CertKey::new(certs, key)))
+            }
+            _ => None,
+        };
+
+        Ok(UpstreamTls {
+            config: config.clone(),
+            ca,
+            client_cert_key,
+        })
+    }
+
+    /// Whether the connection to the upstream uses TLS, if explicitly configured
+    pub fn enabled(&self) -> Option<bool> {
+        self.config.enabled
+    }
+
+    /// The SNI sent to the upstream, if configured
+    pub fn sni(&self) -> Option<&str> {
+        self.config.sni.as_deref()
+    }
+
+    /// Applies the verification settings, CA bundle and client certificate to the peer
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        // a CA bundle is only used to verify the certificate
+        if let Some(verify_cert) = self.config.verify_cert.or(self.ca.as_ref().map(|_| true)) {
+            peer.options.verify_cert = verify_cert;
+        }
+
+        peer.options
+            .alternative_cn
+            .clone_from(&self.config.alternative_cn);
+
+        if self.ca.is_some() {
+            peer.options.ca.clone_from(&self.ca);
+        }
+
+        if self.client_cert_key.is_some() {
+            peer.client_cert_key.clone_from(&self.client_cert_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_invalid_ca_file() {
+        let dir = std::env::temp_dir().join("proksi-upstream-tls-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_file = dir.join("ca.pem");
+        std::fs::write(&ca_file, "not a certificate").unwrap();
+
+        let config = RouteUpstreamTls {
+            ca_file: Some(ca_file),
+            ..Default::default()
+        };
+
+        let err = UpstreamTls::load(&config).err().unwrap();
+        assert!(err.to_string().contains("Failed to parse CA bundle"));
+    }
+
+    #[test]
+    fn test_ca_file_verifies_the_certificate() {
+        let tls = UpstreamTls {
+            config: RouteUpstreamTls {
+                ca_file: Some("/etc/proksi/certs/internal-ca.pem".into()),
+                ..Default::default()
+            },
+            ca: Some(Arc::new(Box::default())),
+            client_cert_key: None,
+        };
+
+        let mut peer = HttpPeer::new("127.0.0.1:443", true, "example.com".to_string());
+        peer.options.verify_cert = false;
+        tls.apply(&mut peer);
+        assert!(peer.options.verify_cert);
+        assert!(peer.options.ca.is_some());
+    }
+}
@@ -10,16 +10,22 @@ use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
+use serde_json::Value;
+use tokio::sync::broadcast::Sender;
 
-use crate::config::Config;
+use crate::{
+    config::{self, Config},
+    MsgProxy,
+};
 
 pub struct FileWatcherService {
     config: Arc<Config>,
+    broadcast: Sender<MsgProxy>,
 }
 
 impl FileWatcherService {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self { config }
+    pub fn new(config: Arc<Config>, broadcast: Sender<MsgProxy>) -> Self {
+        Self { config, broadcast }
     }
 
     /// Watchs a file or directory for changes
@@ -43,9 +49,50 @@ impl FileWatcherService {
     }
 }
 
-pub struct FileWatcherServiceHandler {}
+pub struct FileWatcherServiceHandler {
+    /// The configuration currently applied, serialized to be compared with the new one
+    config: Value,
+    broadcast: Sender<MsgProxy>,
+}
+
+impl FileWatcherServiceHandler {
+    /// Applies the new configuration without restarting the server when only the
+    /// traffic split percentages changed, the routes keep their upstreams and their state
+    /// (connections, health checks, circuit breakers)
+    fn update_traffic_split(&mut self) -> bool {
+        let config = match config::load("/etc/proksi/configs") {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!("failed to load the new configuration: {err}");
+                return false;
+            }
+        };
+
+        let Ok(new_config) = serde_json::to_value(&config) else {
+            return false;
+        };
+
+        if !is_traffic_split_update(&self.config, &new_config) {
+            return false;
+        }
+
+        if self
+            .broadcast
+            .send(MsgProxy::ConfigUpdate(Arc::new(config.routes)))
+            .is_err()
+        {
+            return false;
+        }
+
+        tracing::info!("applied the new traffic split percentages");
+        self.config = new_config;
+        true
+    }
+}
+
 impl EventHandler for FileWatcherServiceHandler {
     /// Handles configuration file changes and restarts the server
+    /// (unless only the traffic split percentages changed)
     fn handle_event(&mut self, notif: notify::Result<notify::Event>) {
         let Ok(n) = notif else {
             tracing::error!("error handling auto_reload event: {:?}", notif);
@@ -62,6 +109,10 @@ impl EventHandler for FileWatcherServiceHandler {
             return;
         }
 
+        if self.update_traffic_split() {
+            return;
+        }
+
         let Ok(cmd) = std::env::current_exe() else {
             return;
         };
@@ -85,6 +136,38 @@ impl EventHandler for FileWatcherServiceHandler {
     }
 }
 
+/// Whether the only changes between the configurations are the percentages
+/// of the traffic split groups (ex: ramping up a canary release)
+fn is_traffic_split_update(current: &Value, new: &Value) -> bool {
+    if current == new {
+        return false;
+    }
+
+    let mut current = current.clone();
+    let mut new = new.clone();
+    remove_traffic_split_percentages(&mut current);
+    remove_traffic_split_percentages(&mut new);
+    current == new
+}
+
+fn remove_traffic_split_percentages(config: &mut Value) {
+    let Some(routes) = config.get_mut("routes").and_then(Value::as_array_mut) else {
+        return;
+    };
+
+    for route in routes {
+        let groups = route
+            .pointer_mut("/traffic_split/groups")
+            .and_then(Value::as_array_mut);
+
+        for group in groups.into_iter().flatten() {
+            if let Some(group) = group.as_object_mut() {
+                group.remove("percent");
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl Service for FileWatcherService {
     async fn start_service(
@@ -107,7 +190,10 @@ impl Service for FileWatcherService {
             tracing::info!("starting config watcher service");
 
             let mut watcher = notify::poll::PollWatcher::new(
-                FileWatcherServiceHandler {},
+                FileWatcherServiceHandler {
+                    config: serde_json::to_value(&*self.config).unwrap_or_default(),
+                    broadcast: self.broadcast.clone(),
+                },
                 notify::Config::default().with_manual_polling(),
             )
             .unwrap();
@@ -145,3 +231,48 @@ impl Service for FileWatcherService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::config::{Route, RouteTrafficSplit, RouteUpstream, RouteUpstreamGroup};
+
+    fn config(percent: u8, upstream_port: u16) -> Value {
+        let config = Config {
+            routes: vec![Route {
+                host: Cow::Borrowed("example.com"),
+                upstreams: vec![RouteUpstream {
+                    ip: Cow::Borrowed("10.0.0.1"),
+                    port: upstream_port,
+                    ..Default::default()
+                }],
+                traffic_split: Some(RouteTrafficSplit {
+                    groups: vec![RouteUpstreamGroup {
+                        name: Cow::Borrowed("canary"),
+                        percent,
+                        upstreams: vec![],
+                    }],
+                    sticky: None,
+                }),
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+        serde_json::to_value(&config).unwrap()
+    }
+
+    #[test]
+    fn test_is_traffic_split_update() {
+        assert!(is_traffic_split_update(&config(5, 3000), &config(10, 3000)));
+        // nothing changed
+        assert!(!is_traffic_split_update(&config(5, 3000), &config(5, 3000)));
+        // other changes need a restart
+        assert!(!is_traffic_split_update(
+            &config(5, 3000),
+            &config(10, 3001)
+        ));
+        assert!(!is_traffic_split_update(&config(5, 3000), &config(5, 3001)));
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::{BTreeSet, HashMap};
 use std::net::ToSocketAddrs;
-use std::{borrow::Cow, str::FromStr, sync::Arc, time::Duration};
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::{borrow::Cow, str::FromStr, sync::Arc};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -7,20 +9,36 @@ use async_trait::async_trait;
 use http::{HeaderName, HeaderValue};
 use openssl::pkey::PKey;
 use openssl::x509::X509;
-use pingora::lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer};
+use pingora::lb::{Backend, Extensions};
 use pingora::{
+    protocols::l4::socket::SocketAddr,
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
 use tokio::sync::broadcast::Sender;
 
-use crate::config::{Route, RouteCache, RouteUpstream};
-use crate::MsgRoute;
+use crate::config::{
+    Route, RouteSslCertificate, RouteTrafficSplit, RouteUpstream, RouteUpstreamGroup,
+    UNIX_SOCKET_PREFIX,
+};
+use crate::proxy_server::circuit_breaker::CircuitBreaker;
+use crate::proxy_server::forwarded::ForwardedHeaders;
+use crate::proxy_server::grpc_web::GrpcWeb;
+use crate::proxy_server::load_balancer::RouteLoadBalancer;
+use crate::proxy_server::mirror::Mirror;
+use crate::proxy_server::redirect::route_redirects;
+use crate::proxy_server::rewrite::PathRewrite;
+use crate::proxy_server::static_files::StaticFiles;
+use crate::proxy_server::sticky::StickySessions;
+use crate::proxy_server::traffic_split::{TrafficSplit, UpstreamGroup};
+use crate::proxy_server::upstream_tls::UpstreamTls;
+use crate::services::health_check;
 use crate::{
-    config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher, RoutePlugin},
+    config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher},
     stores::{self, routes::RouteStoreContainer},
     MsgProxy,
 };
+use crate::{MsgRoute, MsgUpstream};
 
 // Service discovery for load balancers
 pub struct RoutingService {
@@ -36,34 +54,19 @@ impl RoutingService {
     /// From a given configuration file, create the static load balancing configuration
     async fn add_routes_from_config(&mut self) {
         for route in &self.config.routes {
-            let self_signed_cert_on_failure = route
-                .ssl_certificate
-                .as_ref()
-                .and_then(|v| v.self_signed_on_failure);
-
             if let Err(err) = add_route_ssl_to_store(route).await {
                 tracing::error!(
                     "failed to add SSL certificate to store for host {:?}: {err}",
                     route.host
                 );
             }
-
-            add_route_to_router(
-                &route.host,
-                route.upstreams.clone(),
-                route.match_with.clone(),
-                route.headers.as_ref(),
-                route.plugins.as_ref(),
-                route.cache.as_ref(),
-                self_signed_cert_on_failure.unwrap_or(false),
-            );
-
-            tracing::debug!("Added route: {}, {:?}", route.host, route.upstreams);
         }
+
+        add_config_routes_to_router(&self.config.routes).await;
     }
 
     /// Watch for new routes being added and update the Router Store
-    fn watch_for_route_changes(route: MsgRoute) {
+    async fn watch_for_route_changes(route: MsgRoute) {
         // TODO: refactor
         let mut matcher: Option<RouteMatcher> = None;
         let route_clone = route.path_matchers.clone();
@@ -71,7 +74,9 @@ impl RoutingService {
             matcher = Some(RouteMatcher {
                 path: Some(RoutePathMatcher {
                     patterns: route_clone.iter().map(|v| Cow::Owned(v.clone())).collect(),
+                    ..Default::default()
                 }),
+                ..Default::default()
             });
         }
 
@@ -82,40 +87,58 @@ impl RoutingService {
 
         // create route upstreams from ip + port
 
-        let upstreams = route
+        let upstreams_str = route
             .upstreams
             .iter()
-            .flat_map(|u| {
-                if let Ok(scr) = u.to_socket_addrs() {
-                    scr.map(|f| RouteUpstream {
-                        ip: Cow::Owned(f.ip().to_string()),
-                        port: f.port(),
-                        network: None,
-                        weight: Some(1),
-                        headers: None,
-                        sni: None,
-                    })
-                    .collect::<Vec<_>>()
-                } else {
-                    vec![]
-                }
-            })
+            .map(|u| u.addr.clone())
             .collect::<Vec<_>>();
 
-        add_route_to_router(
-            &route.host,
+        // Upstreams with a group label go to the traffic split groups (ex: a canary release)
+        let mut upstreams = vec![];
+        let mut groups: Vec<RouteUpstreamGroup> = vec![];
+        for upstream in &route.upstreams {
+            let route_upstreams = msg_upstream_to_route_upstreams(upstream);
+
+            let Some((name, percent)) = upstream.group.as_ref() else {
+                upstreams.extend(route_upstreams);
+                continue;
+            };
+
+            match groups.iter_mut().find(|group| group.name == name.as_str()) {
+                Some(group) => group.upstreams.extend(route_upstreams),
+                None => groups.push(RouteUpstreamGroup {
+                    name: Cow::Owned(name.clone()),
+                    percent: *percent,
+                    upstreams: route_upstreams,
+                }),
+            }
+        }
+
+        let traffic_split = (!groups.is_empty()).then_some(RouteTrafficSplit {
+            groups,
+            sticky: None,
+        });
+
+        let route_config = Route {
+            host: route.host.clone(),
             upstreams,
-            matcher,
-            Some(&route_header),
-            Some(&route.plugins),
-            None,
-            route.self_signed_certs,
-        );
+            traffic_split,
+            match_with: matcher,
+            headers: Some(route_header),
+            plugins: Some(route.plugins),
+            ssl_certificate: Some(RouteSslCertificate {
+                self_signed_on_failure: Some(route.self_signed_certs),
+            }),
+            health_check: route.health_check,
+            ..Default::default()
+        };
+
+        add_routes_to_router(&route.host, &[&route_config]).await;
 
         tracing::debug!(
             "Added route: {}, {:?} self-signed: {}",
             route.host,
-            route.upstreams,
+            upstreams_str,
             route.self_signed_certs
         );
     }
@@ -134,8 +157,12 @@ impl Service for RoutingService {
 
         // Watch for new hosts being added and configure them accordingly
         let mut receiver = self.broadcast.subscribe();
-        while let Ok(MsgProxy::NewRoute(route)) = receiver.recv().await {
-            Self::watch_for_route_changes(route);
+        while let Ok(message) = receiver.recv().await {
+            match message {
+                MsgProxy::NewRoute(route) => Self::watch_for_route_changes(*route).await,
+                MsgProxy::ConfigUpdate(routes) => update_traffic_split_percentages(&routes),
+                MsgProxy::NewCertificate(_) => {}
+            }
         }
     }
 
@@ -148,65 +175,288 @@ impl Service for RoutingService {
     }
 }
 
-// Check whether the host already exists and if the the upstream list has changed
-fn has_new_backend(host: &str, upstream_input: &LoadBalancer<RoundRobin>) -> bool {
-    if let Some(route_container) = stores::get_route_by_key(host) {
-        let backends = route_container.load_balancer.backends().get_backend();
-        let new_backends = upstream_input.backends().get_backend();
-        // If upstreams are not the same length, return true (update)
-        if backends.len() != new_backends.len() {
-            return true;
-        }
-
-        !backends.iter().all(|be| new_backends.contains(be))
-    } else {
-        false
+// Check whether the upstream list of an existing route has changed
+fn has_new_backend(load_balancer: &RouteLoadBalancer, upstream_input: &RouteLoadBalancer) -> bool {
+    let backends = load_balancer.backends().get_backend();
+    let new_backends = upstream_input.backends().get_backend();
+    // If upstreams are not the same length, return true (update)
+    if backends.len() != new_backends.len() {
+        return true;
     }
+
+    !backends.iter().all(|be| new_backends.contains(be))
 }
 
-/// Adds new routes to the store if there are changes to an existing route or
-/// if the host does not exist in the store.
-fn add_route_to_router(
-    host: &str,
-    upstream_input: Vec<RouteUpstream>,
-    match_with: Option<RouteMatcher>,
-    headers: Option<&RouteHeader>,
-    plugins: Option<&Vec<RoutePlugin>>,
-    cache: Option<&RouteCache>,
-    should_self_sign_cert_on_failure: bool,
-) {
-    // Check if current route already exists
-    let upstream_str = upstream_input
+// Check whether the upstreams of the groups of an existing route resolve to other backends
+fn has_new_group_backends(
+    route_container: &RouteStoreContainer,
+    new_container: &RouteStoreContainer,
+) -> bool {
+    let (Some(existing), Some(traffic_split)) = (
+        route_container.traffic_split.as_deref(),
+        new_container.traffic_split.as_deref(),
+    ) else {
+        return false;
+    };
+
+    existing
+        .groups
         .iter()
-        .map(|u| format!("{}:{}", u.ip, u.port))
-        .collect::<Vec<String>>();
+        .zip(&traffic_split.groups)
+        .any(|(existing, group)| has_new_backend(&existing.load_balancer, &group.load_balancer))
+}
 
-    let Ok(mut upstreams) = LoadBalancer::<RoundRobin>::try_from_iter(upstream_str) else {
-        tracing::info!(
-            "Could not create upstreams for host: {}, upstreams {:?}",
-            host,
-            upstream_input
-        );
-        return;
+// Check whether the routes of an existing host have changed: a route was added/removed,
+// the configuration of a route has changed or its upstreams resolve to other backends.
+// Changes rebuild the routes without resetting the connections to the upstreams
+// (ex: new traffic split percentages), the connection pool of the proxy is shared by every route.
+fn has_route_changes(
+    existing: &[RouteStoreContainer],
+    route_containers: &[RouteStoreContainer],
+) -> bool {
+    if existing.len() != route_containers.len() {
+        return true;
+    }
+
+    existing
+        .iter()
+        .zip(route_containers)
+        .any(|(existing, route_container)| {
+            existing.route_config != route_container.route_config
+                || has_new_backend(&existing.load_balancer, &route_container.load_balancer)
+                || has_new_group_backends(existing, route_container)
+        })
+}
+
+/// The configuration of a route, compared on reloads to find the routes that changed.
+/// The upstreams are sorted, the addresses of docker services resolved through DNS
+/// (ex: `tasks.<service>`) come in any order.
+fn route_config(route: &Route) -> serde_json::Value {
+    let mut route = route.clone();
+    route.upstreams.sort_by_key(RouteUpstream::address);
+    for group in route
+        .traffic_split
+        .iter_mut()
+        .flat_map(|split| &mut split.groups)
+    {
+        group.upstreams.sort_by_key(RouteUpstream::address);
+    }
+
+    serde_json::to_value(&route).unwrap_or_default()
+}
+
+/// Resolves the address of an upstream discovered through docker
+fn msg_upstream_to_route_upstreams(upstream: &MsgUpstream) -> Vec<RouteUpstream> {
+    if upstream.addr.starts_with(UNIX_SOCKET_PREFIX) {
+        return vec![RouteUpstream {
+            ip: Cow::Owned(upstream.addr.clone()),
+            port: 0,
+            weight: Some(upstream.weight.unwrap_or(1)),
+            ..RouteUpstream::default()
+        }];
+    }
+
+    let Ok(addrs) = upstream.addr.to_socket_addrs() else {
+        return vec![];
     };
 
-    if stores::get_route_by_key(host).is_some() && !has_new_backend(host, &upstreams) {
+    addrs
+        .map(|addr| RouteUpstream {
+            ip: Cow::Owned(addr.ip().to_string()),
+            port: addr.port(),
+            weight: Some(upstream.weight.unwrap_or(1)),
+            ..RouteUpstream::default()
+        })
+        .collect()
+}
+
+/// Groups the routes by host, keeping the configured order of the hosts and
+/// of the routes within each host (the first route matching a request wins)
+fn group_routes_by_host(routes: &[Route]) -> Vec<(&str, Vec<&Route>)> {
+    let mut hosts: Vec<(&str, Vec<&Route>)> = Vec::new();
+
+    for route in routes {
+        match hosts.iter_mut().find(|(host, _)| *host == route.host) {
+            Some((_, host_routes)) => host_routes.push(route),
+            None => hosts.push((&route.host, vec![route])),
+        }
+    }
+
+    hosts
+}
+
+/// Resolves the given upstreams into load balancer backends, carrying over
+/// the configured weight of each upstream (defaults to 1).
+/// Weights are used by the selection algorithm (ex: weighted round robin).
+pub fn upstreams_to_backends(upstreams: &[RouteUpstream]) -> std::io::Result<BTreeSet<Backend>> {
+    let mut backends = BTreeSet::new();
+
+    for upstream in upstreams {
+        let weight = upstream
+            .weight
+            .and_then(|w| usize::try_from(w).ok())
+            .filter(|w| *w > 0)
+            .unwrap_or(1);
+
+        if let Some(path) = upstream.unix_socket() {
+            backends.insert(Backend {
+                addr: SocketAddr::Unix(UnixSocketAddr::from_pathname(path)?),
+                weight,
+                ext: Extensions::new(),
+            });
+            continue;
+        }
+
+        for addr in format!("{}:{}", upstream.ip, upstream.port).to_socket_addrs()? {
+            backends.insert(Backend {
+                addr: SocketAddr::Inet(addr),
+                weight,
+                ext: Extensions::new(),
+            });
+        }
+    }
+
+    Ok(backends)
+}
+
+/// Makes the host the default one if one of its routes is configured as such,
+/// or removes it if it was the default host and no longer is
+fn update_default_route_host(host: &str, is_default: bool) {
+    if is_default {
+        stores::set_default_route_host(Some(host.to_string()));
+    } else if stores::get_default_route_host().as_deref() == Some(host) {
+        stores::set_default_route_host(None);
+    }
+}
+
+/// Loads the TLS settings (CA bundle, client certificate) of the upstreams, by address
+fn load_upstream_tls(
+    upstreams: &[RouteUpstream],
+) -> Result<HashMap<String, Arc<UpstreamTls>>, anyhow::Error> {
+    let mut upstream_tls = HashMap::new();
+
+    for upstream in upstreams {
+        if let Some(tls) = upstream.tls.as_ref() {
+            let key = upstream.address();
+            upstream_tls.insert(key, Arc::new(UpstreamTls::load(tls)?));
+        }
+    }
+
+    Ok(upstream_tls)
+}
+
+/// Adds the routes of the configuration file to the store, by host
+async fn add_config_routes_to_router(routes: &[Route]) {
+    for (host, routes) in group_routes_by_host(routes) {
+        add_routes_to_router(host, &routes).await;
+
+        tracing::debug!("Added {} route(s) for host: {}", routes.len(), host);
+    }
+}
+
+/// Applies the new traffic split percentages of the configuration to the routes in place,
+/// the other settings of the routes are the same (see [`crate::services::config`])
+fn update_traffic_split_percentages(routes: &[Route]) {
+    for (host, routes) in group_routes_by_host(routes) {
+        let Some(route_containers) = stores::get_route_by_key(host) else {
+            continue;
+        };
+
+        for (route, route_container) in routes.iter().zip(&route_containers) {
+            let (Some(traffic_split), Some(upstream_groups)) = (
+                route.traffic_split.as_ref(),
+                route_container.traffic_split.as_deref(),
+            ) else {
+                continue;
+            };
+
+            for group in &traffic_split.groups {
+                if let Some(upstream_group) = upstream_groups
+                    .groups
+                    .iter()
+                    .find(|upstream_group| upstream_group.name == group.name)
+                {
+                    upstream_group.set_percent(group.percent);
+                }
+            }
+        }
+    }
+}
+
+/// Adds the routes of a host to the store if there are changes to the existing
+/// routes or if the host does not exist in the store.
+async fn add_routes_to_router(host: &str, routes: &[&Route]) {
+    update_default_route_host(host, routes.iter().any(|r| r.default.unwrap_or(false)));
+
+    let mut route_containers = Vec::with_capacity(routes.len());
+    for route in routes {
+        let Some(route_container) = create_route_container(route).await else {
+            return;
+        };
+
+        route_containers.push(route_container);
+    }
+
+    // Check if current routes already exist
+    if stores::get_route_by_key(host)
+        .is_some_and(|existing| !has_route_changes(&existing, &route_containers))
+    {
         tracing::debug!("skipping update, no routing changes for host: {}", host);
         return;
     }
 
-    // TODO: support defining health checks in the configuration file
-    let tcp_health_check = TcpHealthCheck::new();
-    upstreams.set_health_check(tcp_health_check);
-    upstreams.health_check_frequency = Some(Duration::from_secs(15));
+    stores::insert_route(host.to_string(), route_containers);
+}
+
+/// Creates the routing container (upstreams, matchers, headers, plugins, etc.) of a route
+async fn create_route_container(route: &Route) -> Option<RouteStoreContainer> {
+    let host = route.host.as_ref();
+    let upstream_input = route.upstreams.clone();
+    let should_self_sign_cert_on_failure = route
+        .ssl_certificate
+        .as_ref()
+        .and_then(|v| v.self_signed_on_failure)
+        .unwrap_or(false);
+
+    let group_upstreams = route
+        .traffic_split
+        .iter()
+        .flat_map(|split| &split.groups)
+        .flat_map(|group| group.upstreams.iter().cloned());
+    let all_upstreams = upstream_input
+        .iter()
+        .cloned()
+        .chain(group_upstreams)
+        .collect::<Vec<_>>();
+    let upstream_tls = match load_upstream_tls(&all_upstreams) {
+        Ok(upstream_tls) => upstream_tls,
+        Err(err) => {
+            tracing::error!("Could not load upstream TLS settings for host: {host}: {err}");
+            return None;
+        }
+    };
+
+    let upstreams = create_load_balancer(route, &upstream_input).await?;
+    let health_check = route.health_check.clone().unwrap_or_default();
 
     // Create new routing container
     let mut route_store_container = RouteStoreContainer::new(upstreams);
+    route_store_container.route_config = route_config(route);
     route_store_container.self_signed_certificate = should_self_sign_cert_on_failure;
     route_store_container.upstreams = upstream_input;
-    route_store_container.cache = cache.cloned();
-
-    if let Some(headers) = headers {
+    route_store_container.cache.clone_from(&route.cache);
+    route_store_container.health_check = health_check;
+    route_store_container
+        .circuit_breaker
+        .clone_from(&route.circuit_breaker);
+    route_store_container.retry.clone_from(&route.retry);
+    route_store_container
+        .peer_options
+        .clone_from(&route.peer_options);
+    route_store_container.upstream_tls = upstream_tls;
+    route_store_container.tls_passthrough = route.tls_passthrough;
+
+    if let Some(headers) = route.headers.as_ref() {
         if let Some(headers) = headers.add.as_ref() {
             route_store_container.host_header_add = headers
                 .iter()
@@ -225,7 +475,7 @@ fn add_route_to_router(
         }
     }
 
-    if let Some(plugins) = plugins {
+    if let Some(plugins) = route.plugins.as_ref() {
         for plugin in plugins {
             match plugin.name.as_ref() {
                 "oauth2" | "request_id" | "basic_auth" => {
@@ -239,20 +489,144 @@ fn add_route_to_router(
         }
     }
 
-    // Prepare route matchers
-    // TODO: enable matchers for upstreams for true load balancing based on path
-    if let Some(match_with) = match_with {
-        // Path matchers
-        match match_with.path {
-            Some(path_matcher) if !path_matcher.patterns.is_empty() => {
-                let pattern = path_matcher.patterns;
-                route_store_container.path_matcher.with_pattern(&pattern);
+    // Prepare route matchers (path, methods, headers and query)
+    if let Some(match_with) = route.match_with.as_ref() {
+        if let Err(err) = route_store_container.path_matcher.with_matcher(match_with) {
+            tracing::error!("Could not create matchers for host: {host}: {err}");
+            return None;
+        }
+    }
+
+    // Prepare path rewrites
+    if let Some(rewrite) = route.rewrite.as_ref() {
+        match PathRewrite::from_config(rewrite) {
+            Ok(rewrite) => route_store_container.rewrite = Some(Arc::new(rewrite)),
+            Err(err) => {
+                tracing::error!("Could not create path rewrites for host: {host}: {err}");
+                return None;
+            }
+        }
+    }
+
+    // Prepare static files
+    if let Some(static_files) = route.static_files.as_ref() {
+        match StaticFiles::from_config(static_files) {
+            Ok(static_files) => route_store_container.static_files = Some(Arc::new(static_files)),
+            Err(err) => {
+                tracing::error!("Could not serve static files for host: {host}: {err}");
+                return None;
             }
-            _ => {}
         }
     }
 
-    stores::insert_route(host.to_string(), route_store_container);
+    // Prepare the traffic split between upstream groups
+    if route.traffic_split.is_some() {
+        let Some(traffic_split) = create_traffic_split(route).await else {
+            tracing::error!("Could not create upstream groups for host: {host}");
+            return None;
+        };
+
+        route_store_container.traffic_split = Some(Arc::new(traffic_split));
+    }
+
+    // Prepare sticky sessions
+    route_store_container.sticky = route
+        .sticky
+        .as_ref()
+        .filter(|sticky| sticky.enabled)
+        .map(|sticky| Arc::new(StickySessions::from_config(sticky)));
+
+    // Prepare the forwarding headers
+    route_store_container.forwarded_headers = route
+        .forwarded_headers
+        .as_ref()
+        .map(|forwarded_headers| Arc::new(ForwardedHeaders::from_config(forwarded_headers)));
+
+    // Prepare the gRPC-Web translation
+    route_store_container.grpc_web = route
+        .grpc_web
+        .as_ref()
+        .map(|grpc_web| Arc::new(GrpcWeb::from_config(grpc_web)));
+
+    // Prepare traffic mirroring
+    if let Some(mirror) = route.mirror.as_ref() {
+        match Mirror::from_config(mirror) {
+            Ok(mirror) => route_store_container.mirror = Some(Arc::new(mirror)),
+            Err(err) => {
+                tracing::error!("Could not create traffic mirroring for host: {host}: {err}");
+                return None;
+            }
+        }
+    }
+
+    // Prepare redirects
+    match route_redirects(route) {
+        Ok(redirects) => route_store_container.redirects = redirects,
+        Err(err) => {
+            tracing::error!("Could not create redirects for host: {host}: {err}");
+            return None;
+        }
+    }
+
+    Some(route_store_container)
+}
+
+/// Creates the load balancer of the given upstreams of a route,
+/// with the load balancing, health check and circuit breaker settings of the route
+async fn create_load_balancer(
+    route: &Route,
+    upstream_input: &[RouteUpstream],
+) -> Option<RouteLoadBalancer> {
+    let host = route.host.as_ref();
+
+    let Ok(backends) = upstreams_to_backends(upstream_input) else {
+        tracing::info!(
+            "Could not create upstreams for host: {}, upstreams {:?}",
+            host,
+            upstream_input
+        );
+        return None;
+    };
+
+    let circuit_breaker = route
+        .circuit_breaker
+        .as_ref()
+        .map(|config| CircuitBreaker::new(host, config, &backends));
+
+    let mut upstreams = RouteLoadBalancer::from_backends(
+        &route.load_balancing.clone().unwrap_or_default(),
+        backends,
+    );
+    if let Some(circuit_breaker) = circuit_breaker {
+        upstreams = upstreams.with_circuit_breaker(circuit_breaker);
+    }
+
+    if let Err(err) = upstreams.update().await {
+        tracing::error!("Could not load upstreams for host: {host}: {err}");
+        return None;
+    }
+
+    let health_check = route.health_check.clone().unwrap_or_default();
+    upstreams.set_health_check(health_check::from_route_config(host, &health_check));
+
+    Some(upstreams)
+}
+
+/// Creates the upstream groups of a route, each one with its own load balancer
+async fn create_traffic_split(route: &Route) -> Option<TrafficSplit> {
+    let traffic_split = route.traffic_split.as_ref()?;
+
+    let mut groups = Vec::with_capacity(traffic_split.groups.len());
+    for group in &traffic_split.groups {
+        groups.push(UpstreamGroup::new(
+            group.name.to_string(),
+            group.percent,
+            Arc::new(create_load_balancer(route, &group.upstreams).await?),
+            group.upstreams.clone(),
+        ));
+    }
+
+    Some(TrafficSplit::new(groups, traffic_split.sticky.clone()))
 }
 
 // TODO: refactor this into its own module
@@ -309,7 +683,24 @@ async fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
 
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
     use std::net::ToSocketAddrs;
+    use std::sync::Arc;
+
+    use pingora::lb::{selection::RoundRobin, LoadBalancer};
+
+    use crate::config::{
+        LoadBalancingAlgorithm, Route, RouteLoadBalancing, RouteTrafficSplit, RouteUpstream,
+        RouteUpstreamGroup,
+    };
+    use crate::proxy_server::load_balancer::RouteLoadBalancer;
+    use crate::proxy_server::traffic_split::{TrafficSplit, UpstreamGroup};
+    use crate::stores::{self, routes::RouteStoreContainer};
+
+    use super::{
+        group_routes_by_host, has_route_changes, route_config, update_traffic_split_percentages,
+        upstreams_to_backends,
+    };
 
     #[test]
     fn test_socket_addr() {
@@ -326,6 +717,172 @@ mod test {
         assert!(addr.ip().is_ipv4());
         assert_eq!(addr.port(), 80);
     }
+
+    #[test]
+    fn test_upstreams_to_backends_with_weight() {
+        let upstreams = vec![
+            RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port: 3000,
+                weight: Some(5),
+                ..Default::default()
+            },
+            RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.2"),
+                port: 3000,
+                ..Default::default()
+            },
+        ];
+
+        let backends = upstreams_to_backends(&upstreams).unwrap();
+        let weights = backends
+            .iter()
+            .map(|b| (b.addr.to_string(), b.weight))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            weights,
+            vec![
+                ("127.0.0.1:3000".to_string(), 5),
+                ("127.0.0.2:3000".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upstreams_to_backends_with_unix_socket() {
+        let upstreams = vec![RouteUpstream {
+            ip: Cow::Borrowed("unix:///run/app.sock"),
+            port: 0,
+            ..Default::default()
+        }];
+
+        let backends = upstreams_to_backends(&upstreams).unwrap();
+        let backend = backends.first().unwrap();
+        let path = backend.addr.as_unix().and_then(|addr| addr.as_pathname());
+
+        assert_eq!(path, Some(std::path::Path::new("/run/app.sock")));
+        assert_eq!(backend.weight, 1);
+    }
+
+    #[test]
+    fn test_group_routes_by_host_keeps_order() {
+        let route = |host: &'static str, port: u16| Route {
+            host: Cow::Borrowed(host),
+            upstreams: vec![RouteUpstream {
+                ip: Cow::Borrowed("127.0.0.1"),
+                port,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let routes = vec![
+            route("example.com", 3000),
+            route("other.com", 3001),
+            route("example.com", 3002),
+        ];
+
+        let hosts = group_routes_by_host(&routes)
+            .into_iter()
+            .map(|(host, routes)| (host, routes.iter().map(|r| r.upstreams[0].port).collect()))
+            .collect::<Vec<(&str, Vec<u16>)>>();
+
+        assert_eq!(
+            hosts,
+            vec![("example.com", vec![3000, 3002]), ("other.com", vec![3001])]
+        );
+    }
+
+    #[test]
+    fn test_has_route_changes_compares_the_route_config() {
+        let container = |route: &Route| RouteStoreContainer {
+            route_config: route_config(route),
+            ..Default::default()
+        };
+
+        let route = Route {
+            host: Cow::Borrowed("example.com"),
+            ..Default::default()
+        };
+        let balanced = Route {
+            host: Cow::Borrowed("example.com"),
+            load_balancing: Some(RouteLoadBalancing {
+                algorithm: LoadBalancingAlgorithm::LeastConnections,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert!(!has_route_changes(
+            &[container(&route)],
+            &[container(&route)]
+        ));
+        assert!(has_route_changes(
+            &[container(&route)],
+            &[container(&balanced)]
+        ));
+
+        // the upstreams resolved through DNS come in any order
+        let upstream = |ip: &'static str| RouteUpstream {
+            ip: Cow::Borrowed(ip),
+            port: 3000,
+            ..Default::default()
+        };
+        let resolved = Route {
+            upstreams: vec![upstream("10.0.0.2"), upstream("10.0.0.3")],
+            ..route.clone()
+        };
+        let shuffled = Route {
+            upstreams: vec![upstream("10.0.0.3"), upstream("10.0.0.2")],
+            ..route.clone()
+        };
+        assert!(!has_route_changes(
+            &[container(&resolved)],
+            &[container(&shuffled)]
+        ));
+    }
+    #[test]
+    fn test_update_traffic_split_percentages_keeps_the_groups() {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]);
+        let load_balancer: Arc<RouteLoadBalancer> = Arc::new(load_balancer.unwrap().into());
+        let traffic_split = Arc::new(TrafficSplit::new(
+            vec![UpstreamGroup::new(
+                "canary".to_string(),
+                5,
+                Arc::clone(&load_balancer),
+                vec![],
+            )],
+            None,
+        ));
+        stores::insert_route(
+            "split.discovery.test".to_string(),
+            vec![RouteStoreContainer {
+                traffic_split: Some(traffic_split),
+                ..Default::default()
+            }],
+        );
+
+        let route = Route {
+            host: Cow::Borrowed("split.discovery.test"),
+            traffic_split: Some(RouteTrafficSplit {
+                groups: vec![RouteUpstreamGroup {
+                    name: Cow::Borrowed("canary"),
+                    percent: 25,
+                    upstreams: vec![],
+                }],
+                sticky: None,
+            }),
+            ..Default::default()
+        };
+        update_traffic_split_percentages(&[route]);
+
+        // the route and the load balancer of the group are the same
+        let route_containers = stores::get_route_by_key("split.discovery.test").unwrap();
+        let group = &route_containers[0].traffic_split.as_ref().unwrap().groups[0];
+        assert_eq!(group.percent(), 25);
+        assert!(Arc::ptr_eq(&group.load_balancer, &load_balancer));
+    }
 }
 
 // #[cfg(test)]
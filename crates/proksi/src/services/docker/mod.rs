@@ -17,8 +17,11 @@ use tokio::sync::broadcast::Sender;
 use tracing::{debug, info};
 
 use crate::{
-    config::{Config, DockerServiceMode, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin},
-    MsgProxy, MsgRoute,
+    config::{
+        Config, DockerServiceMode, HealthCheckStatusRange, HealthCheckType, RouteHeaderAdd,
+        RouteHeaderRemove, RouteHealthCheck, RoutePlugin, UNIX_SOCKET_PREFIX,
+    },
+    MsgProxy, MsgRoute, MsgUpstream,
 };
 
 /// Based on the provided endpoint, returns the correct Docker client
@@ -33,19 +36,109 @@ fn connect_to_docker(endpoint: &str) -> Result<Docker, bollard::errors::Error> {
     Docker::connect_with_local_defaults()
 }
 
+/// Parses the `proksi.weight` label, ignoring values that are not a positive number
+fn parse_weight_label(value: &str) -> Option<i8> {
+    match value.trim().parse::<i8>() {
+        Ok(weight) if weight > 0 => Some(weight),
+        _ => {
+            info!("Invalid value for label proksi.weight: {value:?}, using the default weight");
+            None
+        }
+    }
+}
+
+/// Parses the `proksi.socket` label, ignoring values that are not the absolute path
+/// of a unix socket (ex: `unix:///run/app.sock`)
+fn parse_socket_label(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value
+        .strip_prefix(UNIX_SOCKET_PREFIX)
+        .is_some_and(|path| path.starts_with('/'))
+    {
+        return Some(value.to_string());
+    }
+
+    info!("Invalid value for label proksi.socket: {value:?}, expected unix:///path/to.sock");
+    None
+}
+
+/// Parses the `proksi.upstream_group.percent` label, ignoring values that are not a percentage
+fn parse_percent_label(value: &str) -> Option<u8> {
+    match value.trim().parse::<u8>() {
+        Ok(percent) if percent <= 100 => Some(percent),
+        _ => {
+            info!("Invalid value for label proksi.upstream_group.percent: {value:?}, using 0");
+            None
+        }
+    }
+}
+
+/// Parses a `proksi.health_check.*` label into the given health check,
+/// ignoring invalid values
+fn parse_health_check_label(health_check: &mut Option<RouteHealthCheck>, key: &str, value: &str) {
+    let health_check = health_check.get_or_insert_with(RouteHealthCheck::default);
+    let value = value.trim();
+
+    match key.trim_start_matches("proksi.health_check.") {
+        "type" => match value.to_lowercase().as_str() {
+            "tcp" => health_check.check_type = HealthCheckType::Tcp,
+            "http" => health_check.check_type = HealthCheckType::Http,
+            "https" => health_check.check_type = HealthCheckType::Https,
+            _ => info!("Invalid value for label {key}: {value:?}, expected tcp, http or https"),
+        },
+        "path" if value.starts_with('/') => health_check.path = Cow::Owned(value.to_string()),
+        "host" if !value.is_empty() => health_check.host = Some(Cow::Owned(value.to_string())),
+        // ex: "200-399" or "200"
+        "expected_status" => {
+            if let Some(range) = parse_status_range(value) {
+                health_check.expected_status = range;
+            } else {
+                info!("Invalid value for label {key}: {value:?}, expected ex: 200-399");
+            }
+        }
+        "timeout_secs" => set_positive_label(&mut health_check.timeout_secs, key, value),
+        "interval_secs" => set_positive_label(&mut health_check.interval_secs, key, value),
+        "healthy_threshold" => set_positive_label(&mut health_check.healthy_threshold, key, value),
+        "unhealthy_threshold" => {
+            set_positive_label(&mut health_check.unhealthy_threshold, key, value);
+        }
+        _ => info!("Invalid health check label {key}: {value:?}"),
+    }
+}
+
+/// Sets the field to the label value if it is a number greater than 0
+fn set_positive_label<T: FromStr + Default + PartialOrd>(field: &mut T, key: &str, value: &str) {
+    match value.parse::<T>() {
+        Ok(number) if number > T::default() => *field = number,
+        _ => info!("Invalid value for label {key}: {value:?}, expected a positive number"),
+    }
+}
+
+/// Parses a status code range such as "200-399" (or a single status code)
+fn parse_status_range(value: &str) -> Option<HealthCheckStatusRange> {
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+    let range = HealthCheckStatusRange {
+        min: min.trim().parse().ok()?,
+        max: max.trim().parse().ok()?,
+    };
+
+    (range.min >= 100 && range.min <= range.max && range.max <= 599).then_some(range)
+}
+
 #[derive(Debug, Default)]
 pub struct ProksiDockerRoute {
-    upstreams: Vec<String>,
+    upstreams: Vec<MsgUpstream>,
     path_matchers: Vec<String>,
 
     host_header_add: Option<Vec<RouteHeaderAdd>>,
     host_header_remove: Option<Vec<RouteHeaderRemove>>,
     ssl_certificate_self_signed_on_failure: bool,
     plugins: Option<Vec<RoutePlugin>>,
+    health_check: Option<RouteHealthCheck>,
 }
 
 impl ProksiDockerRoute {
-    pub fn new(upstreams: Vec<String>, path_matchers: Vec<String>) -> Self {
+    pub fn new(upstreams: Vec<MsgUpstream>, path_matchers: Vec<String>) -> Self {
         Self {
             upstreams,
             path_matchers,
@@ -53,6 +146,7 @@ impl ProksiDockerRoute {
             host_header_remove: None,
             ssl_certificate_self_signed_on_failure: false,
             plugins: None,
+            health_check: None,
         }
     }
 }
@@ -124,6 +218,11 @@ impl LabelService {
             let mut proxy_enabled = false;
             let mut proxy_host = "";
             let mut proxy_port = "";
+            let mut proxy_socket: Option<String> = None;
+            let mut proxy_weight: Option<i8> = None;
+            let mut upstream_group: Option<String> = None;
+            let mut upstream_group_percent: Option<u8> = None;
+            let mut health_check: Option<RouteHealthCheck> = None;
             let mut match_with_path_patterns = vec![];
             let mut route_header_add: Option<Vec<RouteHeaderAdd>> = None;
             let mut route_header_remove: Option<Vec<RouteHeaderRemove>> = None;
@@ -148,6 +247,15 @@ impl LabelService {
                         "proksi.enabled" => proxy_enabled = v == "true",
                         "proksi.host" => proxy_host = v,
                         "proksi.port" => proxy_port = v,
+                        "proksi.socket" => proxy_socket = parse_socket_label(v),
+                        "proksi.weight" => proxy_weight = parse_weight_label(v),
+                        "proksi.upstream_group" => upstream_group = Some(v.clone()),
+                        "proksi.upstream_group.percent" => {
+                            upstream_group_percent = parse_percent_label(v);
+                        }
+                        k if k.starts_with("proksi.health_check.") => {
+                            parse_health_check_label(&mut health_check, k, v);
+                        }
                         k if k.starts_with("proksi.match_with.path.pattern.") => {
                             match_with_path_patterns.push(v.clone());
                         }
@@ -197,60 +305,74 @@ impl LabelService {
                 continue;
             }
 
-            if proxy_host.is_empty() || proxy_port.is_empty() {
+            if proxy_host.is_empty() || (proxy_port.is_empty() && proxy_socket.is_none()) {
                 info!(
                     "Service {service_name:?} does not have the label
-                    proksi.host set to a valid host or proksi.port set to a valid port"
+                    proksi.host set to a valid host or proksi.port (or proksi.socket) set to a valid port"
                 );
                 continue;
             }
 
-            // TODO offer an option to load balance directly to the container IPs
-            // of the service instead of through the docker dns
-            if !host_map.contains_key(proxy_host) {
-                let mut routed = ProksiDockerRoute::default();
-                routed
-                    .upstreams
-                    .push(format!("tasks.{service_name}:{proxy_port}"));
-                routed.path_matchers = match_with_path_patterns;
-                routed.host_header_add = route_header_add;
-                routed.host_header_remove = route_header_remove;
-                routed.ssl_certificate_self_signed_on_failure =
-                    ssl_certificate_self_signed_on_failure;
-
-                // This part is optional
-                let mut plugins: Vec<RoutePlugin> = vec![];
-                if let Some(plugin) = Self::get_oauth2_plugin(
-                    oauth2_provider,
-                    oauth2_client_id,
-                    oauth2_client_secret,
-                    oauth2_jwt_secret,
-                    oauth2_validations,
-                ) {
-                    plugins.push(plugin);
+            // A group with no percentage receives no traffic until one is set
+            let upstream_group =
+                upstream_group.map(|name| (name, upstream_group_percent.unwrap_or(0)));
+            let upstream = MsgUpstream {
+                addr: proxy_socket.unwrap_or_else(|| format!("tasks.{service_name}:{proxy_port}")),
+                weight: proxy_weight,
+                group: upstream_group,
+            };
+
+            // Other services of the host only join it as upstream groups
+            // (ex: the canary release of the service)
+            if let Some(routed) = host_map.get_mut(proxy_host) {
+                if upstream.group.is_some() || routed.upstreams.iter().all(|u| u.group.is_some()) {
+                    routed.upstreams.push(upstream);
                 }
+                continue;
+            }
 
-                if docker_request_id {
-                    plugins.push(RoutePlugin {
-                        name: Cow::Borrowed("request_id"),
-                        config: None,
-                    });
-                }
+            // TODO offer an option to load balance directly to the container IPs
+            // of the service instead of through the docker dns
+            let mut routed = ProksiDockerRoute::default();
+            routed.upstreams.push(upstream);
+            routed.path_matchers = match_with_path_patterns;
+            routed.host_header_add = route_header_add;
+            routed.host_header_remove = route_header_remove;
+            routed.ssl_certificate_self_signed_on_failure = ssl_certificate_self_signed_on_failure;
+            routed.health_check = health_check;
+
+            // This part is optional
+            let mut plugins: Vec<RoutePlugin> = vec![];
+            if let Some(plugin) = Self::get_oauth2_plugin(
+                oauth2_provider,
+                oauth2_client_id,
+                oauth2_client_secret,
+                oauth2_jwt_secret,
+                oauth2_validations,
+            ) {
+                plugins.push(plugin);
+            }
 
-                if basic_auth_user.is_some() && basic_auth_password.is_some() {
-                    let mut map = HashMap::new();
-                    map.insert(Cow::Borrowed("user"), json!(basic_auth_user.unwrap()));
-                    map.insert(Cow::Borrowed("pass"), json!(basic_auth_password.unwrap()));
+            if docker_request_id {
+                plugins.push(RoutePlugin {
+                    name: Cow::Borrowed("request_id"),
+                    config: None,
+                });
+            }
 
-                    plugins.push(RoutePlugin {
-                        name: Cow::Borrowed("basic_auth"),
-                        config: Some(map),
-                    });
-                }
+            if basic_auth_user.is_some() && basic_auth_password.is_some() {
+                let mut map = HashMap::new();
+                map.insert(Cow::Borrowed("user"), json!(basic_auth_user.unwrap()));
+                map.insert(Cow::Borrowed("pass"), json!(basic_auth_password.unwrap()));
 
-                routed.plugins = Some(plugins);
-                host_map.insert(proxy_host.to_string(), routed);
+                plugins.push(RoutePlugin {
+                    name: Cow::Borrowed("basic_auth"),
+                    config: Some(map),
+                });
             }
+
+            routed.plugins = Some(plugins);
+            host_map.insert(proxy_host.to_string(), routed);
         }
 
         host_map
@@ -259,6 +381,7 @@ impl LabelService {
     /// Generate a list of containers based on the provided filters
     /// This will return a mapping between host <> ips for each container
     /// Does not work for docker in Swarm mode
+    #[allow(clippy::too_many_lines)]
     async fn list_containers<T>(
         &self,
         filters: HashMap<T, Vec<T>>,
@@ -293,6 +416,11 @@ impl LabelService {
             let mut proxy_enabled = false;
             let mut proxy_host = "";
             let mut proxy_port = "";
+            let mut proxy_socket: Option<String> = None;
+            let mut proxy_weight: Option<i8> = None;
+            let mut upstream_group: Option<String> = None;
+            let mut upstream_group_percent: Option<u8> = None;
+            let mut health_check: Option<RouteHealthCheck> = None;
             let mut match_with_path_patterns = vec![];
             let mut route_header_add: Option<Vec<RouteHeaderAdd>> = None;
             let mut route_header_remove: Option<Vec<RouteHeaderRemove>> = None;
@@ -306,6 +434,15 @@ impl LabelService {
                         "proksi.enabled" => proxy_enabled = v == "true",
                         "proksi.host" => proxy_host = v,
                         "proksi.port" => proxy_port = v,
+                        "proksi.socket" => proxy_socket = parse_socket_label(v),
+                        "proksi.weight" => proxy_weight = parse_weight_label(v),
+                        "proksi.upstream_group" => upstream_group = Some(v.clone()),
+                        "proksi.upstream_group.percent" => {
+                            upstream_group_percent = parse_percent_label(v);
+                        }
+                        k if k.starts_with("proksi.health_check.") => {
+                            parse_health_check_label(&mut health_check, k, v);
+                        }
                         "proksi.headers.add" => {
                             let deser: Vec<RouteHeaderAdd> =
                                 serde_json::from_str(v).unwrap_or(vec![]);
@@ -337,10 +474,10 @@ impl LabelService {
                 continue;
             }
 
-            if proxy_port.is_empty() || proxy_host.is_empty() {
+            if (proxy_port.is_empty() && proxy_socket.is_none()) || proxy_host.is_empty() {
                 info!(
                     "Container {container_names:?} does not have a
-                  `proksi.port` label or a `proksi.host` label"
+                  `proksi.port` (or `proksi.socket`) label or a `proksi.host` label"
                 );
                 continue;
             }
@@ -352,9 +489,27 @@ impl LabelService {
                 routed.host_header_remove = route_header_remove;
                 routed.ssl_certificate_self_signed_on_failure =
                     ssl_certificate_self_signed_on_failure;
+                routed.health_check = health_check;
                 host_map.insert(proxy_host.to_string(), routed);
             }
 
+            let upstream_group =
+                upstream_group.map(|name| (name, upstream_group_percent.unwrap_or(0)));
+
+            // Containers listening on a unix socket (shared through a volume) have no endpoints
+            if let Some(socket) = proxy_socket {
+                host_map
+                    .get_mut(proxy_host)
+                    .unwrap()
+                    .upstreams
+                    .push(MsgUpstream {
+                        addr: socket,
+                        weight: proxy_weight,
+                        group: upstream_group,
+                    });
+                continue;
+            }
+
             // map container endpoints
             let network_settings = &container.network_settings.as_ref().unwrap();
             let networks = network_settings.networks.as_ref().unwrap();
@@ -375,7 +530,11 @@ impl LabelService {
                     .get_mut(proxy_host)
                     .unwrap()
                     .upstreams
-                    .push(ip_plus_port);
+                    .push(MsgUpstream {
+                        addr: ip_plus_port,
+                        weight: proxy_weight,
+                        group: upstream_group.clone(),
+                    });
             }
         }
 
@@ -429,16 +588,17 @@ impl LabelService {
 
             // Notify the route discovery service of the new host
             self.sender
-                .send(MsgProxy::NewRoute(MsgRoute {
+                .send(MsgProxy::NewRoute(Box::new(MsgRoute {
                     host: host_value,
                     upstreams: value.upstreams,
                     path_matchers: value.path_matchers,
                     host_headers_add: value.host_header_add.unwrap_or_else(Vec::new),
                     host_headers_remove: value.host_header_remove.unwrap_or_else(Vec::new),
                     plugins: value.plugins.unwrap_or_else(Vec::new),
+                    health_check: value.health_check,
 
                     self_signed_certs: value.ssl_certificate_self_signed_on_failure,
-                }))
+                })))
                 .ok();
         }
     }
@@ -1,13 +1,22 @@
-use std::time::Duration;
+use std::{collections::HashMap, sync::Arc, time::Duration, time::Instant};
 
 use async_trait::async_trait;
 use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    lb::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck},
     server::{ListenFds, ShutdownWatch},
     services::Service,
+    ErrorType::CustomCode,
 };
+use tokio::task::JoinHandle;
 
+use crate::config::{HealthCheckType, RouteHealthCheck};
+use crate::proxy_server::load_balancer::RouteLoadBalancer;
 use crate::stores::{self};
 
+/// How often the service looks for routes that are due for a health check
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Health check service that will run health checks on all upstreams
 /// And update the route store with the new healthy upstreams.
 /// This service will run in a separate thread.
@@ -19,27 +28,117 @@ impl HealthService {
     }
 }
 
+/// Builds the health check for the upstreams of a route from its configuration.
+/// `host` is used as the Host header (and SNI) unless the configuration overrides it.
+pub fn from_route_config(
+    host: &str,
+    config: &RouteHealthCheck,
+) -> Box<dyn HealthCheck + Send + Sync + 'static> {
+    let timeout = Some(Duration::from_secs(config.timeout_secs));
+
+    if config.check_type == HealthCheckType::Tcp {
+        let mut check = TcpHealthCheck::new();
+        check.consecutive_success = config.healthy_threshold;
+        check.consecutive_failure = config.unhealthy_threshold;
+        check.peer_template.options.connection_timeout = timeout;
+        return check;
+    }
+
+    let host = config.host.as_deref().unwrap_or(host);
+    let mut check = HttpHealthCheck::new(host, config.check_type == HealthCheckType::Https);
+    check.consecutive_success = config.healthy_threshold;
+    check.consecutive_failure = config.unhealthy_threshold;
+    check.peer_template.options.connection_timeout = timeout;
+    check.peer_template.options.read_timeout = timeout;
+    // upstreams commonly use self-signed certificates
+    check.peer_template.options.verify_cert = false;
+    check.peer_template.options.verify_hostname = false;
+
+    if let Ok(mut req) = RequestHeader::build("GET", config.path.as_bytes(), None) {
+        req.append_header("Host", host).ok();
+        check.req = req;
+    }
+
+    let expected_status = config.expected_status;
+    check.validator = Some(Box::new(move |resp: &ResponseHeader| {
+        let status = resp.status.as_u16();
+        if expected_status.contains(status) {
+            return Ok(());
+        }
+
+        pingora::Error::e_explain(
+            CustomCode("unexpected status code", status),
+            "during http healthcheck",
+        )
+    }));
+
+    Box::new(check)
+}
+
+/// The last health check of a route
+struct RouteCheck {
+    /// The load balancer of the route when it was checked, the routes of a host
+    /// can be reordered or replaced by a reload
+    load_balancer: Arc<RouteLoadBalancer>,
+    started_at: Instant,
+    task: JoinHandle<()>,
+}
+
 async fn run_health_check_loop() {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
-    interval.tick().await;
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    // The last health check of each route of a host
+    let mut last_checks: HashMap<(String, usize), RouteCheck> = HashMap::new();
 
     loop {
         interval.tick().await;
 
-        for (host, route_container) in &stores::get_routes() {
-            tracing::trace!("Running health check for host {}", host);
+        let routes = stores::get_routes();
+
+        // forget the routes that were removed since the last tick
+        last_checks.retain(|(host, index), _| {
+            routes
+                .get(host)
+                .is_some_and(|host_routes| *index < host_routes.len())
+        });
+
+        for (host, routes) in &routes {
+            for (index, route_container) in routes.iter().enumerate() {
+                let check_interval =
+                    Duration::from_secs(route_container.health_check.interval_secs);
+                let check_key = (host.clone(), index);
+                let is_due = last_checks.get(&check_key).is_none_or(|check| {
+                    !Arc::ptr_eq(&check.load_balancer, &route_container.load_balancer)
+                        || (check.started_at.elapsed() >= check_interval
+                            && check.task.is_finished())
+                });
+
+                if !is_due {
+                    continue;
+                }
+
+                tracing::trace!("Running health check for host {} (route {})", host, index);
 
-            // clone the route_container
-            let route_container = route_container.clone();
-            route_container.load_balancer.update().await.ok();
-            route_container
-                .load_balancer
-                .backends()
-                .run_health_check(false)
-                .await;
+                // the upstream groups of the route share its health check
+                let load_balancers: Vec<_> = route_container.load_balancers().cloned().collect();
 
-            // insert it back into the store
-            stores::insert_route(host.clone(), route_container);
+                // routes are checked concurrently so that slow upstreams
+                // don't delay the checks of the other routes
+                let task = tokio::spawn(async move {
+                    for load_balancer in load_balancers {
+                        load_balancer.update().await.ok();
+                        load_balancer.backends().run_health_check(false).await;
+                    }
+                });
+
+                last_checks.insert(
+                    check_key,
+                    RouteCheck {
+                        load_balancer: route_container.load_balancer.clone(),
+                        started_at: Instant::now(),
+                        task,
+                    },
+                );
+            }
         }
     }
 }
@@ -65,3 +164,22 @@ impl Service for HealthService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_route_config_thresholds() {
+        let config = RouteHealthCheck {
+            check_type: HealthCheckType::Http,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+            ..Default::default()
+        };
+
+        let check = from_route_config("example.com", &config);
+        assert_eq!(check.health_threshold(true), 2);
+        assert_eq!(check.health_threshold(false), 3);
+    }
+}
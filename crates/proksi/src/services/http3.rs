@@ -0,0 +1,561 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use dashmap::DashMap;
+use h2::{
+    client::{ResponseFuture, SendRequest},
+    SendStream,
+};
+use h3::server::RequestStream;
+use http::{header, HeaderName, HeaderValue, Method, Request, Response};
+use pingora::{
+    server::{ListenFds, Server, ShutdownWatch},
+    services::Service,
+};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::config::{Config, ServerCfg};
+use crate::proxy_server::proxy_protocol::{socket_path, ConnectionAddrs};
+use crate::services::relay;
+use crate::stores::{self, certificates::Certificate};
+
+/// How often the certificates are reloaded from the certificate store
+const CERTIFICATES_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The shortest time between two reloads, when handshakes keep missing a certificate
+const MIN_CERTIFICATES_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection-specific headers, which don't exist in HTTP/3
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "expect",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+type H3RequestStream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+type H3RequestSender = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type H3RequestReceiver = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// The certificates of the certificate store, for the TLS handshakes of the QUIC connections.
+///
+/// The handshakes can't wait on the store (ex: Redis), so the certificates of the hosts
+/// are loaded in the background, and right away when a handshake finds none.
+#[derive(Debug, Default)]
+struct QuicCertificates {
+    /// By host, as in the certificate store (ex: `example.com` or `*.example.com`)
+    by_host: DashMap<String, Arc<CertifiedKey>>,
+    missing: Notify,
+}
+
+impl QuicCertificates {
+    /// Finds the certificate of a host like [`crate::proxy_server::cert_store::CertStore`]:
+    /// the one of the host, of its wildcard host and then of the default route
+    fn find(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        let get = |host: &str| self.by_host.get(host).map(|cert| cert.clone());
+
+        get(host)
+            .or_else(|| stores::wildcard_hosts(host).find_map(|wildcard| get(&wildcard)))
+            .or_else(|| get(&stores::get_default_route_host()?))
+    }
+
+    /// Loads the certificates of the hosts of the routes
+    async fn load(&self) {
+        let hosts: HashSet<String> = stores::get_routes().keys().cloned().collect();
+        self.by_host.retain(|host, _| hosts.contains(host));
+
+        let store = stores::global::get_store();
+        for host in hosts {
+            let Some(cert) = store.get_certificate(&host).await else {
+                self.by_host.remove(&host);
+                continue;
+            };
+
+            let is_loaded = self.by_host.get(&host).is_some_and(|loaded| {
+                let leaf = loaded.end_entity_cert().ok();
+                cert.leaf
+                    .to_der()
+                    .is_ok_and(|der| leaf.is_some_and(|leaf| **leaf == der))
+            });
+            if is_loaded {
+                continue;
+            }
+
+            match certified_key(&cert) {
+                Ok(key) => {
+                    self.by_host.insert(host, Arc::new(key));
+                }
+                Err(err) => {
+                    tracing::error!("could not load the certificate of {host} for HTTP/3: {err}");
+                }
+            }
+        }
+    }
+
+    async fn reload(self: Arc<Self>) {
+        loop {
+            self.load().await;
+
+            tokio::time::sleep(MIN_CERTIFICATES_RELOAD_INTERVAL).await;
+            let remaining = CERTIFICATES_RELOAD_INTERVAL - MIN_CERTIFICATES_RELOAD_INTERVAL;
+            tokio::time::timeout(remaining, self.missing.notified())
+                .await
+                .ok();
+        }
+    }
+}
+
+impl ResolvesServerCert for QuicCertificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().unwrap_or_default();
+        let cert = self.find(host);
+        if cert.is_none() {
+            tracing::info!("No certificate found for host: {:?}", host);
+            // ex: a host added since the last reload
+            self.missing.notify_one();
+        }
+
+        cert
+    }
+}
+
+/// Converts a certificate of the store (OpenSSL) to the one of the QUIC handshakes (rustls)
+fn certified_key(cert: &Certificate) -> Result<CertifiedKey, anyhow::Error> {
+    let mut chain = vec![CertificateDer::from(cert.leaf.to_der()?)];
+    if let Some(intermediate) = &cert.chain {
+        chain.push(CertificateDer::from(intermediate.to_der()?));
+    }
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key.private_key_to_pkcs8()?));
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(chain, key))
+}
+
+/// Serves the routes over HTTP/3 (QUIC).
+///
+/// The requests are relayed over HTTP/2 (h2c) to the HTTPS proxy service on a unix socket,
+/// so they are routed exactly like the HTTPS requests (matchers, plugins, upstreams, etc.).
+pub struct Http3Service {
+    address: String,
+    /// The unix socket of the HTTPS proxy service
+    target: Arc<Path>,
+    certificates: Arc<QuicCertificates>,
+}
+
+impl Http3Service {
+    fn endpoint(&self) -> Result<quinn::Endpoint, anyhow::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_cert_resolver(self.certificates.clone());
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        Ok(quinn::Endpoint::server(config, self.address.parse()?)?)
+    }
+
+    async fn serve(&self, endpoint: &quinn::Endpoint) {
+        while let Some(incoming) = endpoint.accept().await {
+            let target = self.target.clone();
+            let local_addr = endpoint.local_addr();
+
+            tokio::spawn(async move {
+                if let Err(err) = serve_connection(incoming, local_addr, target).await {
+                    tracing::debug!("HTTP/3 connection closed: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// The HTTP/2 connection to the HTTPS proxy service relaying the requests of a QUIC connection
+struct Relay {
+    send_request: SendRequest<Bytes>,
+    connection: JoinHandle<()>,
+}
+
+impl Relay {
+    async fn connect(target: &Path, addrs: ConnectionAddrs) -> Result<Self, anyhow::Error> {
+        let (upstream, relayed) = relay::connect(target, addrs).await?;
+
+        // HTTP/2, unlike HTTP/1.1, carries the trailers of the responses (ex: gRPC status)
+        let (send_request, connection) = h2::client::handshake(upstream).await?;
+        let connection = tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                tracing::debug!("HTTP/3 relay connection closed: {err}");
+            }
+            drop(relayed);
+        });
+
+        Ok(Relay {
+            send_request,
+            connection,
+        })
+    }
+}
+
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    local_addr: std::io::Result<SocketAddr>,
+    target: Arc<Path>,
+) -> Result<(), anyhow::Error> {
+    let connection = incoming.await?;
+    let local_addr = local_addr?;
+    let addrs = ConnectionAddrs {
+        client: connection.remote_address(),
+        server: SocketAddr::new(
+            connection.local_ip().unwrap_or(local_addr.ip()),
+            local_addr.port(),
+        ),
+    };
+
+    let mut connection =
+        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+
+    // The requests of the QUIC connection are multiplexed on a single HTTP/2 connection,
+    // opened again if the proxy service closed it (ex: idle timeout)
+    let mut relay: Option<Relay> = None;
+    while let Some(resolver) = connection.accept().await? {
+        if relay
+            .as_ref()
+            .is_none_or(|relay| relay.connection.is_finished())
+        {
+            match Relay::connect(&target, addrs).await {
+                Ok(connected) => relay = Some(connected),
+                Err(err) => {
+                    tracing::debug!("HTTP/3 relay connection failed: {err}");
+                    continue;
+                }
+            }
+        }
+
+        let Some(send_request) = relay.as_ref().map(|relay| relay.send_request.clone()) else {
+            continue;
+        };
+        tokio::spawn(async move {
+            let result = match resolver.resolve_request().await {
+                Ok((request, stream)) => relay_request(&request, stream, send_request).await,
+                Err(err) => Err(err.into()),
+            };
+
+            if let Err(err) = result {
+                tracing::debug!("HTTP/3 request failed: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Sends the request to the HTTPS proxy service and its response back to the client
+async fn relay_request(
+    request: &Request<()>,
+    stream: H3RequestStream,
+    send_request: SendRequest<Bytes>,
+) -> Result<(), anyhow::Error> {
+    let (mut sender, mut receiver) = stream.split();
+
+    // The headers are sent right away, streaming clients (ex: gRPC) can wait on the
+    // response headers before sending a body. Requests without a body are sent
+    // without one to the upstreams.
+    let with_body = has_body(request);
+    let (response, request_body) = send_request
+        .ready()
+        .await?
+        .send_request(relayed_request(request)?, !with_body)?;
+
+    let upload = async {
+        if with_body {
+            send_request_body(&mut receiver, request_body).await
+        } else {
+            Ok(())
+        }
+    };
+    let download = send_response(response, &mut sender);
+    tokio::pin!(upload, download);
+
+    // The response can end before the request body, ex: an error of the upstream
+    tokio::select! {
+        result = &mut download => result,
+        result = &mut upload => {
+            result?;
+            download.await
+        }
+    }
+}
+
+/// Whether the request has a body, from its `Content-Length` or else its method
+fn has_body(request: &Request<()>) -> bool {
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+
+    match content_length {
+        Some(content_length) => content_length > 0,
+        None => ![
+            Method::GET,
+            Method::HEAD,
+            Method::OPTIONS,
+            Method::DELETE,
+            Method::TRACE,
+        ]
+        .contains(request.method()),
+    }
+}
+
+/// Sends the request body and its trailers
+async fn send_request_body(
+    receiver: &mut H3RequestReceiver,
+    mut request_body: SendStream<Bytes>,
+) -> Result<(), anyhow::Error> {
+    while let Some(mut data) = recv_data(receiver).await? {
+        while !data.is_empty() {
+            request_body.reserve_capacity(data.len());
+            let capacity = std::future::poll_fn(|cx| request_body.poll_capacity(cx))
+                .await
+                .ok_or_else(|| anyhow::anyhow!("the request stream was closed"))??;
+            request_body.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+
+    match receiver.recv_trailers().await? {
+        Some(trailers) => request_body.send_trailers(trailers)?,
+        None => request_body.send_data(Bytes::new(), true)?,
+    }
+
+    Ok(())
+}
+
+/// Sends the response of the HTTPS proxy service, its body and trailers to the client
+async fn send_response(
+    response: ResponseFuture,
+    sender: &mut H3RequestSender,
+) -> Result<(), anyhow::Error> {
+    let (parts, mut body) = response.await?.into_parts();
+    sender
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    while let Some(data) = body.data().await {
+        let data = data?;
+        body.flow_control().release_capacity(data.len())?;
+        sender.send_data(data).await?;
+    }
+
+    if let Some(trailers) = body.trailers().await? {
+        sender.send_trailers(trailers).await?;
+    }
+    sender.finish().await?;
+
+    Ok(())
+}
+
+async fn recv_data(receiver: &mut H3RequestReceiver) -> Result<Option<Bytes>, anyhow::Error> {
+    Ok(receiver
+        .recv_data()
+        .await?
+        .map(|mut data| data.copy_to_bytes(data.remaining())))
+}
+
+/// The HTTP/2 request sent to the HTTPS proxy service for an HTTP/3 request
+fn relayed_request(request: &Request<()>) -> Result<Request<()>, anyhow::Error> {
+    let mut relayed = Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .body(())?;
+
+    let headers = relayed.headers_mut();
+    for (name, value) in request.headers() {
+        // HTTP/2 only allows the `trailers` value of TE (ex: gRPC)
+        let is_te_trailers = name == header::TE && value == "trailers";
+        if is_te_trailers || !is_hop_by_hop(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    // HTTP/3 clients can split the cookies in several headers, the routes read a single one
+    let cookies: Vec<_> = request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .collect();
+    if cookies.len() > 1 {
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookies.join("; "))?);
+    }
+
+    Ok(relayed)
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+#[async_trait]
+impl Service for Http3Service {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let endpoint = match self.endpoint() {
+            Ok(endpoint) => endpoint,
+            Err(err) => {
+                tracing::error!(
+                    "Could not start the HTTP/3 listener on {}: {err}",
+                    self.address
+                );
+                return;
+            }
+        };
+
+        tracing::info!("Starting HTTP/3 listener on {}", self.address);
+        tokio::spawn(self.certificates.clone().reload());
+
+        tokio::select! {
+            () = self.serve(&endpoint) => {}
+            _ = shutdown.changed() => {}
+        }
+
+        endpoint.close(0u32.into(), b"");
+        std::fs::remove_file(&self.target).ok();
+    }
+
+    fn name(&self) -> &'static str {
+        "http3_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Adds the HTTP/3 listener to the server if it is enabled, returns the unix socket
+/// the HTTPS proxy service receives the HTTP/3 requests on
+pub fn add_service(server: &mut Server, config: &Config) -> Option<PathBuf> {
+    if !config.server.http3.enabled {
+        return None;
+    }
+
+    let target = socket_path("http3");
+    server.add_service(Http3Service {
+        address: config.server.http3_address().to_string(),
+        target: target.clone().into(),
+        certificates: Arc::default(),
+    });
+
+    Some(target)
+}
+
+/// The `Alt-Svc` header advertising the HTTP/3 listener, `None` if it is disabled
+pub fn alt_svc(server: &ServerCfg) -> Option<HeaderValue> {
+    if !server.http3.enabled {
+        return None;
+    }
+
+    let port = server.http3_address().parse::<SocketAddr>().ok()?.port();
+    let max_age = server.http3.alt_svc_max_age_secs;
+    HeaderValue::from_str(&format!("h3=\":{port}\"; ma={max_age}")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::config::ServerHttp3;
+
+    #[test]
+    fn test_relayed_request() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://example.com/api.Users/List?page=2")
+            .header("cookie", "session=abc")
+            .header("cookie", "theme=dark")
+            .header("connection", "keep-alive")
+            .header("te", "trailers")
+            .header("x-request-id", "42")
+            .body(())
+            .unwrap();
+
+        let relayed = relayed_request(&request).unwrap();
+        assert_eq!(relayed.method(), "POST");
+        assert_eq!(relayed.uri(), "https://example.com/api.Users/List?page=2");
+
+        let header = |name: &str| relayed.headers().get(name).map(|v| v.to_str().unwrap());
+        assert_eq!(header("cookie"), Some("session=abc; theme=dark"));
+        assert_eq!(header("x-request-id"), Some("42"));
+        assert_eq!(header("te"), Some("trailers"));
+        assert_eq!(header("connection"), None);
+
+        let request = Request::builder()
+            .uri("https://example.com/")
+            .header("te", "gzip")
+            .body(())
+            .unwrap();
+        assert!(!relayed_request(&request)
+            .unwrap()
+            .headers()
+            .contains_key("te"));
+    }
+
+    #[test]
+    fn test_has_body() {
+        let request = |method: &str, content_length: Option<&str>| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri("https://example.com/");
+            if let Some(content_length) = content_length {
+                request = request.header("content-length", content_length);
+            }
+            request.body(()).unwrap()
+        };
+
+        assert!(!has_body(&request("GET", None)));
+        assert!(!has_body(&request("POST", Some("0"))));
+        assert!(has_body(&request("POST", Some("12"))));
+        // ex: a streaming gRPC call
+        assert!(has_body(&request("POST", None)));
+        assert!(has_body(&request("DELETE", Some("12"))));
+    }
+
+    #[test]
+    fn test_alt_svc() {
+        let mut server = ServerCfg {
+            https_address: Some(Cow::Borrowed("0.0.0.0:8443")),
+            http_address: None,
+            metrics_address: None,
+            proxy_protocol: Default::default(),
+            client_ip: Default::default(),
+            http3: ServerHttp3::default(),
+        };
+        assert_eq!(alt_svc(&server), None);
+
+        server.http3.enabled = true;
+        assert_eq!(alt_svc(&server).unwrap(), "h3=\":8443\"; ma=86400");
+
+        server.http3.address = Some(Cow::Borrowed("[::]:4433"));
+        server.http3.alt_svc_max_age_secs = 60;
+        assert_eq!(alt_svc(&server).unwrap(), "h3=\":4433\"; ma=60");
+    }
+}
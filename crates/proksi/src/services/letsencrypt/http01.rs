@@ -218,7 +218,14 @@ impl LetsencryptService {
         loop {
             interval.tick().await;
             tracing::debug!("checking for new routes to create certificates for");
-            for (key, value) in &stores::get_routes() {
+            for (key, routes) in &stores::get_routes() {
+                // Passthrough hosts are served with the certificates of their upstreams
+                if routes.iter().any(|route| route.tls_passthrough) {
+                    continue;
+                }
+
+                let self_signed_on_failure =
+                    routes.iter().any(|route| route.self_signed_certificate);
                 if stores::global::get_store()
                     .get_certificates()
                     .await
@@ -227,8 +234,16 @@ impl LetsencryptService {
                     continue;
                 }
 
-                Self::handle_certificate_for_domain(key, account, value.self_signed_certificate)
-                    .await;
+                // Wildcard, IP and local hosts cannot be validated through HTTP-01
+                if !is_http01_issuable(key) {
+                    tracing::debug!("skipping let's encrypt certificate for host {key}");
+                    Self::create_self_signed_certificate(key, self_signed_on_failure)
+                        .await
+                        .ok();
+                    continue;
+                }
+
+                Self::handle_certificate_for_domain(key, account, self_signed_on_failure).await;
             }
         }
     }
@@ -244,7 +259,12 @@ impl LetsencryptService {
 
         loop {
             tracing::debug!("checking for certificates to renew");
-            for (domain, _) in &stores::get_routes() {
+            for (domain, routes) in &stores::get_routes() {
+                let passthrough = routes.iter().any(|route| route.tls_passthrough);
+                if passthrough || !is_http01_issuable(domain) {
+                    continue;
+                }
+
                 let Ok(Some(cert)) = account.certificate(domain) else {
                     continue;
                 };
@@ -307,6 +327,16 @@ impl LetsencryptService {
     }
 }
 
+/// Whether Let's Encrypt can issue a certificate for the host through an HTTP-01 challenge:
+/// wildcard hosts require a DNS-01 challenge, IP addresses and local hosts are not publicly
+/// resolvable.
+fn is_http01_issuable(host: &str) -> bool {
+    !host.contains('*')
+        && host.contains('.')
+        && host.parse::<std::net::IpAddr>().is_err()
+        && !matches!(host.rsplit('.').next(), Some("localhost" | "local"))
+}
+
 #[async_trait]
 impl Service for LetsencryptService {
     async fn start_service(
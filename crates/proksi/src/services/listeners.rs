@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    lb::Backend,
+    protocols::Stream,
+    server::{ListenFds, Server, ShutdownWatch},
+    services::{listening, Service},
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpStream, UdpSocket},
+    sync::OnceCell,
+};
+
+use crate::config::{Config, ConfigListener, ListenerProtocol};
+use crate::proxy_server::load_balancer::{ConnectionGuard, RouteLoadBalancer};
+use crate::services::{
+    discovery::upstreams_to_backends, health_check, metrics::UDP_SESSIONS_DROPPED,
+};
+
+/// How long the connection to an upstream can take
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The upstreams tried for a TCP connection before giving up
+const MAX_CONNECTION_ATTEMPTS: usize = 3;
+
+/// The largest UDP datagram
+const MAX_DATAGRAM_LEN: usize = 65_535;
+
+/// The upstreams of a TCP or UDP listener, balanced and health checked
+/// like the upstreams of the routes
+pub struct ListenerUpstreams {
+    name: String,
+    load_balancer: RouteLoadBalancer,
+    /// `None` when the upstreams are not health checked
+    health_check_interval: Option<Duration>,
+    /// Set once the upstreams are loaded in the load balancer
+    loaded: OnceCell<()>,
+}
+
+impl ListenerUpstreams {
+    pub fn from_config(config: &ConfigListener) -> Result<Self, anyhow::Error> {
+        let backends = upstreams_to_backends(&config.upstreams)?;
+        let mut load_balancer = RouteLoadBalancer::from_backends(
+            &config.load_balancing.clone().unwrap_or_default(),
+            backends,
+        );
+
+        let health_check = match config.protocol {
+            ListenerProtocol::Tcp => Some(config.health_check.clone().unwrap_or_default()),
+            // the default TCP check fails on UDP-only upstreams (ex: game servers)
+            ListenerProtocol::Udp => config.health_check.clone(),
+        };
+        if let Some(health_check) = &health_check {
+            load_balancer
+                .set_health_check(health_check::from_route_config(&config.name, health_check));
+        }
+
+        Ok(ListenerUpstreams {
+            name: config.name.to_string(),
+            load_balancer,
+            health_check_interval: health_check
+                .map(|health_check| Duration::from_secs(health_check.interval_secs)),
+            loaded: OnceCell::new(),
+        })
+    }
+
+    /// Selects a healthy upstream for the client, skipping the `excluded` ones
+    async fn select(
+        &self,
+        client_ip: IpAddr,
+        excluded: &[pingora::protocols::l4::socket::SocketAddr],
+    ) -> Result<(Backend, SocketAddr), anyhow::Error> {
+        self.loaded
+            .get_or_try_init(|| self.load_balancer.update())
+            .await?;
+
+        let key = self.load_balancer.connection_key(client_ip.to_canonical());
+        let backend = self
+            .load_balancer
+            .select(&key, excluded)
+            .filter(|backend| !excluded.contains(&backend.addr))
+            .ok_or_else(|| anyhow!("no upstream of the {} listener is available", self.name))?;
+
+        let addr = backend
+            .addr
+            .as_inet()
+            .copied()
+            .ok_or_else(|| anyhow!("the {} listener only supports IP upstreams", self.name))?;
+
+        Ok((backend, addr))
+    }
+
+    /// Connects to an upstream, trying the next one when the connection fails
+    async fn connect_tcp(
+        &self,
+        client_ip: IpAddr,
+    ) -> Result<(TcpStream, Option<ConnectionGuard>), anyhow::Error> {
+        self.loaded
+            .get_or_try_init(|| self.load_balancer.update())
+            .await?;
+
+        let key = self.load_balancer.connection_key(client_ip.to_canonical());
+        let name = format!("the {} listener", self.name);
+        connect_upstream(&name, &self.load_balancer, &key).await
+    }
+
+    /// Checks the health of the upstreams at the interval of the health check
+    async fn run_health_checks(&self) {
+        let Some(period) = self.health_check_interval else {
+            return;
+        };
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self
+                .loaded
+                .get_or_try_init(|| self.load_balancer.update())
+                .await
+            {
+                tracing::error!(
+                    "Could not load the upstreams of the {} listener: {err}",
+                    self.name
+                );
+                continue;
+            }
+
+            tracing::trace!("Running health check for listener {}", self.name);
+            self.load_balancer.backends().run_health_check(false).await;
+        }
+    }
+}
+
+/// Connects to an upstream of the load balancer selected by `key`,
+/// trying the next one when the connection fails
+pub async fn connect_upstream(
+    name: &str,
+    load_balancer: &RouteLoadBalancer,
+    key: &[u8],
+) -> Result<(TcpStream, Option<ConnectionGuard>), anyhow::Error> {
+    let mut failed = vec![];
+    for _ in 0..MAX_CONNECTION_ATTEMPTS {
+        let Some(backend) = load_balancer
+            .select(key, &failed)
+            .filter(|backend| !failed.contains(&backend.addr))
+        else {
+            break;
+        };
+
+        let Some(addr) = backend.addr.as_inet().copied() else {
+            bail!("{name} only supports IP upstreams");
+        };
+
+        match tokio::time::timeout(CONNECTION_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok((stream, load_balancer.track(&backend))),
+            Ok(Err(err)) => tracing::debug!("{name}, upstream {addr}: {err}"),
+            Err(_) => tracing::debug!("{name}, upstream {addr}: timed out"),
+        }
+
+        failed.push(backend.addr);
+    }
+
+    bail!("could not connect to the upstreams of {name}")
+}
+
+/// Proxies the connections of a TCP listener to its upstreams
+pub struct TcpProxy {
+    upstreams: Arc<ListenerUpstreams>,
+}
+
+impl TcpProxy {
+    async fn relay(&self, mut stream: Stream) -> Result<(), anyhow::Error> {
+        let client_ip = stream
+            .get_socket_digest()
+            .and_then(|digest| Some(digest.peer_addr()?.as_inet()?.ip()))
+            .ok_or_else(|| anyhow!("the {} listener only accepts TCP", self.upstreams.name))?;
+
+        // the connection counts for the least connections algorithms until it closes
+        let (mut upstream, _guard) = self.upstreams.connect_tcp(client_ip).await?;
+        upstream.set_nodelay(true)?;
+        copy_bidirectional(&mut stream, &mut upstream).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Err(err) = self.relay(stream).await {
+            tracing::debug!("{} listener connection closed: {err}", self.upstreams.name);
+        }
+
+        None
+    }
+}
+
+/// A UDP client and the socket connected to its upstream
+struct UdpSession {
+    upstream: UdpSocket,
+    last_active: Mutex<Instant>,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+}
+
+type UdpSessions = Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>;
+
+/// Proxies the datagrams of a UDP listener to its upstreams.
+///
+/// A client stays on the same upstream until no datagram goes either way
+/// for the idle timeout of the listener.
+pub struct UdpProxy {
+    name: String,
+    address: String,
+    upstreams: Arc<ListenerUpstreams>,
+    idle_timeout: Duration,
+    /// The most clients proxied at the same time, each one has its own socket
+    max_sessions: usize,
+}
+
+impl UdpProxy {
+    async fn serve(&self, socket: Arc<UdpSocket>) {
+        let sessions = UdpSessions::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let (len, client) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::debug!("{} listener: {err}", self.name);
+                    continue;
+                }
+            };
+
+            let (session, session_count) = {
+                let sessions = sessions.lock().unwrap();
+                (sessions.get(&client).cloned(), sessions.len())
+            };
+            let session = match session {
+                Some(session) => session,
+                // ex: a flood of spoofed source addresses, which would exhaust the sockets
+                None if session_count >= self.max_sessions => {
+                    tracing::debug!(
+                        "{} listener, client {client}: too many sessions, dropping the datagram",
+                        self.name
+                    );
+                    UDP_SESSIONS_DROPPED
+                        .with_label_values(&[self.name.as_str()])
+                        .inc();
+                    continue;
+                }
+                None => match self.open_session(&socket, &sessions, client).await {
+                    Ok(session) => session,
+                    Err(err) => {
+                        tracing::debug!("{} listener, client {client}: {err}", self.name);
+                        continue;
+                    }
+                },
+            };
+
+            session.touch();
+            if let Err(err) = session.upstream.send(&buf[..len]).await {
+                tracing::debug!("{} listener, client {client}: {err}", self.name);
+            }
+        }
+    }
+
+    /// Picks the upstream of a new client and starts relaying its replies
+    async fn open_session(
+        &self,
+        socket: &Arc<UdpSocket>,
+        sessions: &UdpSessions,
+        client: SocketAddr,
+    ) -> Result<Arc<UdpSession>, anyhow::Error> {
+        let (backend, addr) = self.upstreams.select(client.ip(), &[]).await?;
+
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let upstream = UdpSocket::bind(local).await?;
+        upstream.connect(addr).await?;
+
+        let session = Arc::new(UdpSession {
+            upstream,
+            last_active: Mutex::new(Instant::now()),
+        });
+        sessions.lock().unwrap().insert(client, session.clone());
+
+        tokio::spawn(relay_replies(
+            socket.clone(),
+            sessions.clone(),
+            session.clone(),
+            client,
+            self.idle_timeout,
+            self.upstreams.load_balancer.track(&backend),
+        ));
+
+        Ok(session)
+    }
+}
+
+/// Sends the datagrams of the upstream back to the client until the session is idle
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    sessions: UdpSessions,
+    session: Arc<UdpSession>,
+    client: SocketAddr,
+    idle_timeout: Duration,
+    _guard: Option<ConnectionGuard>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+    loop {
+        match tokio::time::timeout(idle_timeout, session.upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                session.touch();
+                if let Err(err) = socket.send_to(&buf[..len], client).await {
+                    tracing::debug!("UDP client {client}: {err}");
+                }
+            }
+            // ex: the upstream is down, the next datagram of the client picks another one
+            Ok(Err(err)) => {
+                tracing::debug!("UDP client {client}: {err}");
+                break;
+            }
+            // the client is still sending datagrams
+            Err(_) if session.idle_for() < idle_timeout => {}
+            Err(_) => break,
+        }
+    }
+
+    // the client may already have a newer session, ex: after an upstream error
+    let mut sessions = sessions.lock().unwrap();
+    if sessions
+        .get(&client)
+        .is_some_and(|current| Arc::ptr_eq(current, &session))
+    {
+        sessions.remove(&client);
+    }
+}
+
+#[async_trait]
+impl Service for UdpProxy {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let socket = match UdpSocket::bind(self.address.as_str()).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                tracing::error!(
+                    "Could not bind the {} listener to {}: {err}",
+                    self.name,
+                    self.address
+                );
+                return;
+            }
+        };
+
+        tracing::info!("Starting UDP listener {} on {}", self.name, self.address);
+        tokio::select! {
+            () = self.serve(socket) => {}
+            _ = shutdown.changed() => {}
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Runs the health checks of the upstreams of the listeners
+pub struct ListenerHealthService {
+    upstreams: Vec<Arc<ListenerUpstreams>>,
+}
+
+#[async_trait]
+impl Service for ListenerHealthService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        tracing::info!("Starting listener health check service");
+
+        for upstreams in &self.upstreams {
+            let upstreams = upstreams.clone();
+            tokio::spawn(async move { upstreams.run_health_checks().await });
+        }
+
+        shutdown.changed().await.ok();
+    }
+
+    fn name(&self) -> &'static str {
+        "listener_health_check_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Adds the TCP and UDP listeners of the configuration to the server
+pub fn add_services(server: &mut Server, config: &Config) -> Result<(), anyhow::Error> {
+    if config.listeners.is_empty() {
+        return Ok(());
+    }
+
+    let mut all_upstreams = Vec::with_capacity(config.listeners.len());
+    for listener in &config.listeners {
+        let upstreams = Arc::new(ListenerUpstreams::from_config(listener)?);
+
+        match listener.protocol {
+            ListenerProtocol::Tcp => {
+                let proxy = TcpProxy {
+                    upstreams: upstreams.clone(),
+                };
+                let mut service =
+                    listening::Service::new(format!("tcp_listener_{}", listener.name), proxy);
+                service.add_tcp(&listener.address);
+                server.add_service(service);
+            }
+            ListenerProtocol::Udp => server.add_service(UdpProxy {
+                name: format!("udp_listener_{}", listener.name),
+                address: listener.address.to_string(),
+                upstreams: upstreams.clone(),
+                idle_timeout: Duration::from_secs(listener.idle_timeout_secs),
+                max_sessions: listener.max_sessions,
+            }),
+        }
+
+        all_upstreams.push(upstreams);
+    }
+
+    server.add_service(ListenerHealthService {
+        upstreams: all_upstreams,
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::RouteUpstream;
+
+    /// The upstreams of a listener, on the given local ports
+    fn listener(protocol: ListenerProtocol, address: String, ports: &[u16]) -> ListenerUpstreams {
+        let upstreams = ports
+            .iter()
+            .map(|port| RouteUpstream {
+                port: *port,
+                ..Default::default()
+            })
+            .collect();
+
+        ListenerUpstreams::from_config(&ConfigListener {
+            name: Cow::Borrowed("test"),
+            protocol,
+            address: Cow::Owned(address),
+            upstreams,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connects_to_the_next_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up_port = upstream.local_addr().unwrap().port();
+        let down_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let upstreams = listener(
+            ListenerProtocol::Tcp,
+            "127.0.0.1:5432".to_string(),
+            &[down_port, up_port],
+        );
+
+        let client_ip = "203.0.113.7".parse().unwrap();
+        for _ in 0..2 {
+            let (stream, _) = upstreams.connect_tcp(client_ip).await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap().port(), up_port);
+        }
+    }
+
+    /// Starts a UDP upstream echoing the datagrams, returns its port
+    async fn udp_echo() -> u16 {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        echo_port
+    }
+
+    /// Serves a UDP listener of the upstream port and checks that the datagrams are relayed
+    async fn assert_udp_relays(upstream_port: u16, check_health: bool) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+        let upstreams = Arc::new(listener(
+            ListenerProtocol::Udp,
+            address.to_string(),
+            &[upstream_port],
+        ));
+
+        if check_health {
+            upstreams.load_balancer.update().await.unwrap();
+            upstreams.loaded.set(()).unwrap();
+            upstreams
+                .load_balancer
+                .backends()
+                .run_health_check(false)
+                .await;
+        }
+
+        let proxy = UdpProxy {
+            name: "udp_listener_game".to_string(),
+            address: address.to_string(),
+            upstreams,
+            idle_timeout: Duration::from_secs(1),
+            max_sessions: 16,
+        };
+        tokio::spawn(async move { proxy.serve(socket).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(address).await.unwrap();
+        let mut buf = [0u8; 64];
+        for message in [&b"ping"[..], b"pong"] {
+            client.send(message).await.unwrap();
+            let len = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_relays_datagrams() {
+        assert_udp_relays(udp_echo().await, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_relays_datagrams_after_health_check() {
+        // nothing listens on the TCP port of the upstream
+        assert_udp_relays(udp_echo().await, true).await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_drops_new_clients_past_max_sessions() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+        let upstreams = Arc::new(listener(
+            ListenerProtocol::Udp,
+            address.to_string(),
+            &[udp_echo().await],
+        ));
+
+        let proxy = UdpProxy {
+            name: "udp_listener_game".to_string(),
+            address: address.to_string(),
+            upstreams,
+            idle_timeout: Duration::from_secs(5),
+            max_sessions: 1,
+        };
+        tokio::spawn(async move { proxy.serve(socket).await });
+
+        let mut buf = [0u8; 64];
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.connect(address).await.unwrap();
+        first.send(b"ping").await.unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(1), first.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        // the only session is taken by the first client
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.connect(address).await.unwrap();
+        second.send(b"ping").await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_millis(200), second.recv(&mut buf)).await;
+        assert!(reply.is_err());
+    }
+}
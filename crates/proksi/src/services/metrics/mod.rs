@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+use http::Response;
+use once_cell::sync::Lazy;
+use pingora::{
+    apps::http_app::{HttpServer, ServeHttp},
+    protocols::http::ServerSession,
+    services::listening::Service,
+};
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+/// State of the circuit breaker of each upstream (0: closed, 1: open, 2: half-open)
+pub static UPSTREAM_CIRCUIT_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "proksi_upstream_circuit_state",
+        "Circuit breaker state of the upstream (0: closed, 1: open, 2: half-open)",
+        &["host", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Failed requests (connection errors, timeouts and 5xx responses) of each upstream
+pub static UPSTREAM_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_upstream_failures_total",
+        "Failed requests (connection errors, timeouts and 5xx responses) per upstream",
+        &["host", "upstream"]
+    )
+    .unwrap()
+});
+
+/// Requests mirrored to a shadow upstream, by result (sent, failed or dropped)
+pub static MIRROR_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_mirror_requests_total",
+        "Requests mirrored to a shadow upstream, by result (sent, failed or dropped)",
+        &["host", "result"]
+    )
+    .unwrap()
+});
+
+/// UDP clients of a listener whose datagrams were dropped, the listener had too many sessions
+pub static UDP_SESSIONS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_udp_sessions_dropped_total",
+        "Datagrams of new UDP clients dropped because the listener had too many sessions",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+/// Serves the metrics collected by proksi in the Prometheus text format
+pub struct MetricsApp;
+
+#[async_trait]
+impl ServeHttp for MetricsApp {
+    async fn response(&self, _http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let encoder = TextEncoder::new();
+        let mut buffer = vec![];
+        encoder.encode(&prometheus::gather(), &mut buffer).ok();
+
+        Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, encoder.format_type())
+            .header(http::header::CONTENT_LENGTH, buffer.len())
+            .body(buffer)
+            .unwrap()
+    }
+}
+
+/// Creates the HTTP service that exposes the metrics on the given address
+pub fn metrics_service(address: &str) -> Service<HttpServer<MetricsApp>> {
+    let mut service = Service::new("metrics".to_string(), HttpServer::new_app(MetricsApp));
+    service.add_tcp(address);
+    service
+}
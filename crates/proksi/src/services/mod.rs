@@ -14,8 +14,14 @@ pub mod config;
 pub mod discovery;
 pub mod docker;
 pub mod health_check;
+pub mod http3;
 pub mod letsencrypt;
+pub mod listeners;
 pub mod logger;
+pub mod metrics;
+pub mod proxy_protocol;
+pub mod relay;
+pub mod tls_passthrough;
 
 /// Exploring: what if we grouped all the services into a single service using a single thread?
 pub struct BackgroundFunctionService {
@@ -42,7 +48,8 @@ impl pingora::services::Service for BackgroundFunctionService {
         let mut health_service = health_check::HealthService::new();
         let mut docker_service = LabelService::new(self.config.clone(), self.broadcast.clone());
         let mut letsencrypt_service = LetsencryptService::new(self.config.clone());
-        let mut config_server = FileWatcherService::new(self.config.clone());
+        let mut config_server =
+            FileWatcherService::new(self.config.clone(), self.broadcast.clone());
 
         let _ = tokio::join!(
             routing_service.start_service(None, shutdown.clone(), _listeners_per_fd),
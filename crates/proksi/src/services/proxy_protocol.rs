@@ -0,0 +1,334 @@
+use std::{
+    net::SocketAddr, os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc, time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    listeners::ServerAddress,
+    protocols::Stream,
+    server::{Server, ShutdownWatch},
+    services::listening::Service,
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::config::{Config, ProxyProtocolVersion, ServerProxyProtocol};
+use crate::proxy_server::forwarded::TrustedProxies;
+use crate::proxy_server::proxy_protocol::{
+    encode_header, parse_header, socket_path, ConnectionAddrs, ProxyHeader, CLIENT_HEADER,
+    EMITTER_PATH, SERVER_HEADER, VERSION_HEADER,
+};
+use crate::services::relay;
+
+/// The longest CONNECT request accepted by the emitter
+const MAX_CONNECT_REQUEST_LEN: usize = 4096;
+
+/// Reads the PROXY protocol header of the connections of the trusted sources
+/// and relays them to a proxy service listening on a unix socket
+pub struct ProxyProtocolListener {
+    /// The unix socket of the proxy service
+    target: PathBuf,
+    trusted_sources: TrustedProxies,
+    header_timeout: Duration,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(target: PathBuf, config: &ServerProxyProtocol) -> Self {
+        ProxyProtocolListener {
+            target,
+            trusted_sources: TrustedProxies::new(&config.trusted_sources),
+            header_timeout: Duration::from_millis(config.header_timeout_ms),
+        }
+    }
+
+    async fn relay(&self, mut stream: Stream) -> Result<(), anyhow::Error> {
+        let mut addrs = relay::stream_addrs(&stream)?;
+        let peer = addrs.client;
+
+        // Other clients can't tell who they are
+        let mut early_data = vec![];
+        if self.trusted_sources.contains(peer.ip()) {
+            let (header_addrs, data) =
+                tokio::time::timeout(self.header_timeout, read_header(&mut stream))
+                    .await
+                    .map_err(|_| {
+                        anyhow!("timed out reading the PROXY protocol header of {peer}")
+                    })??;
+
+            addrs = header_addrs.unwrap_or(addrs);
+            early_data = data;
+        }
+
+        relay::relay(&mut stream, &self.target, addrs, &early_data).await
+    }
+}
+
+#[async_trait]
+impl ServerApp for ProxyProtocolListener {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Err(err) = self.relay(stream).await {
+            tracing::debug!("PROXY protocol connection closed: {err}");
+        }
+
+        None
+    }
+
+    async fn cleanup(&self) {
+        std::fs::remove_file(&self.target).ok();
+    }
+}
+
+/// Reads the PROXY protocol header, returns the addresses it carries
+/// and the data the client sent after it
+async fn read_header<S>(stream: &mut S) -> Result<(Option<ConnectionAddrs>, Vec<u8>), anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("connection closed before the end of the PROXY protocol header");
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        if let ProxyHeader::Complete { len, addrs } = parse_header(&buf)? {
+            return Ok((addrs, buf.split_off(len)));
+        }
+    }
+}
+
+/// Sends the PROXY protocol header to the upstreams requiring it.
+///
+/// The proxy services connect to the upstreams through this service with a CONNECT request
+/// (see [`crate::proxy_server::proxy_protocol::upstream_proxy`]) carrying the addresses of the client.
+pub struct ProxyProtocolEmitter;
+
+impl ProxyProtocolEmitter {
+    async fn tunnel(&self, mut stream: Stream) -> Result<(), anyhow::Error> {
+        let request = read_connect_request(&mut stream).await?;
+
+        let mut upstream = TcpStream::connect(request.upstream).await?;
+        upstream.set_nodelay(true)?;
+        upstream
+            .write_all(&encode_header(request.version, request.addrs.as_ref()))
+            .await?;
+
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+        copy_bidirectional(&mut stream, &mut upstream).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServerApp for ProxyProtocolEmitter {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Err(err) = self.tunnel(stream).await {
+            tracing::debug!("PROXY protocol upstream connection closed: {err}");
+        }
+
+        None
+    }
+
+    async fn cleanup(&self) {
+        std::fs::remove_file(EMITTER_PATH.as_path()).ok();
+    }
+}
+
+struct ConnectRequest {
+    upstream: SocketAddr,
+    version: ProxyProtocolVersion,
+    addrs: Option<ConnectionAddrs>,
+}
+
+async fn read_connect_request<S>(stream: &mut S) -> Result<ConnectRequest, anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(512);
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= MAX_CONNECT_REQUEST_LEN {
+            bail!("CONNECT request too long");
+        }
+        buf.push(stream.read_u8().await?);
+    }
+
+    parse_connect_request(std::str::from_utf8(&buf)?)
+}
+
+fn parse_connect_request(request: &str) -> Result<ConnectRequest, anyhow::Error> {
+    let mut lines = request.lines();
+    if !lines
+        .next()
+        .is_some_and(|line| line.starts_with("CONNECT "))
+    {
+        bail!("expected a CONNECT request");
+    }
+
+    let (mut upstream, mut client, mut server, mut version) = (None, None, None, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        let value = value.trim();
+        match name.to_lowercase().as_str() {
+            "host" => upstream = value.parse::<SocketAddr>().ok(),
+            CLIENT_HEADER => client = value.parse::<SocketAddr>().ok(),
+            SERVER_HEADER => server = value.parse::<SocketAddr>().ok(),
+            VERSION_HEADER => {
+                version = match value {
+                    "v1" => Some(ProxyProtocolVersion::V1),
+                    "v2" => Some(ProxyProtocolVersion::V2),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    Ok(ConnectRequest {
+        upstream: upstream.ok_or_else(|| anyhow!("CONNECT request without a valid upstream"))?,
+        version: version
+            .ok_or_else(|| anyhow!("CONNECT request without a PROXY protocol version"))?,
+        addrs: client
+            .zip(server)
+            .map(|(client, server)| ConnectionAddrs { client, server }),
+    })
+}
+
+/// Adds the PROXY protocol services to the server, returns the addresses
+/// the HTTPS and HTTP proxy services listen on
+pub fn add_services(
+    server: &mut Server,
+    config: &Config,
+    https_address: &str,
+    http_address: &str,
+) -> (ServerAddress, ServerAddress) {
+    let proxy_protocol = &config.server.proxy_protocol;
+    let addresses = if proxy_protocol.enabled {
+        let https_target = socket_path("https");
+        let http_target = socket_path("http");
+        let addresses = (
+            relay::service_address(&https_target),
+            relay::service_address(&http_target),
+        );
+
+        server.add_service(listener_service(
+            "proxy_protocol_https",
+            https_address,
+            https_target,
+            proxy_protocol,
+        ));
+        server.add_service(listener_service(
+            "proxy_protocol_http",
+            http_address,
+            http_target,
+            proxy_protocol,
+        ));
+        addresses
+    } else {
+        (
+            ServerAddress::Tcp(https_address.to_string(), None),
+            ServerAddress::Tcp(http_address.to_string(), None),
+        )
+    };
+
+    // Upstreams receiving the PROXY protocol header are reached through the emitter
+    let upstreams_use_proxy_protocol = config
+        .routes
+        .iter()
+        .flat_map(|route| {
+            let groups = route.traffic_split.iter().flat_map(|split| &split.groups);
+            let grouped = groups.flat_map(|group| &group.upstreams);
+            route.upstreams.iter().chain(grouped)
+        })
+        .any(|upstream| upstream.proxy_protocol.is_some());
+    if upstreams_use_proxy_protocol {
+        server.add_service(emitter_service());
+    }
+
+    addresses
+}
+
+/// A listener reading the PROXY protocol header on `address`,
+/// relaying the connections to the proxy service listening on `target`
+fn listener_service(
+    name: &str,
+    address: &str,
+    target: PathBuf,
+    config: &ServerProxyProtocol,
+) -> Service<ProxyProtocolListener> {
+    let mut service = Service::new(name.to_string(), ProxyProtocolListener::new(target, config));
+    service.add_tcp(address);
+    service
+}
+
+/// The service sending the PROXY protocol header to the upstreams, on a unix socket
+fn emitter_service() -> Service<ProxyProtocolEmitter> {
+    let mut service = Service::new("proxy_protocol_emitter".to_string(), ProxyProtocolEmitter);
+    let path = EMITTER_PATH.to_string_lossy();
+    service.add_uds(&path, Some(std::fs::Permissions::from_mode(0o600)));
+    service
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header_keeps_the_request() {
+        let mut connection =
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+
+        let (addrs, data) = read_header(&mut connection).await.unwrap();
+        assert_eq!(
+            addrs,
+            Some(ConnectionAddrs {
+                client: "203.0.113.7:56324".parse().unwrap(),
+                server: "10.0.0.1:443".parse().unwrap(),
+            })
+        );
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+
+        let mut connection = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut connection).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_connect_request() {
+        let request = "CONNECT [2001:db8::2]:8080 HTTP/1.1\r\n\
+            Host: [2001:db8::2]:8080\r\n\
+            x-proksi-client: 203.0.113.7:56324\r\n\
+            x-proksi-proxy-protocol: v2\r\n\
+            x-proksi-server: 10.0.0.1:443\r\n\r\n";
+
+        let request = parse_connect_request(request).unwrap();
+        assert_eq!(request.upstream, "[2001:db8::2]:8080".parse().unwrap());
+        assert_eq!(request.version, ProxyProtocolVersion::V2);
+        assert_eq!(
+            request.addrs,
+            Some(ConnectionAddrs {
+                client: "203.0.113.7:56324".parse().unwrap(),
+                server: "10.0.0.1:443".parse().unwrap(),
+            })
+        );
+
+        assert!(parse_connect_request("GET / HTTP/1.1\r\nHost: 10.0.0.2:80\r\n\r\n").is_err());
+    }
+}
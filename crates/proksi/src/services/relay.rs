@@ -0,0 +1,105 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::anyhow;
+use pingora::{listeners::ServerAddress, protocols::Stream};
+use tokio::{
+    io::{copy_bidirectional, AsyncWriteExt},
+    net::{UnixSocket, UnixStream},
+};
+
+use crate::proxy_server::proxy_protocol::{
+    relayed_addrs, socket_path, ConnectionAddrs, RelayedConnection,
+};
+
+/// Numbers the unix sockets the listeners connect from
+static NEXT_RELAYED: AtomicU64 = AtomicU64::new(0);
+
+/// The address of a proxy service behind another listener (ex: a PROXY protocol
+/// or TLS passthrough listener): a unix socket only the user running Proksi can use
+pub fn service_address(path: &Path) -> ServerAddress {
+    ServerAddress::Uds(
+        path.to_string_lossy().into_owned(),
+        Some(std::fs::Permissions::from_mode(0o600)),
+    )
+}
+
+/// The addresses of a connection accepted by a listener in front of a proxy service,
+/// the ones of the original client if another listener relayed it
+pub fn stream_addrs(stream: &Stream) -> Result<ConnectionAddrs, anyhow::Error> {
+    let digest = stream.get_socket_digest();
+    digest
+        .as_ref()
+        .and_then(|d| relayed_addrs(d.peer_addr()?, d.local_addr()?))
+        .ok_or_else(|| anyhow!("unknown addresses of the connection"))
+}
+
+/// Connects to the proxy service listening on `target`, the addresses of the client
+/// are kept for it (see [`crate::proxy_server::proxy_protocol::connection_addrs`])
+/// until the returned [`RelayedConnection`] is dropped
+pub async fn connect(
+    target: &Path,
+    addrs: ConnectionAddrs,
+) -> Result<(UnixStream, RelayedConnection), anyhow::Error> {
+    let id = NEXT_RELAYED.fetch_add(1, Ordering::Relaxed);
+    let path = socket_path(&format!("relayed-{id}"));
+
+    // The proxy service finds the client by the path of the socket the connection comes from
+    let relayed = RelayedConnection::new(path.clone(), addrs);
+    let socket = UnixSocket::new_stream()?;
+    socket.bind(&path)?;
+    let upstream = socket.connect(target).await;
+
+    // The connection keeps the path once the file is removed
+    std::fs::remove_file(&path).ok();
+    Ok((upstream?, relayed))
+}
+
+/// Relays the connection to the proxy service listening on `target`,
+/// `early_data` being the bytes the listener already read from the client
+pub async fn relay(
+    stream: &mut Stream,
+    target: &Path,
+    addrs: ConnectionAddrs,
+    early_data: &[u8],
+) -> Result<(), anyhow::Error> {
+    let (mut upstream, _relayed) = connect(target, addrs).await?;
+
+    upstream.write_all(early_data).await?;
+    copy_bidirectional(stream, &mut upstream).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::protocols::l4::socket::SocketAddr;
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_keeps_the_addresses_of_the_client() {
+        let target = socket_path("relay-test");
+        let listener = UnixListener::bind(&target).unwrap();
+        let addrs = ConnectionAddrs {
+            client: "203.0.113.7:56324".parse().unwrap(),
+            server: "10.0.0.1:443".parse().unwrap(),
+        };
+
+        let (_upstream, relayed) = connect(&target, addrs).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let client = SocketAddr::try_from(accepted.peer_addr().unwrap()).unwrap();
+        let server = SocketAddr::try_from(accepted.local_addr().unwrap()).unwrap();
+        assert_eq!(relayed_addrs(&client, &server), Some(addrs));
+
+        // the addresses are forgotten with the connection
+        drop(relayed);
+        assert_eq!(relayed_addrs(&client, &server), None);
+
+        std::fs::remove_file(&target).ok();
+    }
+}
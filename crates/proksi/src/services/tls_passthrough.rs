@@ -0,0 +1,140 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    listeners::ServerAddress,
+    protocols::Stream,
+    server::{Server, ShutdownWatch},
+    services::listening::Service,
+};
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::config::Config;
+use crate::proxy_server::proxy_protocol::socket_path;
+use crate::proxy_server::tls_passthrough::{parse_client_hello, ClientHello, MAX_CLIENT_HELLO_LEN};
+use crate::services::{listeners::connect_upstream, relay};
+use crate::stores;
+
+/// How long the client can take to send its `ClientHello`
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the SNI of the TLS connections of the HTTPS listener: the connections
+/// of passthrough routes are forwarded to their upstreams as they are, the other
+/// ones are relayed to the HTTPS service listening on a unix socket
+pub struct TlsPassthroughListener {
+    /// The unix socket of the HTTPS service
+    target: PathBuf,
+}
+
+impl TlsPassthroughListener {
+    async fn relay(&self, mut stream: Stream) -> Result<(), anyhow::Error> {
+        let (server_name, client_hello) =
+            tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(&mut stream))
+                .await
+                .map_err(|_| anyhow!("timed out reading the ClientHello"))??;
+
+        // Looked up once the client sent data, a PROXY protocol listener in front
+        // registers the connection before relaying it
+        let addrs = relay::stream_addrs(&stream)?;
+
+        if let Some(server_name) = server_name.as_deref() {
+            if let Some(route) = stores::match_passthrough_route(server_name) {
+                let key = route.load_balancer.connection_key(addrs.client.ip());
+                let (mut upstream, _guard) =
+                    connect_upstream(server_name, &route.load_balancer, &key).await?;
+                upstream.set_nodelay(true)?;
+
+                upstream.write_all(&client_hello).await?;
+                copy_bidirectional(&mut stream, &mut upstream).await?;
+                return Ok(());
+            }
+        }
+
+        relay::relay(&mut stream, &self.target, addrs, &client_hello).await
+    }
+}
+
+#[async_trait]
+impl ServerApp for TlsPassthroughListener {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Err(err) = self.relay(stream).await {
+            tracing::debug!("TLS passthrough connection closed: {err}");
+        }
+
+        None
+    }
+
+    async fn cleanup(&self) {
+        std::fs::remove_file(&self.target).ok();
+    }
+}
+
+/// Reads the `ClientHello` of the connection, returns its SNI and the data read.
+/// Connections that are not TLS or don't send a SNI are terminated by the HTTPS service.
+async fn read_client_hello<S>(stream: &mut S) -> Result<(Option<String>, Vec<u8>), anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("connection closed before the end of the ClientHello");
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        match parse_client_hello(&buf) {
+            Ok(ClientHello::Complete { server_name }) => return Ok((server_name, buf)),
+            Ok(ClientHello::Incomplete) if buf.len() < MAX_CLIENT_HELLO_LEN => {}
+            Ok(ClientHello::Incomplete) | Err(_) => return Ok((None, buf)),
+        }
+    }
+}
+
+/// Adds the TLS passthrough listener to the server when a route uses it,
+/// returns the address the HTTPS service listens on
+pub fn add_service(
+    server: &mut Server,
+    config: &Config,
+    https_address: ServerAddress,
+) -> ServerAddress {
+    if !config.routes.iter().any(|route| route.tls_passthrough) {
+        return https_address;
+    }
+
+    let target = socket_path("tls-passthrough");
+    let target_address = relay::service_address(&target);
+    let mut service = Service::new(
+        "tls_passthrough".to_string(),
+        TlsPassthroughListener { target },
+    );
+    service.add_address(https_address);
+    server.add_service(service);
+
+    target_address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_client_hello_keeps_other_protocols() {
+        let mut connection = &b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..];
+
+        let (server_name, data) = read_client_hello(&mut connection).await.unwrap();
+        assert_eq!(server_name, None);
+        assert_eq!(data, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        let mut connection = &[0x16, 0x03, 0x01, 0x00][..];
+        assert!(read_client_hello(&mut connection).await.is_err());
+    }
+}
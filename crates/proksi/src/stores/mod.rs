@@ -1,7 +1,9 @@
 use std::hash::RandomState;
 
+use arc_swap::ArcSwapOption;
 use once_cell::sync::Lazy;
 use papaya::HashMapRef;
+use pingora::http::RequestHeader;
 use routes::{RouteStore, RouteStoreContainer};
 
 pub mod cache;
@@ -31,19 +33,74 @@ pub use redis_store::RedisStore;
 // ROUTE store
 static ROUTE_STORE: Lazy<RouteStore> = Lazy::new(papaya::HashMap::new);
 
-pub fn get_route_by_key(key: &str) -> Option<RouteStoreContainer> {
+pub fn get_route_by_key(key: &str) -> Option<Vec<RouteStoreContainer>> {
     ROUTE_STORE.pin().get(key).cloned()
 }
 
 pub fn get_routes(
-) -> HashMapRef<'static, String, RouteStoreContainer, RandomState, seize::OwnedGuard<'static>> {
+) -> HashMapRef<'static, String, Vec<RouteStoreContainer>, RandomState, seize::OwnedGuard<'static>>
+{
     ROUTE_STORE.pin_owned()
 }
 
-pub fn insert_route(key: String, value: RouteStoreContainer) {
+pub fn insert_route(key: String, value: Vec<RouteStoreContainer>) {
     ROUTE_STORE.pin().insert(key, value);
 }
 
+// Host of the route serving requests that match no other route
+static DEFAULT_ROUTE_HOST: Lazy<ArcSwapOption<String>> = Lazy::new(ArcSwapOption::empty);
+
+pub fn get_default_route_host() -> Option<String> {
+    DEFAULT_ROUTE_HOST.load().as_deref().cloned()
+}
+
+pub fn set_default_route_host(host: Option<String>) {
+    DEFAULT_ROUTE_HOST.store(host.map(std::sync::Arc::new));
+}
+
+/// Wildcard hosts that can match the given host, the most specific first
+/// (ex: `a.b.example.com` yields `*.b.example.com`, `*.example.com` and `*.com`)
+pub fn wildcard_hosts(host: &str) -> impl Iterator<Item = String> + '_ {
+    host.match_indices('.')
+        .map(|(index, _)| format!("*{}", &host[index..]))
+}
+
+/// Finds the route of a request: the routes of the host are looked up
+/// with an exact match first, then the most specific wildcard host and
+/// finally the default route host (if any).
+/// The first route of the host whose matchers accept the request is returned.
+/// Passthrough routes never serve HTTP requests, their TLS connections are not terminated.
+pub fn match_route(host: &str, req: &RequestHeader) -> Option<RouteStoreContainer> {
+    let store = ROUTE_STORE.pin();
+
+    let routes = store
+        .get(host)
+        .or_else(|| wildcard_hosts(host).find_map(|wildcard| store.get(&wildcard)))
+        .or_else(|| get_default_route_host().and_then(|default_host| store.get(&default_host)))?;
+
+    routes
+        .iter()
+        .find(|route| route.path_matcher.matches(req))
+        .filter(|route| !route.tls_passthrough)
+        .cloned()
+}
+
+/// Finds the passthrough route of a TLS connection by its SNI: the routes of the host
+/// are looked up with an exact match first, then the most specific wildcard host.
+/// Hosts are either all passthrough or not, so only the first route is checked.
+pub fn match_passthrough_route(server_name: &str) -> Option<RouteStoreContainer> {
+    let store = ROUTE_STORE.pin();
+
+    let routes = store
+        .get(server_name)
+        .or_else(|| wildcard_hosts(server_name).find_map(|wildcard| store.get(&wildcard)))?;
+
+    routes
+        .first()
+        .filter(|route| route.tls_passthrough)
+        .cloned()
+}
+
 // CERTIFICATE store
 // static CERTIFICATE_STORE: Lazy<CertificateStore> = Lazy::new(papaya::HashMap::new);
 
@@ -82,3 +139,86 @@ pub fn insert_cache_routing(key: &str, new_value: String, should_override: bool)
 
     CACHE_ROUTING_STORE.pin().insert(key.to_string(), new_value);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_hosts() {
+        let hosts = wildcard_hosts("pr-1.preview.example.com").collect::<Vec<_>>();
+        assert_eq!(
+            hosts,
+            vec!["*.preview.example.com", "*.example.com", "*.com"]
+        );
+        assert_eq!(wildcard_hosts("localhost").count(), 0);
+    }
+
+    #[test]
+    fn test_match_route_precedence() {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+
+        insert_route(
+            "*.preview.match.test".to_string(),
+            vec![RouteStoreContainer::default()],
+        );
+        let exact = RouteStoreContainer {
+            self_signed_certificate: true,
+            ..Default::default()
+        };
+        insert_route("main.preview.match.test".to_string(), vec![exact]);
+
+        assert!(
+            match_route("main.preview.match.test", &req)
+                .unwrap()
+                .self_signed_certificate
+        );
+        assert!(
+            !match_route("pr-1.preview.match.test", &req)
+                .unwrap()
+                .self_signed_certificate
+        );
+        assert!(match_route("preview.match.test", &req).is_none());
+    }
+
+    #[test]
+    fn test_match_route_first_matching_route() {
+        let mut api = RouteStoreContainer {
+            self_signed_certificate: true,
+            ..Default::default()
+        };
+        api.path_matcher
+            .with_pattern(&[std::borrow::Cow::Borrowed("/api/*")]);
+
+        // the catch-all route comes last, the api route takes precedence
+        insert_route(
+            "routes.match.test".to_string(),
+            vec![api, RouteStoreContainer::default()],
+        );
+
+        let request = |path: &str| RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        assert!(
+            match_route("routes.match.test", &request("/api/users"))
+                .unwrap()
+                .self_signed_certificate
+        );
+        assert!(
+            !match_route("routes.match.test", &request("/static/app.js"))
+                .unwrap()
+                .self_signed_certificate
+        );
+    }
+    #[test]
+    fn test_match_route_ignores_passthrough_routes() {
+        let passthrough = RouteStoreContainer {
+            tls_passthrough: true,
+            ..Default::default()
+        };
+        insert_route("passthrough.match.test".to_string(), vec![passthrough]);
+
+        // ex: a Host header naming a passthrough host on a connection terminated by Proksi
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(match_route("passthrough.match.test", &req).is_none());
+        assert!(match_passthrough_route("passthrough.match.test").is_some());
+    }
+}
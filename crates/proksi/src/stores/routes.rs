@@ -1,14 +1,77 @@
 use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
-use http::{HeaderName, HeaderValue};
+use http::{HeaderName, HeaderValue, Method};
 use path_tree::PathTree;
+use pingora::http::RequestHeader;
 use pingora::lb::{selection::RoundRobin, LoadBalancer};
+use regex::Regex;
 
-use crate::config::{RouteCache, RoutePlugin, RouteUpstream};
+use crate::config::{
+    RouteCache, RouteCircuitBreaker, RouteHealthCheck, RouteMatcher, RoutePeerOptions, RoutePlugin,
+    RouteRetry, RouteUpstream, RouteValueMatcher,
+};
+use crate::proxy_server::forwarded::ForwardedHeaders;
+use crate::proxy_server::grpc_web::GrpcWeb;
+use crate::proxy_server::load_balancer::RouteLoadBalancer;
+use crate::proxy_server::mirror::Mirror;
+use crate::proxy_server::redirect::Redirect;
+use crate::proxy_server::rewrite::PathRewrite;
+use crate::proxy_server::static_files::StaticFiles;
+use crate::proxy_server::sticky::StickySessions;
+use crate::proxy_server::traffic_split::{TrafficSplit, UpstreamGroup};
+use crate::proxy_server::upstream_tls::UpstreamTls;
+
+/// How the value of a header or query parameter is matched
+#[derive(Debug, Clone)]
+pub enum RouteStoreValueMatch {
+    Present,
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteStoreValueMatcher {
+    pub name: String,
+    pub value: RouteStoreValueMatch,
+}
+
+impl RouteStoreValueMatcher {
+    fn from_config(matcher: &RouteValueMatcher) -> Result<Self, regex::Error> {
+        let value = if let Some(exact) = matcher.exact.as_ref() {
+            RouteStoreValueMatch::Exact(exact.to_string())
+        } else if let Some(prefix) = matcher.prefix.as_ref() {
+            RouteStoreValueMatch::Prefix(prefix.to_string())
+        } else if let Some(regex) = matcher.regex.as_ref() {
+            RouteStoreValueMatch::Regex(Regex::new(regex)?)
+        } else {
+            RouteStoreValueMatch::Present
+        };
+
+        Ok(RouteStoreValueMatcher {
+            name: matcher.name.to_string(),
+            value,
+        })
+    }
+
+    /// Whether any of the given values (ex: repeated headers) matches
+    fn matches<'a>(&self, mut values: impl Iterator<Item = &'a str>) -> bool {
+        values.any(|value| match &self.value {
+            RouteStoreValueMatch::Present => true,
+            RouteStoreValueMatch::Exact(exact) => value == exact,
+            RouteStoreValueMatch::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            RouteStoreValueMatch::Regex(regex) => regex.is_match(value),
+        })
+    }
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct RouteStorePathMatcher {
     pub pattern: Option<PathTree<usize>>,
+    pub regex: Option<Regex>,
+    pub methods: Vec<Method>,
+    pub headers: Vec<RouteStoreValueMatcher>,
+    pub query: Vec<RouteStoreValueMatcher>,
 }
 
 impl RouteStorePathMatcher {
@@ -31,11 +94,83 @@ impl RouteStorePathMatcher {
         self.pattern = Some(path_tree);
         self
     }
+
+    /// Compiles every matcher of the route (path, methods, headers and query)
+    pub fn with_matcher(&mut self, matcher: &RouteMatcher) -> Result<&mut Self, anyhow::Error> {
+        if let Some(path) = matcher.path.as_ref() {
+            self.with_pattern(&path.patterns);
+            self.regex = path.regex.as_deref().map(Regex::new).transpose()?;
+        }
+
+        self.methods = matcher
+            .methods
+            .iter()
+            .flatten()
+            .map(|method| Method::from_bytes(method.to_uppercase().as_bytes()))
+            .collect::<Result<_, _>>()?;
+
+        self.headers = matcher
+            .headers
+            .iter()
+            .flatten()
+            .map(RouteStoreValueMatcher::from_config)
+            .collect::<Result<_, _>>()?;
+
+        self.query = matcher
+            .query
+            .iter()
+            .flatten()
+            .map(RouteStoreValueMatcher::from_config)
+            .collect::<Result<_, _>>()?;
+
+        Ok(self)
+    }
+
+    /// Whether the request meets every configured matcher
+    pub fn matches(&self, req: &RequestHeader) -> bool {
+        let path = req.uri.path();
+
+        if self
+            .pattern
+            .as_ref()
+            .is_some_and(|p| p.find(path).is_none())
+        {
+            return false;
+        }
+
+        if self.regex.as_ref().is_some_and(|r| !r.is_match(path)) {
+            return false;
+        }
+
+        if !self.methods.is_empty() && !self.methods.contains(&req.method) {
+            return false;
+        }
+
+        let headers_match = self.headers.iter().all(|matcher| {
+            let values = req.headers.get_all(matcher.name.as_str()).iter();
+            matcher.matches(values.filter_map(|v| v.to_str().ok()))
+        });
+
+        if !headers_match {
+            return false;
+        }
+
+        if self.query.is_empty() {
+            return true;
+        }
+
+        let query = req.uri.query().unwrap_or_default().as_bytes();
+        self.query.iter().all(|matcher| {
+            let params = form_urlencoded::parse(query).filter(|(name, _)| name == &matcher.name);
+            let values = params.map(|(_, value)| value).collect::<Vec<_>>();
+            matcher.matches(values.iter().map(AsRef::as_ref))
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct RouteStoreContainer {
-    pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    pub load_balancer: Arc<RouteLoadBalancer>,
     pub path_matcher: RouteStorePathMatcher,
     pub host_header_remove: Vec<String>,
     pub host_header_add: Vec<(HeaderName, HeaderValue)>,
@@ -46,13 +181,41 @@ pub struct RouteStoreContainer {
     pub plugins: HashMap<String, RoutePlugin>,
 
     pub cache: Option<RouteCache>,
+
+    pub health_check: RouteHealthCheck,
+    pub circuit_breaker: Option<RouteCircuitBreaker>,
+    pub retry: Option<RouteRetry>,
+    pub peer_options: Option<RoutePeerOptions>,
+    /// TLS settings of the upstreams, by `ip:port`
+    pub upstream_tls: HashMap<String, Arc<UpstreamTls>>,
+    pub rewrite: Option<Arc<PathRewrite>>,
+    /// Redirects served without reaching the upstreams, in order
+    pub redirects: Vec<Arc<Redirect>>,
+    /// Files served instead of proxying to the upstreams
+    pub static_files: Option<Arc<StaticFiles>>,
+    /// Upstream groups receiving a percentage of the requests (ex: a canary release)
+    pub traffic_split: Option<Arc<TrafficSplit>>,
+    /// Shadow upstream receiving a copy of the requests
+    pub mirror: Option<Arc<Mirror>>,
+    /// Keeps clients on the same upstream with a cookie
+    pub sticky: Option<Arc<StickySessions>>,
+    /// Headers telling the upstreams about the client (ex: `X-Forwarded-For`)
+    pub forwarded_headers: Option<Arc<ForwardedHeaders>>,
+    /// Translates the gRPC-Web requests of browsers to gRPC
+    pub grpc_web: Option<Arc<GrpcWeb>>,
+    /// Forwards the TLS connections to the upstreams without terminating them
+    pub tls_passthrough: bool,
+    /// The configuration the route was built from, to find the routes changed by a reload
+    pub route_config: serde_json::Value,
 }
 
 impl Default for RouteStoreContainer {
     fn default() -> Self {
         RouteStoreContainer {
             load_balancer: Arc::new(
-                LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]).unwrap(),
+                LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"])
+                    .unwrap()
+                    .into(),
             ),
             path_matcher: RouteStorePathMatcher::default(),
             host_header_remove: Vec::with_capacity(0),
@@ -61,12 +224,27 @@ impl Default for RouteStoreContainer {
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(0),
             cache: None,
+            health_check: RouteHealthCheck::default(),
+            circuit_breaker: None,
+            retry: None,
+            peer_options: None,
+            upstream_tls: HashMap::new(),
+            rewrite: None,
+            redirects: Vec::with_capacity(0),
+            static_files: None,
+            traffic_split: None,
+            mirror: None,
+            sticky: None,
+            forwarded_headers: None,
+            grpc_web: None,
+            tls_passthrough: false,
+            route_config: serde_json::Value::Null,
         }
     }
 }
 
 impl RouteStoreContainer {
-    pub fn new(load_balancer: LoadBalancer<RoundRobin>) -> Self {
+    pub fn new(load_balancer: RouteLoadBalancer) -> Self {
         RouteStoreContainer {
             load_balancer: Arc::new(load_balancer),
             path_matcher: RouteStorePathMatcher::new(),
@@ -76,24 +254,64 @@ impl RouteStoreContainer {
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(5),
             cache: None,
+            health_check: RouteHealthCheck::default(),
+            circuit_breaker: None,
+            retry: None,
+            peer_options: None,
+            upstream_tls: HashMap::new(),
+            rewrite: None,
+            redirects: Vec::with_capacity(0),
+            static_files: None,
+            traffic_split: None,
+            mirror: None,
+            sticky: None,
+            forwarded_headers: None,
+            grpc_web: None,
+            tls_passthrough: false,
+            route_config: serde_json::Value::Null,
         }
     }
+
+    /// The load balancer of the given traffic split group,
+    /// or the one of the route's upstreams for `None`
+    pub fn group_load_balancer(&self, group: Option<usize>) -> &Arc<RouteLoadBalancer> {
+        self.group(group)
+            .map_or(&self.load_balancer, |group| &group.load_balancer)
+    }
+
+    /// The upstreams of the given traffic split group, or the ones of the route for `None`
+    pub fn group_upstreams(&self, group: Option<usize>) -> &[RouteUpstream] {
+        self.group(group)
+            .map_or(&self.upstreams, |group| &group.upstreams)
+    }
+
+    /// Every load balancer of the route: the one of its upstreams and the ones of its groups
+    pub fn load_balancers(&self) -> impl Iterator<Item = &Arc<RouteLoadBalancer>> {
+        let groups = self.traffic_split.iter().flat_map(|split| &split.groups);
+        std::iter::once(&self.load_balancer).chain(groups.map(|group| &group.load_balancer))
+    }
+
+    fn group(&self, group: Option<usize>) -> Option<&UpstreamGroup> {
+        self.traffic_split.as_ref()?.groups.get(group?)
+    }
 }
 
 // LoadBalancer<RoundRobin>
-/// A store for routes that is updated in a background thread
-pub type RouteStore = papaya::HashMap<String, RouteStoreContainer>;
+/// A store for routes that is updated in a background thread.
+/// Each host holds its routes in order, the first one matching a request wins.
+pub type RouteStore = papaya::HashMap<String, Vec<RouteStoreContainer>>;
 
 #[cfg(test)]
 
 mod tests {
 
     use super::*;
+    use crate::config::RoutePathMatcher;
 
     #[test]
     fn test_router_container_defaults_empty_pattern() {
         let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["1.1.1.1:80"]).unwrap();
-        let route_store = RouteStoreContainer::new(load_balancer);
+        let route_store = RouteStoreContainer::new(load_balancer.into());
 
         assert!(route_store.path_matcher.pattern.is_none());
     }
@@ -101,7 +319,7 @@ mod tests {
     #[test]
     fn test_router_container_works_with_valid_and_invalid_pattern() {
         let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["1.1.1.1:80"]).unwrap();
-        let mut route_store = RouteStoreContainer::new(load_balancer);
+        let mut route_store = RouteStoreContainer::new(load_balancer.into());
         route_store
             .path_matcher
             .with_pattern(&[Cow::Borrowed("/auth")]);
@@ -117,4 +335,50 @@ mod tests {
 
         assert!(pattern.find("/invalid").is_none());
     }
+
+    #[test]
+    fn test_path_matcher_combines_matchers() {
+        let matcher = RouteMatcher {
+            path: Some(RoutePathMatcher {
+                patterns: vec![Cow::Borrowed("/api/*")],
+                regex: Some(Cow::Borrowed("^/api/v[0-9]+/")),
+            }),
+            methods: Some(vec![Cow::Borrowed("post")]),
+            headers: Some(vec![
+                RouteValueMatcher {
+                    name: Cow::Borrowed("x-api-version"),
+                    exact: Some(Cow::Borrowed("2")),
+                    ..Default::default()
+                },
+                RouteValueMatcher {
+                    name: Cow::Borrowed("accept"),
+                    prefix: Some(Cow::Borrowed("application/json")),
+                    ..Default::default()
+                },
+            ]),
+            query: Some(vec![RouteValueMatcher {
+                name: Cow::Borrowed("tag"),
+                regex: Some(Cow::Borrowed("^beta")),
+                ..Default::default()
+            }]),
+        };
+
+        let mut path_matcher = RouteStorePathMatcher::new();
+        path_matcher.with_matcher(&matcher).unwrap();
+
+        let request = |method: &str, path: &str, version: &str| {
+            let mut req = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+            req.insert_header("x-api-version", version).unwrap();
+            req.insert_header("accept", "application/json; charset=utf-8")
+                .unwrap();
+            req
+        };
+
+        assert!(path_matcher.matches(&request("POST", "/api/v1/users?tag=beta%201", "2")));
+        // every matcher must match
+        assert!(!path_matcher.matches(&request("GET", "/api/v1/users?tag=beta", "2")));
+        assert!(!path_matcher.matches(&request("POST", "/api/users?tag=beta", "2")));
+        assert!(!path_matcher.matches(&request("POST", "/api/v1/users?tag=beta", "1")));
+        assert!(!path_matcher.matches(&request("POST", "/api/v1/users", "2")));
+    }
 }